@@ -18,9 +18,13 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use sphinx_packet::constants::{
     DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH,
 };
-use sphinx_packet::crypto::keygen;
-use sphinx_packet::header::delays;
-use sphinx_packet::route::{Destination, DestinationAddressBytes, Node, NodeAddressBytes};
+use sphinx_packet::crypto::{keygen, DefaultCipherSuite, EphemeralSecret, PrivateKey, PublicKey};
+use sphinx_packet::header::keys::{KeyMaterial, ReplayTag};
+use sphinx_packet::header::routing::EncapsulatedRoutingInformation;
+use sphinx_packet::header::{delays, tlv::TlvStream};
+use sphinx_packet::keyring::NodeKeyring;
+use sphinx_packet::replay::ReplayFilter;
+use sphinx_packet::route::{Destination, DestinationAddressBytes, Node, NodeAddressBytes, Route};
 use sphinx_packet::SphinxPacket;
 use std::time::Duration;
 
@@ -53,17 +57,45 @@ fn bench_new_no_surb(c: &mut Criterion) {
         [4u8; IDENTIFIER_LENGTH],
     );
 
+    let route = Route::new(route.to_vec(), delays, destination).unwrap();
+
     let message = vec![13u8, 16];
 
     c.bench_function("sphinx creation", |b| {
+        b.iter(|| SphinxPacket::new(black_box(message.clone()), black_box(&route)).unwrap())
+    });
+}
+
+// only the routing information, with the keys derived beforehand, so that the cost of
+// its construction is not hidden behind the key derivation and the payload encryption
+fn bench_new_routing_info_no_surb(c: &mut Criterion) {
+    let route: Vec<_> = (0..5u8)
+        .map(|i| {
+            let (_, node_pk) = keygen();
+            Node::new(
+                NodeAddressBytes::from_bytes([i; NODE_ADDRESS_LENGTH]),
+                node_pk,
+            )
+        })
+        .collect();
+    let delays = delays::generate_from_average_duration(route.len(), Duration::from_millis(10));
+    let destination = Destination::new(
+        DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+        [4u8; IDENTIFIER_LENGTH],
+    );
+    let hop_records = vec![TlvStream::new(); route.len()];
+    let key_material = KeyMaterial::<DefaultCipherSuite>::derive(&route, &EphemeralSecret::new());
+
+    c.bench_function("sphinx routing information creation", |b| {
         b.iter(|| {
-            SphinxPacket::new(
-                black_box(message.clone()),
+            EncapsulatedRoutingInformation::new(
                 black_box(&route),
                 black_box(&destination),
                 black_box(&delays),
+                black_box(&hop_records),
+                black_box(&key_material.routing_keys),
+                &Default::default(),
             )
-            .unwrap()
         })
     });
 }
@@ -93,7 +125,8 @@ fn bench_unwrap(c: &mut Criterion) {
     );
 
     let message = vec![13u8, 16];
-    let packet = SphinxPacket::new(message, &route, &destination, &delays).unwrap();
+    let route = Route::new(route.to_vec(), delays, destination).unwrap();
+    let packet = SphinxPacket::new(message, &route).unwrap();
 
     // technically it's not benching only unwrapping, but also "make_packet_copy"
     // but it's relatively small
@@ -106,6 +139,64 @@ fn bench_unwrap(c: &mut Criterion) {
     });
 }
 
-criterion_group!(sphinx, bench_new_no_surb, bench_unwrap);
+// the same packet is processed over and over again, so it must not be rejected as a replay
+struct NoReplayFilter;
+
+impl ReplayFilter for NoReplayFilter {
+    fn contains(&self, _: &ReplayTag) -> bool {
+        false
+    }
+
+    fn insert(&mut self, _: ReplayTag) {}
+}
+
+// the key is found by trial, so packets of the previous epoch take three key exchanges
+// instead of one
+fn bench_keyring_process(c: &mut Criterion) {
+    let previous_key = PrivateKey::new();
+    let previous_node = Node::new(
+        NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+        PublicKey::from(&previous_key),
+    );
+    let mut keyring: NodeKeyring<DefaultCipherSuite, NoReplayFilter> =
+        NodeKeyring::new(1, previous_key, NoReplayFilter);
+    let current_key = PrivateKey::new();
+    let current_node = Node::new(
+        NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+        PublicKey::from(&current_key),
+    );
+    keyring.set_next(2, current_key, NoReplayFilter).unwrap();
+    keyring.rotate().unwrap();
+    keyring
+        .set_next(3, PrivateKey::new(), NoReplayFilter)
+        .unwrap();
+
+    let destination = Destination::new(
+        DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+        [4u8; IDENTIFIER_LENGTH],
+    );
+    let packet_for = |node: Node| {
+        let delays = delays::generate_from_average_duration(1, Duration::from_millis(10));
+        let route = Route::new(vec![node], delays, destination.clone()).unwrap();
+        SphinxPacket::new(vec![13u8, 16], &route).unwrap()
+    };
+    let current_packet = packet_for(current_node);
+    let previous_packet = packet_for(previous_node);
+
+    c.bench_function("keyring processing with current key", |b| {
+        b.iter(|| keyring.process(make_packet_copy(&current_packet)).unwrap())
+    });
+    c.bench_function("keyring processing with previous key", |b| {
+        b.iter(|| keyring.process(make_packet_copy(&previous_packet)).unwrap())
+    });
+}
+
+criterion_group!(
+    sphinx,
+    bench_new_no_surb,
+    bench_new_routing_info_no_surb,
+    bench_unwrap,
+    bench_keyring_process
+);
 
 criterion_main!(sphinx);
@@ -0,0 +1,445 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH};
+use crate::crypto::{DefaultGroup, PrivateKey, PublicKey, SphinxGroup};
+use crate::header::delays::Delay;
+use crate::header::keys::BlindingFactor;
+use crate::header::tlv::{TlvRecord, TlvStream, TlvType};
+use crate::params::SphinxParams;
+use crate::recipient::AEAD_TAG_SIZE;
+use crate::route::{
+    Destination, DestinationAddressBytes, Node, NodeAddressBytes, Route, SURBIdentifier,
+};
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+/// Type of the record carrying the data the recipient encrypted for a hop of its blinded route.
+pub const BLINDED_HOP_RECORD_TYPE: TlvType = 4;
+
+// the data of forward hops is the address of the next hop,
+// while the final hop learns the destination
+const FORWARD_HOP_DATA_LENGTH: usize = NODE_ADDRESS_LENGTH;
+const FINAL_HOP_DATA_LENGTH: usize = DESTINATION_ADDRESS_LENGTH + IDENTIFIER_LENGTH;
+
+const BLINDED_HOP_KDF_INFO: &[u8] = b"sphinx-blinded-hop/";
+
+// every hop data is encrypted under a key derived from a fresh ephemeral key
+const NONCE: [u8; 12] = [0u8; 12];
+
+// keys shared only by the recipient and a single hop of its blinded route
+struct HopKeys {
+    data_key: Key,
+    blinding_factor: BlindingFactor,
+}
+
+impl HopKeys {
+    fn derive<G: SphinxGroup>(
+        shared_secret: &PublicKey<G>,
+        ephemeral_key: &PublicKey<G>,
+    ) -> Result<Self> {
+        if shared_secret.as_bytes().as_ref().iter().all(|b| *b == 0) {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::InvalidGroupElement,
+            ));
+        }
+
+        let info: Vec<u8> = BLINDED_HOP_KDF_INFO
+            .iter()
+            .chain(ephemeral_key.as_bytes().as_ref())
+            .cloned()
+            .collect();
+        let mut data_key = Key::default();
+        let mut blinding_factor = BlindingFactor::default();
+        let mut output = [0u8; 32 + 32];
+        // this can only fail if we requested more than 255 * 32 bytes, which we never do
+        Hkdf::<Sha256>::new(None, shared_secret.as_bytes().as_ref())
+            .expand(&info, &mut output)
+            .unwrap();
+        data_key.copy_from_slice(&output[..32]);
+        blinding_factor.copy_from_slice(&output[32..]);
+
+        Ok(HopKeys {
+            data_key,
+            blinding_factor,
+        })
+    }
+
+    fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+        ChaCha20Poly1305::new(&self.data_key)
+            .encrypt(Nonce::from_slice(&NONCE), data)
+            .map_err(|_| {
+                Error::with_reason(ErrorKind::InvalidRouting, ErrorReason::MalformedBlindedHop)
+            })
+    }
+
+    fn open(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        ChaCha20Poly1305::new(&self.data_key)
+            .decrypt(Nonce::from_slice(&NONCE), ciphertext)
+            .map_err(|_| Error::with_reason(ErrorKind::InvalidRouting, ErrorReason::InvalidMac))
+    }
+}
+
+fn encrypted_data_len<G: SphinxGroup>(data_length: usize) -> usize {
+    G::ELEMENT_SIZE + data_length + AEAD_TAG_SIZE
+}
+
+/// Hop of the blinded route as seen by the sender: a key which can not be linked
+/// to the key of the node, and the data only the node is able to decrypt.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlindedHop<G: SphinxGroup = DefaultGroup> {
+    blinded_key: PublicKey<G>,
+    encrypted_data: Vec<u8>,
+}
+
+impl<G: SphinxGroup> BlindedHop<G> {
+    pub fn blinded_key(&self) -> &PublicKey<G> {
+        &self.blinded_key
+    }
+
+    pub fn encrypted_data(&self) -> &[u8] {
+        &self.encrypted_data
+    }
+
+    pub fn to_record(&self) -> TlvRecord {
+        TlvRecord::new(BLINDED_HOP_RECORD_TYPE, self.encrypted_data.clone())
+    }
+}
+
+/// Route from the introduction node to the destination, prepared and published by the recipient,
+/// so that it could be reached without revealing any of the nodes but the introduction node.
+///
+/// Every hop decrypts the address of the next hop (or the destination) from its record,
+/// and additionally blinds the shared secret of the next hop, whose key the sender
+/// only knows in the blinded form. The sender has to reach the introduction node itself,
+/// see `SphinxPacketBuilder::build_packet_with_blinded_route`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlindedRoute<G: SphinxGroup = DefaultGroup> {
+    introduction_node: NodeAddressBytes,
+    hops: Vec<BlindedHop<G>>,
+}
+
+impl<G: SphinxGroup> BlindedRoute<G> {
+    /// Creates the blinded route through the nodes, starting with the introduction node.
+    pub fn new(nodes: &[Node<G>], destination: &Destination) -> Result<Self> {
+        if nodes.is_empty() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::EmptyRoute,
+            ));
+        }
+
+        // blinding factors of all the previous hops, which they apply to the shared secret
+        let mut blinding_factors: Vec<BlindingFactor> = Vec::with_capacity(nodes.len());
+        let mut hops = Vec::with_capacity(nodes.len());
+        for (i, node) in nodes.iter().enumerate() {
+            let blinded_key = blinding_factors
+                .iter()
+                .fold(node.pub_key, |key, blinding_factor| {
+                    key.blind(blinding_factor)
+                });
+
+            let ephemeral_secret = PrivateKey::<G>::new();
+            let ephemeral_key = PublicKey::from(&ephemeral_secret);
+            let keys = HopKeys::derive(
+                &ephemeral_secret.diffie_hellman(&node.pub_key),
+                &ephemeral_key,
+            )?;
+            let hop_data: Vec<u8> = match nodes.get(i + 1) {
+                Some(next_node) => next_node.address.as_bytes().to_vec(),
+                None => destination
+                    .address
+                    .as_bytes()
+                    .iter()
+                    .chain(destination.identifier.iter())
+                    .cloned()
+                    .collect(),
+            };
+
+            let mut encrypted_data = ephemeral_key.as_bytes().as_ref().to_vec();
+            encrypted_data.extend_from_slice(&keys.seal(&hop_data)?);
+            blinding_factors.push(keys.blinding_factor);
+            hops.push(BlindedHop {
+                blinded_key,
+                encrypted_data,
+            });
+        }
+
+        Ok(BlindedRoute {
+            introduction_node: nodes[0].address,
+            hops,
+        })
+    }
+
+    pub fn introduction_node(&self) -> NodeAddressBytes {
+        self.introduction_node
+    }
+
+    pub fn hops(&self) -> &[BlindedHop<G>] {
+        &self.hops
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.hops.len()
+    }
+
+    /// Joins the nodes chosen by the sender with the hops of the blinded route, returning
+    /// the records which need to be attached to the respective hops. Addresses of the hidden
+    /// hops and the destination are left empty, as they are only revealed to the hops before them.
+    /// Note that the records take space in the routing information, so the params
+    /// might need to allow for longer routes than the number of hops.
+    pub fn route_with_prefix(
+        &self,
+        prefix: &[Node<G>],
+        delays: Vec<Delay>,
+        params: &SphinxParams,
+    ) -> Result<(Route<G>, Vec<TlvStream>)> {
+        let hidden_address = NodeAddressBytes::from_bytes([0u8; NODE_ADDRESS_LENGTH]);
+        let nodes = prefix
+            .iter()
+            .cloned()
+            .chain(self.hops.iter().enumerate().map(|(i, hop)| {
+                let address = if i == 0 {
+                    self.introduction_node
+                } else {
+                    hidden_address
+                };
+                Node::new(address, hop.blinded_key)
+            }))
+            .collect();
+        let hidden_destination = Destination::new(
+            DestinationAddressBytes::from_bytes([0u8; DESTINATION_ADDRESS_LENGTH]),
+            [0u8; IDENTIFIER_LENGTH],
+        );
+        let route = Route::new_with_params(nodes, delays, hidden_destination, params)?;
+
+        let hop_records = vec![TlvStream::new(); prefix.len()]
+            .into_iter()
+            .chain(self.hops.iter().map(|hop| {
+                let mut records = TlvStream::new();
+                records.insert(hop.to_record());
+                records
+            }))
+            .collect();
+        Ok((route, hop_records))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.introduction_node.as_bytes().to_vec();
+        bytes.push(self.hops.len() as u8);
+        for hop in &self.hops {
+            bytes.extend_from_slice(hop.blinded_key.as_bytes().as_ref());
+            bytes.extend_from_slice(&hop.encrypted_data);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let prefix_length = NODE_ADDRESS_LENGTH + 1;
+        if bytes.len() < prefix_length {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::TooShort {
+                    minimum: prefix_length,
+                    actual: bytes.len(),
+                },
+            ));
+        }
+        let introduction_node =
+            NodeAddressBytes::try_from_byte_slice(&bytes[..NODE_ADDRESS_LENGTH])?;
+        let hop_count = bytes[NODE_ADDRESS_LENGTH] as usize;
+        if hop_count == 0 {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::EmptyRoute,
+            ));
+        }
+
+        let forward_hop_length = G::ELEMENT_SIZE + encrypted_data_len::<G>(FORWARD_HOP_DATA_LENGTH);
+        let final_hop_length = G::ELEMENT_SIZE + encrypted_data_len::<G>(FINAL_HOP_DATA_LENGTH);
+        let expected = prefix_length + (hop_count - 1) * forward_hop_length + final_hop_length;
+        if bytes.len() != expected {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::UnexpectedLength {
+                    expected,
+                    actual: bytes.len(),
+                },
+            ));
+        }
+
+        let mut hops = Vec::with_capacity(hop_count);
+        let mut i = prefix_length;
+        for hop_index in 0..hop_count {
+            let hop_length = if hop_index + 1 == hop_count {
+                final_hop_length
+            } else {
+                forward_hop_length
+            };
+            let hop_bytes = &bytes[i..i + hop_length];
+            hops.push(BlindedHop {
+                blinded_key: PublicKey::try_from_byte_slice(&hop_bytes[..G::ELEMENT_SIZE])?,
+                encrypted_data: hop_bytes[G::ELEMENT_SIZE..].to_vec(),
+            });
+            i += hop_length;
+        }
+
+        Ok(BlindedRoute {
+            introduction_node,
+            hops,
+        })
+    }
+}
+
+// recovers the data the recipient encrypted for the hop, if it is a hop of a blinded route
+fn open_hop_data<G: SphinxGroup>(
+    records: &TlvStream,
+    node_secret_key: &PrivateKey<G>,
+    data_length: usize,
+) -> Result<Option<(Vec<u8>, BlindingFactor)>> {
+    let encrypted_data = match records.get(BLINDED_HOP_RECORD_TYPE) {
+        Some(encrypted_data) => encrypted_data,
+        None => return Ok(None),
+    };
+    if encrypted_data.len() != encrypted_data_len::<G>(data_length) {
+        return Err(Error::with_reason(
+            ErrorKind::InvalidRouting,
+            ErrorReason::MalformedBlindedHop,
+        ));
+    }
+
+    let (ephemeral_key_bytes, ciphertext) = encrypted_data.split_at(G::ELEMENT_SIZE);
+    let ephemeral_key = PublicKey::<G>::try_from_byte_slice(ephemeral_key_bytes)?;
+    let keys = HopKeys::derive(
+        &node_secret_key.diffie_hellman(&ephemeral_key),
+        &ephemeral_key,
+    )?;
+    let data = keys.open(ciphertext)?;
+    Ok(Some((data, keys.blinding_factor)))
+}
+
+/// Reveals the next hop of the blinded route to the node, together with the blinding factor
+/// it has to additionally apply to the shared secret of the next hop.
+pub(crate) fn unblind_forward_hop<G: SphinxGroup>(
+    records: &TlvStream,
+    node_secret_key: &PrivateKey<G>,
+) -> Result<Option<(NodeAddressBytes, BlindingFactor)>> {
+    match open_hop_data(records, node_secret_key, FORWARD_HOP_DATA_LENGTH)? {
+        Some((data, blinding_factor)) => Ok(Some((
+            NodeAddressBytes::try_from_byte_slice(&data)?,
+            blinding_factor,
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Reveals the destination of the blinded route to its final hop.
+pub(crate) fn unblind_final_hop<G: SphinxGroup>(
+    records: &TlvStream,
+    node_secret_key: &PrivateKey<G>,
+) -> Result<Option<(DestinationAddressBytes, SURBIdentifier)>> {
+    match open_hop_data(records, node_secret_key, FINAL_HOP_DATA_LENGTH)? {
+        Some((data, _)) => {
+            let (address, identifier_bytes) = data.split_at(DESTINATION_ADDRESS_LENGTH);
+            let mut identifier: SURBIdentifier = Default::default();
+            identifier.copy_from_slice(identifier_bytes);
+            Ok(Some((
+                DestinationAddressBytes::try_from_byte_slice(address)?,
+                identifier,
+            )))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod blinded_route {
+    use super::*;
+    use crate::crypto::keygen;
+    use crate::test_utils::fixtures::destination_fixture;
+
+    fn nodes_and_keys(count: u8) -> (Vec<Node>, Vec<PrivateKey>) {
+        (0..count)
+            .map(|i| {
+                let (secret_key, public_key) = keygen();
+                let address = NodeAddressBytes::from_bytes([i + 1; NODE_ADDRESS_LENGTH]);
+                (Node::new(address, public_key), secret_key)
+            })
+            .unzip()
+    }
+
+    #[test]
+    fn it_hides_all_the_keys_but_the_one_of_introduction_node() {
+        let (nodes, _) = nodes_and_keys(3);
+        let blinded_route = BlindedRoute::new(&nodes, &destination_fixture()).unwrap();
+        assert_eq!(nodes[0].address, blinded_route.introduction_node());
+        assert_eq!(&nodes[0].pub_key, blinded_route.hops()[0].blinded_key());
+        assert_ne!(&nodes[1].pub_key, blinded_route.hops()[1].blinded_key());
+        assert_ne!(&nodes[2].pub_key, blinded_route.hops()[2].blinded_key());
+    }
+
+    #[test]
+    fn only_the_hop_can_recover_its_next_hop() {
+        let (nodes, secret_keys) = nodes_and_keys(2);
+        let destination = destination_fixture();
+        let blinded_route = BlindedRoute::new(&nodes, &destination).unwrap();
+
+        let mut records = TlvStream::new();
+        records.insert(blinded_route.hops()[0].to_record());
+        let (next_hop, _) = unblind_forward_hop(&records, &secret_keys[0])
+            .unwrap()
+            .unwrap();
+        assert_eq!(nodes[1].address, next_hop);
+        assert!(unblind_forward_hop(&records, &secret_keys[1]).is_err());
+
+        let mut records = TlvStream::new();
+        records.insert(blinded_route.hops()[1].to_record());
+        assert_eq!(
+            (destination.address, destination.identifier),
+            unblind_final_hop(&records, &secret_keys[1])
+                .unwrap()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn hops_outside_of_blinded_routes_are_left_alone() {
+        let (_, secret_keys) = nodes_and_keys(1);
+        assert!(unblind_forward_hop(&TlvStream::new(), &secret_keys[0])
+            .unwrap()
+            .is_none());
+        assert!(unblind_final_hop(&TlvStream::new(), &secret_keys[0])
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn it_can_be_converted_to_and_from_bytes() {
+        let (nodes, _) = nodes_and_keys(3);
+        let blinded_route = BlindedRoute::new(&nodes, &destination_fixture()).unwrap();
+        let bytes = blinded_route.to_bytes();
+        assert_eq!(blinded_route, BlindedRoute::from_bytes(&bytes).unwrap());
+        assert!(BlindedRoute::<DefaultGroup>::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn it_can_not_be_empty() {
+        assert!(BlindedRoute::<DefaultGroup>::new(&[], &destination_fixture()).is_err());
+    }
+}
@@ -19,10 +19,12 @@ use sha2::Sha256;
 pub const SECURITY_PARAMETER: usize = 16; // k in the Sphinx paper. Measured in bytes; 128 bits.
 pub const MAX_PATH_LENGTH: usize = 5; // r in the Sphinx paper
 pub const BLINDING_FACTOR_SIZE: usize = 2 * SECURITY_PARAMETER;
+pub const REPLAY_TAG_SIZE: usize = 2 * SECURITY_PARAMETER;
 pub const ROUTING_KEYS_LENGTH: usize = crypto::STREAM_CIPHER_KEY_SIZE
     + INTEGRITY_MAC_KEY_SIZE
     + PAYLOAD_KEY_SIZE
-    + BLINDING_FACTOR_SIZE;
+    + BLINDING_FACTOR_SIZE
+    + REPLAY_TAG_SIZE;
 pub const HKDF_INPUT_SEED: &[u8; 97] = b"Dwste mou enan moxlo arketa makru kai ena upomoxlio gia na ton topothetisw kai tha kinisw thn gh.";
 pub const STREAM_CIPHER_OUTPUT_LENGTH: usize =
     (NODE_META_INFO_SIZE + HEADER_INTEGRITY_MAC_SIZE) * (MAX_PATH_LENGTH + 1);
@@ -38,6 +40,7 @@ pub const NODE_META_INFO_SIZE: usize =
 pub const FINAL_NODE_META_INFO_LENGTH: usize =
     DESTINATION_ADDRESS_LENGTH + IDENTIFIER_LENGTH + FLAG_LENGTH + VERSION_LENGTH; // the meta info for the final hop might be of a different size
 pub const FLAG_LENGTH: usize = 1;
+pub const TLV_SECTION_LENGTH_PREFIX_SIZE: usize = 2; // per-hop tlv records are prefixed with their length as u16
 pub const PAYLOAD_SIZE: usize = 1024;
 pub const VERSION_LENGTH: usize = 3; // since version is represented as 3 u8 values: major, minor and patch
                                      // we need the single byte to detect padding length
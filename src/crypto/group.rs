@@ -0,0 +1,478 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::keys::clamp_scalar_bytes;
+use crate::header::keys::BlindingFactor;
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use curve25519_dalek::{
+    constants::{ED25519_BASEPOINT_TABLE, RISTRETTO_BASEPOINT_TABLE},
+    montgomery::MontgomeryPoint,
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+};
+use ed448_goldilocks::curve::MontgomeryPoint as Curve448MontgomeryPoint;
+use ed448_goldilocks::Scalar as Curve448Scalar;
+use rand::{CryptoRng, RngCore};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use zeroize::Zeroize;
+
+pub const X25519_SCALAR_SIZE: usize = 32;
+pub const X25519_ELEMENT_SIZE: usize = 32;
+pub const RISTRETTO255_SCALAR_SIZE: usize = 32;
+pub const RISTRETTO255_ELEMENT_SIZE: usize = 32;
+pub const X448_SCALAR_SIZE: usize = 56;
+pub const X448_ELEMENT_SIZE: usize = 56;
+
+/// Group in which the sphinx key exchange and the blinding of the shared secrets happen.
+///
+/// The sender picks a random scalar `x` and puts `g^x` into the header. Every hop computes
+/// the shared secret with its own private key and then blinds the group element using the
+/// blinding factor derived from that shared secret before passing the header on.
+/// The sender is thus required to compute `pub_i^{x * b_0 * ... * b_{i-1}}` for every hop `i`,
+/// which is exactly what `blinded_diffie_hellman` does.
+pub trait SphinxGroup:
+    Copy + Clone + fmt::Debug + Default + PartialEq + Eq + Send + Sync + 'static
+{
+    type Scalar: Clone + Zeroize + Send + Sync;
+    type Element: Copy + Clone + fmt::Debug + PartialEq + Eq + Hash + Send + Sync;
+
+    type ScalarBytes: AsRef<[u8]> + Copy + fmt::Debug + PartialEq + Eq;
+    type ElementBytes: AsRef<[u8]> + Copy + fmt::Debug + PartialEq + Eq;
+
+    /// Size of the encoded scalar.
+    const SCALAR_SIZE: usize;
+
+    /// Size of the encoded group element, i.e. of the shared secret put in the header.
+    const ELEMENT_SIZE: usize;
+
+    fn random_scalar<R: RngCore + CryptoRng>(rng: &mut R) -> Self::Scalar;
+
+    /// Maps the blinding factor derived from the shared secret onto a scalar.
+    fn blinding_scalar(blinding_factor: &BlindingFactor) -> Self::Scalar;
+
+    /// Computes `g^scalar`.
+    fn base_mul(scalar: &Self::Scalar) -> Self::Element;
+
+    /// Computes `element^scalar`.
+    fn diffie_hellman(scalar: &Self::Scalar, element: &Self::Element) -> Self::Element;
+
+    /// Computes `element^{scalar * blinders[0] * ... * blinders[n-1]}`.
+    ///
+    /// By default the exponentiations are performed one after another, however, groups
+    /// with well-defined scalar arithmetic should multiply the scalars together first
+    /// so that only a single exponentiation is needed.
+    fn blinded_diffie_hellman(
+        scalar: &Self::Scalar,
+        blinders: &[Self::Scalar],
+        element: &Self::Element,
+    ) -> Self::Element {
+        blinders
+            .iter()
+            .fold(Self::diffie_hellman(scalar, element), |acc, blinder| {
+                Self::diffie_hellman(blinder, &acc)
+            })
+    }
+
+    /// Blinds the element with the scalar corresponding to the provided blinding factor.
+    fn blind(element: &Self::Element, blinding_factor: &BlindingFactor) -> Self::Element {
+        Self::diffie_hellman(&Self::blinding_scalar(blinding_factor), element)
+    }
+
+    fn encode_scalar(scalar: &Self::Scalar) -> Self::ScalarBytes;
+    fn decode_scalar(bytes: &[u8]) -> Result<Self::Scalar>;
+
+    fn encode_element(element: &Self::Element) -> &Self::ElementBytes;
+    fn decode_element(bytes: &[u8]) -> Result<Self::Element>;
+}
+
+/// The original Curve25519 behaviour: scalars are clamped upon creation and multiplied
+/// together (modulo the group order) before the exponentiation, while the elements are
+/// represented by their Montgomery u-coordinate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct X25519;
+
+/// Prime-order group built on top of Curve25519. Since there is no cofactor, there is also
+/// no ambiguity about whether the scalars should be clamped or reduced - they are always
+/// reduced modulo the group order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Ristretto255;
+
+/// Curve448 in its Montgomery form providing ~224-bit security level.
+/// All scalars are clamped as described in RFC 7748 and hence the blinding has to be
+/// performed one exponentiation at a time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct X448;
+
+pub type DefaultGroup = X25519;
+
+impl SphinxGroup for X25519 {
+    type Scalar = Scalar;
+    type Element = MontgomeryPoint;
+    type ScalarBytes = [u8; X25519_SCALAR_SIZE];
+    type ElementBytes = [u8; X25519_ELEMENT_SIZE];
+
+    const SCALAR_SIZE: usize = X25519_SCALAR_SIZE;
+    const ELEMENT_SIZE: usize = X25519_ELEMENT_SIZE;
+
+    fn random_scalar<R: RngCore + CryptoRng>(rng: &mut R) -> Self::Scalar {
+        let mut bytes = [0u8; X25519_SCALAR_SIZE];
+        rng.fill_bytes(&mut bytes);
+        clamp_scalar_bytes(bytes)
+    }
+
+    fn blinding_scalar(blinding_factor: &BlindingFactor) -> Self::Scalar {
+        // TODO: do we need to make the reduction here or could we get away with clamping or even nothing at all?
+        // considering (I *think*) proper reductions will happen during scalar multiplication, i.e. g^x?
+        // So far it *seems* to produce correct result, but could it be the case it introduces
+        // some vulnerabilities? Need some ECC expert here.
+        Scalar::from_bytes_mod_order(*blinding_factor)
+    }
+
+    fn base_mul(scalar: &Self::Scalar) -> Self::Element {
+        // multiplication in edwards using the precomputed ed25519 basepoint table is over 3x quicker
+        // than multiplication inside montgomery using the curve generator
+        (&ED25519_BASEPOINT_TABLE * scalar).to_montgomery()
+    }
+
+    fn diffie_hellman(scalar: &Self::Scalar, element: &Self::Element) -> Self::Element {
+        scalar * element
+    }
+
+    fn blinded_diffie_hellman(
+        scalar: &Self::Scalar,
+        blinders: &[Self::Scalar],
+        element: &Self::Element,
+    ) -> Self::Element {
+        let accumulator = blinders.iter().fold(*scalar, |acc, blinder| acc * blinder);
+        accumulator * element
+    }
+
+    fn encode_scalar(scalar: &Self::Scalar) -> Self::ScalarBytes {
+        scalar.to_bytes()
+    }
+
+    fn decode_scalar(bytes: &[u8]) -> Result<Self::Scalar> {
+        let mut scalar_bytes = [0u8; X25519_SCALAR_SIZE];
+        copy_exact(bytes, &mut scalar_bytes)?;
+        Ok(clamp_scalar_bytes(scalar_bytes))
+    }
+
+    fn encode_element(element: &Self::Element) -> &Self::ElementBytes {
+        element.as_bytes()
+    }
+
+    fn decode_element(bytes: &[u8]) -> Result<Self::Element> {
+        let mut element_bytes = [0u8; X25519_ELEMENT_SIZE];
+        copy_exact(bytes, &mut element_bytes)?;
+        Ok(MontgomeryPoint(element_bytes))
+    }
+}
+
+/// Ristretto point kept together with its encoding, so that the encoding
+/// does not need to be recomputed every time the header is serialized.
+#[derive(Clone, Copy)]
+pub struct RistrettoElement {
+    compressed: CompressedRistretto,
+    point: RistrettoPoint,
+}
+
+impl From<RistrettoPoint> for RistrettoElement {
+    fn from(point: RistrettoPoint) -> Self {
+        RistrettoElement {
+            compressed: point.compress(),
+            point,
+        }
+    }
+}
+
+impl fmt::Debug for RistrettoElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RistrettoElement({:?})", self.compressed.as_bytes())
+    }
+}
+
+impl PartialEq for RistrettoElement {
+    fn eq(&self, other: &Self) -> bool {
+        self.compressed == other.compressed
+    }
+}
+
+impl Eq for RistrettoElement {}
+
+impl Hash for RistrettoElement {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.compressed.hash(state)
+    }
+}
+
+impl SphinxGroup for Ristretto255 {
+    type Scalar = Scalar;
+    type Element = RistrettoElement;
+    type ScalarBytes = [u8; RISTRETTO255_SCALAR_SIZE];
+    type ElementBytes = [u8; RISTRETTO255_ELEMENT_SIZE];
+
+    const SCALAR_SIZE: usize = RISTRETTO255_SCALAR_SIZE;
+    const ELEMENT_SIZE: usize = RISTRETTO255_ELEMENT_SIZE;
+
+    fn random_scalar<R: RngCore + CryptoRng>(rng: &mut R) -> Self::Scalar {
+        Scalar::random(rng)
+    }
+
+    fn blinding_scalar(blinding_factor: &BlindingFactor) -> Self::Scalar {
+        Scalar::from_bytes_mod_order(*blinding_factor)
+    }
+
+    fn base_mul(scalar: &Self::Scalar) -> Self::Element {
+        (&RISTRETTO_BASEPOINT_TABLE * scalar).into()
+    }
+
+    fn diffie_hellman(scalar: &Self::Scalar, element: &Self::Element) -> Self::Element {
+        (scalar * element.point).into()
+    }
+
+    fn blinded_diffie_hellman(
+        scalar: &Self::Scalar,
+        blinders: &[Self::Scalar],
+        element: &Self::Element,
+    ) -> Self::Element {
+        let accumulator = blinders.iter().fold(*scalar, |acc, blinder| acc * blinder);
+        (accumulator * element.point).into()
+    }
+
+    fn encode_scalar(scalar: &Self::Scalar) -> Self::ScalarBytes {
+        scalar.to_bytes()
+    }
+
+    fn decode_scalar(bytes: &[u8]) -> Result<Self::Scalar> {
+        let mut scalar_bytes = [0u8; RISTRETTO255_SCALAR_SIZE];
+        copy_exact(bytes, &mut scalar_bytes)?;
+        Scalar::from_canonical_bytes(scalar_bytes)
+            .ok_or_else(|| Error::with_reason(ErrorKind::InvalidHeader, ErrorReason::InvalidScalar))
+    }
+
+    fn encode_element(element: &Self::Element) -> &Self::ElementBytes {
+        element.compressed.as_bytes()
+    }
+
+    fn decode_element(bytes: &[u8]) -> Result<Self::Element> {
+        let mut element_bytes = [0u8; RISTRETTO255_ELEMENT_SIZE];
+        copy_exact(bytes, &mut element_bytes)?;
+        let compressed = CompressedRistretto(element_bytes);
+        match compressed.decompress() {
+            Some(point) => Ok(RistrettoElement { compressed, point }),
+            None => Err(Error::with_reason(
+                ErrorKind::InvalidHeader,
+                ErrorReason::InvalidGroupElement,
+            )),
+        }
+    }
+}
+
+fn clamp_x448_scalar_bytes(mut scalar_bytes: [u8; X448_SCALAR_SIZE]) -> [u8; X448_SCALAR_SIZE] {
+    scalar_bytes[0] &= 252;
+    scalar_bytes[X448_SCALAR_SIZE - 1] |= 128;
+    scalar_bytes
+}
+
+impl SphinxGroup for X448 {
+    // we keep the clamped scalar bytes around rather than `ed448_goldilocks::Scalar` as the
+    // Montgomery ladder only produces correct results for clamped, non-reduced scalars
+    type Scalar = [u8; X448_SCALAR_SIZE];
+    type Element = [u8; X448_ELEMENT_SIZE];
+    type ScalarBytes = [u8; X448_SCALAR_SIZE];
+    type ElementBytes = [u8; X448_ELEMENT_SIZE];
+
+    const SCALAR_SIZE: usize = X448_SCALAR_SIZE;
+    const ELEMENT_SIZE: usize = X448_ELEMENT_SIZE;
+
+    fn random_scalar<R: RngCore + CryptoRng>(rng: &mut R) -> Self::Scalar {
+        let mut bytes = [0u8; X448_SCALAR_SIZE];
+        rng.fill_bytes(&mut bytes);
+        clamp_x448_scalar_bytes(bytes)
+    }
+
+    fn blinding_scalar(blinding_factor: &BlindingFactor) -> Self::Scalar {
+        let mut bytes = [0u8; X448_SCALAR_SIZE];
+        bytes[..blinding_factor.len()].copy_from_slice(blinding_factor);
+        clamp_x448_scalar_bytes(bytes)
+    }
+
+    fn base_mul(scalar: &Self::Scalar) -> Self::Element {
+        let generator = Curve448MontgomeryPoint::generator();
+        (&generator * &Curve448Scalar::from_bytes(*scalar)).0
+    }
+
+    fn diffie_hellman(scalar: &Self::Scalar, element: &Self::Element) -> Self::Element {
+        (&Curve448MontgomeryPoint(*element) * &Curve448Scalar::from_bytes(*scalar)).0
+    }
+
+    fn encode_scalar(scalar: &Self::Scalar) -> Self::ScalarBytes {
+        *scalar
+    }
+
+    fn decode_scalar(bytes: &[u8]) -> Result<Self::Scalar> {
+        let mut scalar_bytes = [0u8; X448_SCALAR_SIZE];
+        copy_exact(bytes, &mut scalar_bytes)?;
+        Ok(clamp_x448_scalar_bytes(scalar_bytes))
+    }
+
+    fn encode_element(element: &Self::Element) -> &Self::ElementBytes {
+        element
+    }
+
+    fn decode_element(bytes: &[u8]) -> Result<Self::Element> {
+        let mut element_bytes = [0u8; X448_ELEMENT_SIZE];
+        copy_exact(bytes, &mut element_bytes)?;
+        if Curve448MontgomeryPoint(element_bytes).is_low_order() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidHeader,
+                ErrorReason::InvalidGroupElement,
+            ));
+        }
+        Ok(element_bytes)
+    }
+}
+
+fn copy_exact(bytes: &[u8], output: &mut [u8]) -> Result<()> {
+    if bytes.len() != output.len() {
+        return Err(Error::with_reason(
+            ErrorKind::InvalidHeader,
+            ErrorReason::UnexpectedLength {
+                expected: output.len(),
+                actual: bytes.len(),
+            },
+        ));
+    }
+    output.copy_from_slice(bytes);
+    Ok(())
+}
+
+#[cfg(test)]
+mod sphinx_groups {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn blinding_factors() -> Vec<BlindingFactor> {
+        vec![[1u8; 32], [42u8; 32], [255u8; 32]]
+    }
+
+    fn blinded_diffie_hellman_is_consistent_with_hop_by_hop_blinding<G: SphinxGroup>() {
+        let sender_secret = G::random_scalar(&mut OsRng);
+        let node_secret = G::random_scalar(&mut OsRng);
+        let node_public = G::base_mul(&node_secret);
+
+        let blinding_factors = blinding_factors();
+        let blinders: Vec<_> = blinding_factors.iter().map(G::blinding_scalar).collect();
+
+        // what the sender computes
+        let sender_view = G::blinded_diffie_hellman(&sender_secret, &blinders, &node_public);
+
+        // what the node sees after the previous hops have blinded the header
+        let blinded_header_element = blinding_factors
+            .iter()
+            .fold(G::base_mul(&sender_secret), |acc, factor| {
+                G::blind(&acc, factor)
+            });
+        let node_view = G::diffie_hellman(&node_secret, &blinded_header_element);
+
+        assert_eq!(sender_view, node_view);
+    }
+
+    fn elements_can_be_encoded_and_decoded<G: SphinxGroup>() {
+        let element = G::base_mul(&G::random_scalar(&mut OsRng));
+        let encoded = G::encode_element(&element);
+        assert_eq!(G::ELEMENT_SIZE, encoded.as_ref().len());
+        assert_eq!(element, G::decode_element(encoded.as_ref()).unwrap());
+        assert!(G::decode_element(&encoded.as_ref()[1..]).is_err());
+    }
+
+    fn scalars_can_be_encoded_and_decoded<G: SphinxGroup>() {
+        let scalar = G::random_scalar(&mut OsRng);
+        let encoded = G::encode_scalar(&scalar);
+        assert_eq!(G::SCALAR_SIZE, encoded.as_ref().len());
+        let decoded = G::decode_scalar(encoded.as_ref()).unwrap();
+        assert_eq!(G::base_mul(&scalar), G::base_mul(&decoded));
+    }
+
+    #[test]
+    fn x25519_blinding_is_consistent() {
+        blinded_diffie_hellman_is_consistent_with_hop_by_hop_blinding::<X25519>()
+    }
+
+    #[test]
+    fn ristretto255_blinding_is_consistent() {
+        blinded_diffie_hellman_is_consistent_with_hop_by_hop_blinding::<Ristretto255>()
+    }
+
+    #[test]
+    fn x448_blinding_is_consistent() {
+        blinded_diffie_hellman_is_consistent_with_hop_by_hop_blinding::<X448>()
+    }
+
+    #[test]
+    fn x25519_encoding_roundtrips() {
+        elements_can_be_encoded_and_decoded::<X25519>();
+        scalars_can_be_encoded_and_decoded::<X25519>();
+    }
+
+    #[test]
+    fn ristretto255_encoding_roundtrips() {
+        elements_can_be_encoded_and_decoded::<Ristretto255>();
+        scalars_can_be_encoded_and_decoded::<Ristretto255>();
+    }
+
+    #[test]
+    fn x448_encoding_roundtrips() {
+        elements_can_be_encoded_and_decoded::<X448>();
+        scalars_can_be_encoded_and_decoded::<X448>();
+    }
+
+    #[test]
+    fn ristretto255_rejects_invalid_elements() {
+        // not a canonical encoding of a field element
+        assert!(Ristretto255::decode_element(&[255u8; RISTRETTO255_ELEMENT_SIZE]).is_err());
+    }
+
+    #[test]
+    fn x448_rejects_low_order_elements() {
+        assert!(X448::decode_element(&[0u8; X448_ELEMENT_SIZE]).is_err());
+    }
+
+    #[test]
+    fn x448_matches_rfc7748_test_vector() {
+        let scalar: [u8; X448_SCALAR_SIZE] = [
+            0x3d, 0x26, 0x2f, 0xdd, 0xf9, 0xec, 0x8e, 0x88, 0x49, 0x52, 0x66, 0xfe, 0xa1, 0x9a,
+            0x34, 0xd2, 0x88, 0x82, 0xac, 0xef, 0x04, 0x51, 0x04, 0xd0, 0xd1, 0xaa, 0xe1, 0x21,
+            0x70, 0x0a, 0x77, 0x9c, 0x98, 0x4c, 0x24, 0xf8, 0xcd, 0xd7, 0x8f, 0xbf, 0xf4, 0x49,
+            0x43, 0xeb, 0xa3, 0x68, 0xf5, 0x4b, 0x29, 0x25, 0x9a, 0x4f, 0x1c, 0x60, 0x0a, 0xd3,
+        ];
+        let element: [u8; X448_ELEMENT_SIZE] = [
+            0x06, 0xfc, 0xe6, 0x40, 0xfa, 0x34, 0x87, 0xbf, 0xda, 0x5f, 0x6c, 0xf2, 0xd5, 0x26,
+            0x3f, 0x8a, 0xad, 0x88, 0x33, 0x4c, 0xbd, 0x07, 0x43, 0x7f, 0x02, 0x0f, 0x08, 0xf9,
+            0x81, 0x4d, 0xc0, 0x31, 0xdd, 0xbd, 0xc3, 0x8c, 0x19, 0xc6, 0xda, 0x25, 0x83, 0xfa,
+            0x54, 0x29, 0xdb, 0x94, 0xad, 0xa1, 0x8a, 0xa7, 0xa7, 0xfb, 0x4e, 0xf8, 0xa0, 0x86,
+        ];
+        let expected: [u8; X448_ELEMENT_SIZE] = [
+            0xce, 0x3e, 0x4f, 0xf9, 0x5a, 0x60, 0xdc, 0x66, 0x97, 0xda, 0x1d, 0xb1, 0xd8, 0x5e,
+            0x6a, 0xfb, 0xdf, 0x79, 0xb5, 0x0a, 0x24, 0x12, 0xd7, 0x54, 0x6d, 0x5f, 0x23, 0x9f,
+            0xe1, 0x4f, 0xba, 0xad, 0xeb, 0x44, 0x5f, 0xc6, 0x6a, 0x01, 0xb0, 0x77, 0x9d, 0x98,
+            0x22, 0x39, 0x61, 0x11, 0x1e, 0x21, 0x76, 0x62, 0x82, 0xf7, 0x3d, 0xd9, 0x6b, 0x6f,
+        ];
+
+        let scalar = X448::decode_scalar(&scalar).unwrap();
+        let element = X448::decode_element(&element).unwrap();
+        assert_eq!(expected, X448::diffie_hellman(&scalar, &element));
+    }
+}
@@ -19,10 +19,13 @@
 // to obtain g^{xyz} we compute `tmp = x*y*z` followed by g^tmp rather than
 // G1 = g^x, G2 = G1^y, G3 = G2^z
 
-use curve25519_dalek::{
-    constants::ED25519_BASEPOINT_TABLE, montgomery::MontgomeryPoint, scalar::Scalar,
-};
+use crate::crypto::group::{DefaultGroup, SphinxGroup};
+use crate::header::keys::BlindingFactor;
+use crate::Result;
+use curve25519_dalek::{montgomery::MontgomeryPoint, scalar::Scalar};
 use rand::{rngs::OsRng, CryptoRng, RngCore};
+use std::hash::{Hash, Hasher};
+use zeroize::Zeroize;
 
 pub const PRIVATE_KEY_SIZE: usize = 32;
 pub const PUBLIC_KEY_SIZE: usize = 32;
@@ -40,30 +43,32 @@ pub fn clamp_scalar_bytes(mut scalar_bytes: [u8; PRIVATE_KEY_SIZE]) -> Scalar {
     Scalar::from_bits(scalar_bytes)
 }
 
-// TODO: similarly to what x25519_dalek is doing, we should probably
-// derive zeroize::Zeroize on drop here
-pub struct PrivateKey(Scalar);
+pub struct PrivateKey<G: SphinxGroup = DefaultGroup>(G::Scalar);
 
-// Because the lint below was renamed in nightly but not in stable, making this problematic in CI
-// which tests both, for a brief period we allow renamed lints.
-#[allow(renamed_and_removed_lints)] // TODO: remove this in next version
-#[allow(clippy::derive_hash_xor_eq)] // TODO: we must be careful about that one if anything changes in the future
-#[derive(Copy, Clone, Debug, Hash)]
-pub struct PublicKey(MontgomeryPoint);
+pub struct PublicKey<G: SphinxGroup = DefaultGroup>(G::Element);
 
 // type aliases for easier reasoning
-pub type EphemeralSecret = PrivateKey;
-pub type SharedSecret = PublicKey;
+pub type EphemeralSecret<G = DefaultGroup> = PrivateKey<G>;
+pub type SharedSecret<G = DefaultGroup> = PublicKey<G>;
 
-impl PrivateKey {
+impl<G: SphinxGroup> PrivateKey<G> {
     /// Perform a key exchange with another public key
-    pub fn diffie_hellman(&self, remote_public_key: &PublicKey) -> SharedSecret {
-        PublicKey(self.0 * remote_public_key.0)
+    pub fn diffie_hellman(&self, remote_public_key: &PublicKey<G>) -> SharedSecret<G> {
+        PublicKey(G::diffie_hellman(&self.0, &remote_public_key.0))
     }
 
-    // Do not expose this. It can lead to serious security issues if used incorrectly.
-    pub(crate) fn clone(&self) -> Self {
-        PrivateKey(self.0)
+    /// Perform a key exchange with another public key, additionally blinding the result
+    /// with all of the provided blinding factors.
+    pub(crate) fn blinded_diffie_hellman(
+        &self,
+        blinders: &[G::Scalar],
+        remote_public_key: &PublicKey<G>,
+    ) -> SharedSecret<G> {
+        PublicKey(G::blinded_diffie_hellman(
+            &self.0,
+            blinders,
+            &remote_public_key.0,
+        ))
     }
 
     // honestly, this method shouldn't really exist, but right now we have no decent
@@ -74,24 +79,33 @@ impl PrivateKey {
     }
 
     pub fn new_with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
-        let mut bytes = [0u8; PRIVATE_KEY_SIZE];
-        rng.fill_bytes(&mut bytes);
-        PrivateKey(clamp_scalar_bytes(bytes))
+        PrivateKey(G::random_scalar(rng))
+    }
+
+    pub fn to_bytes(&self) -> G::ScalarBytes {
+        G::encode_scalar(&self.0)
+    }
+
+    pub fn try_from_byte_slice(bytes: &[u8]) -> Result<Self> {
+        G::decode_scalar(bytes).map(PrivateKey)
     }
+}
 
-    pub fn to_bytes(&self) -> [u8; PRIVATE_KEY_SIZE] {
-        self.0.to_bytes()
+// similarly to what x25519_dalek is doing, make sure the scalar does not outlive the key
+impl<G: SphinxGroup> Drop for PrivateKey<G> {
+    fn drop(&mut self) {
+        self.0.zeroize()
     }
 }
 
-impl Default for PrivateKey {
+impl<G: SphinxGroup> Default for PrivateKey<G> {
     fn default() -> Self {
         PrivateKey::new()
     }
 }
 
 // TODO: is this 'safe' ?
-impl<'a, 'b> std::ops::Mul<&'b Scalar> for &'a EphemeralSecret {
+impl<'b> std::ops::Mul<&'b Scalar> for &EphemeralSecret {
     type Output = EphemeralSecret;
     fn mul(self, rhs: &'b Scalar) -> EphemeralSecret {
         PrivateKey(self.0 * rhs)
@@ -119,17 +133,24 @@ impl From<[u8; PRIVATE_KEY_SIZE]> for PrivateKey {
     }
 }
 
-impl PublicKey {
-    pub fn as_bytes(&self) -> &[u8; PUBLIC_KEY_SIZE] {
-        self.0.as_bytes()
+impl<G: SphinxGroup> PublicKey<G> {
+    pub fn as_bytes(&self) -> &G::ElementBytes {
+        G::encode_element(&self.0)
+    }
+
+    pub fn try_from_byte_slice(bytes: &[u8]) -> Result<Self> {
+        G::decode_element(bytes).map(PublicKey)
+    }
+
+    /// Blinds the group element with the provided blinding factor, i.e. computes `self^b`.
+    pub(crate) fn blind(&self, blinding_factor: &BlindingFactor) -> Self {
+        PublicKey(G::blind(&self.0, blinding_factor))
     }
 }
 
-impl<'a> From<&'a PrivateKey> for PublicKey {
-    fn from(private_key: &'a PrivateKey) -> PublicKey {
-        // multiplication in edwards using the precomputed ed25519 basepoint table is over 3x quicker
-        // than multiplication inside montgomery using the curve generator
-        PublicKey((&ED25519_BASEPOINT_TABLE * &private_key.0).to_montgomery())
+impl<'a, G: SphinxGroup> From<&'a PrivateKey<G>> for PublicKey<G> {
+    fn from(private_key: &'a PrivateKey<G>) -> PublicKey<G> {
+        PublicKey(G::base_mul(&private_key.0))
     }
 }
 
@@ -139,13 +160,33 @@ impl From<[u8; PUBLIC_KEY_SIZE]> for PublicKey {
     }
 }
 
-impl PartialEq for PublicKey {
+impl<G: SphinxGroup> Copy for PublicKey<G> {}
+
+impl<G: SphinxGroup> Clone for PublicKey<G> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<G: SphinxGroup> std::fmt::Debug for PublicKey<G> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PublicKey").field(&self.0).finish()
+    }
+}
+
+impl<G: SphinxGroup> Hash for PublicKey<G> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl<G: SphinxGroup> PartialEq for PublicKey<G> {
     fn eq(&self, other: &Self) -> bool {
         self.0.eq(&other.0)
     }
 }
 
-impl Eq for PublicKey {}
+impl<G: SphinxGroup> Eq for PublicKey<G> {}
 
 pub fn keygen() -> (PrivateKey, PublicKey) {
     let private_key = PrivateKey::new();
@@ -12,16 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use aes::cipher::{NewCipher, StreamCipher};
+use aes::cipher::{NewCipher, StreamCipher, StreamCipherSeek};
 use aes::Aes128Ctr;
 use digest::generic_array::{ArrayLength, GenericArray};
 use digest::{BlockInput, FixedOutput, Reset, Update};
 use hmac::{crypto_mac, Hmac, Mac, NewMac};
 
+pub mod group;
 pub mod keys;
+pub mod suite;
 
 // to not break existing imports
+pub use group::{DefaultGroup, Ristretto255, SphinxGroup, X25519, X448};
 pub use keys::*;
+pub use suite::{Aes128HmacSha256Suite, ChaCha20Blake2bSuite, CipherSuite, DefaultCipherSuite};
 
 pub const STREAM_CIPHER_KEY_SIZE: usize = 16;
 pub const STREAM_CIPHER_INIT_VECTOR: [u8; 16] = [0u8; 16];
@@ -46,6 +50,22 @@ pub fn generate_pseudorandom_bytes(
     data
 }
 
+/// XORs the data with the output of the same PRNG as in `generate_pseudorandom_bytes`,
+/// starting at the given offset, without allocating it.
+pub fn apply_pseudorandom_bytes(
+    key: &[u8; STREAM_CIPHER_KEY_SIZE],
+    iv: &[u8; STREAM_CIPHER_KEY_SIZE],
+    offset: usize,
+    data: &mut [u8],
+) {
+    let cipher_key = GenericArray::from_slice(&key[..]);
+    let cipher_nonce = GenericArray::from_slice(&iv[..]);
+
+    let mut cipher = Aes128Ctr::new(cipher_key, cipher_nonce);
+    cipher.seek(offset as u64);
+    cipher.apply_keystream(data);
+}
+
 /// Compute keyed hmac
 pub fn compute_keyed_hmac<D>(key: &[u8], data: &[u8]) -> HmacOutput<D>
 where
@@ -73,4 +93,15 @@ mod generating_pseudorandom_bytes {
         let rand_bytes = generate_pseudorandom_bytes(&key, &iv, 10000);
         assert_eq!(10000, rand_bytes.len());
     }
+
+    #[test]
+    fn it_applies_the_same_output_from_the_given_offset() {
+        let key = [1u8; STREAM_CIPHER_KEY_SIZE];
+        let iv = [0u8; STREAM_CIPHER_KEY_SIZE];
+
+        let rand_bytes = generate_pseudorandom_bytes(&key, &iv, 200);
+        let mut data = [0u8; 100];
+        apply_pseudorandom_bytes(&key, &iv, 77, &mut data);
+        assert_eq!(&rand_bytes[77..177], &data[..]);
+    }
 }
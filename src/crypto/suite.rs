@@ -0,0 +1,267 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::constants::{HeaderIntegrityHmacAlgorithm, HKDF_INPUT_SEED, INTEGRITY_MAC_KEY_SIZE};
+use crate::crypto::group::{DefaultGroup, SphinxGroup};
+use crate::crypto::{self, STREAM_CIPHER_INIT_VECTOR, STREAM_CIPHER_KEY_SIZE};
+use crate::header::keys::PayloadKey;
+use crate::params::MAX_HEADER_INTEGRITY_MAC_SIZE;
+use crate::utils;
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use arrayref::array_ref;
+use blake2::digest::{Input, VariableOutput};
+use blake2::VarBlake2b;
+use chacha::{ChaCha, KeyStream, SeekableKeyStream};
+use hkdf::Hkdf;
+use lioness::Lioness;
+use sha2::Sha256;
+use std::fmt;
+use std::marker::PhantomData;
+
+pub const CHACHA20_KEY_SIZE: usize = 32;
+pub const CHACHA20_NONCE: [u8; 8] = [0u8; 8];
+pub const BLAKE2B_MAC_KEY_SIZE: usize = 32;
+pub const BLAKE2B_MAC_SIZE: usize = MAX_HEADER_INTEGRITY_MAC_SIZE;
+
+/// Set of symmetric primitives used for constructing and processing sphinx packets.
+///
+/// The stream cipher is used to encrypt the routing information and to generate the filler,
+/// the MAC protects integrity of the header, the KDF expands the per-hop shared secret into
+/// all routing keys and the wide-block SPRP is used to layer-encrypt the payload.
+///
+/// Note that the output of the MAC is always truncated to the size specified by `SphinxParams` and the
+/// SPRP is always keyed with `PAYLOAD_KEY_SIZE` bytes so that the layout of the header and
+/// the SURBs does not depend on the chosen suite. The only exception is the group used for
+/// the key exchange, as its elements are put directly in the header.
+pub trait CipherSuite:
+    Copy + Clone + fmt::Debug + Default + PartialEq + Send + Sync + 'static
+{
+    /// Group in which the key exchange with every hop happens.
+    type Group: SphinxGroup;
+
+    type StreamCipherKey: AsRef<[u8]> + AsMut<[u8]> + Copy + Default + fmt::Debug + PartialEq;
+    type IntegrityMacKey: AsRef<[u8]> + AsMut<[u8]> + Copy + Default + fmt::Debug + PartialEq;
+
+    /// Minimum size of the block that can be encrypted with the SPRP.
+    const MIN_PAYLOAD_BLOCK_SIZE: usize;
+
+    /// Generates `length` bytes of keystream of the stream cipher under the provided key.
+    fn generate_pseudorandom_bytes(key: &Self::StreamCipherKey, length: usize) -> Vec<u8>;
+
+    /// XORs the data with the keystream of the stream cipher starting at the provided offset.
+    /// Suites should override it so that the keystream is not allocated.
+    fn apply_keystream(key: &Self::StreamCipherKey, offset: usize, data: &mut [u8]) {
+        let keystream = Self::generate_pseudorandom_bytes(key, offset + data.len());
+        utils::bytes::xor_with(data, &keystream[offset..]);
+    }
+
+    /// Computes the (untruncated) integrity MAC on the provided data.
+    /// It must be at least `MAX_HEADER_INTEGRITY_MAC_SIZE` bytes long.
+    fn compute_integrity_mac(key: &Self::IntegrityMacKey, data: &[u8]) -> Vec<u8>;
+
+    /// Computes the integrity MAC truncated to the length of the output.
+    /// Suites should override it so that the MAC is not allocated.
+    fn compute_truncated_integrity_mac(
+        key: &Self::IntegrityMacKey,
+        data: &[u8],
+        output: &mut [u8],
+    ) {
+        output.copy_from_slice(&Self::compute_integrity_mac(key, data)[..output.len()]);
+    }
+
+    /// Expands the shared secret into the provided output buffer.
+    fn expand_shared_secret(shared_secret: &[u8], output: &mut [u8]);
+
+    /// Encrypts the block in place using the wide-block SPRP.
+    fn encrypt_payload(key: &PayloadKey, block: &mut [u8]) -> Result<()>;
+
+    /// Decrypts the block in place using the wide-block SPRP.
+    fn decrypt_payload(key: &PayloadKey, block: &mut [u8]) -> Result<()>;
+}
+
+/// AES-128-CTR, truncated HMAC-SHA256, HKDF-SHA256
+/// and Lioness (BLAKE2b and ChaCha20) for the payload, with the key exchange happening
+/// in the provided group.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Aes128HmacSha256Suite<G: SphinxGroup = DefaultGroup>(PhantomData<G>);
+
+/// ChaCha20, truncated keyed BLAKE2b, HKDF-SHA256
+/// and Lioness (BLAKE2b and ChaCha20) for the payload.
+/// It does not rely on AES in any way and hence is the preferred choice for platforms
+/// without hardware AES support.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChaCha20Blake2bSuite<G: SphinxGroup = DefaultGroup>(PhantomData<G>);
+
+pub type DefaultCipherSuite = Aes128HmacSha256Suite;
+
+impl<G: SphinxGroup> CipherSuite for Aes128HmacSha256Suite<G> {
+    type Group = G;
+    type StreamCipherKey = [u8; STREAM_CIPHER_KEY_SIZE];
+    type IntegrityMacKey = [u8; INTEGRITY_MAC_KEY_SIZE];
+
+    const MIN_PAYLOAD_BLOCK_SIZE: usize = lioness::DIGEST_RESULT_SIZE;
+
+    fn generate_pseudorandom_bytes(key: &Self::StreamCipherKey, length: usize) -> Vec<u8> {
+        crypto::generate_pseudorandom_bytes(key, &STREAM_CIPHER_INIT_VECTOR, length)
+    }
+
+    fn apply_keystream(key: &Self::StreamCipherKey, offset: usize, data: &mut [u8]) {
+        crypto::apply_pseudorandom_bytes(key, &STREAM_CIPHER_INIT_VECTOR, offset, data)
+    }
+
+    fn compute_integrity_mac(key: &Self::IntegrityMacKey, data: &[u8]) -> Vec<u8> {
+        crypto::compute_keyed_hmac::<HeaderIntegrityHmacAlgorithm>(key, data)
+            .into_bytes()
+            .to_vec()
+    }
+
+    fn compute_truncated_integrity_mac(
+        key: &Self::IntegrityMacKey,
+        data: &[u8],
+        output: &mut [u8],
+    ) {
+        let mac = crypto::compute_keyed_hmac::<HeaderIntegrityHmacAlgorithm>(key, data);
+        output.copy_from_slice(&mac.into_bytes()[..output.len()]);
+    }
+
+    fn expand_shared_secret(shared_secret: &[u8], output: &mut [u8]) {
+        hkdf_sha256_expand(shared_secret, output)
+    }
+
+    fn encrypt_payload(key: &PayloadKey, block: &mut [u8]) -> Result<()> {
+        lioness_encrypt(key, block)
+    }
+
+    fn decrypt_payload(key: &PayloadKey, block: &mut [u8]) -> Result<()> {
+        lioness_decrypt(key, block)
+    }
+}
+
+impl<G: SphinxGroup> CipherSuite for ChaCha20Blake2bSuite<G> {
+    type Group = G;
+    type StreamCipherKey = [u8; CHACHA20_KEY_SIZE];
+    type IntegrityMacKey = [u8; BLAKE2B_MAC_KEY_SIZE];
+
+    const MIN_PAYLOAD_BLOCK_SIZE: usize = lioness::DIGEST_RESULT_SIZE;
+
+    fn generate_pseudorandom_bytes(key: &Self::StreamCipherKey, length: usize) -> Vec<u8> {
+        let mut cipher = ChaCha::new_chacha20(key, &CHACHA20_NONCE);
+        let mut data = vec![0u8; length];
+        // the only possible error is reaching the end of the keystream (2^70 bytes)
+        cipher
+            .xor_read(&mut data)
+            .expect("requested more keystream than ChaCha20 can produce");
+        data
+    }
+
+    fn apply_keystream(key: &Self::StreamCipherKey, offset: usize, data: &mut [u8]) {
+        let mut cipher = ChaCha::new_chacha20(key, &CHACHA20_NONCE);
+        cipher
+            .seek_to(offset as u64)
+            .and_then(|_| cipher.xor_read(data))
+            .expect("requested more keystream than ChaCha20 can produce");
+    }
+
+    fn compute_integrity_mac(key: &Self::IntegrityMacKey, data: &[u8]) -> Vec<u8> {
+        let mut output = vec![0u8; BLAKE2B_MAC_SIZE];
+        Self::compute_truncated_integrity_mac(key, data, &mut output);
+        output
+    }
+
+    fn compute_truncated_integrity_mac(
+        key: &Self::IntegrityMacKey,
+        data: &[u8],
+        output: &mut [u8],
+    ) {
+        let mut mac = VarBlake2b::new_keyed(key, BLAKE2B_MAC_SIZE);
+        mac.input(data);
+        mac.variable_result(|res| output.copy_from_slice(&res[..output.len()]));
+    }
+
+    fn expand_shared_secret(shared_secret: &[u8], output: &mut [u8]) {
+        hkdf_sha256_expand(shared_secret, output)
+    }
+
+    fn encrypt_payload(key: &PayloadKey, block: &mut [u8]) -> Result<()> {
+        lioness_encrypt(key, block)
+    }
+
+    fn decrypt_payload(key: &PayloadKey, block: &mut [u8]) -> Result<()> {
+        lioness_decrypt(key, block)
+    }
+}
+
+fn hkdf_sha256_expand(shared_secret: &[u8], output: &mut [u8]) {
+    let hkdf = Hkdf::<Sha256>::new(None, shared_secret);
+    // this can only fail if we requested more than 255 * 32 bytes, which we never do
+    hkdf.expand(HKDF_INPUT_SEED, output).unwrap();
+}
+
+fn lioness_cipher(key: &PayloadKey) -> Lioness<VarBlake2b, ChaCha> {
+    Lioness::<VarBlake2b, ChaCha>::new_raw(array_ref!(key, 0, lioness::RAW_KEY_SIZE))
+}
+
+fn lioness_encrypt(key: &PayloadKey, block: &mut [u8]) -> Result<()> {
+    lioness_cipher(key).encrypt(block).map_err(|_| {
+        Error::with_reason(ErrorKind::InvalidPayload, ErrorReason::PayloadCipherFailure)
+    })
+}
+
+fn lioness_decrypt(key: &PayloadKey, block: &mut [u8]) -> Result<()> {
+    lioness_cipher(key).decrypt(block).map_err(|_| {
+        Error::with_reason(ErrorKind::InvalidPayload, ErrorReason::PayloadCipherFailure)
+    })
+}
+
+#[cfg(test)]
+mod chacha_blake2b_suite {
+    use super::*;
+
+    #[test]
+    fn it_generates_keystream_of_requested_length() {
+        let key = [1u8; CHACHA20_KEY_SIZE];
+        let keystream = <ChaCha20Blake2bSuite>::generate_pseudorandom_bytes(&key, 10000);
+        assert_eq!(10000, keystream.len());
+    }
+
+    #[test]
+    fn it_generates_different_keystream_than_the_default_suite() {
+        let key = [1u8; CHACHA20_KEY_SIZE];
+        let default_key = [1u8; STREAM_CIPHER_KEY_SIZE];
+        assert_ne!(
+            <ChaCha20Blake2bSuite>::generate_pseudorandom_bytes(&key, 128),
+            DefaultCipherSuite::generate_pseudorandom_bytes(&default_key, 128)
+        );
+    }
+
+    #[test]
+    fn keystream_is_applied_from_the_given_offset() {
+        let key = [1u8; CHACHA20_KEY_SIZE];
+        let keystream = <ChaCha20Blake2bSuite>::generate_pseudorandom_bytes(&key, 200);
+        let mut data = [0u8; 100];
+        <ChaCha20Blake2bSuite>::apply_keystream(&key, 77, &mut data);
+        assert_eq!(&keystream[77..177], &data[..]);
+    }
+
+    #[test]
+    fn integrity_mac_depends_on_the_key() {
+        let data = [42u8; 100];
+        let mac1 =
+            <ChaCha20Blake2bSuite>::compute_integrity_mac(&[1u8; BLAKE2B_MAC_KEY_SIZE], &data);
+        let mac2 =
+            <ChaCha20Blake2bSuite>::compute_integrity_mac(&[2u8; BLAKE2B_MAC_KEY_SIZE], &data);
+        assert_eq!(BLAKE2B_MAC_SIZE, mac1.len());
+        assert_ne!(mac1, mac2);
+    }
+}
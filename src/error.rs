@@ -38,6 +38,7 @@ impl StdError for Error {}
 #[derive(Debug)]
 enum Repr {
     Simple(ErrorKind),
+    Detailed(Box<Detailed>),
     Custom(Box<Custom>),
 }
 
@@ -57,6 +58,301 @@ pub enum ErrorKind {
 
     /// Error originating routing information related functionality.
     InvalidRouting,
+
+    /// Error originating from managing the keys of the node, e.g. their rotation.
+    InvalidKeyring,
+
+    /// The packet has already been processed before.
+    Replay,
+}
+
+/// Specific reason of the failure carrying the details relevant to it, so that it could be
+/// handled without inspecting the error message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ErrorReason {
+    /// Provided bytes are not of the length required to recover the data.
+    UnexpectedLength {
+        expected: usize,
+        actual: usize,
+    },
+
+    /// Provided bytes are too short to recover the data.
+    TooShort {
+        minimum: usize,
+        actual: usize,
+    },
+
+    /// Provided bytes do not encode a valid group element.
+    InvalidGroupElement,
+
+    /// Provided bytes do not encode a canonical scalar.
+    InvalidScalar,
+
+    /// Value of the named parameter is outside of the allowed range.
+    InvalidParameter {
+        name: &'static str,
+        value: usize,
+        minimum: usize,
+        maximum: usize,
+    },
+
+    /// Integrity MAC could not be verified.
+    InvalidMac,
+
+    /// Forward hop was processed without providing the blinded shared secret for the next hop.
+    MissingBlindedSecret,
+
+    /// Routing information starts with a flag that is not known.
+    UnknownRoutingFlag {
+        flag: u8,
+    },
+
+    /// Data was created with a version of the format that is not supported.
+    UnsupportedVersion {
+        version: u8,
+    },
+
+    /// Failure report verified, but its contents do not follow the format.
+    MalformedFailure,
+
+    /// Record of the hop of a blinded route is not of the expected length.
+    MalformedBlindedHop,
+
+    /// More than one header layout was given for the same major version.
+    DuplicateVersion {
+        version: u8,
+    },
+
+    EmptyRoute,
+
+    RouteTooLong {
+        length: usize,
+        maximum: usize,
+    },
+
+    /// Route goes through the node at the given index right after going through the same node.
+    RepeatedHop {
+        index: usize,
+    },
+
+    /// Number of delays, records or keys provided does not match the number of hops of the route.
+    HopCountMismatch {
+        hops: usize,
+        provided: usize,
+    },
+
+    /// Routing information of the hops does not fit in the header.
+    RoutingInformationTooLong {
+        required: usize,
+        available: usize,
+    },
+
+    /// Routing information ends before all of the data of the hop.
+    TruncatedRoutingInformation,
+
+    /// Address has non-zero bytes beyond the prefix put in the header.
+    InvalidNodeAddress,
+
+    /// Address could not be decoded from its textual representation.
+    MalformedAddress,
+
+    /// Tlv records of a single hop are longer than the length prefix can express.
+    RecordsTooLong {
+        length: usize,
+        maximum: usize,
+    },
+
+    /// Tlv records are truncated, not sorted by their type or not canonically encoded.
+    MalformedRecords,
+
+    /// Routing information is not bound to the epoch of the key used to process it.
+    EpochMismatch {
+        epoch: u32,
+    },
+
+    /// Key of the next epoch is not after the current one or is not known.
+    InvalidEpoch {
+        epoch: Option<u32>,
+    },
+
+    /// Packet with the same shared secret has already been processed.
+    Replayed,
+
+    /// Message does not fit in the payload.
+    PayloadTooLarge {
+        size: usize,
+        maximum: usize,
+    },
+
+    /// Payload is too small to carry the overhead or to be encrypted.
+    PayloadTooSmall {
+        size: usize,
+        minimum: usize,
+    },
+
+    /// Payload was encrypted with different keys or was tampered with.
+    MalformedPadding,
+
+    PayloadCipherFailure,
+
+    /// Message requires more fragments than can be addressed.
+    TooManyFragments {
+        required: usize,
+        maximum: usize,
+    },
+
+    /// Fragment is malformed or inconsistent with the other fragments of its message.
+    MalformedFragment,
+
+    /// Fragment would start a new message or be buffered beyond the limits of the reassembler.
+    ReassemblyLimitReached,
+
+    /// Redundancy of the erasure coding is not a finite non-negative number.
+    InvalidRedundancy,
+
+    /// Fragments could not be encoded or the message could not be reconstructed from them.
+    ErasureCodingFailure,
+
+    /// SURB can't be encoded or its keys are malformed or belong to a different SURB.
+    MalformedSURB,
+
+    /// There are no keys stored for the SURB.
+    UnknownSURB,
+
+    /// Keys of the SURB have already been used.
+    SURBAlreadyUsed,
+
+    /// Worker of the header pool has stopped, as its route source panicked.
+    HeaderPoolStopped,
+
+    /// False positive rate of the replay filter is not strictly between 0 and 1.
+    InvalidFalsePositiveRate,
+
+    /// Storage of the SURB key store failed.
+    KeyStoreIo {
+        kind: std::io::ErrorKind,
+    },
+}
+
+impl Display for ErrorReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorReason::UnexpectedLength { expected, actual } => {
+                write!(f, "got {} bytes, expected {}", actual, expected)
+            }
+            ErrorReason::TooShort { minimum, actual } => {
+                write!(f, "got {} bytes, expected at least {}", actual, minimum)
+            }
+            ErrorReason::InvalidGroupElement => write!(f, "invalid group element"),
+            ErrorReason::InvalidScalar => write!(f, "non-canonical scalar"),
+            ErrorReason::InvalidParameter {
+                name,
+                value,
+                minimum,
+                maximum,
+            } => write!(
+                f,
+                "{} must be between {} and {}, got {}",
+                name, minimum, maximum, value
+            ),
+            ErrorReason::InvalidMac => write!(f, "failed to verify integrity MAC"),
+            ErrorReason::MissingBlindedSecret => {
+                write!(f, "tried to process forward hop without blinded secret")
+            }
+            ErrorReason::UnknownRoutingFlag { flag } => write!(f, "unknown routing flag {}", flag),
+            ErrorReason::UnsupportedVersion { version } => {
+                write!(f, "unsupported version {}", version)
+            }
+            ErrorReason::MalformedFailure => write!(f, "malformed failure report"),
+            ErrorReason::MalformedBlindedHop => write!(f, "malformed hop of the blinded route"),
+            ErrorReason::DuplicateVersion { version } => {
+                write!(f, "more than one layout for version {}", version)
+            }
+            ErrorReason::EmptyRoute => write!(f, "empty route"),
+            ErrorReason::RouteTooLong { length, maximum } => write!(
+                f,
+                "route of length {} is longer than the maximum of {}",
+                length, maximum
+            ),
+            ErrorReason::RepeatedHop { index } => {
+                write!(f, "hop {} repeats the previous node", index)
+            }
+            ErrorReason::HopCountMismatch { hops, provided } => write!(
+                f,
+                "route has {} hops while data for {} hops was provided",
+                hops, provided
+            ),
+            ErrorReason::RoutingInformationTooLong {
+                required,
+                available,
+            } => write!(
+                f,
+                "routing information requires {} bytes, only {} are available",
+                required, available
+            ),
+            ErrorReason::TruncatedRoutingInformation => {
+                write!(
+                    f,
+                    "routing information is too short to contain the hop data"
+                )
+            }
+            ErrorReason::InvalidNodeAddress => write!(
+                f,
+                "node address does not fit in the address length of the header"
+            ),
+            ErrorReason::MalformedAddress => write!(f, "malformed address"),
+            ErrorReason::RecordsTooLong { length, maximum } => write!(
+                f,
+                "tlv records of {} bytes are longer than the maximum of {}",
+                length, maximum
+            ),
+            ErrorReason::MalformedRecords => write!(f, "malformed tlv records"),
+            ErrorReason::EpochMismatch { epoch } => {
+                write!(f, "routing information is not bound to epoch {}", epoch)
+            }
+            ErrorReason::InvalidEpoch { epoch: Some(epoch) } => {
+                write!(f, "epoch {} is not after the current one", epoch)
+            }
+            ErrorReason::InvalidEpoch { epoch: None } => {
+                write!(f, "key of the next epoch is not known")
+            }
+            ErrorReason::Replayed => write!(f, "already processed"),
+            ErrorReason::PayloadTooLarge { size, maximum } => write!(
+                f,
+                "message of {} bytes is longer than the maximum of {}",
+                size, maximum
+            ),
+            ErrorReason::PayloadTooSmall { size, minimum } => write!(
+                f,
+                "payload of {} bytes is smaller than the minimum of {}",
+                size, minimum
+            ),
+            ErrorReason::MalformedPadding => write!(f, "malformed payload padding"),
+            ErrorReason::PayloadCipherFailure => write!(f, "payload cipher failure"),
+            ErrorReason::TooManyFragments { required, maximum } => write!(
+                f,
+                "message would need {} fragments, at most {} are supported",
+                required, maximum
+            ),
+            ErrorReason::MalformedFragment => write!(f, "malformed fragment"),
+            ErrorReason::ReassemblyLimitReached => {
+                write!(f, "too many fragments waiting for reassembly")
+            }
+            ErrorReason::InvalidRedundancy => {
+                write!(f, "redundancy has to be finite and non-negative")
+            }
+            ErrorReason::ErasureCodingFailure => write!(f, "erasure coding failure"),
+            ErrorReason::MalformedSURB => write!(f, "malformed SURB"),
+            ErrorReason::UnknownSURB => write!(f, "there are no keys stored for the SURB"),
+            ErrorReason::SURBAlreadyUsed => write!(f, "SURB has already been used"),
+            ErrorReason::HeaderPoolStopped => write!(f, "header pool is not refilled anymore"),
+            ErrorReason::InvalidFalsePositiveRate => {
+                write!(f, "false positive rate has to be between 0 and 1")
+            }
+            ErrorReason::KeyStoreIo { kind } => write!(f, "SURB key store io failure - {}", kind),
+        }
+    }
 }
 
 impl ErrorKind {
@@ -67,6 +363,8 @@ impl ErrorKind {
             ErrorKind::InvalidPayload => "payload processing failure",
             ErrorKind::InvalidSURB => "SURB processing failure",
             ErrorKind::InvalidRouting => "routing information processing failure",
+            ErrorKind::InvalidKeyring => "keyring management failure",
+            ErrorKind::Replay => "replayed packet",
         }
     }
 }
@@ -83,6 +381,7 @@ impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::result::Result<(), fmt::Error> {
         match self.repr {
             Repr::Simple(kind) => write!(f, "{}", kind.as_str()),
+            Repr::Detailed(ref d) => write!(f, "{}: {}", d.kind.as_str(), d.reason),
             Repr::Custom(ref c) => write!(f, "{}: {}", c.kind.as_str(), c.error),
         }
     }
@@ -94,6 +393,12 @@ impl fmt::Debug for Error {
     }
 }
 
+#[derive(Debug)]
+struct Detailed {
+    kind: ErrorKind,
+    reason: ErrorReason,
+}
+
 #[derive(Debug)]
 struct Custom {
     kind: ErrorKind,
@@ -113,10 +418,25 @@ impl Error {
         }
     }
 
+    pub fn with_reason(kind: ErrorKind, reason: ErrorReason) -> Self {
+        Error {
+            repr: Repr::Detailed(Box::new(Detailed { kind, reason })),
+        }
+    }
+
     pub fn kind(&self) -> ErrorKind {
         match self.repr {
             Repr::Custom(ref c) => c.kind,
+            Repr::Detailed(ref d) => d.kind,
             Repr::Simple(kind) => kind,
         }
     }
+
+    /// Specific reason of the failure, unless the error wraps an error of some other library.
+    pub fn reason(&self) -> Option<&ErrorReason> {
+        match self.repr {
+            Repr::Detailed(ref d) => Some(&d.reason),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,1005 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::constants::IDENTIFIER_LENGTH;
+use crate::crypto::{CipherSuite, DefaultCipherSuite, DefaultGroup, PrivateKey, SphinxGroup};
+use crate::header::keys::{PayloadKey, RoutingKeys};
+use crate::header::mac::HeaderIntegrityMac;
+use crate::header::tlv::{TlvRecord, TlvStream, TlvType};
+use crate::header::SphinxHeader;
+use crate::params::SphinxParams;
+use crate::payload::Payload;
+use crate::route::{Node, SURBIdentifier};
+use crate::surb::SURB;
+use crate::{Error, ErrorKind, ErrorReason, Result, SphinxPacket};
+use byteorder::{BigEndian, ByteOrder};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// Type of the record carrying the `FailureReturn`, attached to the first hop of the packet.
+pub const FAILURE_RETURN_RECORD_TYPE: TlvType = 3;
+/// Type of the record carrying the reference of the `FailureReport`, attached to
+/// the only hop of the packet passing the report back.
+pub const FAILURE_REPORT_RECORD_TYPE: TlvType = 5;
+/// Type of the record carrying the identifier of the SURB deposited with the first hop,
+/// attached to the only hop of the packet carrying the `FailureSurbDeposit`.
+pub const FAILURE_SURB_DEPOSIT_RECORD_TYPE: TlvType = 6;
+
+pub const PACKET_REFERENCE_SIZE: usize = 16;
+const PACKET_REFERENCE_PREFIX: &[u8] = b"sphinx-failure-reference/";
+
+/// All failure messages are of the same size, so that their length does not reveal the failure.
+pub const FAILURE_MESSAGE_SIZE: usize = 256;
+pub const FAILURE_MAC_SIZE: usize = 32;
+// the code followed by the length of the data
+const FAILURE_PREFIX_SIZE: usize = 2 + 2;
+pub const MAX_FAILURE_DATA_LENGTH: usize =
+    FAILURE_MESSAGE_SIZE - FAILURE_MAC_SIZE - FAILURE_PREFIX_SIZE;
+
+const FAILURE_KDF_INFO: &[u8] = b"sphinx-failure/";
+
+pub type FailureCode = u16;
+
+pub const INVALID_MAC_FAILURE: FailureCode = 1;
+pub const UNKNOWN_NEXT_HOP_FAILURE: FailureCode = 2;
+pub const POLICY_REJECTION_FAILURE: FailureCode = 3;
+
+/// Keys a hop uses to report the failure of a packet, or to wrap the report of the hops
+/// after it. They are derived from the payload key of the hop, which both the hop
+/// (see `SphinxHeader::compute_routing_keys`) and the sender of the packet know.
+#[derive(Clone)]
+pub struct FailureKeys<C: CipherSuite = DefaultCipherSuite> {
+    stream_cipher_key: C::StreamCipherKey,
+    integrity_mac_key: C::IntegrityMacKey,
+}
+
+impl<C: CipherSuite> FailureKeys<C> {
+    pub fn derive(payload_key: &PayloadKey) -> Self {
+        let mut stream_cipher_key = C::StreamCipherKey::default();
+        let mut integrity_mac_key = C::IntegrityMacKey::default();
+        let stream_cipher_key_size = stream_cipher_key.as_ref().len();
+
+        let mut output = vec![0u8; stream_cipher_key_size + integrity_mac_key.as_ref().len()];
+        // this can only fail if we requested more than 255 * 32 bytes, which we never do
+        Hkdf::<Sha256>::new(None, payload_key)
+            .expand(FAILURE_KDF_INFO, &mut output)
+            .unwrap();
+        stream_cipher_key
+            .as_mut()
+            .copy_from_slice(&output[..stream_cipher_key_size]);
+        integrity_mac_key
+            .as_mut()
+            .copy_from_slice(&output[stream_cipher_key_size..]);
+
+        FailureKeys {
+            stream_cipher_key,
+            integrity_mac_key,
+        }
+    }
+
+    pub fn from_routing_keys(routing_keys: &RoutingKeys<C>) -> Self {
+        Self::derive(&routing_keys.payload_key)
+    }
+}
+
+impl<C: CipherSuite> fmt::Debug for FailureKeys<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // do not leak the keys
+        write!(f, "FailureKeys")
+    }
+}
+
+/// Reason of the failure reported by a hop, with optional details specific to the code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Failure {
+    code: FailureCode,
+    data: Vec<u8>,
+}
+
+impl Failure {
+    pub fn new(code: FailureCode, data: Vec<u8>) -> Result<Self> {
+        if data.len() > MAX_FAILURE_DATA_LENGTH {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::PayloadTooLarge {
+                    size: data.len(),
+                    maximum: MAX_FAILURE_DATA_LENGTH,
+                },
+            ));
+        }
+        Ok(Failure { code, data })
+    }
+
+    pub fn code(&self) -> FailureCode {
+        self.code
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn write_to(&self, body: &mut [u8]) {
+        BigEndian::write_u16(&mut body[..2], self.code);
+        BigEndian::write_u16(&mut body[2..FAILURE_PREFIX_SIZE], self.data.len() as u16);
+        body[FAILURE_PREFIX_SIZE..FAILURE_PREFIX_SIZE + self.data.len()]
+            .copy_from_slice(&self.data);
+    }
+
+    fn from_body(body: &[u8]) -> Result<Self> {
+        let code = BigEndian::read_u16(&body[..2]);
+        let data_length = BigEndian::read_u16(&body[2..FAILURE_PREFIX_SIZE]) as usize;
+        if data_length > MAX_FAILURE_DATA_LENGTH {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::MalformedFailure,
+            ));
+        }
+        Failure::new(
+            code,
+            body[FAILURE_PREFIX_SIZE..FAILURE_PREFIX_SIZE + data_length].to_vec(),
+        )
+    }
+}
+
+/// Failure report on its way back to the sender, encrypted by the failing hop
+/// and then by every hop before it, in the same way as the error onions of Lightning.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FailureMessage(Vec<u8>);
+
+impl FailureMessage {
+    /// Creates the report of the failing hop, authenticated with its keys.
+    pub fn new<C: CipherSuite>(failure: &Failure, keys: &FailureKeys<C>) -> Self {
+        let mut bytes = vec![0u8; FAILURE_MESSAGE_SIZE];
+        let (mac, body) = bytes.split_at_mut(FAILURE_MAC_SIZE);
+        failure.write_to(body);
+        C::compute_truncated_integrity_mac(&keys.integrity_mac_key, body, mac);
+        FailureMessage(bytes).wrap(keys)
+    }
+
+    /// Adds the layer of encryption of the hop passing the report back towards the sender.
+    pub fn wrap<C: CipherSuite>(mut self, keys: &FailureKeys<C>) -> Self {
+        C::apply_keystream(&keys.stream_cipher_key, 0, &mut self.0);
+        self
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != FAILURE_MESSAGE_SIZE {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::UnexpectedLength {
+                    expected: FAILURE_MESSAGE_SIZE,
+                    actual: bytes.len(),
+                },
+            ));
+        }
+        Ok(FailureMessage(bytes.to_vec()))
+    }
+}
+
+/// Record attached to the first hop of the packet, referring to the SURB the sender has deposited
+/// with that node beforehand, see `FailureSurbDeposit`. The first hop delivers the fully wrapped
+/// failure report with it, so that the report reaches the sender as a regular packet.
+/// The SURB is built with the params and payload size of any other packet, while the record
+/// only takes `IDENTIFIER_LENGTH` bytes of the routing information. A header of its own would
+/// not fit in there, since it is longer than the whole routing information of the packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FailureReturn {
+    surb_identifier: SURBIdentifier,
+}
+
+impl FailureReturn {
+    pub fn new(surb_identifier: SURBIdentifier) -> Self {
+        FailureReturn { surb_identifier }
+    }
+
+    pub fn surb_identifier(&self) -> &SURBIdentifier {
+        &self.surb_identifier
+    }
+
+    pub fn to_record(&self) -> TlvRecord {
+        TlvRecord::new(FAILURE_RETURN_RECORD_TYPE, self.surb_identifier.to_vec())
+    }
+
+    /// Recovers the reference to the SURB from the records of the first hop,
+    /// if the sender attached it.
+    pub fn from_records(records: &TlvStream) -> Result<Option<Self>> {
+        let bytes = match records.get(FAILURE_RETURN_RECORD_TYPE) {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        if bytes.len() != IDENTIFIER_LENGTH {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidSURB,
+                ErrorReason::UnexpectedLength {
+                    expected: IDENTIFIER_LENGTH,
+                    actual: bytes.len(),
+                },
+            ));
+        }
+
+        let mut surb_identifier = [0u8; IDENTIFIER_LENGTH];
+        surb_identifier.copy_from_slice(bytes);
+        Ok(Some(FailureReturn { surb_identifier }))
+    }
+}
+
+/// Reference to the packet known to both the hop forwarding it and the hop receiving it,
+/// derived from the shared secret in its header.
+pub type PacketReference = [u8; PACKET_REFERENCE_SIZE];
+
+pub fn packet_reference<C: CipherSuite>(packet: &SphinxPacket<C>) -> PacketReference {
+    let digest = Sha256::new()
+        .chain(PACKET_REFERENCE_PREFIX)
+        .chain(packet.shared_secret().as_bytes())
+        .finalize();
+    let mut reference = [0u8; PACKET_REFERENCE_SIZE];
+    reference.copy_from_slice(&digest[..PACKET_REFERENCE_SIZE]);
+    reference
+}
+
+/// Failure message passed to the hop which forwarded the referenced packet.
+/// Hops send it inside a regular packet, see `SphinxPacketBuilder::build_failure_report`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FailureReport {
+    reference: PacketReference,
+    message: FailureMessage,
+}
+
+impl FailureReport {
+    pub fn new(reference: PacketReference, message: FailureMessage) -> Self {
+        FailureReport { reference, message }
+    }
+
+    pub fn reference(&self) -> &PacketReference {
+        &self.reference
+    }
+
+    pub fn message(&self) -> &FailureMessage {
+        &self.message
+    }
+
+    pub fn to_record(&self) -> TlvRecord {
+        TlvRecord::new(FAILURE_REPORT_RECORD_TYPE, self.reference.to_vec())
+    }
+
+    /// Recovers the report from the packet the node received as its final hop,
+    /// if the packet carries one.
+    pub fn from_final_hop<C: CipherSuite>(
+        records: &TlvStream,
+        payload: Payload<C>,
+    ) -> Result<Option<Self>> {
+        let bytes = match records.get(FAILURE_REPORT_RECORD_TYPE) {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        if bytes.len() != PACKET_REFERENCE_SIZE {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::UnexpectedLength {
+                    expected: PACKET_REFERENCE_SIZE,
+                    actual: bytes.len(),
+                },
+            ));
+        }
+
+        let mut reference = [0u8; PACKET_REFERENCE_SIZE];
+        reference.copy_from_slice(bytes);
+        let message = FailureMessage::from_bytes(&payload.recover_plaintext()?)?;
+        Ok(Some(FailureReport { reference, message }))
+    }
+}
+
+/// Where the node has to pass the failure report next.
+#[derive(Clone, Debug)]
+pub enum FailureReturnRoute<G: SphinxGroup = DefaultGroup> {
+    /// Report for the hop the packet came from, see `SphinxPacketBuilder::build_failure_report`.
+    PreviousHop(Node<G>, FailureReport),
+    /// Fully wrapped report, to be delivered to the sender with the SURB it deposited
+    /// under the identifier, see `FailureSurbStore::take`.
+    Sender(SURBIdentifier, FailureMessage),
+}
+
+/// What the node has to know about the received packet in order to report its failure
+/// or to pass back the reports of the hops after it. It has to be captured before
+/// the packet is processed.
+pub struct ReceivedPacket<C: CipherSuite = DefaultCipherSuite> {
+    reference: PacketReference,
+    previous_hop: Option<Node<C::Group>>,
+    keys: FailureKeys<C>,
+}
+
+impl<C: CipherSuite> ReceivedPacket<C> {
+    /// The previous hop is the node the packet came from, or `None` if the node
+    /// is the first hop and received the packet from its sender.
+    pub fn new(
+        packet: &SphinxPacket<C>,
+        node_secret_key: &PrivateKey<C::Group>,
+        previous_hop: Option<Node<C::Group>>,
+    ) -> Self {
+        let routing_keys =
+            SphinxHeader::<C>::compute_routing_keys(&packet.shared_secret(), node_secret_key);
+        ReceivedPacket {
+            reference: packet_reference(packet),
+            previous_hop,
+            keys: FailureKeys::from_routing_keys(&routing_keys),
+        }
+    }
+
+    /// Reports the failure of the packet at this node. The first hop needs the records
+    /// of the packet to find the SURB of the sender, hence the report is only possible
+    /// if it managed to process the header. Returns `None` if the failure can not be reported.
+    pub fn report(
+        &self,
+        failure: &Failure,
+        records: &TlvStream,
+    ) -> Result<Option<FailureReturnRoute<C::Group>>> {
+        let message = FailureMessage::new(failure, &self.keys);
+        Ok(self.return_route(records)?.map(|route| route.with(message)))
+    }
+
+    fn return_route(&self, records: &TlvStream) -> Result<Option<ReturnRoute<C::Group>>> {
+        match &self.previous_hop {
+            Some(previous_hop) => Ok(Some(ReturnRoute::PreviousHop(
+                previous_hop.clone(),
+                self.reference,
+            ))),
+            None => Ok(FailureReturn::from_records(records)?
+                .map(|failure_return| ReturnRoute::Sender(failure_return.surb_identifier))),
+        }
+    }
+}
+
+enum ReturnRoute<G: SphinxGroup> {
+    PreviousHop(Node<G>, PacketReference),
+    Sender(SURBIdentifier),
+}
+
+impl<G: SphinxGroup> ReturnRoute<G> {
+    fn with(self, message: FailureMessage) -> FailureReturnRoute<G> {
+        match self {
+            ReturnRoute::PreviousHop(node, reference) => {
+                FailureReturnRoute::PreviousHop(node, FailureReport::new(reference, message))
+            }
+            ReturnRoute::Sender(surb_identifier) => {
+                FailureReturnRoute::Sender(surb_identifier, message)
+            }
+        }
+    }
+}
+
+struct ForwardedPacket<C: CipherSuite> {
+    return_route: ReturnRoute<C::Group>,
+    keys: FailureKeys<C>,
+}
+
+/// Default number of forwarded packets remembered by `FailureRelay`.
+pub const DEFAULT_FAILURE_RELAY_CAPACITY: usize = 1 << 16;
+
+/// Remembers the packets the node has forwarded, so that the failure reports coming back
+/// from the next hops could be wrapped and passed on towards their senders.
+/// Only the most recently forwarded packets are remembered, up to the capacity,
+/// and the reports for the forgotten ones are dropped.
+pub struct FailureRelay<C: CipherSuite = DefaultCipherSuite> {
+    forwarded: HashMap<PacketReference, ForwardedPacket<C>>,
+    // order in which the packets were forwarded, so that the oldest ones are forgotten first
+    forwarded_order: VecDeque<PacketReference>,
+    capacity: usize,
+}
+
+impl<C: CipherSuite> Default for FailureRelay<C> {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_FAILURE_RELAY_CAPACITY)
+    }
+}
+
+impl<C: CipherSuite> FailureRelay<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        FailureRelay {
+            forwarded: HashMap::new(),
+            forwarded_order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Number of forwarded packets still remembered.
+    pub fn len(&self) -> usize {
+        self.forwarded.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.forwarded.is_empty()
+    }
+
+    /// Remembers the packet forwarded after processing the received one, together with
+    /// the records of the received packet. The first hop only remembers the packets
+    /// whose senders attached `FailureReturn`.
+    pub fn remember(
+        &mut self,
+        received: ReceivedPacket<C>,
+        forwarded: &SphinxPacket<C>,
+        records: &TlvStream,
+    ) -> Result<()> {
+        let return_route = match received.return_route(records)? {
+            Some(return_route) => return_route,
+            None => return Ok(()),
+        };
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        if self.forwarded_order.len() >= self.capacity {
+            if let Some(oldest) = self.forwarded_order.pop_front() {
+                self.forwarded.remove(&oldest);
+            }
+        }
+
+        let reference = packet_reference(forwarded);
+        let forwarded_packet = ForwardedPacket {
+            return_route,
+            keys: received.keys,
+        };
+        if self.forwarded.insert(reference, forwarded_packet).is_none() {
+            self.forwarded_order.push_back(reference);
+        }
+        Ok(())
+    }
+
+    /// Wraps the report of the next hop and tells where to pass it. Returns `None`
+    /// if the referenced packet has not been forwarded by the node or has been forgotten.
+    /// Every packet is only reported once.
+    pub fn relay(&mut self, report: FailureReport) -> Option<FailureReturnRoute<C::Group>> {
+        let forwarded_packet = self.forwarded.remove(&report.reference)?;
+        self.forwarded_order
+            .retain(|reference| reference != &report.reference);
+        let message = report.message.wrap(&forwarded_packet.keys);
+        Some(forwarded_packet.return_route.with(message))
+    }
+}
+
+/// SURB the sender deposits with the first hop of its packets, so that the first hop could
+/// deliver the failure reports to the sender. Nodes send it inside a regular packet,
+/// see `SphinxPacketBuilder::build_failure_surb_deposit`.
+pub struct FailureSurbDeposit<C: CipherSuite = DefaultCipherSuite> {
+    surb_identifier: SURBIdentifier,
+    surb: SURB<C>,
+}
+
+impl<C: CipherSuite> FailureSurbDeposit<C> {
+    /// The identifier is the one of the SURB keys, see `SURBDecryptionKeys::identifier`,
+    /// and the sender refers to it with `FailureReturn`.
+    pub fn new(surb_identifier: SURBIdentifier, surb: SURB<C>) -> Self {
+        FailureSurbDeposit {
+            surb_identifier,
+            surb,
+        }
+    }
+
+    pub fn surb_identifier(&self) -> &SURBIdentifier {
+        &self.surb_identifier
+    }
+
+    pub fn surb(&self) -> &SURB<C> {
+        &self.surb
+    }
+
+    pub fn to_record(&self) -> TlvRecord {
+        TlvRecord::new(
+            FAILURE_SURB_DEPOSIT_RECORD_TYPE,
+            self.surb_identifier.to_vec(),
+        )
+    }
+
+    /// Recovers the deposit from the packet the node received as its final hop,
+    /// if the packet carries one. The SURB has to be built with the given params.
+    pub fn from_final_hop(
+        records: &TlvStream,
+        payload: Payload<C>,
+        params: &SphinxParams,
+    ) -> Result<Option<Self>> {
+        let bytes = match records.get(FAILURE_SURB_DEPOSIT_RECORD_TYPE) {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        if bytes.len() != IDENTIFIER_LENGTH {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidSURB,
+                ErrorReason::UnexpectedLength {
+                    expected: IDENTIFIER_LENGTH,
+                    actual: bytes.len(),
+                },
+            ));
+        }
+
+        let mut surb_identifier = [0u8; IDENTIFIER_LENGTH];
+        surb_identifier.copy_from_slice(bytes);
+        let surb = SURB::from_bytes_with_params(&payload.recover_plaintext()?, params)?;
+        Ok(Some(FailureSurbDeposit {
+            surb_identifier,
+            surb,
+        }))
+    }
+}
+
+/// Default number of deposited SURBs kept by `FailureSurbStore`.
+pub const DEFAULT_FAILURE_SURB_STORE_CAPACITY: usize = 1 << 16;
+
+/// SURBs deposited with the node by the senders of the packets it is the first hop of.
+/// Only the most recently deposited SURBs are kept, up to the capacity, and every SURB
+/// is only taken once, as it can only be used once.
+pub struct FailureSurbStore<C: CipherSuite = DefaultCipherSuite> {
+    surbs: HashMap<SURBIdentifier, SURB<C>>,
+    // order in which the SURBs were deposited, so that the oldest ones are dropped first
+    deposit_order: VecDeque<SURBIdentifier>,
+    capacity: usize,
+}
+
+impl<C: CipherSuite> Default for FailureSurbStore<C> {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_FAILURE_SURB_STORE_CAPACITY)
+    }
+}
+
+impl<C: CipherSuite> FailureSurbStore<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        FailureSurbStore {
+            surbs: HashMap::new(),
+            deposit_order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Number of deposited SURBs still kept.
+    pub fn len(&self) -> usize {
+        self.surbs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.surbs.is_empty()
+    }
+
+    /// Keeps the SURB until the failure report for the sender arrives. A SURB deposited again
+    /// under the same identifier replaces the previous one.
+    pub fn deposit(&mut self, deposit: FailureSurbDeposit<C>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.surbs.contains_key(&deposit.surb_identifier)
+            && self.deposit_order.len() >= self.capacity
+        {
+            if let Some(oldest) = self.deposit_order.pop_front() {
+                self.surbs.remove(&oldest);
+            }
+        }
+
+        if self
+            .surbs
+            .insert(deposit.surb_identifier, deposit.surb)
+            .is_none()
+        {
+            self.deposit_order.push_back(deposit.surb_identifier);
+        }
+    }
+
+    /// Takes the SURB the report routed to `FailureReturnRoute::Sender` has to be sent with.
+    /// Returns `None` if no SURB has been deposited under the identifier or it has been dropped.
+    pub fn take(&mut self, surb_identifier: &SURBIdentifier) -> Option<SURB<C>> {
+        let surb = self.surbs.remove(surb_identifier)?;
+        self.deposit_order
+            .retain(|identifier| identifier != surb_identifier);
+        Some(surb)
+    }
+}
+
+/// Keys the sender of the packet has to keep in order to read the failure report.
+pub struct FailureDecryptionKeys<C: CipherSuite = DefaultCipherSuite> {
+    hop_keys: Vec<FailureKeys<C>>,
+}
+
+impl<C: CipherSuite> FailureDecryptionKeys<C> {
+    /// Keys of the packet sent with the given payload keys of the hops.
+    pub(crate) fn new(payload_keys: &[PayloadKey]) -> Self {
+        FailureDecryptionKeys {
+            hop_keys: payload_keys.iter().map(FailureKeys::derive).collect(),
+        }
+    }
+
+    /// Peels the layers of the report, i.e. the reply recovered with the keys of the SURB
+    /// referenced by `FailureReturn`, and returns the index of the failing hop within
+    /// the route of the packet, together with the reason it reported.
+    pub fn recover_failure(&self, report: &[u8]) -> Result<(usize, Failure)> {
+        let mut message = FailureMessage::from_bytes(report)?;
+
+        for (hop_index, keys) in self.hop_keys.iter().enumerate() {
+            message = message.wrap(keys);
+            let (mac, body) = message.0.split_at(FAILURE_MAC_SIZE);
+            if HeaderIntegrityMac::verify_bytes::<C>(&keys.integrity_mac_key, body, mac) {
+                return Ok((hop_index, Failure::from_body(body)?));
+            }
+        }
+        Err(Error::with_reason(
+            ErrorKind::InvalidPayload,
+            ErrorReason::InvalidMac,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod failure_reports {
+    use super::*;
+
+    fn payload_keys() -> Vec<PayloadKey> {
+        (0..3u8).map(|i| [i + 1; 192]).collect()
+    }
+
+    fn decryption_keys(payload_keys: &[PayloadKey]) -> FailureDecryptionKeys {
+        FailureDecryptionKeys::new(payload_keys)
+    }
+
+    // wraps the report of the failing hop with the keys of all the hops before it
+    fn report_of(failing_hop: usize, failure: &Failure) -> Vec<u8> {
+        let payload_keys = payload_keys();
+        let keys: Vec<FailureKeys> = payload_keys.iter().map(FailureKeys::derive).collect();
+        let mut message = FailureMessage::new(failure, &keys[failing_hop]);
+        for hop_keys in keys[..failing_hop].iter().rev() {
+            message = message.wrap(hop_keys);
+        }
+        message.as_bytes().to_vec()
+    }
+
+    #[test]
+    fn sender_identifies_the_failing_hop_and_its_reason() {
+        let failure = Failure::new(UNKNOWN_NEXT_HOP_FAILURE, vec![42u8; 10]).unwrap();
+        for failing_hop in 0..3 {
+            let (hop_index, recovered) = decryption_keys(&payload_keys())
+                .recover_failure(&report_of(failing_hop, &failure))
+                .unwrap();
+            assert_eq!(failing_hop, hop_index);
+            assert_eq!(failure, recovered);
+        }
+    }
+
+    #[test]
+    fn reports_are_of_the_same_size_regardless_of_the_failure() {
+        let keys = FailureKeys::<DefaultCipherSuite>::derive(&[1u8; 192]);
+        let short = Failure::new(POLICY_REJECTION_FAILURE, Vec::new()).unwrap();
+        let long = Failure::new(INVALID_MAC_FAILURE, vec![1u8; MAX_FAILURE_DATA_LENGTH]).unwrap();
+        assert_eq!(
+            FailureMessage::new(&short, &keys).as_bytes().len(),
+            FailureMessage::new(&long, &keys).as_bytes().len()
+        );
+        assert!(Failure::new(INVALID_MAC_FAILURE, vec![1u8; MAX_FAILURE_DATA_LENGTH + 1]).is_err());
+    }
+
+    #[test]
+    fn tampered_report_can_not_be_attributed_to_any_hop() {
+        let failure = Failure::new(POLICY_REJECTION_FAILURE, Vec::new()).unwrap();
+        let mut bytes = report_of(2, &failure);
+        bytes[FAILURE_MAC_SIZE] ^= 1;
+
+        let err = decryption_keys(&payload_keys())
+            .recover_failure(&bytes)
+            .unwrap_err();
+        assert_eq!(Some(&ErrorReason::InvalidMac), err.reason());
+    }
+
+    #[test]
+    fn report_is_not_attributed_to_hops_outside_the_route() {
+        let failure = Failure::new(POLICY_REJECTION_FAILURE, Vec::new()).unwrap();
+        let payload_keys = payload_keys();
+        // the sender only knows the keys of the first two hops
+        assert!(decryption_keys(&payload_keys[..2])
+            .recover_failure(&report_of(2, &failure))
+            .is_err());
+    }
+
+    #[test]
+    fn failure_return_record_only_carries_the_surb_identifier() {
+        let failure_return = FailureReturn::new([7u8; IDENTIFIER_LENGTH]);
+        let mut records = TlvStream::new();
+        records.insert(failure_return.to_record());
+        assert_eq!(
+            Some(failure_return),
+            FailureReturn::from_records(&records).unwrap()
+        );
+        assert_eq!(
+            None,
+            FailureReturn::from_records(&TlvStream::new()).unwrap()
+        );
+
+        let mut records = TlvStream::new();
+        records.insert(TlvRecord::new(FAILURE_RETURN_RECORD_TYPE, vec![7u8; 3]));
+        assert!(FailureReturn::from_records(&records).is_err());
+    }
+}
+
+#[cfg(test)]
+mod relaying_failure_reports {
+    use super::*;
+    use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::crypto;
+    use crate::header::delays::Delay;
+    use crate::route::{DestinationAddressBytes, NodeAddressBytes, Route};
+    use crate::test_utils::fixtures::destination_fixture;
+    use crate::SphinxPacketBuilder;
+
+    struct Hop {
+        secret_key: PrivateKey,
+        node: Node,
+    }
+
+    fn hops(count: u8) -> Vec<Hop> {
+        (0..count)
+            .map(|i| {
+                let (secret_key, pub_key) = crypto::keygen();
+                let node = Node::new(
+                    NodeAddressBytes::from_bytes([i; NODE_ADDRESS_LENGTH]),
+                    pub_key,
+                );
+                Hop { secret_key, node }
+            })
+            .collect()
+    }
+
+    fn packet_through(hops: &[Hop]) -> SphinxPacket {
+        let route = Route::new(
+            hops.iter().map(|hop| hop.node.clone()).collect(),
+            vec![Delay::new_from_nanos(0); hops.len()],
+            destination_fixture(),
+        )
+        .unwrap();
+        SphinxPacketBuilder::new()
+            .build_packet(b"hello", &route)
+            .unwrap()
+    }
+
+    // processes the packet at the first of the hops and remembers it at the relay
+    fn forward(relay: &mut FailureRelay, packet: SphinxPacket, hops: &[Hop]) -> SphinxPacket {
+        let received =
+            ReceivedPacket::new(&packet, &hops[1].secret_key, Some(hops[0].node.clone()));
+        match packet.process(&hops[1].secret_key).unwrap() {
+            crate::ProcessedPacket::ForwardHop(next_packet, _, _, records, _) => {
+                relay.remember(received, &next_packet, &records).unwrap();
+                *next_packet
+            }
+            _ => panic!("packet has not been forwarded"),
+        }
+    }
+
+    #[test]
+    fn report_of_the_next_hop_is_wrapped_and_passed_to_the_previous_hop() {
+        let hops = hops(3);
+        let packet = packet_through(&hops[1..]);
+        let mut relay = FailureRelay::new();
+        let forwarded = forward(&mut relay, packet, &hops);
+
+        let failure = Failure::new(POLICY_REJECTION_FAILURE, Vec::new()).unwrap();
+        let received =
+            ReceivedPacket::new(&forwarded, &hops[2].secret_key, Some(hops[1].node.clone()));
+        let report = match received.report(&failure, &TlvStream::new()).unwrap() {
+            Some(FailureReturnRoute::PreviousHop(node, report)) => {
+                assert_eq!(hops[1].node.address, node.address);
+                report
+            }
+            _ => panic!("report is not for the previous hop"),
+        };
+
+        match relay.relay(report.clone()) {
+            Some(FailureReturnRoute::PreviousHop(node, relayed)) => {
+                assert_eq!(hops[0].node.address, node.address);
+                assert_ne!(report.message(), relayed.message());
+            }
+            _ => panic!("report has not been relayed"),
+        }
+        // reports are only relayed once
+        assert!(relay.relay(report).is_none());
+        assert!(relay.is_empty());
+    }
+
+    #[test]
+    fn relay_forgets_the_oldest_packets_over_capacity() {
+        let hops = hops(3);
+        let mut relay = FailureRelay::with_capacity(2);
+        let forwarded: Vec<_> = (0..3)
+            .map(|_| forward(&mut relay, packet_through(&hops[1..]), &hops))
+            .collect();
+        assert_eq!(2, relay.len());
+
+        let message = FailureMessage(vec![0u8; FAILURE_MESSAGE_SIZE]);
+        let report_for =
+            |packet: &SphinxPacket| FailureReport::new(packet_reference(packet), message.clone());
+        assert!(relay.relay(report_for(&forwarded[0])).is_none());
+        assert!(relay.relay(report_for(&forwarded[1])).is_some());
+        assert!(relay.relay(report_for(&forwarded[2])).is_some());
+    }
+
+    #[test]
+    fn first_hop_only_remembers_packets_with_failure_return() {
+        let hops = hops(2);
+        let route = Route::new(
+            hops.iter().map(|hop| hop.node.clone()).collect(),
+            vec![Delay::new_from_nanos(0); hops.len()],
+            destination_fixture(),
+        )
+        .unwrap();
+        let builder = SphinxPacketBuilder::<DefaultCipherSuite>::new();
+        let mut relay = FailureRelay::new();
+
+        for packet in [
+            builder.build_packet(b"hello", &route).unwrap(),
+            builder
+                .build_packet_with_failure_return(
+                    b"hello",
+                    &route,
+                    &FailureReturn::new([9u8; IDENTIFIER_LENGTH]),
+                )
+                .unwrap()
+                .0,
+        ] {
+            let received = ReceivedPacket::new(&packet, &hops[0].secret_key, None);
+            match packet.process(&hops[0].secret_key).unwrap() {
+                crate::ProcessedPacket::ForwardHop(next_packet, _, _, records, _) => {
+                    relay.remember(received, &next_packet, &records).unwrap()
+                }
+                _ => panic!("packet has not been forwarded"),
+            }
+        }
+        assert_eq!(1, relay.len());
+    }
+
+    #[test]
+    fn report_is_recovered_from_the_packet_sent_to_the_previous_hop() {
+        let hops = hops(1);
+        let report = FailureReport::new(
+            [3u8; PACKET_REFERENCE_SIZE],
+            FailureMessage(vec![5u8; FAILURE_MESSAGE_SIZE]),
+        );
+        let builder = SphinxPacketBuilder::new();
+        let packet: SphinxPacket = builder
+            .build_failure_report(&report, &hops[0].node)
+            .unwrap();
+        let ordinary = packet_through(&hops);
+        assert_eq!(ordinary.len(), packet.len());
+
+        match packet.process(&hops[0].secret_key).unwrap() {
+            crate::ProcessedPacket::FinalHop(address, _, payload, records, _) => {
+                assert_eq!(
+                    DestinationAddressBytes::from_bytes(hops[0].node.address.as_bytes()),
+                    address
+                );
+                assert_eq!(
+                    Some(report),
+                    FailureReport::from_final_hop(&records, payload).unwrap()
+                );
+            }
+            _ => panic!("report has not been delivered"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod depositing_failure_surbs {
+    use super::*;
+    use crate::constants::DESTINATION_ADDRESS_LENGTH;
+    use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::crypto;
+    use crate::header::delays::Delay;
+    use crate::route::{Destination, DestinationAddressBytes, NodeAddressBytes, Route};
+    use crate::{SURBMaterial, SphinxPacketBuilder};
+
+    fn node(i: u8) -> (PrivateKey, Node) {
+        let (secret_key, pub_key) = crypto::keygen();
+        (
+            secret_key,
+            Node::new(
+                NodeAddressBytes::from_bytes([i; NODE_ADDRESS_LENGTH]),
+                pub_key,
+            ),
+        )
+    }
+
+    // the identifier of the SURB is the one of its destination
+    fn deposit(i: u8) -> FailureSurbDeposit {
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([i; DESTINATION_ADDRESS_LENGTH]),
+            [i; IDENTIFIER_LENGTH],
+        );
+        let route = Route::new(
+            vec![node(1).1, node(2).1],
+            vec![Delay::new_from_nanos(0); 2],
+            destination,
+        )
+        .unwrap();
+        let (surb, keys) = SURBMaterial::new(route).construct_SURB().unwrap();
+        FailureSurbDeposit::new(*keys.identifier(), surb)
+    }
+
+    #[test]
+    fn deposit_is_recovered_from_the_packet_sent_to_the_first_hop() {
+        let (secret_key, first_hop) = node(0);
+        let deposit = deposit(1);
+        let builder = SphinxPacketBuilder::new();
+        let packet: SphinxPacket = builder
+            .build_failure_surb_deposit(&deposit, &first_hop)
+            .unwrap();
+        let ordinary = builder
+            .build_failure_report(
+                &FailureReport::new(
+                    [0u8; PACKET_REFERENCE_SIZE],
+                    FailureMessage(vec![0u8; FAILURE_MESSAGE_SIZE]),
+                ),
+                &first_hop,
+            )
+            .unwrap();
+        assert_eq!(ordinary.len(), packet.len());
+
+        match packet.process(&secret_key).unwrap() {
+            crate::ProcessedPacket::FinalHop(_, _, payload, records, _) => {
+                let recovered =
+                    FailureSurbDeposit::from_final_hop(&records, payload, &SphinxParams::default())
+                        .unwrap()
+                        .unwrap();
+                assert_eq!(deposit.surb_identifier(), recovered.surb_identifier());
+                assert_eq!(
+                    deposit.surb().to_bytes().unwrap(),
+                    recovered.surb().to_bytes().unwrap()
+                );
+            }
+            _ => panic!("deposit has not been delivered"),
+        }
+    }
+
+    #[test]
+    fn deposited_surb_is_only_taken_once() {
+        let deposit = deposit(1);
+        let surb_identifier = *deposit.surb_identifier();
+        let mut store = FailureSurbStore::new();
+        store.deposit(deposit);
+
+        assert!(store.take(&[2u8; IDENTIFIER_LENGTH]).is_none());
+        assert!(store.take(&surb_identifier).is_some());
+        assert!(store.take(&surb_identifier).is_none());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn store_drops_the_oldest_surbs_over_capacity() {
+        let mut store = FailureSurbStore::with_capacity(2);
+        let deposits: Vec<_> = (0..3).map(deposit).collect();
+        let identifiers: Vec<_> = deposits
+            .iter()
+            .map(|deposit| *deposit.surb_identifier())
+            .collect();
+        for deposit in deposits {
+            store.deposit(deposit);
+        }
+        assert_eq!(2, store.len());
+
+        assert!(store.take(&identifiers[0]).is_none());
+        assert!(store.take(&identifiers[1]).is_some());
+        assert!(store.take(&identifiers[2]).is_some());
+    }
+}
@@ -0,0 +1,492 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::CipherSuite;
+use crate::fragment::{MessageId, MESSAGE_ID_LENGTH};
+use crate::header::keys::PayloadKey;
+use crate::payload::{Payload, PAYLOAD_OVERHEAD_SIZE};
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use byteorder::{BigEndian, ByteOrder};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// message id, index of the fragment, number of data and parity fragments and the message length
+pub const CODED_FRAGMENT_HEADER_SIZE: usize = MESSAGE_ID_LENGTH + 1 + 1 + 1 + 4;
+
+/// Reed-Solomon over GF(2^8) supports at most this many fragments in total.
+pub const MAX_CODED_FRAGMENTS: usize = 256;
+
+/// Number of data fragments is encoded in a single byte, so there can be at most this many.
+pub const MAX_DATA_FRAGMENTS: usize = u8::MAX as usize;
+
+/// Fragment of a Reed-Solomon coded message. Any `data_fragments` out of all the fragments
+/// of the message, either data or parity ones, are enough to recover it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodedFragment {
+    message_id: MessageId,
+    index: u8,
+    data_fragments: u8,
+    parity_fragments: u8,
+    message_len: u32,
+    shard: Vec<u8>,
+}
+
+impl CodedFragment {
+    pub fn message_id(&self) -> &MessageId {
+        &self.message_id
+    }
+
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+
+    pub fn data_fragments(&self) -> usize {
+        self.data_fragments as usize
+    }
+
+    pub fn parity_fragments(&self) -> usize {
+        self.parity_fragments as usize
+    }
+
+    fn total(&self) -> usize {
+        self.data_fragments() + self.parity_fragments()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(CODED_FRAGMENT_HEADER_SIZE + self.shard.len());
+        bytes.extend_from_slice(&self.message_id);
+        bytes.push(self.index);
+        bytes.push(self.data_fragments);
+        bytes.push(self.parity_fragments);
+        bytes.extend_from_slice(&self.message_len.to_be_bytes());
+        bytes.extend_from_slice(&self.shard);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < CODED_FRAGMENT_HEADER_SIZE {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::TooShort {
+                    minimum: CODED_FRAGMENT_HEADER_SIZE,
+                    actual: bytes.len(),
+                },
+            ));
+        }
+
+        let mut message_id = [0u8; MESSAGE_ID_LENGTH];
+        message_id.copy_from_slice(&bytes[..MESSAGE_ID_LENGTH]);
+        let fragment = CodedFragment {
+            message_id,
+            index: bytes[MESSAGE_ID_LENGTH],
+            data_fragments: bytes[MESSAGE_ID_LENGTH + 1],
+            parity_fragments: bytes[MESSAGE_ID_LENGTH + 2],
+            message_len: BigEndian::read_u32(&bytes[MESSAGE_ID_LENGTH + 3..]),
+            shard: bytes[CODED_FRAGMENT_HEADER_SIZE..].to_vec(),
+        };
+        if fragment.data_fragments == 0
+            || fragment.index() >= fragment.total()
+            || fragment.total() > MAX_CODED_FRAGMENTS
+            || fragment.message_len as usize > fragment.data_fragments() * fragment.shard.len()
+        {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::MalformedFragment,
+            ));
+        }
+        Ok(fragment)
+    }
+
+    /// Encapsulates the fragment in the payload the same way `SphinxPacketBuilder` would.
+    pub fn encapsulate<C: CipherSuite>(
+        &self,
+        payload_keys: &[PayloadKey],
+        payload_size: usize,
+    ) -> Result<Payload<C>> {
+        Payload::encapsulate_message(&self.to_bytes(), payload_keys, payload_size)
+    }
+
+    /// Recovers the fragment from the plaintext of the payload received at the final hop.
+    pub fn from_payload<C: CipherSuite>(payload: Payload<C>) -> Result<Self> {
+        Self::from_bytes(&payload.recover_plaintext()?)
+    }
+}
+
+fn coding_error(_: reed_solomon_erasure::Error) -> Error {
+    Error::with_reason(ErrorKind::InvalidPayload, ErrorReason::ErasureCodingFailure)
+}
+
+/// Splits the message into data fragments fitting in the payload of the given size and adds
+/// `ceil(redundancy * data_fragments)` parity fragments, so that losing up to that many
+/// of the packets still allows the recipient to recover the message.
+pub fn encode_message(
+    message: &[u8],
+    payload_size: usize,
+    redundancy: f64,
+) -> Result<Vec<CodedFragment>> {
+    let mut message_id = [0u8; MESSAGE_ID_LENGTH];
+    OsRng.fill_bytes(&mut message_id);
+    encode_message_with_id(message_id, message, payload_size, redundancy)
+}
+
+pub fn encode_message_with_id(
+    message_id: MessageId,
+    message: &[u8],
+    payload_size: usize,
+    redundancy: f64,
+) -> Result<Vec<CodedFragment>> {
+    if !redundancy.is_finite() || redundancy < 0.0 {
+        return Err(Error::with_reason(
+            ErrorKind::InvalidPayload,
+            ErrorReason::InvalidRedundancy,
+        ));
+    }
+    let shard_size =
+        match payload_size.checked_sub(PAYLOAD_OVERHEAD_SIZE + CODED_FRAGMENT_HEADER_SIZE) {
+            Some(size) if size > 0 => size,
+            _ => {
+                return Err(Error::with_reason(
+                    ErrorKind::InvalidPayload,
+                    ErrorReason::PayloadTooSmall {
+                        size: payload_size,
+                        minimum: PAYLOAD_OVERHEAD_SIZE + CODED_FRAGMENT_HEADER_SIZE + 1,
+                    },
+                ))
+            }
+        };
+
+    let data_fragments = message.len().div_ceil(shard_size).max(1);
+    let parity_fragments = (data_fragments as f64 * redundancy).ceil() as usize;
+    if data_fragments > MAX_DATA_FRAGMENTS {
+        return Err(Error::with_reason(
+            ErrorKind::InvalidPayload,
+            ErrorReason::TooManyFragments {
+                required: data_fragments,
+                maximum: MAX_DATA_FRAGMENTS,
+            },
+        ));
+    }
+    // with at least a single data fragment, this also keeps the parity ones within a byte
+    if data_fragments.saturating_add(parity_fragments) > MAX_CODED_FRAGMENTS
+        || message.len() > u32::MAX as usize
+    {
+        return Err(Error::with_reason(
+            ErrorKind::InvalidPayload,
+            ErrorReason::TooManyFragments {
+                required: data_fragments.saturating_add(parity_fragments),
+                maximum: MAX_CODED_FRAGMENTS,
+            },
+        ));
+    }
+
+    let mut shards: Vec<Vec<u8>> = (0..data_fragments + parity_fragments)
+        .map(|i| {
+            let start = (i * shard_size).min(message.len());
+            let end = ((i + 1) * shard_size).min(message.len());
+            let mut shard = if i < data_fragments {
+                message[start..end].to_vec()
+            } else {
+                Vec::new()
+            };
+            shard.resize(shard_size, 0);
+            shard
+        })
+        .collect();
+    if parity_fragments > 0 {
+        ReedSolomon::new(data_fragments, parity_fragments)
+            .and_then(|coder| coder.encode(&mut shards))
+            .map_err(coding_error)?;
+    }
+
+    Ok(shards
+        .into_iter()
+        .enumerate()
+        .map(|(index, shard)| CodedFragment {
+            message_id,
+            index: index as u8,
+            data_fragments: data_fragments as u8,
+            parity_fragments: parity_fragments as u8,
+            message_len: message.len() as u32,
+            shard,
+        })
+        .collect())
+}
+
+struct PartialMessage {
+    template: CodedFragment,
+    shards: Vec<Option<Vec<u8>>>,
+    received: usize,
+    first_received: Instant,
+}
+
+/// Buffers coded fragments received at the final hop until enough of them arrive to recover
+/// the message. Ids of recovered messages are remembered until they expire, so that
+/// the remaining fragments of those messages are ignored rather than buffered again.
+pub struct Decoder {
+    timeout: Duration,
+    pending: HashMap<MessageId, PartialMessage>,
+    recovered: HashMap<MessageId, Instant>,
+}
+
+impl Decoder {
+    pub fn new(timeout: Duration) -> Self {
+        Decoder {
+            timeout,
+            pending: HashMap::new(),
+            recovered: HashMap::new(),
+        }
+    }
+
+    /// Number of messages for which not enough fragments arrived yet.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Inserts the fragment and returns the message once enough fragments have arrived.
+    pub fn insert(&mut self, fragment: CodedFragment) -> Result<Option<Vec<u8>>> {
+        if self.recovered.contains_key(&fragment.message_id) {
+            return Ok(None);
+        }
+
+        let partial = self
+            .pending
+            .entry(fragment.message_id)
+            .or_insert_with(|| PartialMessage {
+                template: CodedFragment {
+                    shard: Vec::new(),
+                    ..fragment.clone()
+                },
+                shards: vec![None; fragment.total()],
+                received: 0,
+                first_received: Instant::now(),
+            });
+        if partial.template.data_fragments != fragment.data_fragments
+            || partial.template.parity_fragments != fragment.parity_fragments
+            || partial.template.message_len != fragment.message_len
+            || partial
+                .shards
+                .iter()
+                .flatten()
+                .any(|shard| shard.len() != fragment.shard.len())
+        {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::MalformedFragment,
+            ));
+        }
+
+        let index = fragment.index();
+        if partial.shards[index].is_none() {
+            partial.shards[index] = Some(fragment.shard);
+            partial.received += 1;
+        }
+        if partial.received < partial.template.data_fragments() {
+            return Ok(None);
+        }
+
+        let mut complete = self.pending.remove(&fragment.message_id).unwrap();
+        let data_fragments = complete.template.data_fragments();
+        if complete.shards[..data_fragments]
+            .iter()
+            .any(Option::is_none)
+        {
+            ReedSolomon::new(data_fragments, complete.template.parity_fragments())
+                .and_then(|coder| coder.reconstruct_data(&mut complete.shards))
+                .map_err(coding_error)?;
+        }
+        self.recovered
+            .insert(fragment.message_id, complete.first_received);
+
+        let mut message: Vec<u8> = complete
+            .shards
+            .into_iter()
+            .take(data_fragments)
+            .flatten()
+            .flatten()
+            .collect();
+        message.truncate(complete.template.message_len as usize);
+        Ok(Some(message))
+    }
+
+    /// Inserts the fragment carried in the payload received at the final hop.
+    pub fn insert_payload<C: CipherSuite>(
+        &mut self,
+        payload: Payload<C>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.insert(CodedFragment::from_payload(payload)?)
+    }
+
+    /// Drops messages whose first fragment arrived more than the timeout before `now`
+    /// and returns ids of those which could not be recovered.
+    pub fn expire(&mut self, now: Instant) -> Vec<MessageId> {
+        let timeout = self.timeout;
+        let is_expired =
+            |first_received: &Instant| now.saturating_duration_since(*first_received) > timeout;
+
+        self.recovered
+            .retain(|_, first_received| !is_expired(first_received));
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, partial)| is_expired(&partial.first_received))
+            .map(|(message_id, _)| *message_id)
+            .collect();
+        for message_id in &expired {
+            self.pending.remove(message_id);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod erasure_coding {
+    use super::*;
+    use crate::crypto::DefaultCipherSuite;
+
+    const PAYLOAD_SIZE: usize = 128;
+
+    fn message() -> Vec<u8> {
+        (0..1000).map(|i| (i * 7) as u8).collect()
+    }
+
+    #[test]
+    fn message_is_recovered_from_any_data_fragments_worth_of_payloads() {
+        let message = message();
+        let fragments = encode_message(&message, PAYLOAD_SIZE, 0.5).unwrap();
+        let data_fragments = fragments[0].data_fragments();
+        let parity_fragments = fragments[0].parity_fragments();
+        assert_eq!(
+            (data_fragments as f64 * 0.5).ceil() as usize,
+            parity_fragments
+        );
+
+        // lose the first `parity_fragments` packets, which are all data ones
+        let mut decoder = Decoder::new(Duration::from_secs(10));
+        let mut recovered = None;
+        for fragment in fragments.iter().skip(parity_fragments) {
+            let payload = fragment
+                .encapsulate::<DefaultCipherSuite>(&[], PAYLOAD_SIZE)
+                .unwrap();
+            assert!(recovered.is_none());
+            recovered = decoder.insert_payload(payload).unwrap();
+        }
+        assert_eq!(Some(message), recovered);
+        assert_eq!(0, decoder.pending());
+    }
+
+    #[test]
+    fn message_is_not_recovered_from_fewer_fragments() {
+        let fragments = encode_message(&message(), PAYLOAD_SIZE, 1.0).unwrap();
+        let data_fragments = fragments[0].data_fragments();
+
+        let mut decoder = Decoder::new(Duration::from_secs(10));
+        for fragment in fragments.iter().rev().take(data_fragments - 1) {
+            assert_eq!(None, decoder.insert(fragment.clone()).unwrap());
+            // duplicates do not count
+            assert_eq!(None, decoder.insert(fragment.clone()).unwrap());
+        }
+        assert_eq!(1, decoder.pending());
+        assert_eq!(
+            1,
+            decoder
+                .expire(Instant::now() + Duration::from_secs(11))
+                .len()
+        );
+    }
+
+    #[test]
+    fn remaining_fragments_of_recovered_message_are_ignored() {
+        let message = message();
+        let fragments = encode_message(&message, PAYLOAD_SIZE, 0.2).unwrap();
+        let data_fragments = fragments[0].data_fragments();
+
+        let mut decoder = Decoder::new(Duration::from_secs(10));
+        let recovered: Vec<_> = fragments
+            .into_iter()
+            .filter_map(|fragment| decoder.insert(fragment).unwrap())
+            .collect();
+        assert_eq!(vec![message], recovered);
+        assert_eq!(0, decoder.pending());
+        assert!(data_fragments > 1);
+    }
+
+    #[test]
+    fn message_without_redundancy_has_only_data_fragments() {
+        let fragments = encode_message(&message(), PAYLOAD_SIZE, 0.0).unwrap();
+        assert_eq!(0, fragments[0].parity_fragments());
+
+        let mut decoder = Decoder::new(Duration::from_secs(10));
+        let recovered = fragments
+            .into_iter()
+            .filter_map(|fragment| decoder.insert(fragment).unwrap())
+            .next();
+        assert_eq!(Some(message()), recovered);
+    }
+
+    #[test]
+    fn too_many_fragments_are_rejected() {
+        assert!(encode_message(&[1u8; 100_000], PAYLOAD_SIZE, 1.0).is_err());
+        assert!(encode_message(&message(), PAYLOAD_SIZE, -1.0).is_err());
+    }
+
+    #[test]
+    fn redundancy_has_to_be_a_finite_non_negative_number() {
+        for redundancy in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY, -1.0] {
+            let err = encode_message(&message(), PAYLOAD_SIZE, redundancy).unwrap_err();
+            assert_eq!(Some(&ErrorReason::InvalidRedundancy), err.reason());
+        }
+
+        // parity fragments saturate rather than overflow
+        let err = encode_message(&message(), PAYLOAD_SIZE, 1e30).unwrap_err();
+        assert_eq!(
+            Some(&ErrorReason::TooManyFragments {
+                required: usize::MAX,
+                maximum: MAX_CODED_FRAGMENTS
+            }),
+            err.reason()
+        );
+    }
+
+    #[test]
+    fn number_of_data_fragments_fits_in_a_byte() {
+        let shard_size = 8;
+        let payload_size = PAYLOAD_OVERHEAD_SIZE + CODED_FRAGMENT_HEADER_SIZE + shard_size;
+        let err = encode_message(&vec![1u8; 256 * shard_size], payload_size, 0.0).unwrap_err();
+        assert_eq!(
+            Some(&ErrorReason::TooManyFragments {
+                required: 256,
+                maximum: MAX_DATA_FRAGMENTS
+            }),
+            err.reason()
+        );
+
+        // the largest message which can be coded without redundancy is still decoded
+        let message: Vec<u8> = (0..MAX_DATA_FRAGMENTS * shard_size)
+            .map(|i| i as u8)
+            .collect();
+        let fragments = encode_message(&message, payload_size, 0.0).unwrap();
+        assert_eq!(MAX_DATA_FRAGMENTS, fragments.len());
+
+        let mut decoder = Decoder::new(Duration::from_secs(10));
+        let recovered = fragments
+            .iter()
+            .map(|fragment| CodedFragment::from_bytes(&fragment.to_bytes()).unwrap())
+            .filter_map(|fragment| decoder.insert(fragment).unwrap())
+            .next();
+        assert_eq!(Some(message), recovered);
+    }
+}
@@ -0,0 +1,411 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::CipherSuite;
+use crate::payload::{Payload, PAYLOAD_OVERHEAD_SIZE};
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use byteorder::{BigEndian, ByteOrder};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub const MESSAGE_ID_LENGTH: usize = 8;
+// message id, followed by index of the fragment and the total number of fragments
+pub const FRAGMENT_HEADER_SIZE: usize = MESSAGE_ID_LENGTH + 2 + 2;
+
+pub type MessageId = [u8; MESSAGE_ID_LENGTH];
+
+/// Part of a message small enough to fit in the payload of a single packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fragment {
+    message_id: MessageId,
+    index: u16,
+    total: u16,
+    data: Vec<u8>,
+}
+
+impl Fragment {
+    pub fn message_id(&self) -> &MessageId {
+        &self.message_id
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn total(&self) -> u16 {
+        self.total
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(FRAGMENT_HEADER_SIZE + self.data.len());
+        bytes.extend_from_slice(&self.message_id);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.total.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < FRAGMENT_HEADER_SIZE {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::TooShort {
+                    minimum: FRAGMENT_HEADER_SIZE,
+                    actual: bytes.len(),
+                },
+            ));
+        }
+
+        let mut message_id = [0u8; MESSAGE_ID_LENGTH];
+        message_id.copy_from_slice(&bytes[..MESSAGE_ID_LENGTH]);
+        let index = BigEndian::read_u16(&bytes[MESSAGE_ID_LENGTH..]);
+        let total = BigEndian::read_u16(&bytes[MESSAGE_ID_LENGTH + 2..]);
+        if index >= total {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::MalformedFragment,
+            ));
+        }
+
+        Ok(Fragment {
+            message_id,
+            index,
+            total,
+            data: bytes[FRAGMENT_HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    /// Recovers the fragment from the plaintext of the payload received at the final hop.
+    pub fn from_payload<C: CipherSuite>(payload: Payload<C>) -> Result<Self> {
+        Self::from_bytes(&payload.recover_plaintext()?)
+    }
+}
+
+/// Number of bytes of the message carried by each fragment, so that it fits in the payload
+/// of the given size.
+pub fn fragment_data_size(payload_size: usize) -> Result<usize> {
+    match payload_size.checked_sub(PAYLOAD_OVERHEAD_SIZE + FRAGMENT_HEADER_SIZE) {
+        Some(size) if size > 0 => Ok(size),
+        _ => Err(Error::with_reason(
+            ErrorKind::InvalidPayload,
+            ErrorReason::PayloadTooSmall {
+                size: payload_size,
+                minimum: PAYLOAD_OVERHEAD_SIZE + FRAGMENT_HEADER_SIZE + 1,
+            },
+        )),
+    }
+}
+
+/// Splits the message into fragments under a fresh random message id, each fitting
+/// in the payload of the given size, e.g. the one used by the `SphinxPacketBuilder`.
+pub fn split_message(message: &[u8], payload_size: usize) -> Result<Vec<Fragment>> {
+    let mut message_id = [0u8; MESSAGE_ID_LENGTH];
+    OsRng.fill_bytes(&mut message_id);
+    split_message_with_id(message_id, message, payload_size)
+}
+
+pub fn split_message_with_id(
+    message_id: MessageId,
+    message: &[u8],
+    payload_size: usize,
+) -> Result<Vec<Fragment>> {
+    let data_size = fragment_data_size(payload_size)?;
+    // empty message still needs a single fragment to be delivered
+    let total = message.len().div_ceil(data_size).max(1);
+    if total > u16::MAX as usize {
+        return Err(Error::with_reason(
+            ErrorKind::InvalidPayload,
+            ErrorReason::TooManyFragments {
+                required: total,
+                maximum: u16::MAX as usize,
+            },
+        ));
+    }
+
+    let chunks: Vec<&[u8]> = if message.is_empty() {
+        vec![&[]]
+    } else {
+        message.chunks(data_size).collect()
+    };
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| Fragment {
+            message_id,
+            index: index as u16,
+            total: total as u16,
+            data: data.to_vec(),
+        })
+        .collect())
+}
+
+/// Default number of incomplete messages the `Reassembler` keeps at once.
+pub const DEFAULT_MAX_PENDING_MESSAGES: usize = 1024;
+/// Default number of fragments of the incomplete messages the `Reassembler` keeps at once.
+pub const DEFAULT_MAX_BUFFERED_FRAGMENTS: usize = 1 << 16;
+
+struct PartialMessage {
+    // fragments are unauthenticated, so the slots are only taken once they arrive
+    fragments: HashMap<u16, Vec<u8>>,
+    total: u16,
+    first_received: Instant,
+}
+
+/// Buffers fragments received at the final hop until all the fragments of a message arrive.
+/// Fragments might arrive in any order and duplicates are ignored. Messages which did not
+/// get completed within the timeout since their first fragment arrived are dropped by `expire`.
+/// Since anyone can send fragments, the number of incomplete messages and of their buffered
+/// fragments is limited, and fragments beyond the limits are rejected until some expire.
+pub struct Reassembler {
+    timeout: Duration,
+    max_pending_messages: usize,
+    max_buffered_fragments: usize,
+    buffered_fragments: usize,
+    pending: HashMap<MessageId, PartialMessage>,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Reassembler {
+            timeout,
+            max_pending_messages: DEFAULT_MAX_PENDING_MESSAGES,
+            max_buffered_fragments: DEFAULT_MAX_BUFFERED_FRAGMENTS,
+            buffered_fragments: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn with_max_pending_messages(mut self, max_pending_messages: usize) -> Self {
+        self.max_pending_messages = max_pending_messages;
+        self
+    }
+
+    pub fn with_max_buffered_fragments(mut self, max_buffered_fragments: usize) -> Self {
+        self.max_buffered_fragments = max_buffered_fragments;
+        self
+    }
+
+    /// Number of messages with some of the fragments still missing.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Number of fragments of the incomplete messages.
+    pub fn buffered_fragments(&self) -> usize {
+        self.buffered_fragments
+    }
+
+    /// Inserts the fragment and returns the whole message once its last missing fragment arrives.
+    /// Note that fragments of an already completed message start a new one, which then expires.
+    pub fn insert(&mut self, fragment: Fragment) -> Result<Option<Vec<u8>>> {
+        if fragment.total == 1 {
+            return Ok(Some(fragment.data));
+        }
+
+        let limit_reached_error = || {
+            Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::ReassemblyLimitReached,
+            )
+        };
+        if !self.pending.contains_key(&fragment.message_id)
+            && self.pending.len() >= self.max_pending_messages
+        {
+            return Err(limit_reached_error());
+        }
+
+        let partial = self
+            .pending
+            .entry(fragment.message_id)
+            .or_insert_with(|| PartialMessage {
+                fragments: HashMap::new(),
+                total: fragment.total,
+                first_received: Instant::now(),
+            });
+        if partial.total != fragment.total {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::MalformedFragment,
+            ));
+        }
+
+        if !partial.fragments.contains_key(&fragment.index) {
+            // the last fragment completes the message, so it never has to be buffered
+            if partial.fragments.len() + 1 < partial.total as usize
+                && self.buffered_fragments >= self.max_buffered_fragments
+            {
+                if partial.fragments.is_empty() {
+                    self.pending.remove(&fragment.message_id);
+                }
+                return Err(limit_reached_error());
+            }
+            partial.fragments.insert(fragment.index, fragment.data);
+            self.buffered_fragments += 1;
+        }
+        if partial.fragments.len() < partial.total as usize {
+            return Ok(None);
+        }
+
+        let mut complete = self.pending.remove(&fragment.message_id).unwrap();
+        self.buffered_fragments -= complete.fragments.len();
+        Ok(Some(
+            (0..complete.total)
+                .flat_map(|index| complete.fragments.remove(&index).unwrap())
+                .collect(),
+        ))
+    }
+
+    /// Inserts the fragment carried in the payload received at the final hop.
+    pub fn insert_payload<C: CipherSuite>(
+        &mut self,
+        payload: Payload<C>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.insert(Fragment::from_payload(payload)?)
+    }
+
+    /// Drops incomplete messages whose first fragment arrived more than the timeout before `now`
+    /// and returns their ids.
+    pub fn expire(&mut self, now: Instant) -> Vec<MessageId> {
+        let timeout = self.timeout;
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, partial)| now.saturating_duration_since(partial.first_received) > timeout)
+            .map(|(message_id, _)| *message_id)
+            .collect();
+        for message_id in &expired {
+            if let Some(partial) = self.pending.remove(message_id) {
+                self.buffered_fragments -= partial.fragments.len();
+            }
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod fragmentation {
+    use super::*;
+
+    const PAYLOAD_SIZE: usize = 64;
+
+    #[test]
+    fn fragments_fit_in_the_payload() {
+        let message = vec![42u8; 1000];
+        for fragment in split_message(&message, PAYLOAD_SIZE).unwrap() {
+            assert!(fragment.to_bytes().len() <= PAYLOAD_SIZE - PAYLOAD_OVERHEAD_SIZE);
+            let payload: Payload =
+                Payload::encapsulate_message(&fragment.to_bytes(), &[], PAYLOAD_SIZE).unwrap();
+            assert_eq!(fragment, Fragment::from_payload(payload).unwrap());
+        }
+    }
+
+    #[test]
+    fn message_is_reassembled_from_reordered_and_duplicated_fragments() {
+        let message: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let fragments = split_message(&message, PAYLOAD_SIZE).unwrap();
+        assert!(fragments.len() > 2);
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+        let (last, rest) = fragments.split_last().unwrap();
+        for fragment in rest.iter().rev().chain(rest.iter()) {
+            assert_eq!(None, reassembler.insert(fragment.clone()).unwrap());
+        }
+        assert_eq!(Some(message), reassembler.insert(last.clone()).unwrap());
+        assert_eq!(0, reassembler.pending());
+        assert_eq!(0, reassembler.buffered_fragments());
+    }
+
+    #[test]
+    fn empty_message_is_sent_in_single_fragment() {
+        let fragments = split_message(&[], PAYLOAD_SIZE).unwrap();
+        assert_eq!(1, fragments.len());
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+        let fragment = Fragment::from_bytes(&fragments[0].to_bytes()).unwrap();
+        assert_eq!(Some(Vec::new()), reassembler.insert(fragment).unwrap());
+    }
+
+    #[test]
+    fn incomplete_messages_expire() {
+        let fragments = split_message(&[1u8; 100], PAYLOAD_SIZE).unwrap();
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+        reassembler.insert(fragments[0].clone()).unwrap();
+
+        assert!(reassembler.expire(Instant::now()).is_empty());
+        assert_eq!(
+            vec![*fragments[0].message_id()],
+            reassembler.expire(Instant::now() + Duration::from_secs(11))
+        );
+        assert_eq!(0, reassembler.pending());
+    }
+
+    #[test]
+    fn fragments_with_inconsistent_totals_are_rejected() {
+        let fragments =
+            split_message_with_id([1u8; MESSAGE_ID_LENGTH], &[1u8; 100], PAYLOAD_SIZE).unwrap();
+        let other_fragments =
+            split_message_with_id([1u8; MESSAGE_ID_LENGTH], &[1u8; 200], PAYLOAD_SIZE).unwrap();
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+        reassembler.insert(fragments[0].clone()).unwrap();
+        assert!(reassembler.insert(other_fragments[1].clone()).is_err());
+    }
+
+    #[test]
+    fn fragments_beyond_the_limits_are_rejected() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(10))
+            .with_max_pending_messages(2)
+            .with_max_buffered_fragments(3);
+        // claims the maximum number of fragments, which is not allocated up front
+        let huge = Fragment::from_bytes(
+            &[vec![1u8; MESSAGE_ID_LENGTH], vec![0, 0], vec![0xff, 0xff]].concat(),
+        )
+        .unwrap();
+        assert_eq!(None, reassembler.insert(huge).unwrap());
+
+        let fragments =
+            split_message_with_id([2u8; MESSAGE_ID_LENGTH], &[1u8; 200], PAYLOAD_SIZE).unwrap();
+        assert!(fragments.len() > 3);
+        reassembler.insert(fragments[0].clone()).unwrap();
+        reassembler.insert(fragments[1].clone()).unwrap();
+        assert_eq!(3, reassembler.buffered_fragments());
+        assert_eq!(
+            Some(&ErrorReason::ReassemblyLimitReached),
+            reassembler
+                .insert(fragments[2].clone())
+                .unwrap_err()
+                .reason()
+        );
+
+        let other_fragments =
+            split_message_with_id([3u8; MESSAGE_ID_LENGTH], &[1u8; 200], PAYLOAD_SIZE).unwrap();
+        assert!(reassembler.insert(other_fragments[0].clone()).is_err());
+        assert_eq!(2, reassembler.pending());
+
+        reassembler.expire(Instant::now() + Duration::from_secs(11));
+        assert_eq!(0, reassembler.buffered_fragments());
+        assert!(reassembler.insert(other_fragments[0].clone()).is_ok());
+    }
+
+    #[test]
+    fn payload_too_small_for_any_data_is_rejected() {
+        assert!(split_message(&[1u8; 10], PAYLOAD_OVERHEAD_SIZE + FRAGMENT_HEADER_SIZE).is_err());
+    }
+}
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use crate::constants::DELAY_LENGTH;
+use crate::{Error, ErrorKind, ErrorReason, Result};
 use byteorder::{BigEndian, ByteOrder};
 use rand_distr::{Distribution, Exp};
 use std::{borrow::Borrow, time::Duration};
@@ -51,6 +52,40 @@ impl Delay {
     pub fn from_bytes(delay_bytes: [u8; DELAY_LENGTH]) -> Self {
         Delay(BigEndian::read_u64(&delay_bytes))
     }
+
+    /// Encodes the delay using only `length` bytes. Delays not fitting in that many bytes
+    /// get saturated to the maximum representable value.
+    pub fn to_bytes_with_length(&self, length: usize) -> Result<Vec<u8>> {
+        if length == 0 || length > DELAY_LENGTH {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::InvalidParameter {
+                    name: "delay length",
+                    value: length,
+                    minimum: 1,
+                    maximum: DELAY_LENGTH,
+                },
+            ));
+        }
+        let max_value = u64::MAX >> (8 * (DELAY_LENGTH - length));
+        let full_bytes = Delay(self.0.min(max_value)).to_bytes();
+        Ok(full_bytes[DELAY_LENGTH - length..].to_vec())
+    }
+
+    pub fn from_byte_slice(delay_bytes: &[u8]) -> Result<Self> {
+        if delay_bytes.len() > DELAY_LENGTH {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::UnexpectedLength {
+                    expected: DELAY_LENGTH,
+                    actual: delay_bytes.len(),
+                },
+            ));
+        }
+        let mut full_bytes = [0u8; DELAY_LENGTH];
+        full_bytes[DELAY_LENGTH - delay_bytes.len()..].copy_from_slice(delay_bytes);
+        Ok(Self::from_bytes(full_bytes))
+    }
 }
 
 impl<T> std::iter::Sum<T> for Delay
@@ -150,6 +185,43 @@ mod test_delay_generation {
         assert_eq!(delay, recovered_delay);
     }
 
+    #[test]
+    fn it_is_possible_to_convert_it_to_and_from_shorter_bytes() {
+        let delay = Delay::new_from_nanos(1_234_567_890);
+        let delay_bytes = delay.to_bytes_with_length(4).unwrap();
+        assert_eq!(4, delay_bytes.len());
+        assert_eq!(delay, Delay::from_byte_slice(&delay_bytes).unwrap());
+
+        // and the full length is equivalent to the default encoding
+        assert_eq!(
+            delay.to_bytes().to_vec(),
+            delay.to_bytes_with_length(DELAY_LENGTH).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_can_not_be_encoded_in_zero_or_more_than_the_full_length() {
+        let delay = Delay::new_from_nanos(42);
+        for length in [0, DELAY_LENGTH + 1] {
+            let err = delay.to_bytes_with_length(length).unwrap_err();
+            assert_eq!(ErrorKind::InvalidRouting, err.kind());
+            assert!(matches!(
+                err.reason(),
+                Some(ErrorReason::InvalidParameter { value, .. }) if *value == length
+            ));
+        }
+    }
+
+    #[test]
+    fn it_saturates_delays_not_fitting_in_shorter_bytes() {
+        let delay = Delay::new_from_nanos(1 << 40);
+        let delay_bytes = delay.to_bytes_with_length(4).unwrap();
+        assert_eq!(
+            Delay::new_from_nanos(u32::MAX as u64),
+            Delay::from_byte_slice(&delay_bytes).unwrap()
+        );
+    }
+
     #[test]
     fn it_is_possible_to_convert_it_to_and_from_nanos_without_data_loss() {
         let expected_delay_nanos = 1_234_567_890; // 1.234... s
@@ -171,6 +243,7 @@ mod delay_summing {
     use super::*;
 
     #[test]
+    #[allow(clippy::op_ref)]
     fn works_with_std_ops_only() {
         let delay1 = Delay(42);
         let delay2 = Delay(123);
@@ -185,7 +258,7 @@ mod delay_summing {
 
     #[test]
     fn works_with_iterator() {
-        let delays = vec![Delay(42), Delay(123), Delay(100)];
+        let delays = [Delay(42), Delay(123), Delay(100)];
         let expected = Delay(265);
 
         assert_eq!(expected, delays.iter().sum());
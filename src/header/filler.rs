@@ -12,68 +12,108 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::constants::{HEADER_INTEGRITY_MAC_SIZE, MAX_PATH_LENGTH, NODE_META_INFO_SIZE};
-use crate::crypto;
+use crate::constants::{HEADER_INTEGRITY_MAC_SIZE, NODE_META_INFO_SIZE};
+use crate::crypto::{CipherSuite, DefaultCipherSuite};
 use crate::header::keys::RoutingKeys;
-use crate::{constants, utils};
+use crate::params::SphinxParams;
+use crate::utils;
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use std::marker::PhantomData;
 
 pub const FILLER_STEP_SIZE_INCREASE: usize = NODE_META_INFO_SIZE + HEADER_INTEGRITY_MAC_SIZE;
 
 #[derive(Debug, PartialEq, Eq)]
-pub struct Filler {
+pub struct Filler<C: CipherSuite = DefaultCipherSuite> {
     value: Vec<u8>,
+    _cipher_suite: PhantomData<C>,
 }
 
-impl Filler {
-    pub fn new(routing_keys: &[RoutingKeys]) -> Self {
-        assert!(routing_keys.len() <= MAX_PATH_LENGTH);
-        let filler_value = routing_keys
+impl<C: CipherSuite> Filler<C> {
+    pub fn new(routing_keys: &[RoutingKeys<C>], params: &SphinxParams) -> Result<Self> {
+        if routing_keys.len() > params.max_path_length() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::RouteTooLong {
+                    length: routing_keys.len(),
+                    maximum: params.max_path_length(),
+                },
+            ));
+        }
+        let hop_sizes = vec![params.filler_step_size(); routing_keys.len()];
+        Self::new_with_hop_sizes(routing_keys, &hop_sizes, params)
+    }
+
+    /// Creates filler for hops whose routing information might be longer than a single step,
+    /// i.e. hops with attached tlv records.
+    pub fn new_with_hop_sizes(
+        routing_keys: &[RoutingKeys<C>],
+        hop_sizes: &[usize],
+        params: &SphinxParams,
+    ) -> Result<Self> {
+        if routing_keys.len() != hop_sizes.len() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::HopCountMismatch {
+                    hops: routing_keys.len(),
+                    provided: hop_sizes.len(),
+                },
+            ));
+        }
+        let filler_len = hop_sizes.iter().sum::<usize>();
+        if filler_len > params.encrypted_routing_info_size() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::RoutingInformationTooLong {
+                    required: filler_len,
+                    available: params.encrypted_routing_info_size(),
+                },
+            ));
+        }
+        let keystreams: Vec<_> = routing_keys
             .iter()
-            .map(|node_routing_keys| node_routing_keys.stream_cipher_key) // we only want the cipher key
-            .map(|cipher_key| {
-                crypto::generate_pseudorandom_bytes(
-                    &cipher_key,
-                    &crypto::STREAM_CIPHER_INIT_VECTOR,
-                    constants::STREAM_CIPHER_OUTPUT_LENGTH,
+            .zip(hop_sizes)
+            .map(|(node_routing_keys, hop_size)| {
+                C::generate_pseudorandom_bytes(
+                    &node_routing_keys.stream_cipher_key,
+                    params.encrypted_routing_info_size() + hop_size,
                 )
-            }) // the actual cipher key is only used to generate the pseudorandom bytes
-            .enumerate() // we need to know index of each element to take correct slice of the PRNG output
-            .map(|(i, pseudorandom_bytes)| (i + 1, pseudorandom_bytes)) // the zeroth step is the empty filler and we add on top of it
-            .fold(
-                Vec::new(),
-                |filler_string_accumulator, (i, pseudorandom_bytes)| {
-                    Self::filler_step(filler_string_accumulator, i, pseudorandom_bytes)
-                },
-            );
-        Self {
+            })
+            .collect();
+
+        let mut filler_value = vec![0u8; filler_len];
+        Self::fill(&mut filler_value, &keystreams, hop_sizes, params);
+        Ok(Self {
             value: filler_value,
-        }
+            _cipher_suite: PhantomData,
+        })
     }
 
-    fn filler_step(
-        mut filler_string_accumulator: Vec<u8>,
-        i: usize,
-        pseudorandom_bytes: Vec<u8>,
-    ) -> Vec<u8> {
-        assert_eq!(
-            pseudorandom_bytes.len(),
-            constants::STREAM_CIPHER_OUTPUT_LENGTH
-        );
-        assert_eq!(
-            filler_string_accumulator.len(),
-            FILLER_STEP_SIZE_INCREASE * (i - 1) // make sure it has length of the previous step
-        );
-        let zero_bytes = vec![0u8; FILLER_STEP_SIZE_INCREASE];
-        filler_string_accumulator.extend(&zero_bytes);
-
-        // after computing the output vector of AES_CTR we take the last 3*k*i elements of the returned vector
-        // and xor it with the current filler string
-        utils::bytes::xor_with(
-            &mut filler_string_accumulator,
-            &pseudorandom_bytes[pseudorandom_bytes.len() - i * FILLER_STEP_SIZE_INCREASE..],
-        );
+    /// Xors the filler into the provided zeroed buffer using the already generated keystreams
+    /// of the hops, so that they could be reused for encrypting their routing information.
+    /// Each keystream has to be longer than the encrypted routing information by the size of the hop.
+    pub(crate) fn fill(
+        filler: &mut [u8],
+        keystreams: &[Vec<u8>],
+        hop_sizes: &[usize],
+        params: &SphinxParams,
+    ) {
+        assert_eq!(keystreams.len(), hop_sizes.len());
+        assert_eq!(filler.len(), hop_sizes.iter().sum::<usize>());
 
-        filler_string_accumulator
+        let mut filler_len = 0;
+        for (pseudorandom_bytes, &hop_size) in keystreams.iter().zip(hop_sizes) {
+            assert_eq!(
+                pseudorandom_bytes.len(),
+                params.encrypted_routing_info_size() + hop_size
+            );
+            // each step appends zero bytes of the size of the hop to the filler string,
+            // which is then xored with the same number of last elements of the output of AES_CTR
+            filler_len += hop_size;
+            utils::bytes::xor_with(
+                &mut filler[..filler_len],
+                &pseudorandom_bytes[pseudorandom_bytes.len() - filler_len..],
+            );
+        }
     }
 
     pub fn get_value(self) -> Vec<u8> {
@@ -81,64 +121,67 @@ impl Filler {
     }
 
     pub(crate) fn from_raw(raw_value: Vec<u8>) -> Self {
-        Filler { value: raw_value }
+        Filler {
+            value: raw_value,
+            _cipher_suite: PhantomData,
+        }
     }
 }
 
 #[cfg(test)]
 mod test_creating_pseudorandom_bytes {
+    use crate::constants;
     use crate::header::keys;
 
     use super::*;
-    use crypto::{EphemeralSecret, SharedSecret};
+    use crate::crypto::{EphemeralSecret, SharedSecret};
 
     #[test]
     fn with_no_keys_it_generates_empty_filler_string() {
         let routing_keys: Vec<RoutingKeys> = vec![];
-        let filler_string = Filler::new(&routing_keys);
+        let filler_string = Filler::new(&routing_keys, &Default::default()).unwrap();
 
         assert_eq!(0, filler_string.value.len());
     }
 
     #[test]
     fn with_1_key_it_generates_filler_of_length_1_times_3_times_security_parameter() {
-        let shared_keys = vec![SharedSecret::from(&EphemeralSecret::new())];
-        let routing_keys: Vec<_> = shared_keys
+        let shared_keys = [SharedSecret::from(&EphemeralSecret::new())];
+        let routing_keys: Vec<RoutingKeys> = shared_keys
             .iter()
             .map(|&key| keys::RoutingKeys::derive(key))
             .collect();
-        let filler_string = Filler::new(&routing_keys);
+        let filler_string = Filler::new(&routing_keys, &Default::default()).unwrap();
 
         assert_eq!(FILLER_STEP_SIZE_INCREASE, filler_string.value.len());
     }
 
     #[test]
     fn with_3_key_it_generates_filler_of_length_3_times_3_times_security_parameter() {
-        let shared_keys = vec![
+        let shared_keys = [
             SharedSecret::from(&EphemeralSecret::new()),
             SharedSecret::from(&EphemeralSecret::new()),
             SharedSecret::from(&EphemeralSecret::new()),
         ];
-        let routing_keys: Vec<_> = shared_keys
+        let routing_keys: Vec<RoutingKeys> = shared_keys
             .iter()
             .map(|&key| keys::RoutingKeys::derive(key))
             .collect();
-        let filler_string = Filler::new(&routing_keys);
+        let filler_string = Filler::new(&routing_keys, &Default::default()).unwrap();
         assert_eq!(3 * FILLER_STEP_SIZE_INCREASE, filler_string.value.len());
     }
 
     #[test]
-    #[should_panic]
-    fn panics_with_more_keys_than_the_maximum_path_length() {
+    fn it_fails_with_more_keys_than_the_maximum_path_length() {
         let shared_keys: Vec<_> = std::iter::repeat(())
             .take(constants::MAX_PATH_LENGTH + 1)
             .map(|_| SharedSecret::from(&EphemeralSecret::new()))
             .collect();
-        let routing_keys: Vec<_> = shared_keys
+        let routing_keys: Vec<RoutingKeys> = shared_keys
             .iter()
             .map(|&key| keys::RoutingKeys::derive(key))
             .collect();
-        Filler::new(&routing_keys);
+        assert!(Filler::new(&routing_keys, &Default::default()).is_err());
     }
 }
 
@@ -153,7 +196,7 @@ mod test_new_filler_bytes {
         let routing_key_2 = routing_keys_fixture();
         let routing_key_3 = routing_keys_fixture();
         let routing_keys = [routing_key_1, routing_key_2, routing_key_3];
-        let filler = Filler::new(&routing_keys);
+        let filler = Filler::new(&routing_keys, &Default::default()).unwrap();
         assert_eq!(
             FILLER_STEP_SIZE_INCREASE * (routing_keys.len()),
             filler.get_value().len()
@@ -167,7 +210,7 @@ mod test_new_filler_bytes {
         let routing_key_3 = routing_keys_fixture();
         let routing_key_4 = routing_keys_fixture();
         let routing_keys = [routing_key_1, routing_key_2, routing_key_3, routing_key_4];
-        let filler = Filler::new(&routing_keys);
+        let filler = Filler::new(&routing_keys, &Default::default()).unwrap();
         assert_eq!(
             FILLER_STEP_SIZE_INCREASE * (routing_keys.len()),
             filler.get_value().len()
@@ -178,17 +221,21 @@ mod test_new_filler_bytes {
 #[cfg(test)]
 mod test_generating_filler_bytes {
     use super::*;
+    use crate::constants;
 
     mod for_valid_inputs {
         use super::*;
 
         #[test]
         fn it_returns_the_xored_byte_vector_of_a_correct_length_for_i_1() {
-            let pseudorandom_bytes = vec![0; constants::STREAM_CIPHER_OUTPUT_LENGTH];
-            let filler_string_accumulator = vec![];
-            let filler_string =
-                Filler::filler_step(filler_string_accumulator, 1, pseudorandom_bytes);
-            assert_eq!(FILLER_STEP_SIZE_INCREASE, filler_string.len());
+            let keystreams = vec![vec![0; constants::STREAM_CIPHER_OUTPUT_LENGTH]];
+            let mut filler_string = vec![0u8; FILLER_STEP_SIZE_INCREASE];
+            Filler::<DefaultCipherSuite>::fill(
+                &mut filler_string,
+                &keystreams,
+                &[FILLER_STEP_SIZE_INCREASE],
+                &Default::default(),
+            );
             for x in filler_string {
                 assert_eq!(0, x); // XOR of 0 + 0 == 0
             }
@@ -196,24 +243,48 @@ mod test_generating_filler_bytes {
 
         #[test]
         fn it_returns_the_xored_byte_vector_of_a_correct_length_for_i_3() {
-            let pseudorandom_bytes = vec![0; constants::STREAM_CIPHER_OUTPUT_LENGTH];
-            let filler_string_accumulator = vec![0u8; 2 * FILLER_STEP_SIZE_INCREASE];
-            let filler_string =
-                Filler::filler_step(filler_string_accumulator, 3, pseudorandom_bytes);
-            assert_eq!(FILLER_STEP_SIZE_INCREASE * 3, filler_string.len());
+            let keystreams = vec![vec![0; constants::STREAM_CIPHER_OUTPUT_LENGTH]; 3];
+            let mut filler_string = vec![0u8; 3 * FILLER_STEP_SIZE_INCREASE];
+            Filler::<DefaultCipherSuite>::fill(
+                &mut filler_string,
+                &keystreams,
+                &[FILLER_STEP_SIZE_INCREASE; 3],
+                &Default::default(),
+            );
             for x in filler_string {
                 assert_eq!(0, x); // XOR of 0 + 0 == 0
             }
         }
 
-        mod for_an_empty_filler_string_accumulator {
+        #[test]
+        fn it_xors_each_step_with_the_end_of_the_keystream() {
+            let keystreams = vec![
+                vec![1; constants::STREAM_CIPHER_OUTPUT_LENGTH],
+                vec![2; constants::STREAM_CIPHER_OUTPUT_LENGTH],
+            ];
+            let mut filler_string = vec![0u8; 2 * FILLER_STEP_SIZE_INCREASE];
+            Filler::<DefaultCipherSuite>::fill(
+                &mut filler_string,
+                &keystreams,
+                &[FILLER_STEP_SIZE_INCREASE; 2],
+                &Default::default(),
+            );
+            assert!(filler_string[..FILLER_STEP_SIZE_INCREASE]
+                .iter()
+                .all(|&x| x == 3));
+            assert!(filler_string[FILLER_STEP_SIZE_INCREASE..]
+                .iter()
+                .all(|&x| x == 2));
+        }
+
+        mod for_an_empty_filler_string {
             use super::*;
 
             #[test]
             #[should_panic]
             fn it_panics() {
-                let pseudorandom_bytes = vec![0; constants::STREAM_CIPHER_OUTPUT_LENGTH];
-                Filler::filler_step(vec![], 0, pseudorandom_bytes);
+                let keystreams = vec![vec![0; constants::STREAM_CIPHER_OUTPUT_LENGTH]];
+                Filler::<DefaultCipherSuite>::fill(&mut [], &keystreams, &[], &Default::default());
             }
         }
     }
@@ -223,17 +294,51 @@ mod test_generating_filler_bytes {
 
         #[test]
         #[should_panic]
-        fn panics_for_incorrectly_sized_pseudorandom_bytes_vector_and_accumulator_vector() {
-            let pseudorandom_bytes = vec![0; 1];
-            Filler::filler_step(vec![], 0, pseudorandom_bytes);
+        fn panics_for_incorrectly_sized_pseudorandom_bytes_vector() {
+            let keystreams = vec![vec![0; 1]];
+            Filler::<DefaultCipherSuite>::fill(
+                &mut [0u8; FILLER_STEP_SIZE_INCREASE],
+                &keystreams,
+                &[FILLER_STEP_SIZE_INCREASE],
+                &Default::default(),
+            );
         }
 
         #[test]
         #[should_panic]
-        fn panics_with_incorrect_length_filler_accumulator() {
-            let good_pseudorandom_bytes = vec![0; constants::STREAM_CIPHER_OUTPUT_LENGTH];
-            let wrong_accumulator = vec![0; 25];
-            Filler::filler_step(wrong_accumulator, 1, good_pseudorandom_bytes);
+        fn panics_with_incorrect_length_filler() {
+            let keystreams = vec![vec![0; constants::STREAM_CIPHER_OUTPUT_LENGTH]];
+            Filler::<DefaultCipherSuite>::fill(
+                &mut [0u8; 25],
+                &keystreams,
+                &[FILLER_STEP_SIZE_INCREASE],
+                &Default::default(),
+            );
         }
     }
 }
+
+#[cfg(test)]
+mod test_creating_filler_for_hops_of_different_sizes {
+    use super::*;
+    use crate::test_utils::fixtures::routing_keys_fixture;
+
+    #[test]
+    fn it_returns_filler_of_length_equal_to_the_sum_of_hop_sizes() {
+        let routing_keys = [routing_keys_fixture(), routing_keys_fixture()];
+        let hop_sizes = [FILLER_STEP_SIZE_INCREASE + 10, FILLER_STEP_SIZE_INCREASE];
+        let filler =
+            Filler::new_with_hop_sizes(&routing_keys, &hop_sizes, &Default::default()).unwrap();
+        assert_eq!(2 * FILLER_STEP_SIZE_INCREASE + 10, filler.get_value().len());
+    }
+
+    #[test]
+    fn it_is_equivalent_to_normal_filler_for_hops_of_a_single_step() {
+        let routing_keys = [routing_keys_fixture(), routing_keys_fixture()];
+        let hop_sizes = [FILLER_STEP_SIZE_INCREASE; 2];
+        assert_eq!(
+            Filler::new(&routing_keys, &Default::default()).unwrap(),
+            Filler::new_with_hop_sizes(&routing_keys, &hop_sizes, &Default::default()).unwrap()
+        );
+    }
+}
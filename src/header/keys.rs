@@ -15,71 +15,97 @@
 use std::fmt;
 
 use crate::constants::{
-    BLINDING_FACTOR_SIZE, HKDF_INPUT_SEED, INTEGRITY_MAC_KEY_SIZE, PAYLOAD_KEY_SIZE,
-    ROUTING_KEYS_LENGTH,
+    BLINDING_FACTOR_SIZE, INTEGRITY_MAC_KEY_SIZE, PAYLOAD_KEY_SIZE, REPLAY_TAG_SIZE,
 };
 use crate::crypto::STREAM_CIPHER_KEY_SIZE;
-use crate::crypto::{self, EphemeralSecret};
+use crate::crypto::{self, CipherSuite, DefaultCipherSuite, EphemeralSecret, SphinxGroup};
 use crate::route::Node;
 use crypto::SharedSecret;
-use curve25519_dalek::scalar::Scalar;
-use hkdf::Hkdf;
-use sha2::Sha256;
 
+// key types of the default cipher suite
 pub type StreamCipherKey = [u8; STREAM_CIPHER_KEY_SIZE];
 pub type HeaderIntegrityMacKey = [u8; INTEGRITY_MAC_KEY_SIZE];
 // TODO: perhaps change PayloadKey to a Vec considering it's almost 200 bytes long?
 // we will lose length assertions but won't need to copy all that data every single function call
 pub type PayloadKey = [u8; PAYLOAD_KEY_SIZE];
 pub type BlindingFactor = [u8; BLINDING_FACTOR_SIZE];
+// identifies the shared secret a node derived for the packet without revealing it
+pub type ReplayTag = [u8; REPLAY_TAG_SIZE];
+
+const KDF_STACK_OUTPUT_SIZE: usize =
+    2 * 32 + PAYLOAD_KEY_SIZE + BLINDING_FACTOR_SIZE + REPLAY_TAG_SIZE;
 
 #[derive(Clone)]
-pub struct RoutingKeys {
-    pub stream_cipher_key: StreamCipherKey,
-    pub header_integrity_hmac_key: HeaderIntegrityMacKey,
+pub struct RoutingKeys<C: CipherSuite = DefaultCipherSuite> {
+    pub stream_cipher_key: C::StreamCipherKey,
+    pub header_integrity_hmac_key: C::IntegrityMacKey,
     pub payload_key: PayloadKey,
     pub blinding_factor: BlindingFactor,
+    pub replay_tag: ReplayTag,
 }
 
-impl RoutingKeys {
+impl<C: CipherSuite> RoutingKeys<C> {
     // or should this be renamed to 'new'?
     // Given that everything here except RoutingKeys lives in the `crypto` module, I think
     // that this one could potentially move most of its functionality there quite profitably.
-    pub fn derive(shared_key: crypto::SharedSecret) -> Self {
-        let hkdf = Hkdf::<Sha256>::new(None, shared_key.as_bytes());
+    pub fn derive(shared_key: crypto::SharedSecret<C::Group>) -> Self {
+        let mut stream_cipher_key = C::StreamCipherKey::default();
+        let mut header_integrity_hmac_key = C::IntegrityMacKey::default();
+        let stream_cipher_key_size = stream_cipher_key.as_ref().len();
+        let integrity_mac_key_size = header_integrity_hmac_key.as_ref().len();
 
         let mut i = 0;
-        let mut output = [0u8; ROUTING_KEYS_LENGTH];
-        hkdf.expand(HKDF_INPUT_SEED, &mut output).unwrap();
-
-        let mut stream_cipher_key: [u8; crypto::STREAM_CIPHER_KEY_SIZE] = Default::default();
-        stream_cipher_key.copy_from_slice(&output[i..i + crypto::STREAM_CIPHER_KEY_SIZE]);
-        i += crypto::STREAM_CIPHER_KEY_SIZE;
-
-        let mut header_integrity_hmac_key: [u8; INTEGRITY_MAC_KEY_SIZE] = Default::default();
-        header_integrity_hmac_key.copy_from_slice(&output[i..i + INTEGRITY_MAC_KEY_SIZE]);
-        i += INTEGRITY_MAC_KEY_SIZE;
+        let output_size = stream_cipher_key_size
+            + integrity_mac_key_size
+            + PAYLOAD_KEY_SIZE
+            + BLINDING_FACTOR_SIZE
+            + REPLAY_TAG_SIZE;
+        // keys of all the built-in suites fit on the stack, so that processing does not allocate
+        let mut stack_output = [0u8; KDF_STACK_OUTPUT_SIZE];
+        let mut heap_output = Vec::new();
+        let output = if output_size <= KDF_STACK_OUTPUT_SIZE {
+            &mut stack_output[..output_size]
+        } else {
+            heap_output.resize(output_size, 0);
+            &mut heap_output[..]
+        };
+        C::expand_shared_secret(shared_key.as_bytes().as_ref(), output);
+
+        stream_cipher_key
+            .as_mut()
+            .copy_from_slice(&output[i..i + stream_cipher_key_size]);
+        i += stream_cipher_key_size;
+
+        header_integrity_hmac_key
+            .as_mut()
+            .copy_from_slice(&output[i..i + integrity_mac_key_size]);
+        i += integrity_mac_key_size;
 
         let mut payload_key: [u8; PAYLOAD_KEY_SIZE] = [0u8; PAYLOAD_KEY_SIZE];
         payload_key.copy_from_slice(&output[i..i + PAYLOAD_KEY_SIZE]);
         i += PAYLOAD_KEY_SIZE;
 
-        // TODO: we later treat blinding factor as a Scalar, the question is, should it be clamped
-        // and/or go through montgomery reduction? We kinda need somebody with good ECC knowledge
-        // to answer this question (and other related ones).
+        // it is up to the group to decide how the blinding factor is mapped onto its scalar,
+        // see `SphinxGroup::blinding_scalar`
         let mut blinding_factor: [u8; BLINDING_FACTOR_SIZE] = Default::default();
         blinding_factor.copy_from_slice(&output[i..i + BLINDING_FACTOR_SIZE]);
+        i += BLINDING_FACTOR_SIZE;
+
+        // derived last so that the remaining keys do not depend on whether it's used
+        let mut replay_tag: ReplayTag = Default::default();
+        replay_tag.copy_from_slice(&output[i..i + REPLAY_TAG_SIZE]);
 
         Self {
             stream_cipher_key,
             header_integrity_hmac_key,
             payload_key,
             blinding_factor,
+            replay_tag,
         }
     }
 }
 
-impl fmt::Debug for RoutingKeys {
+impl<C: CipherSuite> fmt::Debug for RoutingKeys<C> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
@@ -91,53 +117,36 @@ impl fmt::Debug for RoutingKeys {
     }
 }
 
-impl PartialEq for RoutingKeys {
-    fn eq(&self, other: &RoutingKeys) -> bool {
+impl<C: CipherSuite> PartialEq for RoutingKeys<C> {
+    fn eq(&self, other: &RoutingKeys<C>) -> bool {
         self.stream_cipher_key == other.stream_cipher_key
             && self.header_integrity_hmac_key == other.header_integrity_hmac_key
             && self.payload_key.to_vec() == other.payload_key.to_vec()
     }
 }
 
-pub struct KeyMaterial {
-    pub initial_shared_secret: crypto::SharedSecret,
+pub struct KeyMaterial<C: CipherSuite = DefaultCipherSuite> {
+    pub initial_shared_secret: crypto::SharedSecret<C::Group>,
     // why this is here?
-    pub routing_keys: Vec<RoutingKeys>,
+    pub routing_keys: Vec<RoutingKeys<C>>,
 }
 
-impl KeyMaterial {
+impl<C: CipherSuite> KeyMaterial<C> {
     // derive shared keys, group elements, blinding factors
-    pub fn derive(route: &[Node], initial_secret: &EphemeralSecret) -> Self {
+    pub fn derive(route: &[Node<C::Group>], initial_secret: &EphemeralSecret<C::Group>) -> Self {
         let initial_shared_secret = SharedSecret::from(initial_secret);
         let mut routing_keys = Vec::with_capacity(route.len());
 
-        let mut accumulator = initial_secret.clone();
-        for (i, node) in route.iter().enumerate() {
-            // pub^{a * b * ...}
-            let shared_key = accumulator.diffie_hellman(&node.pub_key);
-            // let shared_key = Self::compute_shared_key(node.pub_key, &accumulator);
+        // blinding factors of all the previous hops, so that we could compute pub^{a * b * ...};
+        // it is up to the group to decide whether they get multiplied together first
+        let mut blinders = Vec::with_capacity(route.len());
+        for node in route {
+            let shared_key = initial_secret.blinded_diffie_hellman(&blinders, &node.pub_key);
             let node_routing_keys = RoutingKeys::derive(shared_key);
 
-            // it's not the last iteration
-            if i != route.len() + 1 {
-                // TODO: do we need to make the reduction here or could we get away with clamping or even nothing at all?
-                // considering (I *think*) proper reductions will happen during scalar multiplication, i.e. g^x?
-                // So far it *seems* to produce correct result, but could it be the case it introduces
-                // some vulnerabilities? Need some ECC expert here.
-
-                // performs montgomery reduction
-                let blinding_factor_scalar =
-                    &Scalar::from_bytes_mod_order(node_routing_keys.blinding_factor);
-                // alternatives:
-
-                // 'only' clamps the scalar
-                // let blinding_factor_scalar = crypto::clamp_scalar_bytes(node_routing_keys.blinding_factor);
-
-                // 'only' makes it 255 bit long
-                // let blinding_factor_scalar = Scalar::from_bits(node_routing_keys.blinding_factor);
-                accumulator *= blinding_factor_scalar;
-            }
-
+            blinders.push(<C::Group as SphinxGroup>::blinding_scalar(
+                &node_routing_keys.blinding_factor,
+            ));
             routing_keys.push(node_routing_keys);
         }
 
@@ -162,7 +171,7 @@ mod deriving_key_material {
             let empty_route: Vec<Node> = vec![];
             let initial_secret = EphemeralSecret::new();
             let hacky_secret_copy = EphemeralSecret::from(initial_secret.to_bytes());
-            let key_material = KeyMaterial::derive(&empty_route, &initial_secret);
+            let key_material: KeyMaterial = KeyMaterial::derive(&empty_route, &initial_secret);
             assert_eq!(0, key_material.routing_keys.len());
             assert_eq!(
                 SharedSecret::from(&hacky_secret_copy).as_bytes(),
@@ -175,6 +184,7 @@ mod deriving_key_material {
     mod for_a_route_with_3_forward_hops {
         use super::*;
         use crate::test_utils::random_node;
+        use curve25519_dalek::scalar::Scalar;
 
         fn setup() -> (Vec<Node>, EphemeralSecret, KeyMaterial) {
             let route: Vec<Node> = vec![random_node(), random_node(), random_node()];
@@ -210,7 +220,7 @@ mod deriving_key_material {
             let mut expected_accumulator = initial_secret;
             for (i, node) in route.iter().enumerate() {
                 let expected_shared_key = expected_accumulator.diffie_hellman(&node.pub_key);
-                let expected_routing_keys = RoutingKeys::derive(expected_shared_key);
+                let expected_routing_keys: RoutingKeys = RoutingKeys::derive(expected_shared_key);
 
                 expected_accumulator = &expected_accumulator
                     * &Scalar::from_bytes_mod_order(expected_routing_keys.blinding_factor);
@@ -229,18 +239,41 @@ mod key_derivation_function {
     fn it_expands_the_seed_key_to_expected_length() {
         let initial_secret = EphemeralSecret::new();
         let shared_key = SharedSecret::from(&initial_secret);
-        let routing_keys = RoutingKeys::derive(shared_key);
+        let routing_keys: RoutingKeys = RoutingKeys::derive(shared_key);
         assert_eq!(
             crypto::STREAM_CIPHER_KEY_SIZE,
             routing_keys.stream_cipher_key.len()
         );
+        assert_eq!(
+            crate::constants::ROUTING_KEYS_LENGTH,
+            routing_keys.stream_cipher_key.len()
+                + routing_keys.header_integrity_hmac_key.len()
+                + routing_keys.payload_key.len()
+                + routing_keys.blinding_factor.len()
+                + routing_keys.replay_tag.len()
+        );
+    }
+
+    #[test]
+    fn it_expands_the_seed_key_to_keys_of_the_chosen_cipher_suite() {
+        let initial_secret = EphemeralSecret::new();
+        let shared_key = SharedSecret::from(&initial_secret);
+        let routing_keys = RoutingKeys::<crypto::ChaCha20Blake2bSuite>::derive(shared_key);
+        assert_eq!(
+            crypto::suite::CHACHA20_KEY_SIZE,
+            routing_keys.stream_cipher_key.len()
+        );
+        assert_eq!(
+            crypto::suite::BLAKE2B_MAC_KEY_SIZE,
+            routing_keys.header_integrity_hmac_key.len()
+        );
     }
 
     #[test]
     fn it_returns_the_same_output_for_two_equal_inputs() {
         let initial_secret = EphemeralSecret::new();
         let shared_key = SharedSecret::from(&initial_secret);
-        let routing_keys1 = RoutingKeys::derive(shared_key);
+        let routing_keys1: RoutingKeys = RoutingKeys::derive(shared_key);
         let routing_keys2 = RoutingKeys::derive(shared_key);
         assert_eq!(routing_keys1, routing_keys2);
     }
@@ -12,49 +12,71 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::constants::{
-    HeaderIntegrityHmacAlgorithm, HeaderIntegrityMacSize, HEADER_INTEGRITY_MAC_SIZE,
-};
-use crate::crypto;
-use crate::header::keys::HeaderIntegrityMacKey;
-use digest::generic_array::GenericArray;
+use crate::crypto::CipherSuite;
+use crate::params::MAX_HEADER_INTEGRITY_MAC_SIZE;
+use crate::{Error, ErrorKind, ErrorReason, Result};
 use subtle::{Choice, ConstantTimeEq};
 
 // In paper gamma
 #[derive(Clone, Debug)]
-pub struct HeaderIntegrityMac(GenericArray<u8, HeaderIntegrityMacSize>);
+pub struct HeaderIntegrityMac(Vec<u8>);
 
 impl HeaderIntegrityMac {
-    pub(crate) fn compute(key: HeaderIntegrityMacKey, header_data: &[u8]) -> Self {
-        let routing_info_mac =
-            crypto::compute_keyed_hmac::<HeaderIntegrityHmacAlgorithm>(&key, header_data);
-
+    pub(crate) fn compute<C: CipherSuite>(
+        key: C::IntegrityMacKey,
+        header_data: &[u8],
+        mac_size: usize,
+    ) -> Result<Self> {
         // NOTE: BE EXTREMELY CAREFUL HOW YOU MANAGE THOSE BYTES
         // YOU CAN'T TREAT THEM AS NORMAL ONES
-        let mac_bytes = routing_info_mac.into_bytes();
-        if mac_bytes.len() < HEADER_INTEGRITY_MAC_SIZE {
-            panic!("Algorithm used for computing header integrity mac produced output smaller than minimum length of {}", HEADER_INTEGRITY_MAC_SIZE)
+        let mut mac_bytes = C::compute_integrity_mac(&key, header_data);
+        if mac_size == 0 || mac_bytes.len() < mac_size {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidHeader,
+                ErrorReason::InvalidParameter {
+                    name: "header integrity mac size",
+                    value: mac_size,
+                    minimum: 1,
+                    maximum: mac_bytes.len(),
+                },
+            ));
         }
 
-        // only take first HEADER_INTEGRITY_MAC_SIZE bytes
-        Self(
-            mac_bytes
-                .into_iter()
-                .take(HEADER_INTEGRITY_MAC_SIZE)
-                .collect(),
-        )
+        // only take first mac_size bytes
+        mac_bytes.truncate(mac_size);
+        Ok(Self(mac_bytes))
     }
 
-    pub fn verify(
+    pub fn verify<C: CipherSuite>(
         &self,
-        integrity_mac_key: HeaderIntegrityMacKey,
+        integrity_mac_key: C::IntegrityMacKey,
         enc_routing_info: &[u8],
     ) -> bool {
-        let recomputed_integrity_mac = Self::compute(integrity_mac_key, enc_routing_info);
-        self.ct_eq(&recomputed_integrity_mac).into()
+        match Self::compute::<C>(integrity_mac_key, enc_routing_info, self.0.len()) {
+            Ok(recomputed_integrity_mac) => self.ct_eq(&recomputed_integrity_mac).into(),
+            Err(_) => false,
+        }
     }
 
-    pub fn into_inner(self) -> GenericArray<u8, HeaderIntegrityMacSize> {
+    /// Verifies the mac given as raw bytes without allocating the recomputed one.
+    pub(crate) fn verify_bytes<C: CipherSuite>(
+        integrity_mac_key: &C::IntegrityMacKey,
+        enc_routing_info: &[u8],
+        mac: &[u8],
+    ) -> bool {
+        if mac.len() > MAX_HEADER_INTEGRITY_MAC_SIZE {
+            return false;
+        }
+        let mut recomputed_integrity_mac = [0u8; MAX_HEADER_INTEGRITY_MAC_SIZE];
+        C::compute_truncated_integrity_mac(
+            integrity_mac_key,
+            enc_routing_info,
+            &mut recomputed_integrity_mac[..mac.len()],
+        );
+        recomputed_integrity_mac[..mac.len()].ct_eq(mac).into()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
         self.0
     }
 
@@ -62,8 +84,8 @@ impl HeaderIntegrityMac {
         &self.0
     }
 
-    pub fn from_bytes(bytes: [u8; HEADER_INTEGRITY_MAC_SIZE]) -> Self {
-        Self(bytes.into())
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec())
     }
 }
 
@@ -76,24 +98,100 @@ impl ConstantTimeEq for HeaderIntegrityMac {
 #[cfg(test)]
 mod computing_integrity_mac {
     use super::*;
-    use crate::constants::INTEGRITY_MAC_KEY_SIZE;
+    use crate::constants::{HEADER_INTEGRITY_MAC_SIZE, INTEGRITY_MAC_KEY_SIZE};
+    use crate::crypto::{suite::BLAKE2B_MAC_KEY_SIZE, ChaCha20Blake2bSuite, DefaultCipherSuite};
     use crate::header::routing::ENCRYPTED_ROUTING_INFO_SIZE;
 
     #[test]
     fn it_is_possible_to_verify_correct_mac() {
         let key = [2u8; INTEGRITY_MAC_KEY_SIZE];
         let data = vec![3u8; ENCRYPTED_ROUTING_INFO_SIZE];
-        let integrity_mac = HeaderIntegrityMac::compute(key, &data);
+        let integrity_mac = HeaderIntegrityMac::compute::<DefaultCipherSuite>(
+            key,
+            &data,
+            HEADER_INTEGRITY_MAC_SIZE,
+        )
+        .unwrap();
 
-        assert!(integrity_mac.verify(key, &data));
+        assert!(integrity_mac.verify::<DefaultCipherSuite>(key, &data));
     }
 
     #[test]
     fn it_lets_detecting_flipped_data_bits() {
         let key = [2u8; INTEGRITY_MAC_KEY_SIZE];
         let mut data = vec![3u8; ENCRYPTED_ROUTING_INFO_SIZE];
-        let integrity_mac = HeaderIntegrityMac::compute(key, &data);
+        let integrity_mac = HeaderIntegrityMac::compute::<DefaultCipherSuite>(
+            key,
+            &data,
+            HEADER_INTEGRITY_MAC_SIZE,
+        )
+        .unwrap();
+        data[10] = !data[10];
+        assert!(!integrity_mac.verify::<DefaultCipherSuite>(key, &data));
+    }
+
+    #[test]
+    fn it_is_possible_to_verify_correct_mac_with_alternative_suite() {
+        let key = [2u8; BLAKE2B_MAC_KEY_SIZE];
+        let mut data = vec![3u8; ENCRYPTED_ROUTING_INFO_SIZE];
+        let integrity_mac = HeaderIntegrityMac::compute::<ChaCha20Blake2bSuite>(
+            key,
+            &data,
+            HEADER_INTEGRITY_MAC_SIZE,
+        )
+        .unwrap();
+        assert!(integrity_mac.verify::<ChaCha20Blake2bSuite>(key, &data));
+
+        data[10] = !data[10];
+        assert!(!integrity_mac.verify::<ChaCha20Blake2bSuite>(key, &data));
+    }
+
+    #[test]
+    fn it_is_possible_to_verify_mac_bytes() {
+        let key = [2u8; INTEGRITY_MAC_KEY_SIZE];
+        let mut data = vec![3u8; ENCRYPTED_ROUTING_INFO_SIZE];
+        let integrity_mac = HeaderIntegrityMac::compute::<DefaultCipherSuite>(
+            key,
+            &data,
+            HEADER_INTEGRITY_MAC_SIZE,
+        )
+        .unwrap();
+        assert!(HeaderIntegrityMac::verify_bytes::<DefaultCipherSuite>(
+            &key,
+            &data,
+            integrity_mac.as_bytes()
+        ));
+
         data[10] = !data[10];
-        assert!(!integrity_mac.verify(key, &data));
+        assert!(!HeaderIntegrityMac::verify_bytes::<DefaultCipherSuite>(
+            &key,
+            &data,
+            integrity_mac.as_bytes()
+        ));
+    }
+
+    #[test]
+    fn it_is_possible_to_verify_correct_mac_of_non_default_size() {
+        let key = [2u8; INTEGRITY_MAC_KEY_SIZE];
+        let data = vec![3u8; ENCRYPTED_ROUTING_INFO_SIZE];
+        let integrity_mac =
+            HeaderIntegrityMac::compute::<DefaultCipherSuite>(key, &data, 32).unwrap();
+        assert_eq!(32, integrity_mac.as_bytes().len());
+        assert!(integrity_mac.verify::<DefaultCipherSuite>(key, &data));
+    }
+
+    #[test]
+    fn it_can_not_be_longer_than_the_output_of_the_suite_or_empty() {
+        let key = [2u8; INTEGRITY_MAC_KEY_SIZE];
+        let data = vec![3u8; ENCRYPTED_ROUTING_INFO_SIZE];
+        for mac_size in [0, 33] {
+            let err = HeaderIntegrityMac::compute::<DefaultCipherSuite>(key, &data, mac_size)
+                .unwrap_err();
+            assert_eq!(ErrorKind::InvalidHeader, err.kind());
+            assert!(matches!(
+                err.reason(),
+                Some(ErrorReason::InvalidParameter { maximum: 32, .. })
+            ));
+        }
     }
 }
@@ -12,71 +12,226 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::constants::HEADER_INTEGRITY_MAC_SIZE;
-use crate::crypto;
+use crate::blinded;
+use crate::constants::{FINAL_NODE_META_INFO_LENGTH, HEADER_INTEGRITY_MAC_SIZE};
+use crate::crypto::{self, CipherSuite, DefaultCipherSuite};
 use crate::header::delays::Delay;
-use crate::header::filler::Filler;
 use crate::header::keys::{BlindingFactor, PayloadKey};
-use crate::header::routing::nodes::ParsedRawRoutingInformation;
-use crate::header::routing::{EncapsulatedRoutingInformation, ENCRYPTED_ROUTING_INFO_SIZE};
-use crate::route::{Destination, DestinationAddressBytes, Node, NodeAddressBytes, SURBIdentifier};
-use crate::{Error, ErrorKind, Result};
-use crypto::{EphemeralSecret, PrivateKey, SharedSecret};
-use curve25519_dalek::scalar::Scalar;
+use crate::header::mac::HeaderIntegrityMac;
+use crate::header::routing::nodes::{
+    self, ParsedRawRoutingInformation, ParsedRoutingInformationInPlace,
+};
+use crate::header::routing::{
+    EncapsulatedRoutingInformation, RouteKind, Version, ENCRYPTED_ROUTING_INFO_SIZE,
+};
+use crate::header::tlv::TlvStream;
+use crate::params::SphinxParams;
+use crate::route::{DestinationAddressBytes, NodeAddressBytes, Route, SURBIdentifier};
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use crypto::{EphemeralSecret, PrivateKey, SharedSecret, SphinxGroup};
 use keys::RoutingKeys;
 
 pub mod delays;
 pub mod filler;
 pub mod keys;
 pub mod mac;
+pub mod pool;
 pub mod routing;
+pub mod tlv;
 
-// 32 represents size of a MontgomeryPoint on Curve25519
+// 32 represents size of a MontgomeryPoint on Curve25519, i.e. this is the size of the header
+// when using the default group and the default params; in general use `SphinxParams::header_size`
 pub const HEADER_SIZE: usize = 32 + HEADER_INTEGRITY_MAC_SIZE + ENCRYPTED_ROUTING_INFO_SIZE;
 
-#[derive(Debug)]
-#[cfg_attr(test, derive(Clone))]
-pub struct SphinxHeader {
-    pub shared_secret: SharedSecret,
-    pub routing_info: EncapsulatedRoutingInformation,
+#[derive(Clone, Debug)]
+pub struct SphinxHeader<C: CipherSuite = DefaultCipherSuite> {
+    pub shared_secret: SharedSecret<C::Group>,
+    pub routing_info: EncapsulatedRoutingInformation<C>,
+    params: SphinxParams,
 }
 
-pub enum ProcessedHeader {
-    ForwardHop(Box<SphinxHeader>, NodeAddressBytes, Delay, PayloadKey),
-    FinalHop(DestinationAddressBytes, SURBIdentifier, PayloadKey),
+pub enum ProcessedHeader<C: CipherSuite = DefaultCipherSuite> {
+    ForwardHop(
+        Box<SphinxHeader<C>>,
+        NodeAddressBytes,
+        Delay,
+        PayloadKey,
+        TlvStream,
+        Version,
+    ),
+    FinalHop(
+        DestinationAddressBytes,
+        SURBIdentifier,
+        PayloadKey,
+        TlvStream,
+        Version,
+    ),
+    TrampolineHop(NodeAddressBytes, PayloadKey, TlvStream, Version),
 }
 
-impl SphinxHeader {
+impl<C: CipherSuite> ProcessedHeader<C> {
+    /// Reveals the next hop, or the destination, of the hop of a blinded route, which the sender
+    /// does not know and which the recipient has encrypted for the node in its records instead.
+    /// Hops of regular routes are returned unchanged. It is a part of [SphinxHeader::process],
+    /// but has to be called explicitly after processing with derived keys.
+    pub fn resolve_blinded_hop(self, node_secret_key: &PrivateKey<C::Group>) -> Result<Self> {
+        match self {
+            ProcessedHeader::ForwardHop(
+                mut header,
+                next_hop_address,
+                delay,
+                payload_key,
+                records,
+                version,
+            ) => {
+                let next_hop_address =
+                    match blinded::unblind_forward_hop(&records, node_secret_key)? {
+                        Some((next_hop_address, blinding_factor)) => {
+                            // the sender only knows the key of the next hop in the blinded form
+                            header.shared_secret = header.shared_secret.blind(&blinding_factor);
+                            next_hop_address
+                        }
+                        None => next_hop_address,
+                    };
+                Ok(ProcessedHeader::ForwardHop(
+                    header,
+                    next_hop_address,
+                    delay,
+                    payload_key,
+                    records,
+                    version,
+                ))
+            }
+            ProcessedHeader::FinalHop(destination, identifier, payload_key, records, version) => {
+                let (destination, identifier) =
+                    blinded::unblind_final_hop(&records, node_secret_key)?
+                        .unwrap_or((destination, identifier));
+                Ok(ProcessedHeader::FinalHop(
+                    destination,
+                    identifier,
+                    payload_key,
+                    records,
+                    version,
+                ))
+            }
+            trampoline_hop @ ProcessedHeader::TrampolineHop(..) => Ok(trampoline_hop),
+        }
+    }
+}
+
+impl<C: CipherSuite> SphinxHeader<C> {
     // needs client's secret key, how should we inject this?
     // needs to deal with SURBs too at some point
     pub fn new(
-        initial_secret: &EphemeralSecret,
-        route: &[Node],
-        delays: &[Delay],
-        destination: &Destination,
-    ) -> (Self, Vec<PayloadKey>) {
-        let key_material = keys::KeyMaterial::derive(route, initial_secret);
-        let filler_string = Filler::new(&key_material.routing_keys[..route.len() - 1]);
-        let routing_info = routing::EncapsulatedRoutingInformation::new(
+        initial_secret: &EphemeralSecret<C::Group>,
+        route: &Route<C::Group>,
+        params: &SphinxParams,
+    ) -> Result<(Self, Vec<PayloadKey>)> {
+        let hop_records = vec![TlvStream::new(); route.len()];
+        Self::new_with_hop_records(initial_secret, route, &hop_records, params)
+    }
+
+    /// Creates the header with the provided tlv records attached to the routing information
+    /// of the respective hops, where the last records are delivered to the final hop.
+    pub fn new_with_hop_records(
+        initial_secret: &EphemeralSecret<C::Group>,
+        route: &Route<C::Group>,
+        hop_records: &[TlvStream],
+        params: &SphinxParams,
+    ) -> Result<(Self, Vec<PayloadKey>)> {
+        Self::new_of_kind(
+            initial_secret,
             route,
-            destination,
-            delays,
+            hop_records,
+            RouteKind::Regular,
+            params,
+        )
+    }
+
+    /// Creates the header whose hops are marked according to the kind of the route, e.g. whose
+    /// final hop forwards the inner packet carried in the payload to the node with the address
+    /// given as the destination of the route.
+    pub(crate) fn new_of_kind(
+        initial_secret: &EphemeralSecret<C::Group>,
+        route: &Route<C::Group>,
+        hop_records: &[TlvStream],
+        kind: RouteKind,
+        params: &SphinxParams,
+    ) -> Result<(Self, Vec<PayloadKey>)> {
+        // the route might have been checked against different params
+        if route.len() > params.max_path_length() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::RouteTooLong {
+                    length: route.len(),
+                    maximum: params.max_path_length(),
+                },
+            ));
+        }
+        if hop_records.len() != route.len() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::HopCountMismatch {
+                    hops: route.len(),
+                    provided: hop_records.len(),
+                },
+            ));
+        }
+        if let Some(records) = hop_records
+            .iter()
+            .find(|records| records.encoded_len() > u16::MAX as usize)
+        {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::RecordsTooLong {
+                    length: records.encoded_len(),
+                    maximum: u16::MAX as usize,
+                },
+            ));
+        }
+
+        let (final_records, forward_records) = hop_records.split_last().unwrap();
+        let forward_hop_sizes: Vec<_> = forward_records
+            .iter()
+            .map(|records| params.filler_step_size() + records.section_len())
+            .collect();
+        let required_space = forward_hop_sizes.iter().sum::<usize>()
+            + FINAL_NODE_META_INFO_LENGTH
+            + final_records.section_len();
+        if required_space > params.encrypted_routing_info_size() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::RoutingInformationTooLong {
+                    required: required_space,
+                    available: params.encrypted_routing_info_size(),
+                },
+            ));
+        }
+
+        let key_material = keys::KeyMaterial::<C>::derive(route.nodes(), initial_secret);
+        let routing_info = routing::EncapsulatedRoutingInformation::new_of_kind(
+            route.nodes(),
+            route.destination(),
+            route.delays(),
+            hop_records,
             &key_material.routing_keys,
-            filler_string,
-        );
+            kind,
+            params,
+        )?;
 
         // encapsulate header.routing information, compute MACs
-        (
+        Ok((
             SphinxHeader {
                 shared_secret: key_material.initial_shared_secret,
                 routing_info,
+                params: *params,
             },
             key_material
                 .routing_keys
                 .iter()
                 .map(|routing_key| routing_key.payload_key)
                 .collect(),
-        )
+        ))
     }
 
     /// Processes the header with the provided derived keys.
@@ -84,55 +239,70 @@ impl SphinxHeader {
     /// and we could cache processing results.
     ///
     /// However, unless you know exactly what you are doing, you should NEVER use this method!
-    /// Prefer normal [process] instead.
+    /// Prefer normal [process] instead. Note that the hops of blinded routes additionally need
+    /// to be resolved with `ProcessedHeader::resolve_blinded_hop`.
     pub fn process_with_derived_keys(
         self,
-        new_blinded_secret: &Option<SharedSecret>,
-        routing_keys: &RoutingKeys,
-    ) -> Result<ProcessedHeader> {
-        if !self.routing_info.integrity_mac.verify(
-            routing_keys.header_integrity_hmac_key,
-            self.routing_info.enc_routing_information.get_value_ref(),
-        ) {
-            return Err(Error::new(
+        new_blinded_secret: &Option<SharedSecret<C::Group>>,
+        routing_keys: &RoutingKeys<C>,
+    ) -> Result<ProcessedHeader<C>> {
+        if !self.has_valid_mac(routing_keys) {
+            return Err(Error::with_reason(
                 ErrorKind::InvalidHeader,
-                "failed to verify integrity MAC",
+                ErrorReason::InvalidMac,
             ));
         }
 
         let unwrapped_routing_information = self
             .routing_info
             .enc_routing_information
-            .unwrap(routing_keys.stream_cipher_key)
-            .unwrap();
+            .unwrap(routing_keys.stream_cipher_key, &self.params)?;
         match unwrapped_routing_information {
             ParsedRawRoutingInformation::ForwardHop(
                 next_hop_address,
                 delay,
                 new_encapsulated_routing_info,
+                records,
+                version,
             ) => {
                 if let Some(new_blinded_secret) = new_blinded_secret {
                     Ok(ProcessedHeader::ForwardHop(
                         Box::new(SphinxHeader {
                             shared_secret: *new_blinded_secret,
                             routing_info: *new_encapsulated_routing_info,
+                            params: self.params,
                         }),
                         next_hop_address,
                         delay,
                         routing_keys.payload_key,
+                        records,
+                        version,
                     ))
                 } else {
-                    Err(Error::new(
+                    Err(Error::with_reason(
                         ErrorKind::InvalidHeader,
-                        "tried to process forward hop without blinded secret",
+                        ErrorReason::MissingBlindedSecret,
                     ))
                 }
             }
-            ParsedRawRoutingInformation::FinalHop(destination_address, identifier) => {
-                Ok(ProcessedHeader::FinalHop(
-                    destination_address,
-                    identifier,
+            ParsedRawRoutingInformation::FinalHop(
+                destination_address,
+                identifier,
+                records,
+                version,
+            ) => Ok(ProcessedHeader::FinalHop(
+                destination_address,
+                identifier,
+                routing_keys.payload_key,
+                records,
+                version,
+            )),
+            ParsedRawRoutingInformation::TrampolineHop(next_hop_address, records, version) => {
+                Ok(ProcessedHeader::TrampolineHop(
+                    next_hop_address,
                     routing_keys.payload_key,
+                    records,
+                    version,
                 ))
             }
         }
@@ -140,36 +310,53 @@ impl SphinxHeader {
 
     /// Using the provided shared_secret and node's secret key, derive all routing keys for this hop.
     pub fn compute_routing_keys(
-        shared_secret: &SharedSecret,
-        node_secret_key: &PrivateKey,
-    ) -> RoutingKeys {
+        shared_secret: &SharedSecret<C::Group>,
+        node_secret_key: &PrivateKey<C::Group>,
+    ) -> RoutingKeys<C> {
         let shared_key = node_secret_key.diffie_hellman(shared_secret);
         keys::RoutingKeys::derive(shared_key)
     }
 
-    pub fn process(self, node_secret_key: &PrivateKey) -> Result<ProcessedHeader> {
+    pub fn process(self, node_secret_key: &PrivateKey<C::Group>) -> Result<ProcessedHeader<C>> {
         let routing_keys = Self::compute_routing_keys(&self.shared_secret, node_secret_key);
+        self.process_with_routing_keys(&routing_keys)?
+            .resolve_blinded_hop(node_secret_key)
+    }
 
-        if !self.routing_info.integrity_mac.verify(
+    /// Checks the integrity mac of the routing information without processing the header,
+    /// e.g. to find which of the node's keys the sender used.
+    pub(crate) fn has_valid_mac(&self, routing_keys: &RoutingKeys<C>) -> bool {
+        self.routing_info.integrity_mac.verify::<C>(
             routing_keys.header_integrity_hmac_key,
             self.routing_info.enc_routing_information.get_value_ref(),
-        ) {
-            return Err(Error::new(
+        )
+    }
+
+    /// Processes the header using routing keys derived from the node's own shared secret,
+    /// i.e. the shared secret in the header gets blinded for the next hop.
+    pub(crate) fn process_with_routing_keys(
+        self,
+        routing_keys: &RoutingKeys<C>,
+    ) -> Result<ProcessedHeader<C>> {
+        if !self.has_valid_mac(routing_keys) {
+            return Err(Error::with_reason(
                 ErrorKind::InvalidHeader,
-                "failed to verify integrity MAC",
+                ErrorReason::InvalidMac,
             ));
         }
 
         let unwrapped_routing_information = self
             .routing_info
             .enc_routing_information
-            .unwrap(routing_keys.stream_cipher_key)?;
+            .unwrap(routing_keys.stream_cipher_key, &self.params)?;
 
         match unwrapped_routing_information {
             ParsedRawRoutingInformation::ForwardHop(
                 next_hop_address,
                 delay,
                 new_encapsulated_routing_info,
+                records,
+                version,
             ) => {
                 // blind the shared_secret in the header
                 let new_shared_secret =
@@ -179,17 +366,33 @@ impl SphinxHeader {
                     Box::new(SphinxHeader {
                         shared_secret: new_shared_secret,
                         routing_info: *new_encapsulated_routing_info,
+                        params: self.params,
                     }),
                     next_hop_address,
                     delay,
                     routing_keys.payload_key,
+                    records,
+                    version,
                 ))
             }
-            ParsedRawRoutingInformation::FinalHop(destination_address, identifier) => {
-                Ok(ProcessedHeader::FinalHop(
-                    destination_address,
-                    identifier,
+            ParsedRawRoutingInformation::FinalHop(
+                destination_address,
+                identifier,
+                records,
+                version,
+            ) => Ok(ProcessedHeader::FinalHop(
+                destination_address,
+                identifier,
+                routing_keys.payload_key,
+                records,
+                version,
+            )),
+            ParsedRawRoutingInformation::TrampolineHop(next_hop_address, records, version) => {
+                Ok(ProcessedHeader::TrampolineHop(
+                    next_hop_address,
                     routing_keys.payload_key,
+                    records,
+                    version,
                 ))
             }
         }
@@ -198,56 +401,104 @@ impl SphinxHeader {
     pub fn to_bytes(&self) -> Vec<u8> {
         self.shared_secret
             .as_bytes()
+            .as_ref()
             .iter()
             .cloned()
             .chain(self.routing_info.to_bytes())
             .collect()
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        if bytes.len() != HEADER_SIZE {
-            return Err(Error::new(
+    /// Tells whether the header belongs to a hop of the inner route, whose payload has been padded
+    /// by the trampoline.
+    pub(crate) fn is_inner_route_hop(&self, routing_keys: &RoutingKeys<C>) -> bool {
+        nodes::is_inner_route_hop::<C>(
+            self.routing_info.enc_routing_information.get_value_ref(),
+            &routing_keys.stream_cipher_key,
+        )
+    }
+
+    /// Parameters the header was created with and which are used for its processing.
+    pub fn params(&self) -> &SphinxParams {
+        &self.params
+    }
+
+    pub fn from_bytes(bytes: &[u8], params: &SphinxParams) -> Result<Self> {
+        let header_size = params.header_size::<C::Group>();
+        if bytes.len() != header_size {
+            return Err(Error::with_reason(
                 ErrorKind::InvalidHeader,
-                format!(
-                    "tried to recover using {} bytes, expected {}",
-                    bytes.len(),
-                    HEADER_SIZE
-                ),
+                ErrorReason::UnexpectedLength {
+                    expected: header_size,
+                    actual: bytes.len(),
+                },
             ));
         }
 
-        let mut shared_secret_bytes = [0u8; 32];
-        // first 32 bytes represent the shared secret
-        shared_secret_bytes.copy_from_slice(&bytes[..32]);
-        let shared_secret = SharedSecret::from(shared_secret_bytes);
+        let element_size = <C::Group as SphinxGroup>::ELEMENT_SIZE;
+        // first bytes represent the shared secret
+        let shared_secret = SharedSecret::try_from_byte_slice(&bytes[..element_size])?;
 
         // the rest are for the encapsulated routing info
-        let encapsulated_routing_info_bytes = bytes[32..HEADER_SIZE].to_vec();
+        let encapsulated_routing_info_bytes = bytes[element_size..header_size].to_vec();
 
         let routing_info =
-            EncapsulatedRoutingInformation::from_bytes(&encapsulated_routing_info_bytes)?;
+            EncapsulatedRoutingInformation::from_bytes(&encapsulated_routing_info_bytes, params)?;
 
         Ok(SphinxHeader {
             shared_secret,
             routing_info,
+            params: *params,
         })
     }
 
+    /// Processes the header serialized in the buffer, overwriting it with the header
+    /// for the next hop in case of a forward hop.
+    pub(crate) fn process_in_place(
+        header: &mut [u8],
+        shared_secret: &SharedSecret<C::Group>,
+        routing_keys: &RoutingKeys<C>,
+        params: &SphinxParams,
+    ) -> Result<ParsedRoutingInformationInPlace> {
+        let element_size = <C::Group as SphinxGroup>::ELEMENT_SIZE;
+        let (shared_secret_bytes, mac_and_routing_info) = header.split_at_mut(element_size);
+        let (mac, routing_info) = mac_and_routing_info.split_at(params.header_integrity_mac_size());
+        if !HeaderIntegrityMac::verify_bytes::<C>(
+            &routing_keys.header_integrity_hmac_key,
+            routing_info,
+            mac,
+        ) {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidHeader,
+                ErrorReason::InvalidMac,
+            ));
+        }
+
+        let processed = nodes::unwrap_in_place::<C>(
+            mac_and_routing_info,
+            &routing_keys.stream_cipher_key,
+            params,
+        )?;
+        if let ParsedRoutingInformationInPlace::ForwardHop(..) = processed {
+            let new_shared_secret =
+                Self::blind_the_shared_secret(*shared_secret, routing_keys.blinding_factor);
+            shared_secret_bytes.copy_from_slice(new_shared_secret.as_bytes().as_ref());
+        }
+        Ok(processed)
+    }
+
     fn blind_the_shared_secret(
-        shared_secret: SharedSecret,
+        shared_secret: SharedSecret<C::Group>,
         blinding_factor: BlindingFactor,
-    ) -> SharedSecret {
-        // TODO BEFORE PR: clamping, reduction, etc.
-        let blinding_factor = Scalar::from_bytes_mod_order(blinding_factor);
-        let blinder: EphemeralSecret = blinding_factor.into();
+    ) -> SharedSecret<C::Group> {
         // shared_secret * blinding_factor
-        blinder.diffie_hellman(&shared_secret)
+        shared_secret.blind(&blinding_factor)
     }
 }
 
 #[cfg(test)]
 mod create_and_process_sphinx_packet_header {
     use super::*;
+    use crate::route::Node;
     use crate::{constants::NODE_ADDRESS_LENGTH, test_utils::fixtures::destination_fixture};
     use std::time::Duration;
 
@@ -274,11 +525,13 @@ mod create_and_process_sphinx_packet_header {
         let average_delay = 1;
         let delays =
             delays::generate_from_average_duration(route.len(), Duration::from_secs(average_delay));
-        let (sphinx_header, _) = SphinxHeader::new(&initial_secret, &route, &delays, &destination);
+        let route = Route::new(route.to_vec(), delays.clone(), destination.clone()).unwrap();
+        let (sphinx_header, _): (SphinxHeader, _) =
+            SphinxHeader::new(&initial_secret, &route, &Default::default()).unwrap();
 
         //let (new_header, next_hop_address, _) = sphinx_header.process(node1_sk).unwrap();
         let new_header = match sphinx_header.process(&node1_sk).unwrap() {
-            ProcessedHeader::ForwardHop(new_header, next_hop_address, delay, _) => {
+            ProcessedHeader::ForwardHop(new_header, next_hop_address, delay, _, _, _) => {
                 assert_eq!(
                     NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
                     next_hop_address
@@ -290,7 +543,7 @@ mod create_and_process_sphinx_packet_header {
         };
 
         let new_header2 = match new_header.process(&node2_sk).unwrap() {
-            ProcessedHeader::ForwardHop(new_header, next_hop_address, delay, _) => {
+            ProcessedHeader::ForwardHop(new_header, next_hop_address, delay, _, _, _) => {
                 assert_eq!(
                     NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]),
                     next_hop_address
@@ -301,12 +554,122 @@ mod create_and_process_sphinx_packet_header {
             _ => panic!(),
         };
         match new_header2.process(&node3_sk).unwrap() {
-            ProcessedHeader::FinalHop(final_destination, _, _) => {
+            ProcessedHeader::FinalHop(final_destination, _, _, _, _) => {
                 assert_eq!(destination.address, final_destination);
             }
             _ => panic!(),
         };
     }
+
+    #[test]
+    fn it_returns_correct_routing_information_when_using_alternative_cipher_suite() {
+        let (node1_sk, node1_pk) = crypto::keygen();
+        let node1 = Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node1_pk,
+        );
+        let (node2_sk, node2_pk) = crypto::keygen();
+        let node2 = Node::new(
+            NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+            node2_pk,
+        );
+        let route = [node1, node2];
+        let destination = destination_fixture();
+        let initial_secret = EphemeralSecret::new();
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_secs(1));
+        let route = Route::new(route.to_vec(), delays.clone(), destination.clone()).unwrap();
+        let (sphinx_header, _) = SphinxHeader::<crypto::ChaCha20Blake2bSuite>::new(
+            &initial_secret,
+            &route,
+            &Default::default(),
+        )
+        .unwrap();
+
+        // make sure the header has exactly the same layout as the default one
+        assert_eq!(HEADER_SIZE, sphinx_header.to_bytes().len());
+
+        let new_header = match sphinx_header.process(&node1_sk).unwrap() {
+            ProcessedHeader::ForwardHop(new_header, next_hop_address, delay, _, _, _) => {
+                assert_eq!(
+                    NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+                    next_hop_address
+                );
+                assert_eq!(delays[0], delay);
+                new_header
+            }
+            _ => panic!(),
+        };
+
+        match new_header.process(&node2_sk).unwrap() {
+            ProcessedHeader::FinalHop(final_destination, _, _, _, _) => {
+                assert_eq!(destination.address, final_destination);
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn it_fails_to_process_header_created_with_different_cipher_suite() {
+        let (node1_sk, node1_pk) = crypto::keygen();
+        let node1 = Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node1_pk,
+        );
+        let route = [node1];
+        let destination = destination_fixture();
+        let initial_secret = EphemeralSecret::new();
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_secs(1));
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        let (sphinx_header, _) = SphinxHeader::<crypto::ChaCha20Blake2bSuite>::new(
+            &initial_secret,
+            &route,
+            &Default::default(),
+        )
+        .unwrap();
+
+        let header_bytes = sphinx_header.to_bytes();
+        let recovered_header: SphinxHeader =
+            SphinxHeader::from_bytes(&header_bytes, &Default::default()).unwrap();
+        match recovered_header.process(&node1_sk) {
+            Err(err) => assert_eq!(Some(&ErrorReason::InvalidMac), err.reason()),
+            Ok(_) => panic!("processed header created with a different cipher suite"),
+        }
+    }
+
+    #[test]
+    fn it_has_size_depending_on_the_group_element_size() {
+        type X448Suite = crypto::Aes128HmacSha256Suite<crypto::X448>;
+        let params = SphinxParams::default();
+
+        let node1_sk = PrivateKey::<crypto::X448>::new();
+        let node1 = Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            crypto::PublicKey::from(&node1_sk),
+        );
+        let route = [node1];
+        let destination = destination_fixture();
+        let initial_secret = EphemeralSecret::new();
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_secs(1));
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        let (sphinx_header, _) =
+            SphinxHeader::<X448Suite>::new(&initial_secret, &route, &params).unwrap();
+
+        let header_bytes = sphinx_header.to_bytes();
+        assert_eq!(params.header_size::<crypto::X448>(), header_bytes.len());
+        assert_eq!(HEADER_SIZE + 24, header_bytes.len());
+        assert!(SphinxHeader::<X448Suite>::from_bytes(&header_bytes, &params).is_ok());
+        assert!(SphinxHeader::<DefaultCipherSuite>::from_bytes(&header_bytes, &params).is_err());
+    }
+
+    #[test]
+    fn it_fails_to_recover_header_with_invalid_group_element() {
+        type RistrettoSuite = crypto::Aes128HmacSha256Suite<crypto::Ristretto255>;
+
+        let params = SphinxParams::default();
+
+        let header_bytes = vec![255u8; params.header_size::<crypto::Ristretto255>()];
+        assert!(SphinxHeader::<RistrettoSuite>::from_bytes(&header_bytes, &params).is_err());
+    }
 }
 
 #[cfg(test)]
@@ -314,7 +677,7 @@ mod unwrap_routing_information {
     use super::*;
     use crate::constants::{
         HEADER_INTEGRITY_MAC_SIZE, NODE_ADDRESS_LENGTH, NODE_META_INFO_SIZE,
-        STREAM_CIPHER_OUTPUT_LENGTH,
+        STREAM_CIPHER_OUTPUT_LENGTH, VERSION_LENGTH,
     };
     use crate::crypto;
     use crate::header::routing::nodes::EncryptedRoutingInformation;
@@ -325,6 +688,7 @@ mod unwrap_routing_information {
     fn it_returns_correct_unwrapped_routing_information() {
         let mut routing_info = [9u8; ENCRYPTED_ROUTING_INFO_SIZE];
         routing_info[0] = FORWARD_HOP;
+        routing_info[1..1 + VERSION_LENGTH].copy_from_slice(&Version::new().to_bytes());
         let stream_cipher_key = [1u8; crypto::STREAM_CIPHER_KEY_SIZE];
         let pseudorandom_bytes = crypto::generate_pseudorandom_bytes(
             &stream_cipher_key,
@@ -335,11 +699,8 @@ mod unwrap_routing_information {
             &routing_info,
             &pseudorandom_bytes[..ENCRYPTED_ROUTING_INFO_SIZE],
         );
-        let mut encrypted_routing_info_array = [0u8; ENCRYPTED_ROUTING_INFO_SIZE];
-        encrypted_routing_info_array.copy_from_slice(&encrypted_routing_info_vec);
-
-        let enc_routing_info =
-            EncryptedRoutingInformation::from_bytes(encrypted_routing_info_array);
+        let enc_routing_info: EncryptedRoutingInformation =
+            EncryptedRoutingInformation::from_bytes(encrypted_routing_info_vec);
 
         let expected_next_hop_encrypted_routing_information = [
             routing_info[NODE_META_INFO_SIZE + HEADER_INTEGRITY_MAC_SIZE..].to_vec(),
@@ -348,30 +709,34 @@ mod unwrap_routing_information {
                 .to_vec(),
         ]
         .concat();
-        let next_hop_encapsulated_routing_info =
-            match enc_routing_info.unwrap(stream_cipher_key).unwrap() {
-                ParsedRawRoutingInformation::ForwardHop(
-                    next_hop_address,
-                    _delay,
-                    next_hop_encapsulated_routing_info,
-                ) => {
-                    assert_eq!(
-                        routing_info[1..1 + NODE_ADDRESS_LENGTH],
-                        next_hop_address.as_bytes()
-                    );
-                    assert_eq!(
-                        routing_info
-                            [NODE_ADDRESS_LENGTH..NODE_ADDRESS_LENGTH + HEADER_INTEGRITY_MAC_SIZE]
-                            .to_vec(),
-                        next_hop_encapsulated_routing_info
-                            .integrity_mac
-                            .as_bytes()
-                            .to_vec()
-                    );
+        let next_hop_encapsulated_routing_info = match enc_routing_info
+            .unwrap(stream_cipher_key, &Default::default())
+            .unwrap()
+        {
+            ParsedRawRoutingInformation::ForwardHop(
+                next_hop_address,
+                _delay,
+                next_hop_encapsulated_routing_info,
+                _,
+                _,
+            ) => {
+                assert_eq!(
+                    routing_info[1 + VERSION_LENGTH..1 + VERSION_LENGTH + NODE_ADDRESS_LENGTH],
+                    next_hop_address.as_bytes()
+                );
+                assert_eq!(
+                    routing_info
+                        [NODE_ADDRESS_LENGTH..NODE_ADDRESS_LENGTH + HEADER_INTEGRITY_MAC_SIZE]
+                        .to_vec(),
                     next_hop_encapsulated_routing_info
-                }
-                _ => panic!(),
-            };
+                        .integrity_mac
+                        .as_bytes()
+                        .to_vec()
+                );
+                next_hop_encapsulated_routing_info
+            }
+            _ => panic!(),
+        };
 
         let next_hop_encrypted_routing_information = next_hop_encapsulated_routing_info
             .enc_routing_information
@@ -390,6 +755,7 @@ mod unwrap_routing_information {
 mod unwrapping_using_previously_derived_keys {
     use super::*;
     use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::route::Node;
     use crate::test_utils::fixtures::destination_fixture;
     use std::time::Duration;
 
@@ -411,7 +777,9 @@ mod unwrapping_using_previously_derived_keys {
         let average_delay = 1;
         let delays =
             delays::generate_from_average_duration(route.len(), Duration::from_secs(average_delay));
-        let (sphinx_header, _) = SphinxHeader::new(&initial_secret, &route, &delays, &destination);
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        let (sphinx_header, _): (SphinxHeader, _) =
+            SphinxHeader::new(&initial_secret, &route, &Default::default()).unwrap();
         let initial_secret = sphinx_header.shared_secret;
 
         let normally_unwrapped = match sphinx_header.clone().process(&node1_sk).unwrap() {
@@ -453,11 +821,15 @@ mod unwrapping_using_previously_derived_keys {
         let average_delay = 1;
         let delays =
             delays::generate_from_average_duration(route.len(), Duration::from_secs(average_delay));
-        let (sphinx_header, _) = SphinxHeader::new(&initial_secret, &route, &delays, &destination);
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        let (sphinx_header, _): (SphinxHeader, _) =
+            SphinxHeader::new(&initial_secret, &route, &Default::default()).unwrap();
         let initial_secret = sphinx_header.shared_secret;
 
         let normally_unwrapped = match sphinx_header.clone().process(&node1_sk).unwrap() {
-            ProcessedHeader::FinalHop(destination, surb_id, keys) => (destination, surb_id, keys),
+            ProcessedHeader::FinalHop(destination, surb_id, keys, _, _) => {
+                (destination, surb_id, keys)
+            }
             _ => unreachable!(),
         };
 
@@ -467,7 +839,9 @@ mod unwrapping_using_previously_derived_keys {
             .process_with_derived_keys(&None, &routing_keys)
             .unwrap()
         {
-            ProcessedHeader::FinalHop(destination, surb_id, keys) => (destination, surb_id, keys),
+            ProcessedHeader::FinalHop(destination, surb_id, keys, _, _) => {
+                (destination, surb_id, keys)
+            }
             _ => unreachable!(),
         };
 
@@ -488,10 +862,12 @@ mod converting_header_to_bytes {
         let header = SphinxHeader {
             shared_secret: SharedSecret::from(&EphemeralSecret::new()),
             routing_info: encapsulated_routing_info,
+            params: Default::default(),
         };
 
         let header_bytes = header.to_bytes();
-        let recovered_header = SphinxHeader::from_bytes(&header_bytes).unwrap();
+        let recovered_header: SphinxHeader =
+            SphinxHeader::from_bytes(&header_bytes, &Default::default()).unwrap();
 
         assert_eq!(
             header.shared_secret.as_bytes(),
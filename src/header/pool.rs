@@ -0,0 +1,362 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::{CipherSuite, DefaultCipherSuite, EphemeralSecret};
+use crate::header::keys::PayloadKey;
+use crate::header::SphinxHeader;
+use crate::params::SphinxParams;
+use crate::route::Route;
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Header built ahead of time together with the keys required to encapsulate the payload.
+pub struct PreparedHeader<C: CipherSuite = DefaultCipherSuite> {
+    pub header: SphinxHeader<C>,
+    pub payload_keys: Vec<PayloadKey>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeaderPoolStats {
+    /// Number of headers currently waiting in the pool.
+    pub depth: usize,
+    /// Number of headers the pool is being refilled to.
+    pub target_depth: usize,
+    /// Number of headers built since the pool was created.
+    pub built: u64,
+    /// Number of routes for which the header could not be built, e.g. because they
+    /// do not fit in the header with the params of the pool.
+    pub failed: u64,
+    /// Headers built per second of refilling, i.e. the rate at which the pool
+    /// is able to refill, not counting the time it was full.
+    pub refill_rate: f64,
+}
+
+// first wait of the worker after the header could not be built for the route,
+// doubled with every failure in a row up to the maximum
+const MIN_FAILURE_BACKOFF: Duration = Duration::from_millis(1);
+const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(1);
+
+struct PoolState<C: CipherSuite> {
+    headers: VecDeque<PreparedHeader<C>>,
+    shutdown: bool,
+    // set once the route source panicked, as the worker can't continue after that
+    stopped: bool,
+    built: u64,
+    failed: u64,
+    // reason of the last failure, kept until a header is built again
+    last_failure: Option<(ErrorKind, Option<ErrorReason>)>,
+    refilling_time: Duration,
+}
+
+struct SharedPool<C: CipherSuite> {
+    state: Mutex<PoolState<C>>,
+    // signalled whenever a header is taken or the pool is shut down
+    needs_refill: Condvar,
+    // signalled whenever a header is put into the pool or the worker fails to build it
+    header_available: Condvar,
+    target_depth: usize,
+}
+
+impl<C: CipherSuite> SharedPool<C> {
+    // the state is consistent at all times, so it is fine to keep using it
+    // even if some other thread panicked while holding the lock
+    fn lock(&self) -> MutexGuard<'_, PoolState<C>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Headers built in a background thread ahead of time, so that packets, e.g. cover traffic
+/// sent on a timer, could be created without the cost of the key exchange with every hop.
+/// The pool is refilled to its target depth as soon as headers are taken out of it,
+/// each of them with a fresh initial secret and for a route given by the provided source.
+/// After the header could not be built for the route, the worker backs off exponentially
+/// before asking the source for another one.
+pub struct HeaderPool<C: CipherSuite = DefaultCipherSuite> {
+    shared: Arc<SharedPool<C>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<C: CipherSuite> HeaderPool<C> {
+    pub fn new<F>(target_depth: usize, params: SphinxParams, mut route_source: F) -> Result<Self>
+    where
+        F: FnMut() -> Route<C::Group> + Send + 'static,
+    {
+        if target_depth == 0 {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidHeader,
+                ErrorReason::InvalidParameter {
+                    name: "header pool target depth",
+                    value: target_depth,
+                    minimum: 1,
+                    maximum: usize::MAX,
+                },
+            ));
+        }
+        let shared = Arc::new(SharedPool {
+            state: Mutex::new(PoolState {
+                headers: VecDeque::with_capacity(target_depth),
+                shutdown: false,
+                stopped: false,
+                built: 0,
+                failed: 0,
+                last_failure: None,
+                refilling_time: Duration::default(),
+            }),
+            needs_refill: Condvar::new(),
+            header_available: Condvar::new(),
+            target_depth,
+        });
+
+        let worker_shared = Arc::clone(&shared);
+        let worker = thread::spawn(move || {
+            let mut backoff = Duration::default();
+            loop {
+                {
+                    let mut state = worker_shared.lock();
+                    if backoff > Duration::default() && !state.shutdown {
+                        state = worker_shared
+                            .needs_refill
+                            .wait_timeout_while(state, backoff, |state| !state.shutdown)
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .0;
+                    }
+                    while !state.shutdown && state.headers.len() >= worker_shared.target_depth {
+                        state = worker_shared
+                            .needs_refill
+                            .wait(state)
+                            .unwrap_or_else(PoisonError::into_inner);
+                    }
+                    if state.shutdown {
+                        return;
+                    }
+                }
+
+                let start = Instant::now();
+                let route = match panic::catch_unwind(AssertUnwindSafe(&mut route_source)) {
+                    Ok(route) => route,
+                    Err(_) => {
+                        worker_shared.lock().stopped = true;
+                        worker_shared.header_available.notify_all();
+                        return;
+                    }
+                };
+                let result = SphinxHeader::new(&EphemeralSecret::new(), &route, &params);
+
+                let mut state = worker_shared.lock();
+                state.refilling_time += start.elapsed();
+                match result {
+                    Ok((header, payload_keys)) => {
+                        state.headers.push_back(PreparedHeader {
+                            header,
+                            payload_keys,
+                        });
+                        state.built += 1;
+                        state.last_failure = None;
+                        backoff = Duration::default();
+                        worker_shared.header_available.notify_one();
+                    }
+                    Err(err) => {
+                        state.failed += 1;
+                        state.last_failure = Some((err.kind(), err.reason().cloned()));
+                        backoff = (backoff * 2).clamp(MIN_FAILURE_BACKOFF, MAX_FAILURE_BACKOFF);
+                        worker_shared.header_available.notify_all();
+                    }
+                }
+            }
+        });
+
+        Ok(HeaderPool {
+            shared,
+            worker: Some(worker),
+        })
+    }
+
+    /// Takes the oldest header out of the pool, if there is any.
+    pub fn take(&self) -> Option<PreparedHeader<C>> {
+        let mut state = self.shared.lock();
+        let header = state.headers.pop_front();
+        if header.is_some() {
+            self.shared.needs_refill.notify_one();
+        }
+        header
+    }
+
+    /// Takes the oldest header out of the pool, waiting for the pool to be refilled if it is empty.
+    /// Rather than waiting, it fails with the reason of the last failure if the header could not
+    /// be built for the last route, or with `ErrorReason::HeaderPoolStopped` if the route source
+    /// panicked and the pool is not refilled anymore.
+    pub fn take_blocking(&self) -> Result<PreparedHeader<C>> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(header) = state.headers.pop_front() {
+                self.shared.needs_refill.notify_one();
+                return Ok(header);
+            }
+            if state.stopped {
+                return Err(Error::with_reason(
+                    ErrorKind::InvalidRouting,
+                    ErrorReason::HeaderPoolStopped,
+                ));
+            }
+            if let Some((kind, reason)) = state.last_failure.clone() {
+                return Err(match reason {
+                    Some(reason) => Error::with_reason(kind, reason),
+                    None => kind.into(),
+                });
+            }
+            state = self
+                .shared
+                .header_available
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.shared.lock().headers.len()
+    }
+
+    pub fn stats(&self) -> HeaderPoolStats {
+        let state = self.shared.lock();
+        let refilling_secs = state.refilling_time.as_secs_f64();
+        HeaderPoolStats {
+            depth: state.headers.len(),
+            target_depth: self.shared.target_depth,
+            built: state.built,
+            failed: state.failed,
+            refill_rate: if refilling_secs > 0.0 {
+                state.built as f64 / refilling_secs
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+impl<C: CipherSuite> Drop for HeaderPool<C> {
+    fn drop(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.needs_refill.notify_one();
+        if let Some(worker) = self.worker.take() {
+            // panics of the route source are caught by the worker itself
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod header_pool {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::header::delays;
+    use crate::route::Node;
+    use crate::test_utils::fixtures::destination_fixture;
+    use crate::test_utils::random_node;
+    use crate::SphinxPacketBuilder;
+
+    fn route_with_first_hop(first_hop: Node) -> Route {
+        let nodes = vec![first_hop, random_node()];
+        let delays = delays::generate_from_average_duration(2, Duration::from_millis(10));
+        Route::new(nodes, delays, destination_fixture()).unwrap()
+    }
+
+    fn route_source() -> Route {
+        route_with_first_hop(random_node())
+    }
+
+    fn wait_for_depth(pool: &HeaderPool, depth: usize) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pool.depth() != depth {
+            assert!(Instant::now() < deadline, "pool has not been refilled");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn it_is_refilled_to_target_depth_after_headers_are_taken() {
+        let pool: HeaderPool = HeaderPool::new(3, Default::default(), route_source).unwrap();
+        wait_for_depth(&pool, 3);
+
+        assert!(pool.take().is_some());
+        pool.take_blocking().unwrap();
+        wait_for_depth(&pool, 3);
+
+        let stats = pool.stats();
+        assert_eq!(3, stats.target_depth);
+        assert_eq!(5, stats.built);
+        assert_eq!(0, stats.failed);
+        assert!(stats.refill_rate > 0.0);
+    }
+
+    #[test]
+    fn it_counts_routes_for_which_header_could_not_be_built() {
+        // routes with more than a single hop do not fit in the header with these params
+        let params = SphinxParams::new(1, 32, 16, 8).unwrap();
+        let pool: HeaderPool = HeaderPool::new(1, params, route_source).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pool.stats().failed == 0 {
+            assert!(Instant::now() < deadline, "route has not been used");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(pool.take().is_none());
+        // rather than waiting for the header forever
+        let err = pool.take_blocking().err().unwrap();
+        assert_eq!(ErrorKind::InvalidRouting, err.kind());
+
+        // while the worker backs off instead of trying again right away
+        thread::sleep(Duration::from_millis(100));
+        assert!(pool.stats().failed < 20);
+    }
+
+    #[test]
+    fn it_fails_instead_of_waiting_once_the_route_source_panicked() {
+        let pool: HeaderPool =
+            HeaderPool::new(1, Default::default(), || panic!("no routes")).unwrap();
+        let err = pool.take_blocking().err().unwrap();
+        assert_eq!(Some(&ErrorReason::HeaderPoolStopped), err.reason());
+        // and the pool can still be used and dropped
+        assert!(pool.take().is_none());
+        assert_eq!(0, pool.stats().built);
+    }
+
+    #[test]
+    fn it_needs_a_positive_target_depth() {
+        let result: Result<HeaderPool> = HeaderPool::new(0, Default::default(), route_source);
+        assert!(matches!(
+            result.err().unwrap().reason(),
+            Some(ErrorReason::InvalidParameter { value: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn packets_with_prepared_headers_can_be_processed_by_the_first_hop() {
+        let node_sk = PrivateKey::new();
+        let first_hop = Node::new(random_node().address, (&node_sk).into());
+        let pool: HeaderPool = HeaderPool::new(1, Default::default(), move || {
+            route_with_first_hop(first_hop.clone())
+        })
+        .unwrap();
+
+        let prepared = pool.take_blocking().unwrap();
+        assert_eq!(2, prepared.payload_keys.len());
+        let packet = SphinxPacketBuilder::new()
+            .build_packet_with_header(b"foomp", prepared)
+            .unwrap();
+        assert!(packet.process(&node_sk).is_ok());
+    }
+}
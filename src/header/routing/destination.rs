@@ -12,148 +12,149 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::constants::{
-    FINAL_NODE_META_INFO_LENGTH, MAX_PATH_LENGTH, SECURITY_PARAMETER, STREAM_CIPHER_OUTPUT_LENGTH,
+use crate::constants::{DESTINATION_ADDRESS_LENGTH, FINAL_NODE_META_INFO_LENGTH};
+use crate::crypto::CipherSuite;
+use crate::header::routing::{
+    RoutingFlag, Version, FINAL_HOP, FINAL_HOP_WITH_TLV, INNER_ROUTE_HOP, TRAMPOLINE_HOP,
+    TRAMPOLINE_HOP_WITH_TLV,
 };
-use crate::crypto;
-use crate::crypto::STREAM_CIPHER_INIT_VECTOR;
-use crate::header::filler::{Filler, FILLER_STEP_SIZE_INCREASE};
-use crate::header::keys::StreamCipherKey;
-use crate::header::routing::nodes::EncryptedRoutingInformation;
-use crate::header::routing::{RoutingFlag, Version, ENCRYPTED_ROUTING_INFO_SIZE, FINAL_HOP};
+use crate::header::tlv::TlvStream;
+use crate::params::SphinxParams;
 use crate::route::{Destination, DestinationAddressBytes, SURBIdentifier};
-use crate::utils;
+use crate::{Error, ErrorKind, ErrorReason, Result};
 use rand::rngs::OsRng;
+use rand::RngCore;
 
-// this is going through the following transformations:
-/*
-    FinalRoutingInformation -> PaddedFinalRoutingInformation -> EncryptedPaddedFinalRoutingInformation ->
-    Encrypted Padded Destination with Filler - this can be treated as EncryptedRoutingInformation
-*/
-
-// TODO: perhaps add route_len to all final_routing_info related structs to simplify everything?
-// because it seems weird that say 'encrypt' requires route_len argument
-pub(super) struct FinalRoutingInformation {
+// the final routing information is written in front of the filler as D || I || PAD
+// and then encrypted in place, so that together with the filler it can be treated as EncryptedRoutingInformation
+pub(super) struct FinalRoutingInformation<'a> {
     flag: RoutingFlag,
     version: Version,
     destination: DestinationAddressBytes,
     // in paper delta
     identifier: SURBIdentifier, // in paper I
+    records: &'a TlvStream,
+}
+
+/// Space available for the destination address and the records of the final hop
+/// after the filler of the given length.
+pub(crate) fn max_destination_length(filler_len: usize, params: &SphinxParams) -> usize {
+    // everything that is left after the meta info other than the destination itself
+    max_padded_destination_identifier_length(filler_len, params)
+        - (FINAL_NODE_META_INFO_LENGTH - DESTINATION_ADDRESS_LENGTH)
+}
+
+// the filler takes the space of the routing information of all forward hops
+fn max_padded_destination_identifier_length(filler_len: usize, params: &SphinxParams) -> usize {
+    params.encrypted_routing_info_size() - filler_len
 }
 
-impl FinalRoutingInformation {
-    // TODO: this should really return a Result in case the assertion failed
-    pub fn new(dest: &Destination, route_len: usize) -> Self {
-        assert!(dest.address.as_bytes_ref().len() <= Self::max_destination_length(route_len));
+impl<'a> FinalRoutingInformation<'a> {
+    pub fn new(
+        dest: &Destination,
+        records: &'a TlvStream,
+        filler_len: usize,
+        params: &SphinxParams,
+    ) -> Result<Self> {
+        let required = dest.address.as_bytes_ref().len() + records.section_len();
+        let available = max_destination_length(filler_len, params);
+        if required > available {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::RoutingInformationTooLong {
+                    required,
+                    available,
+                },
+            ));
+        }
+
+        let flag = if records.is_empty() {
+            FINAL_HOP
+        } else {
+            FINAL_HOP_WITH_TLV
+        };
 
-        Self {
-            flag: FINAL_HOP,
-            version: Version::new(),
+        Ok(Self {
+            flag,
+            version: params.version(),
             destination: dest.address,
             identifier: dest.identifier,
-        }
+            records,
+        })
     }
 
-    fn max_destination_length(route_len: usize) -> usize {
-        (3 * (MAX_PATH_LENGTH - route_len) + 2) * SECURITY_PARAMETER
+    /// Marks the hop as the trampoline forwarding the inner packet, the layout stays the same.
+    pub(super) fn into_trampoline(mut self) -> Self {
+        self.flag = if self.records.is_empty() {
+            TRAMPOLINE_HOP
+        } else {
+            TRAMPOLINE_HOP_WITH_TLV
+        };
+        self
     }
 
-    fn max_padded_destination_identifier_length(route_len: usize) -> usize {
-        // this should evaluate to (3 * (MAX_PATH_LENGTH - route_len) + 3) * SECURITY_PARAMETER
-        ENCRYPTED_ROUTING_INFO_SIZE - (FILLER_STEP_SIZE_INCREASE * (route_len - 1))
+    /// Marks the hop as the final hop of the inner route, the layout stays the same.
+    pub(super) fn into_inner_route(mut self) -> Self {
+        self.flag |= INNER_ROUTE_HOP;
+        self
     }
 
-    pub(super) fn add_padding(self, route_len: usize) -> PaddedFinalRoutingInformation {
-        // paper uses 0 bytes for this, however, we use random instead so that we would not be affected by the
-        // attack on sphinx described by Kuhn et al.
-        let padding = utils::bytes::random(
-            &mut OsRng,
-            ENCRYPTED_ROUTING_INFO_SIZE
-                - (FILLER_STEP_SIZE_INCREASE * (route_len - 1))
-                - FINAL_NODE_META_INFO_LENGTH,
-        );
-
-        // return D || I || PAD
-        PaddedFinalRoutingInformation {
-            value: std::iter::once(self.flag)
-                .chain(self.version.to_bytes().into_iter())
-                .chain(self.destination.as_bytes().iter().cloned())
-                .chain(self.identifier.iter().cloned())
-                .chain(padding.iter().cloned())
-                .collect(),
+    // writes D || I || PAD into the space in front of the filler
+    fn write_padded(&self, output: &mut [u8]) {
+        let records_bytes = self.records.to_bytes();
+        let records_length_prefix = if self.records.is_empty() {
+            Vec::new()
+        } else {
+            (records_bytes.len() as u16).to_be_bytes().to_vec()
+        };
+
+        let mut offset = 0;
+        for component in [
+            &[self.flag][..],
+            &self.version.to_bytes(),
+            &records_length_prefix,
+            self.destination.as_bytes_ref(),
+            &self.identifier,
+            &records_bytes,
+        ] {
+            output[offset..offset + component.len()].copy_from_slice(component);
+            offset += component.len();
         }
-    }
-}
-
-// in paper D || I || 0
-pub(super) struct PaddedFinalRoutingInformation {
-    value: Vec<u8>,
-}
-
-impl PaddedFinalRoutingInformation {
-    pub(super) fn encrypt(
-        self,
-        key: StreamCipherKey,
-        route_len: usize,
-    ) -> EncryptedPaddedFinalRoutingInformation {
-        assert_eq!(
-            FinalRoutingInformation::max_padded_destination_identifier_length(route_len),
-            self.value.len()
-        );
 
-        let pseudorandom_bytes = crypto::generate_pseudorandom_bytes(
-            &key,
-            &STREAM_CIPHER_INIT_VECTOR,
-            STREAM_CIPHER_OUTPUT_LENGTH,
-        );
-
-        EncryptedPaddedFinalRoutingInformation {
-            value: utils::bytes::xor(
-                &self.value,
-                &pseudorandom_bytes[..self.value.len()], // we already asserted it has correct length
-            ),
-        }
+        // paper uses 0 bytes for this, however, we use random instead so that we would not be affected by the
+        // attack on sphinx described by Kuhn et al.
+        OsRng.fill_bytes(&mut output[offset..]);
     }
-}
-
-// in paper XOR ( (D || I || 0), rho(h_{rho}(s)) )
-pub(super) struct EncryptedPaddedFinalRoutingInformation {
-    value: Vec<u8>,
-}
 
-impl EncryptedPaddedFinalRoutingInformation {
-    // technically it's not exactly EncryptedRoutingInformation
-    // as it's EncryptedPaddedFinalRoutingInformation with possibly concatenated filler string
-    // however, for all of our purposes, it behaves exactly like EncryptedRoutingInformation
-    pub(super) fn combine_with_filler(
-        self,
-        filler: Filler,
-        route_len: usize,
-    ) -> EncryptedRoutingInformation {
-        let filler_value = filler.get_value();
+    // in paper XOR ( (D || I || 0), rho(h_{rho}(s)) )
+    pub(super) fn encrypt_in_place<C: CipherSuite>(
+        &self,
+        padded_routing_info: &mut [u8],
+        key: &C::StreamCipherKey,
+        filler_len: usize,
+        params: &SphinxParams,
+    ) {
         assert_eq!(
-            filler_value.len(),
-            FILLER_STEP_SIZE_INCREASE * (route_len - 1)
+            max_padded_destination_identifier_length(filler_len, params),
+            padded_routing_info.len()
         );
 
-        let final_routing_info_vec: Vec<u8> = self.value.into_iter().chain(filler_value).collect();
-
-        // sanity check assertion, because we're using vectors
-        assert_eq!(final_routing_info_vec.len(), ENCRYPTED_ROUTING_INFO_SIZE);
-        let mut final_routing_information = [0u8; ENCRYPTED_ROUTING_INFO_SIZE];
-        final_routing_information
-            .copy_from_slice(&final_routing_info_vec[..ENCRYPTED_ROUTING_INFO_SIZE]);
-        EncryptedRoutingInformation::from_bytes(final_routing_information)
+        self.write_padded(padded_routing_info);
+        C::apply_keystream(key, 0, padded_routing_info);
     }
 }
 
 #[cfg(test)]
 mod test_encapsulating_final_routing_information_and_mac {
+    use crate::constants::HEADER_INTEGRITY_MAC_SIZE;
+    use crate::crypto::DefaultCipherSuite;
+    use crate::header::delays::Delay;
     use crate::header::mac::HeaderIntegrityMac;
+    use crate::header::tlv::TlvStream;
     use crate::{
         header::routing::EncapsulatedRoutingInformation,
         test_utils::{
-            fixtures::{destination_fixture, filler_fixture, routing_keys_fixture},
+            fixtures::{destination_fixture, routing_keys_fixture},
             random_node,
         },
     };
@@ -161,25 +162,25 @@ mod test_encapsulating_final_routing_information_and_mac {
     #[test]
     fn it_returns_mac_on_correct_data() {
         // this test is created to ensure we MAC the encrypted data BEFORE it is truncated
-        let route = [random_node(), random_node(), random_node()];
-        let routing_keys = [
-            routing_keys_fixture(),
-            routing_keys_fixture(),
-            routing_keys_fixture(),
-        ];
-        let filler = filler_fixture(route.len() - 1);
+        let route = [random_node()];
+        let routing_keys = [routing_keys_fixture()];
         let destination = destination_fixture();
-        let final_routing_info = EncapsulatedRoutingInformation::for_final_hop(
+        let final_routing_info = EncapsulatedRoutingInformation::new(
+            &route,
             &destination,
-            routing_keys.last().unwrap(),
-            filler,
-            route.len(),
-        );
-
-        let expected_mac = HeaderIntegrityMac::compute(
+            &[Delay::new_from_nanos(10)],
+            &[TlvStream::new()],
+            &routing_keys,
+            &Default::default(),
+        )
+        .unwrap();
+
+        let expected_mac = HeaderIntegrityMac::compute::<DefaultCipherSuite>(
             routing_keys.last().unwrap().header_integrity_hmac_key,
             final_routing_info.enc_routing_information.get_value_ref(),
-        );
+            HEADER_INTEGRITY_MAC_SIZE,
+        )
+        .unwrap();
         assert_eq!(
             expected_mac.into_inner(),
             final_routing_info.integrity_mac.into_inner()
@@ -190,82 +191,96 @@ mod test_encapsulating_final_routing_information_and_mac {
 #[cfg(test)]
 mod test_encapsulating_final_routing_information {
     use super::*;
-    use crate::test_utils::fixtures::{destination_fixture, filler_fixture, routing_keys_fixture};
-
-    #[test]
-    fn it_produces_result_of_length_filler_plus_padded_concatenated_destination_and_identifier_and_flag_for_route_of_length_5(
-    ) {
+    use crate::constants::VERSION_LENGTH;
+    use crate::crypto::DefaultCipherSuite;
+    use crate::header::filler::FILLER_STEP_SIZE_INCREASE;
+    use crate::header::routing::ENCRYPTED_ROUTING_INFO_SIZE;
+    use crate::test_utils::fixtures::{destination_fixture, routing_keys_fixture};
+
+    fn assert_destination_is_put_in_front_of_filler(route_len: usize) {
+        let params = SphinxParams::default();
         let final_keys = routing_keys_fixture();
-        let route_len = 5;
-        let filler = filler_fixture(route_len - 1);
+        let filler_len = FILLER_STEP_SIZE_INCREASE * (route_len - 1);
         let destination = destination_fixture();
-
-        let final_routing_header = FinalRoutingInformation::new(&destination, route_len)
-            .add_padding(route_len)
-            .encrypt(final_keys.stream_cipher_key, route_len)
-            .combine_with_filler(filler, route_len);
-
-        let expected_final_header_len = ENCRYPTED_ROUTING_INFO_SIZE;
-
+        let records = TlvStream::new();
+
+        let mut final_routing_info = vec![0u8; ENCRYPTED_ROUTING_INFO_SIZE - filler_len];
+        FinalRoutingInformation::new(&destination, &records, filler_len, &params)
+            .unwrap()
+            .encrypt_in_place::<DefaultCipherSuite>(
+                &mut final_routing_info,
+                &final_keys.stream_cipher_key,
+                filler_len,
+                &params,
+            );
+
+        DefaultCipherSuite::apply_keystream(
+            &final_keys.stream_cipher_key,
+            0,
+            &mut final_routing_info,
+        );
+        let destination_offset = 1 + VERSION_LENGTH;
+        assert_eq!(FINAL_HOP, final_routing_info[0]);
         assert_eq!(
-            expected_final_header_len,
-            final_routing_header.get_value_ref().len()
+            destination.address.as_bytes_ref(),
+            &final_routing_info
+                [destination_offset..destination_offset + DESTINATION_ADDRESS_LENGTH]
         );
     }
 
     #[test]
-    fn it_produces_result_of_length_filler_plus_padded_concatenated_destination_and_identifier_and_flag_for_route_of_length_3(
-    ) {
-        let final_keys = routing_keys_fixture();
-        let route_len = 3;
-        let filler = filler_fixture(route_len - 1);
-        let destination = destination_fixture();
-
-        let final_routing_header = FinalRoutingInformation::new(&destination, route_len)
-            .add_padding(route_len)
-            .encrypt(final_keys.stream_cipher_key, route_len)
-            .combine_with_filler(filler, route_len);
-
-        let expected_final_header_len = ENCRYPTED_ROUTING_INFO_SIZE;
-
-        assert_eq!(
-            expected_final_header_len,
-            final_routing_header.get_value_ref().len()
-        );
+    fn it_puts_padded_destination_and_identifier_and_flag_in_front_of_filler_for_route_of_length_5()
+    {
+        assert_destination_is_put_in_front_of_filler(5)
     }
 
     #[test]
-    fn it_produces_result_of_length_filler_plus_padded_concatenated_destination_and_identifier_and_flag_for_route_of_length_1(
-    ) {
-        let final_keys = routing_keys_fixture();
-        let route_len = 1;
-        let filler = filler_fixture(route_len - 1);
-        let destination = destination_fixture();
-
-        let final_routing_header = FinalRoutingInformation::new(&destination, route_len)
-            .add_padding(route_len)
-            .encrypt(final_keys.stream_cipher_key, route_len)
-            .combine_with_filler(filler, route_len);
-
-        let expected_final_header_len = ENCRYPTED_ROUTING_INFO_SIZE;
+    fn it_puts_padded_destination_and_identifier_and_flag_in_front_of_filler_for_route_of_length_3()
+    {
+        assert_destination_is_put_in_front_of_filler(3)
+    }
 
-        assert_eq!(
-            expected_final_header_len,
-            final_routing_header.get_value_ref().len()
-        );
+    #[test]
+    fn it_puts_padded_destination_and_identifier_and_flag_in_front_of_filler_for_route_of_length_1()
+    {
+        assert_destination_is_put_in_front_of_filler(1)
     }
 
     #[test]
     #[should_panic]
     fn it_panics_if_it_receives_filler_different_than_filler_step_multiplied_with_i() {
+        let params = SphinxParams::default();
         let final_keys = routing_keys_fixture();
         let route_len = 3;
-        let filler = filler_fixture(route_len);
+        let filler_len = FILLER_STEP_SIZE_INCREASE * (route_len - 1);
         let destination = destination_fixture();
+        let records = TlvStream::new();
+
+        let mut final_routing_info =
+            vec![0u8; ENCRYPTED_ROUTING_INFO_SIZE - FILLER_STEP_SIZE_INCREASE * route_len];
+        FinalRoutingInformation::new(&destination, &records, filler_len, &params)
+            .unwrap()
+            .encrypt_in_place::<DefaultCipherSuite>(
+                &mut final_routing_info,
+                &final_keys.stream_cipher_key,
+                filler_len,
+                &params,
+            );
+    }
 
-        FinalRoutingInformation::new(&destination, route_len)
-            .add_padding(route_len)
-            .encrypt(final_keys.stream_cipher_key, route_len)
-            .combine_with_filler(filler, route_len);
+    #[test]
+    fn it_fails_if_records_do_not_fit_in_front_of_filler() {
+        let params = SphinxParams::default();
+        let filler_len = FILLER_STEP_SIZE_INCREASE * (params.max_path_length() - 1);
+        let mut records = TlvStream::new();
+        records.insert(crate::header::tlv::TlvRecord::new(5, vec![0u8; 20]));
+
+        match FinalRoutingInformation::new(&destination_fixture(), &records, filler_len, &params) {
+            Err(err) => assert!(matches!(
+                err.reason(),
+                Some(ErrorReason::RoutingInformationTooLong { .. })
+            )),
+            Ok(_) => panic!("expected records not to fit"),
+        }
     }
 }
@@ -12,16 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::constants::{HEADER_INTEGRITY_MAC_SIZE, MAX_PATH_LENGTH, NODE_META_INFO_SIZE};
+use crate::constants::{
+    HEADER_INTEGRITY_MAC_SIZE, MAX_PATH_LENGTH, NODE_META_INFO_SIZE, VERSION_LENGTH,
+};
+use crate::crypto::{CipherSuite, DefaultCipherSuite};
 use crate::header::delays::Delay;
 use crate::header::filler::Filler;
 use crate::header::keys::RoutingKeys;
 use crate::header::mac::HeaderIntegrityMac;
 use crate::header::routing::destination::FinalRoutingInformation;
 use crate::header::routing::nodes::{EncryptedRoutingInformation, RoutingInformation};
-use crate::route::{Destination, Node, NodeAddressBytes};
-use crate::{Error, ErrorKind, Result};
+use crate::header::tlv::TlvStream;
+use crate::params::SphinxParams;
+use crate::route::{Destination, Node};
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use std::fmt;
 
+// sizes of the routing information when using the default `SphinxParams`
 pub const TRUNCATED_ROUTING_INFO_SIZE: usize =
     ENCRYPTED_ROUTING_INFO_SIZE - (NODE_META_INFO_SIZE + HEADER_INTEGRITY_MAC_SIZE);
 pub const ENCRYPTED_ROUTING_INFO_SIZE: usize =
@@ -32,10 +39,34 @@ pub mod nodes;
 
 pub const FORWARD_HOP: RoutingFlag = 1;
 pub const FINAL_HOP: RoutingFlag = 2;
+// hops with attached tlv records, their routing information is longer by the size of the records
+pub const FORWARD_HOP_WITH_TLV: RoutingFlag = 3;
+pub const FINAL_HOP_WITH_TLV: RoutingFlag = 4;
+// final hops of the outer route whose payload carries the inner packet, laid out as the final hops
+// with the address of the first hop of the inner packet in place of the destination
+pub const TRAMPOLINE_HOP: RoutingFlag = 5;
+pub const TRAMPOLINE_HOP_WITH_TLV: RoutingFlag = 6;
+// set alongside the flags of the forward and final hops of the inner route, whose payload has been
+// padded by the trampoline to the size of the outer packet. Only its prefix, shorter by the size
+// of the header and `PAYLOAD_OVERHEAD_SIZE`, is the actual payload
+pub const INNER_ROUTE_HOP: RoutingFlag = 0x80;
 
 pub type RoutingFlag = u8;
 
-#[derive(Default)]
+/// What the hops of the route do with the payload, as told by their routing flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RouteKind {
+    Regular,
+    /// The final hop forwards the inner packet carried in the payload.
+    Trampoline,
+    /// The route of the inner packet, whose payload gets padded at the trampoline.
+    Inner,
+}
+
+/// Version of the header layout written into the routing information of every hop.
+/// Nodes only process the hops whose major version matches the one of their `SphinxParams`,
+/// while minor and patch versions have to stay compatible with each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub struct Version {
     major: u8,
     minor: u8,
@@ -45,27 +76,57 @@ pub struct Version {
 impl Version {
     pub fn new() -> Self {
         Self {
-            major: env!("CARGO_PKG_VERSION_MAJOR").to_string().parse().unwrap(),
-            minor: env!("CARGO_PKG_VERSION_MINOR").to_string().parse().unwrap(),
-            patch: env!("CARGO_PKG_VERSION_PATCH").to_string().parse().unwrap(),
+            major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap(),
+            minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap(),
+            patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap(),
+        }
+    }
+
+    pub fn from_parts(major: u8, minor: u8, patch: u8) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
         }
     }
 
+    pub fn major(&self) -> u8 {
+        self.major
+    }
+
+    pub fn minor(&self) -> u8 {
+        self.minor
+    }
+
+    pub fn patch(&self) -> u8 {
+        self.patch
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         vec![self.major, self.minor, self.patch]
     }
+
+    pub fn from_bytes(bytes: [u8; VERSION_LENGTH]) -> Self {
+        Self::from_parts(bytes[0], bytes[1], bytes[2])
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
 }
 
 // the derivation is only required for the tests. please remove it in production
 #[derive(Clone, Debug)]
-pub struct EncapsulatedRoutingInformation {
-    pub(crate) enc_routing_information: EncryptedRoutingInformation,
+pub struct EncapsulatedRoutingInformation<C: CipherSuite = DefaultCipherSuite> {
+    pub(crate) enc_routing_information: EncryptedRoutingInformation<C>,
     pub(crate) integrity_mac: HeaderIntegrityMac,
 }
 
-impl EncapsulatedRoutingInformation {
+impl<C: CipherSuite> EncapsulatedRoutingInformation<C> {
     pub fn encapsulate(
-        enc_routing_information: EncryptedRoutingInformation,
+        enc_routing_information: EncryptedRoutingInformation<C>,
         integrity_mac: HeaderIntegrityMac,
     ) -> Self {
         Self {
@@ -74,79 +135,130 @@ impl EncapsulatedRoutingInformation {
         }
     }
 
+    /// Builds the routing information of all the hops at once. The keystream of every forward hop
+    /// is generated just once, as it is needed both for the filler and for encrypting the layer,
+    /// and all the layers are written into the same buffer, starting from the destination.
     pub fn new(
-        route: &[Node],
+        route: &[Node<C::Group>],
         destination: &Destination,
         delays: &[Delay],
-        routing_keys: &[RoutingKeys],
-        filler: Filler,
-    ) -> Self {
-        assert_eq!(route.len(), routing_keys.len());
-        assert_eq!(delays.len(), route.len());
-
-        let final_keys = match routing_keys.last() {
-            Some(k) => k,
-            None => panic!("empty keys"),
-        };
-
-        let encapsulated_destination_routing_info =
-            Self::for_final_hop(destination, final_keys, filler, route.len());
-
-        Self::for_forward_hops(
-            encapsulated_destination_routing_info,
-            delays,
+        hop_records: &[TlvStream],
+        routing_keys: &[RoutingKeys<C>],
+        params: &SphinxParams,
+    ) -> Result<Self> {
+        Self::new_of_kind(
             route,
+            destination,
+            delays,
+            hop_records,
             routing_keys,
+            RouteKind::Regular,
+            params,
         )
     }
 
-    fn for_final_hop(
-        dest: &Destination,
-        routing_keys: &RoutingKeys,
-        filler: Filler,
-        route_len: usize,
-    ) -> Self {
-        // personal note: I like how this looks so much.
-        FinalRoutingInformation::new(dest, route_len)
-            .add_padding(route_len) // add padding to obtain correct destination length
-            .encrypt(routing_keys.stream_cipher_key, route_len) // encrypt with the key of final node (in our case service provider)
-            .combine_with_filler(filler, route_len) // add filler to get header of correct length
-            .encapsulate_with_mac(routing_keys.header_integrity_hmac_key) // combine the previous data with a MAC on the header (also calculated with the SPs key)
-    }
-
-    fn for_forward_hops(
-        encapsulated_destination_routing_info: Self,
+    /// Builds the routing information like [new], but marks the hops according to the kind
+    /// of the route, e.g. tells the final hop of the trampoline route to forward the inner packet
+    /// from its payload to the node whose address is put in place of the destination.
+    pub(crate) fn new_of_kind(
+        route: &[Node<C::Group>],
+        destination: &Destination,
         delays: &[Delay],
-        route: &[Node],               // [Mix0, Mix1, Mix2, ..., Mix_{v-1}, Mix_v]
-        routing_keys: &[RoutingKeys], // [Keys0, Keys1, Keys2, ..., Keys_{v-1}, Keys_v]
-    ) -> Self {
-        route
+        hop_records: &[TlvStream],
+        routing_keys: &[RoutingKeys<C>],
+        kind: RouteKind,
+        params: &SphinxParams,
+    ) -> Result<Self> {
+        if route.is_empty() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::EmptyRoute,
+            ));
+        }
+        for provided in [routing_keys.len(), delays.len(), hop_records.len()] {
+            if provided != route.len() {
+                return Err(Error::with_reason(
+                    ErrorKind::InvalidRouting,
+                    ErrorReason::HopCountMismatch {
+                        hops: route.len(),
+                        provided,
+                    },
+                ));
+            }
+        }
+
+        let (final_keys, forward_keys) = routing_keys.split_last().unwrap();
+        // records of the final node are put next to the destination
+        let (final_records, forward_records) = hop_records.split_last().unwrap();
+        let forward_hop_sizes: Vec<_> = forward_records
             .iter()
-            .skip(1) // we don't want the first element as person creating the packet knows the address of the first hop
-            .map(|node| node.address.as_bytes()) // we only care about the address field
-            .zip(
-                // we need both route (i.e. address field) and corresponding keys of the PREVIOUS hop
-                routing_keys.iter().take(routing_keys.len() - 1), // we don't want last element - it was already used to encrypt the destination
-            )
-            .zip(delays.iter().take(delays.len() - 1)) // no need for the delay for the final node
-            .rev() // we are working from the 'inside'
-            // we should be getting here
-            // [(Mix_v, Keys_{v-1}, Delay_{v-1}), (Mix_{v-1}, Keys_{v-2}, Delay_{v-2}), ..., (Mix2, Keys1, Delay1), (Mix1, Keys0, Delay0)]
-            .fold(
-                // we start from the already created encrypted final routing info and mac for the destination
-                // (encrypted with Keys_v)
-                encapsulated_destination_routing_info,
-                |next_hop_encapsulated_routing_information,
-                 ((current_node_address, previous_node_routing_keys), delay)| {
-                    RoutingInformation::new(
-                        NodeAddressBytes::from_bytes(current_node_address),
-                        delay.to_owned(),
-                        next_hop_encapsulated_routing_information,
-                    )
-                    .encrypt(previous_node_routing_keys.stream_cipher_key)
-                    .encapsulate_with_mac(previous_node_routing_keys.header_integrity_hmac_key)
-                },
-            )
+            .map(|records| params.filler_step_size() + records.section_len())
+            .collect();
+        let filler_len = forward_hop_sizes.iter().sum::<usize>();
+        let encrypted_routing_info_size = params.encrypted_routing_info_size();
+        let mac_size = params.header_integrity_mac_size();
+
+        let keystreams: Vec<_> = forward_keys
+            .iter()
+            .zip(&forward_hop_sizes)
+            .map(|(keys, hop_size)| {
+                C::generate_pseudorandom_bytes(
+                    &keys.stream_cipher_key,
+                    encrypted_routing_info_size + hop_size,
+                )
+            })
+            .collect();
+
+        let mut routing_info = vec![0u8; encrypted_routing_info_size];
+        let (padded_destination, filler) =
+            routing_info.split_at_mut(encrypted_routing_info_size - filler_len);
+        Filler::<C>::fill(filler, &keystreams, &forward_hop_sizes, params);
+        let mut final_routing_info =
+            FinalRoutingInformation::new(destination, final_records, filler_len, params)?;
+        match kind {
+            RouteKind::Regular => {}
+            RouteKind::Trampoline => final_routing_info = final_routing_info.into_trampoline(),
+            RouteKind::Inner => final_routing_info = final_routing_info.into_inner_route(),
+        }
+        final_routing_info.encrypt_in_place::<C>(
+            padded_destination,
+            &final_keys.stream_cipher_key,
+            filler_len,
+            params,
+        );
+        // the mac is computed on the whole encrypted routing information, including the filler
+        let mut integrity_mac = HeaderIntegrityMac::compute::<C>(
+            final_keys.header_integrity_hmac_key,
+            &routing_info,
+            mac_size,
+        )?;
+
+        // we are working from the 'inside'. Each hop gets the address of the following node,
+        // as the person creating the packet knows the address of the first hop
+        for (i, keystream) in keystreams.iter().enumerate().rev() {
+            let mut hop_routing_info = RoutingInformation::new(
+                route[i + 1].address,
+                delays[i],
+                &forward_records[i],
+                integrity_mac,
+                params,
+            )?;
+            if kind == RouteKind::Inner {
+                hop_routing_info = hop_routing_info.into_inner_route();
+            }
+            hop_routing_info
+                .encrypt_in_place(&mut routing_info, &keystream[..encrypted_routing_info_size]);
+            integrity_mac = HeaderIntegrityMac::compute::<C>(
+                forward_keys[i].header_integrity_hmac_key,
+                &routing_info,
+                mac_size,
+            )?;
+        }
+
+        Ok(EncapsulatedRoutingInformation {
+            enc_routing_information: EncryptedRoutingInformation::from_bytes(routing_info),
+            integrity_mac,
+        })
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -158,32 +270,24 @@ impl EncapsulatedRoutingInformation {
             .collect()
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        if bytes.len() != HEADER_INTEGRITY_MAC_SIZE + ENCRYPTED_ROUTING_INFO_SIZE {
-            return Err(Error::new(
+    pub fn from_bytes(bytes: &[u8], params: &SphinxParams) -> Result<Self> {
+        let mac_size = params.header_integrity_mac_size();
+        let expected_size = mac_size + params.encrypted_routing_info_size();
+        if bytes.len() != expected_size {
+            return Err(Error::with_reason(
                 ErrorKind::InvalidRouting,
-                format!(
-                    "tried to recover routing information using {} bytes, expected {}",
-                    bytes.len(),
-                    HEADER_INTEGRITY_MAC_SIZE + ENCRYPTED_ROUTING_INFO_SIZE
-                ),
+                ErrorReason::UnexpectedLength {
+                    expected: expected_size,
+                    actual: bytes.len(),
+                },
             ));
         }
 
-        let mut integrity_mac_bytes = [0u8; HEADER_INTEGRITY_MAC_SIZE];
-        let mut enc_routing_info_bytes = [0u8; ENCRYPTED_ROUTING_INFO_SIZE];
-
         // first bytes represent the mac
-        integrity_mac_bytes.copy_from_slice(&bytes[..HEADER_INTEGRITY_MAC_SIZE]);
+        let integrity_mac = HeaderIntegrityMac::from_bytes(&bytes[..mac_size]);
         // the rest are for the routing info
-        enc_routing_info_bytes.copy_from_slice(
-            &bytes[HEADER_INTEGRITY_MAC_SIZE
-                ..HEADER_INTEGRITY_MAC_SIZE + ENCRYPTED_ROUTING_INFO_SIZE],
-        );
-
-        let integrity_mac = HeaderIntegrityMac::from_bytes(integrity_mac_bytes);
         let enc_routing_information =
-            EncryptedRoutingInformation::from_bytes(enc_routing_info_bytes);
+            EncryptedRoutingInformation::from_bytes(bytes[mac_size..].to_vec());
 
         Ok(EncapsulatedRoutingInformation {
             enc_routing_information,
@@ -196,13 +300,12 @@ impl EncapsulatedRoutingInformation {
 mod encapsulating_all_routing_information {
     use super::*;
     use crate::test_utils::{
-        fixtures::{destination_fixture, filler_fixture, routing_keys_fixture},
+        fixtures::{destination_fixture, routing_keys_fixture},
         random_node,
     };
 
     #[test]
-    #[should_panic]
-    fn it_panics_if_route_is_longer_than_keys() {
+    fn it_fails_if_route_is_longer_than_keys() {
         let route = [random_node(), random_node(), random_node()];
         let destination = destination_fixture();
         let delays = [
@@ -211,14 +314,19 @@ mod encapsulating_all_routing_information {
             Delay::new_from_nanos(30),
         ];
         let keys = [routing_keys_fixture(), routing_keys_fixture()];
-        let filler = filler_fixture(route.len() - 1);
-
-        EncapsulatedRoutingInformation::new(&route, &destination, &delays, &keys, filler);
+        assert!(EncapsulatedRoutingInformation::new(
+            &route,
+            &destination,
+            &delays,
+            &vec![TlvStream::new(); route.len()],
+            &keys,
+            &Default::default(),
+        )
+        .is_err());
     }
 
     #[test]
-    #[should_panic]
-    fn it_panics_if_keys_are_longer_than_route() {
+    fn it_fails_if_keys_are_longer_than_route() {
         let route = [random_node(), random_node()];
         let destination = destination_fixture();
         let delays = [
@@ -231,14 +339,19 @@ mod encapsulating_all_routing_information {
             routing_keys_fixture(),
             routing_keys_fixture(),
         ];
-        let filler = filler_fixture(route.len() - 1);
-
-        EncapsulatedRoutingInformation::new(&route, &destination, &delays, &keys, filler);
+        assert!(EncapsulatedRoutingInformation::new(
+            &route,
+            &destination,
+            &delays,
+            &vec![TlvStream::new(); route.len()],
+            &keys,
+            &Default::default(),
+        )
+        .is_err());
     }
 
     #[test]
-    #[should_panic]
-    fn it_panics_if_empty_route_is_provided() {
+    fn it_fails_if_empty_route_is_provided() {
         let route = vec![];
         let destination = destination_fixture();
         let delays = [
@@ -251,14 +364,19 @@ mod encapsulating_all_routing_information {
             routing_keys_fixture(),
             routing_keys_fixture(),
         ];
-        let filler = filler_fixture(route.len() - 1);
-
-        EncapsulatedRoutingInformation::new(&route, &destination, &delays, &keys, filler);
+        assert!(EncapsulatedRoutingInformation::new(
+            &route,
+            &destination,
+            &delays,
+            &vec![TlvStream::new(); route.len()],
+            &keys,
+            &Default::default(),
+        )
+        .is_err());
     }
 
     #[test]
-    #[should_panic]
-    fn it_panic_if_empty_keys_are_provided() {
+    fn it_fails_if_empty_keys_are_provided() {
         let route = [random_node(), random_node()];
         let destination = destination_fixture();
         let delays = [
@@ -266,98 +384,150 @@ mod encapsulating_all_routing_information {
             Delay::new_from_nanos(20),
             Delay::new_from_nanos(30),
         ];
-        let keys = vec![];
-        let filler = filler_fixture(route.len() - 1);
-
-        EncapsulatedRoutingInformation::new(&route, &destination, &delays, &keys, filler);
+        let keys: Vec<RoutingKeys> = vec![];
+        assert!(EncapsulatedRoutingInformation::new(
+            &route,
+            &destination,
+            &delays,
+            &vec![TlvStream::new(); route.len()],
+            &keys,
+            &Default::default(),
+        )
+        .is_err());
     }
 }
 
 #[cfg(test)]
 mod encapsulating_forward_routing_information {
     use super::*;
-    use crate::test_utils::{
-        fixtures::{destination_fixture, filler_fixture, routing_keys_fixture},
-        random_node,
-    };
+    use crate::crypto::{EphemeralSecret, SharedSecret};
+    use crate::header::routing::nodes::ParsedRawRoutingInformation;
+    use crate::route::NodeAddressBytes;
+    use crate::test_utils::{fixtures::destination_fixture, random_node};
+
+    fn unwrap_layer(
+        layer: EncapsulatedRoutingInformation,
+        routing_keys: &RoutingKeys,
+        params: &SphinxParams,
+    ) -> ParsedRawRoutingInformation {
+        assert!(layer.integrity_mac.verify::<DefaultCipherSuite>(
+            routing_keys.header_integrity_hmac_key,
+            layer.enc_routing_information.get_value_ref()
+        ));
+        layer
+            .enc_routing_information
+            .unwrap(routing_keys.stream_cipher_key, params)
+            .unwrap()
+    }
+
+    // puts the unwrapped layer back into the previous one
+    fn wrap_layer(
+        layer: &EncapsulatedRoutingInformation,
+        node_address: NodeAddressBytes,
+        delay: Delay,
+        routing_keys: &RoutingKeys,
+        params: &SphinxParams,
+    ) -> Vec<u8> {
+        let records = TlvStream::new();
+        let mut routing_info = layer.enc_routing_information.get_value_ref().to_vec();
+        let keystream = DefaultCipherSuite::generate_pseudorandom_bytes(
+            &routing_keys.stream_cipher_key,
+            params.encrypted_routing_info_size(),
+        );
+        RoutingInformation::new(
+            node_address,
+            delay,
+            &records,
+            layer.integrity_mac.clone(),
+            params,
+        )
+        .unwrap()
+        .encrypt_in_place(&mut routing_info, &keystream);
+        let integrity_mac = HeaderIntegrityMac::compute::<DefaultCipherSuite>(
+            routing_keys.header_integrity_hmac_key,
+            &routing_info,
+            params.header_integrity_mac_size(),
+        )
+        .unwrap();
+        EncapsulatedRoutingInformation::<DefaultCipherSuite>::encapsulate(
+            EncryptedRoutingInformation::from_bytes(routing_info),
+            integrity_mac,
+        )
+        .to_bytes()
+    }
 
     #[test]
     fn it_correctly_generates_sphinx_routing_information_for_route_of_length_3() {
         // this is basically loop unwrapping, but considering the complex logic behind it, it's warranted
+        let params = SphinxParams::default();
         let route = [random_node(), random_node(), random_node()];
         let destination = destination_fixture();
         let delay0 = Delay::new_from_nanos(10);
         let delay1 = Delay::new_from_nanos(20);
         let delay2 = Delay::new_from_nanos(30);
-        let delays = [delay0.clone(), delay1.clone(), delay2].to_vec();
-        let routing_keys = [
-            routing_keys_fixture(),
-            routing_keys_fixture(),
-            routing_keys_fixture(),
-        ];
-        let filler = filler_fixture(route.len() - 1);
-        let filler_copy = filler_fixture(route.len() - 1);
-        assert_eq!(filler, filler_copy);
+        let delays = [delay0, delay1, delay2].to_vec();
+        let routing_keys: Vec<RoutingKeys> = (0..route.len())
+            .map(|_| RoutingKeys::derive(SharedSecret::from(&EphemeralSecret::new())))
+            .collect();
 
-        let destination_routing_info = EncapsulatedRoutingInformation::for_final_hop(
+        let routing_info = EncapsulatedRoutingInformation::new(
+            &route,
             &destination,
-            routing_keys.last().unwrap(),
-            filler,
-            route.len(),
-        );
-
-        let destination_routing_info_copy = destination_routing_info.clone();
-
-        // sanity check to make sure our 'copy' worked
-        assert_eq!(
-            destination_routing_info
-                .enc_routing_information
-                .get_value_ref()
-                .to_vec(),
-            destination_routing_info_copy
-                .enc_routing_information
-                .get_value_ref()
-                .to_vec()
-        );
-        assert_eq!(
-            destination_routing_info.integrity_mac.as_bytes().to_vec(),
-            destination_routing_info_copy
-                .integrity_mac
-                .as_bytes()
-                .to_vec()
-        );
-
-        let routing_info = EncapsulatedRoutingInformation::for_forward_hops(
-            destination_routing_info,
             &delays,
-            &route,
+            &vec![TlvStream::new(); route.len()],
             &routing_keys,
-        );
-
-        let layer_1_routing =
-            RoutingInformation::new(route[2].address, delay1, destination_routing_info_copy)
-                .encrypt(routing_keys[1].stream_cipher_key)
-                .encapsulate_with_mac(routing_keys[1].header_integrity_hmac_key);
-
-        // this is what first mix should receive
-        let layer_0_routing = RoutingInformation::new(route[1].address, delay0, layer_1_routing)
-            .encrypt(routing_keys[0].stream_cipher_key)
-            .encapsulate_with_mac(routing_keys[0].header_integrity_hmac_key);
-
+            &params,
+        )
+        .unwrap();
+        let layer_0_routing = routing_info.to_bytes();
+
+        // this is what first mix should forward
+        let layer_1_routing = match unwrap_layer(routing_info, &routing_keys[0], &params) {
+            ParsedRawRoutingInformation::ForwardHop(next_hop_address, delay, layer, _, _) => {
+                assert_eq!(route[1].address, next_hop_address);
+                assert_eq!(delay0, delay);
+                *layer
+            }
+            _ => panic!("expected forward hop"),
+        };
         assert_eq!(
-            routing_info
-                .enc_routing_information
-                .get_value_ref()
-                .to_vec(),
-            layer_0_routing
-                .enc_routing_information
-                .get_value_ref()
-                .to_vec()
+            layer_0_routing,
+            wrap_layer(
+                &layer_1_routing,
+                route[1].address,
+                delay0,
+                &routing_keys[0],
+                &params
+            )
         );
+
+        let destination_routing_info =
+            match unwrap_layer(layer_1_routing.clone(), &routing_keys[1], &params) {
+                ParsedRawRoutingInformation::ForwardHop(next_hop_address, delay, layer, _, _) => {
+                    assert_eq!(route[2].address, next_hop_address);
+                    assert_eq!(delay1, delay);
+                    *layer
+                }
+                _ => panic!("expected forward hop"),
+            };
         assert_eq!(
-            routing_info.integrity_mac.into_inner(),
-            layer_0_routing.integrity_mac.into_inner()
+            layer_1_routing.to_bytes(),
+            wrap_layer(
+                &destination_routing_info,
+                route[2].address,
+                delay1,
+                &routing_keys[1],
+                &params
+            )
         );
+
+        match unwrap_layer(destination_routing_info, &routing_keys[2], &params) {
+            ParsedRawRoutingInformation::FinalHop(address, identifier, _, _) => {
+                assert_eq!(destination.address, address);
+                assert_eq!(destination.identifier, identifier);
+            }
+            _ => panic!("expected final hop"),
+        }
     }
     #[test]
     fn it_correctly_generates_sphinx_routing_information_for_route_of_max_length() {
@@ -389,8 +559,12 @@ mod converting_encapsulated_routing_info_to_bytes {
         let encapsulated_routing_info = encapsulated_routing_information_fixture();
         let encapsulated_routing_info_bytes = encapsulated_routing_info.to_bytes();
 
-        let recovered_routing_info =
-            EncapsulatedRoutingInformation::from_bytes(&encapsulated_routing_info_bytes).unwrap();
+        let recovered_routing_info: EncapsulatedRoutingInformation =
+            EncapsulatedRoutingInformation::from_bytes(
+                &encapsulated_routing_info_bytes,
+                &Default::default(),
+            )
+            .unwrap();
         assert_eq!(
             encapsulated_routing_info
                 .enc_routing_information
@@ -13,269 +13,580 @@
 // limitations under the License.
 
 use crate::constants::{
-    DELAY_LENGTH, DESTINATION_ADDRESS_LENGTH, HEADER_INTEGRITY_MAC_SIZE, NODE_ADDRESS_LENGTH,
-    NODE_META_INFO_SIZE, STREAM_CIPHER_OUTPUT_LENGTH, VERSION_LENGTH,
+    DESTINATION_ADDRESS_LENGTH, HEADER_INTEGRITY_MAC_SIZE, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH,
+    NODE_META_INFO_SIZE, TLV_SECTION_LENGTH_PREFIX_SIZE, VERSION_LENGTH,
 };
-use crate::crypto;
-use crate::crypto::STREAM_CIPHER_INIT_VECTOR;
+use crate::crypto::{CipherSuite, DefaultCipherSuite};
 use crate::header::delays::Delay;
-use crate::header::keys::{HeaderIntegrityMacKey, StreamCipherKey};
 use crate::header::mac::HeaderIntegrityMac;
 use crate::header::routing::{
     EncapsulatedRoutingInformation, RoutingFlag, Version, ENCRYPTED_ROUTING_INFO_SIZE, FINAL_HOP,
-    FORWARD_HOP, TRUNCATED_ROUTING_INFO_SIZE,
+    FINAL_HOP_WITH_TLV, FORWARD_HOP, FORWARD_HOP_WITH_TLV, INNER_ROUTE_HOP, TRAMPOLINE_HOP,
+    TRAMPOLINE_HOP_WITH_TLV,
 };
+use crate::header::tlv::TlvStream;
+use crate::params::SphinxParams;
 use crate::route::{DestinationAddressBytes, NodeAddressBytes, SURBIdentifier};
 use crate::utils;
-use crate::{Error, ErrorKind, Result};
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use byteorder::{BigEndian, ByteOrder};
 use std::fmt;
+use std::marker::PhantomData;
 
 pub const PADDED_ENCRYPTED_ROUTING_INFO_SIZE: usize =
     ENCRYPTED_ROUTING_INFO_SIZE + NODE_META_INFO_SIZE + HEADER_INTEGRITY_MAC_SIZE;
 
-// in paper beta
-pub(super) struct RoutingInformation {
+// in paper beta, without the truncated routing information of the next hop
+pub(super) struct RoutingInformation<'a> {
     flag: RoutingFlag,
     version: Version,
     // in paper nu
     node_address: NodeAddressBytes,
-    delay: Delay,
+    delay_bytes: Vec<u8>,
+    records: &'a TlvStream,
     // in paper gamma
     header_integrity_mac: HeaderIntegrityMac,
-    // in paper also beta (!)
-    next_routing_information: TruncatedRoutingInformation,
+    params: SphinxParams,
 }
 
-impl RoutingInformation {
+impl<'a> RoutingInformation<'a> {
     pub(super) fn new(
         node_address: NodeAddressBytes,
         delay: Delay,
-        next_encapsulated_routing_information: EncapsulatedRoutingInformation,
-    ) -> Self {
-        RoutingInformation {
-            flag: FORWARD_HOP,
-            version: Version::new(),
-            node_address,
-            delay,
-            header_integrity_mac: next_encapsulated_routing_information.integrity_mac,
-            next_routing_information: next_encapsulated_routing_information
-                .enc_routing_information
-                .truncate(),
+        records: &'a TlvStream,
+        next_header_integrity_mac: HeaderIntegrityMac,
+        params: &SphinxParams,
+    ) -> Result<Self> {
+        // only the prefix of the address of the configured length is put in the header
+        if node_address.as_bytes_ref()[params.node_address_length()..]
+            .iter()
+            .any(|&b| b != 0)
+        {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::InvalidNodeAddress,
+            ));
         }
-    }
 
-    fn concatenate_components(self) -> Vec<u8> {
-        std::iter::once(self.flag)
-            .chain(self.version.to_bytes().iter().cloned())
-            .chain(self.node_address.as_bytes_ref().iter().cloned())
-            .chain(self.delay.to_bytes().iter().cloned())
-            .chain(self.header_integrity_mac.into_inner().into_iter())
-            .chain(self.next_routing_information.iter().cloned())
-            .collect()
-    }
+        let flag = if records.is_empty() {
+            FORWARD_HOP
+        } else {
+            FORWARD_HOP_WITH_TLV
+        };
 
-    pub(super) fn encrypt(self, key: StreamCipherKey) -> EncryptedRoutingInformation {
-        let routing_info_components = self.concatenate_components();
-        assert_eq!(ENCRYPTED_ROUTING_INFO_SIZE, routing_info_components.len());
+        Ok(RoutingInformation {
+            flag,
+            version: params.version(),
+            node_address,
+            delay_bytes: delay.to_bytes_with_length(params.delay_length())?,
+            records,
+            header_integrity_mac: next_header_integrity_mac,
+            params: *params,
+        })
+    }
 
-        let pseudorandom_bytes = crypto::generate_pseudorandom_bytes(
-            &key,
-            &STREAM_CIPHER_INIT_VECTOR,
-            STREAM_CIPHER_OUTPUT_LENGTH,
-        );
+    /// Marks the hop as a hop of the inner route, the layout stays the same.
+    pub(super) fn into_inner_route(mut self) -> Self {
+        self.flag |= INNER_ROUTE_HOP;
+        self
+    }
 
-        let encrypted_routing_info_vec = utils::bytes::xor(
-            &routing_info_components,
-            &pseudorandom_bytes[..ENCRYPTED_ROUTING_INFO_SIZE],
-        );
+    fn hop_size(&self) -> usize {
+        self.params.filler_step_size() + self.records.section_len()
+    }
 
-        let mut encrypted_routing_info = [0u8; ENCRYPTED_ROUTING_INFO_SIZE];
-        encrypted_routing_info.copy_from_slice(&encrypted_routing_info_vec);
+    fn write_components(&self, output: &mut [u8]) {
+        let records_bytes = self.records.to_bytes();
+        let records_length_prefix = if self.records.is_empty() {
+            Vec::new()
+        } else {
+            (records_bytes.len() as u16).to_be_bytes().to_vec()
+        };
 
-        EncryptedRoutingInformation {
-            value: encrypted_routing_info,
+        let mut offset = 0;
+        for component in [
+            &[self.flag][..],
+            &self.version.to_bytes(),
+            &records_length_prefix,
+            &self.node_address.as_bytes_ref()[..self.params.node_address_length()],
+            &self.delay_bytes,
+            &records_bytes,
+            self.header_integrity_mac.as_bytes(),
+        ] {
+            output[offset..offset + component.len()].copy_from_slice(component);
+            offset += component.len();
         }
+        assert_eq!(output.len(), offset);
+    }
+
+    /// Prepends the routing information of the hop to the already encrypted routing information
+    /// of the next hop, which gets truncated to keep the size, and encrypts the result in place
+    /// with the keystream of the hop.
+    pub(super) fn encrypt_in_place(&self, routing_info: &mut [u8], keystream: &[u8]) {
+        let encrypted_routing_info_size = self.params.encrypted_routing_info_size();
+        assert_eq!(encrypted_routing_info_size, routing_info.len());
+        assert_eq!(encrypted_routing_info_size, keystream.len());
+
+        let hop_size = self.hop_size();
+        routing_info.copy_within(..encrypted_routing_info_size - hop_size, hop_size);
+        self.write_components(&mut routing_info[..hop_size]);
+        utils::bytes::xor_with(routing_info, keystream);
     }
 }
 
 // result of xoring beta with rho (output of PRNG)
 // the derivation is only required for the tests. please remove it in production
 #[derive(Clone)]
-pub struct EncryptedRoutingInformation {
-    value: [u8; ENCRYPTED_ROUTING_INFO_SIZE],
+pub struct EncryptedRoutingInformation<C: CipherSuite = DefaultCipherSuite> {
+    value: Vec<u8>,
+    _cipher_suite: PhantomData<C>,
 }
 
-impl fmt::Debug for EncryptedRoutingInformation {
+impl<C: CipherSuite> fmt::Debug for EncryptedRoutingInformation<C> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
             "EncryptedRoutingInformation: {{ value: {:?} }}",
-            self.value.to_vec()
+            self.value
         )
     }
 }
 
-impl EncryptedRoutingInformation {
-    pub fn from_bytes(bytes: [u8; ENCRYPTED_ROUTING_INFO_SIZE]) -> Self {
-        Self { value: bytes }
-    }
-
-    fn truncate(self) -> TruncatedRoutingInformation {
-        let mut truncated_routing_info = [0u8; TRUNCATED_ROUTING_INFO_SIZE];
-        truncated_routing_info.copy_from_slice(&self.value[..TRUNCATED_ROUTING_INFO_SIZE]);
-        truncated_routing_info
+impl<C: CipherSuite> EncryptedRoutingInformation<C> {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self {
+            value: bytes,
+            _cipher_suite: PhantomData,
+        }
     }
 
     pub fn get_value_ref(&self) -> &[u8] {
         self.value.as_ref()
     }
 
-    pub(super) fn encapsulate_with_mac(
-        self,
-        key: HeaderIntegrityMacKey,
-    ) -> EncapsulatedRoutingInformation {
-        let integrity_mac = HeaderIntegrityMac::compute(key, &self.value);
-        EncapsulatedRoutingInformation {
-            enc_routing_information: self,
-            integrity_mac,
-        }
-    }
-
-    fn add_zero_padding(self) -> PaddedEncryptedRoutingInformation {
-        let zero_bytes =
-            std::iter::repeat(0u8).take(NODE_META_INFO_SIZE + HEADER_INTEGRITY_MAC_SIZE);
-        let padded_enc_routing_info: Vec<u8> =
-            self.value.iter().cloned().chain(zero_bytes).collect();
+    fn add_zero_padding(self, params: &SphinxParams) -> PaddedEncryptedRoutingInformation<C> {
+        let zero_bytes = std::iter::repeat(0u8).take(params.filler_step_size());
+        let padded_enc_routing_info: Vec<u8> = self.value.into_iter().chain(zero_bytes).collect();
 
         assert_eq!(
-            PADDED_ENCRYPTED_ROUTING_INFO_SIZE,
+            params.stream_cipher_output_length(),
             padded_enc_routing_info.len()
         );
         PaddedEncryptedRoutingInformation {
             value: padded_enc_routing_info,
+            _cipher_suite: PhantomData,
         }
     }
 
     pub(crate) fn unwrap(
         self,
-        stream_cipher_key: StreamCipherKey,
-    ) -> Result<ParsedRawRoutingInformation> {
+        stream_cipher_key: C::StreamCipherKey,
+        params: &SphinxParams,
+    ) -> Result<ParsedRawRoutingInformation<C>> {
         // we have to add padding to the encrypted routing information before decrypting, otherwise we gonna lose information
-        self.add_zero_padding().decrypt(stream_cipher_key).parse()
+        let mut raw_routing_information = self.add_zero_padding(params).decrypt(stream_cipher_key);
+
+        // routing information of hops with tlv records is longer than a single step,
+        // so more bytes have to be shifted in for the next hop
+        let hop_size = raw_routing_information.hop_size(params)?;
+        if hop_size > params.filler_step_size() {
+            raw_routing_information.extend_padding(stream_cipher_key, hop_size, params);
+        }
+        raw_routing_information.parse(params)
     }
 }
 
-pub struct PaddedEncryptedRoutingInformation {
+pub struct PaddedEncryptedRoutingInformation<C: CipherSuite = DefaultCipherSuite> {
     value: Vec<u8>,
+    _cipher_suite: PhantomData<C>,
 }
 
-impl PaddedEncryptedRoutingInformation {
-    pub fn decrypt(self, key: StreamCipherKey) -> RawRoutingInformation {
-        let pseudorandom_bytes = crypto::generate_pseudorandom_bytes(
-            &key,
-            &crypto::STREAM_CIPHER_INIT_VECTOR,
-            STREAM_CIPHER_OUTPUT_LENGTH,
-        );
+impl<C: CipherSuite> PaddedEncryptedRoutingInformation<C> {
+    pub fn decrypt(self, key: C::StreamCipherKey) -> RawRoutingInformation<C> {
+        // padded routing information is exactly as long as the stream cipher output
+        let pseudorandom_bytes = C::generate_pseudorandom_bytes(&key, self.value.len());
 
         assert_eq!(self.value.len(), pseudorandom_bytes.len());
         RawRoutingInformation {
             value: utils::bytes::xor(&self.value, &pseudorandom_bytes),
+            _cipher_suite: PhantomData,
         }
     }
 }
 
-pub struct RawRoutingInformation {
+pub struct RawRoutingInformation<C: CipherSuite = DefaultCipherSuite> {
     value: Vec<u8>,
+    _cipher_suite: PhantomData<C>,
 }
 
-pub enum ParsedRawRoutingInformation {
-    ForwardHop(NodeAddressBytes, Delay, Box<EncapsulatedRoutingInformation>),
-    FinalHop(DestinationAddressBytes, SURBIdentifier),
+pub enum ParsedRawRoutingInformation<C: CipherSuite = DefaultCipherSuite> {
+    ForwardHop(
+        NodeAddressBytes,
+        Delay,
+        Box<EncapsulatedRoutingInformation<C>>,
+        TlvStream,
+        Version,
+    ),
+    FinalHop(DestinationAddressBytes, SURBIdentifier, TlvStream, Version),
+    TrampolineHop(NodeAddressBytes, TlvStream, Version),
 }
 
-impl RawRoutingInformation {
-    pub fn parse(self) -> Result<ParsedRawRoutingInformation> {
-        assert_eq!(
-            NODE_META_INFO_SIZE + HEADER_INTEGRITY_MAC_SIZE + ENCRYPTED_ROUTING_INFO_SIZE,
-            self.value.len()
-        );
+impl<C: CipherSuite> RawRoutingInformation<C> {
+    // length of the tlv records, it is put right after the flag and the version
+    fn tlv_section_length(&self) -> Result<usize> {
+        let i = 1 + VERSION_LENGTH;
+        self.value
+            .get(i..i + TLV_SECTION_LENGTH_PREFIX_SIZE)
+            .map(|length_bytes| BigEndian::read_u16(length_bytes) as usize)
+            .ok_or_else(|| {
+                Error::with_reason(
+                    ErrorKind::InvalidRouting,
+                    ErrorReason::TruncatedRoutingInformation,
+                )
+            })
+    }
+
+    /// Size of the routing information of this hop, i.e. by how much it has to be shifted
+    /// to obtain the routing information for the next hop.
+    fn hop_size(&self, params: &SphinxParams) -> Result<usize> {
+        if self.value[0] & !INNER_ROUTE_HOP != FORWARD_HOP_WITH_TLV {
+            return Ok(params.filler_step_size());
+        }
+
+        let hop_size = params.filler_step_size()
+            + TLV_SECTION_LENGTH_PREFIX_SIZE
+            + self.tlv_section_length()?;
+        if hop_size > params.encrypted_routing_info_size() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::RoutingInformationTooLong {
+                    required: hop_size,
+                    available: params.encrypted_routing_info_size(),
+                },
+            ));
+        }
+        Ok(hop_size)
+    }
 
+    // since the padding consists of zeroes, its decryption is just the pseudorandom bytes
+    fn extend_padding(&mut self, key: C::StreamCipherKey, hop_size: usize, params: &SphinxParams) {
+        let pseudorandom_bytes =
+            C::generate_pseudorandom_bytes(&key, params.encrypted_routing_info_size() + hop_size);
+        self.value
+            .extend_from_slice(&pseudorandom_bytes[self.value.len()..]);
+    }
+
+    pub fn parse(self, params: &SphinxParams) -> Result<ParsedRawRoutingInformation<C>> {
+        // the meaning of the flags might change between the major versions
+        let version = parse_version(&self.value, params)?;
         let flag = self.value[0];
-        match flag {
-            FORWARD_HOP => Ok(self.parse_as_forward_hop()),
-            FINAL_HOP => Ok(self.parse_as_final_hop()),
-            _ => Err(Error::new(
+        // hops of the inner route are laid out as any other hops
+        let hop_flag = flag & !INNER_ROUTE_HOP;
+        match hop_flag {
+            FORWARD_HOP | FORWARD_HOP_WITH_TLV => {
+                let hop_size = self.hop_size(params)?;
+                if self.value.len() < params.encrypted_routing_info_size() + hop_size {
+                    return Err(Error::with_reason(
+                        ErrorKind::InvalidRouting,
+                        ErrorReason::UnexpectedLength {
+                            expected: params.encrypted_routing_info_size() + hop_size,
+                            actual: self.value.len(),
+                        },
+                    ));
+                }
+                self.parse_as_forward_hop(params, version, hop_flag == FORWARD_HOP_WITH_TLV)
+            }
+            FINAL_HOP | FINAL_HOP_WITH_TLV => {
+                let (destination, identifier, records) =
+                    self.parse_as_final_hop(hop_flag == FINAL_HOP_WITH_TLV)?;
+                Ok(ParsedRawRoutingInformation::FinalHop(
+                    destination,
+                    identifier,
+                    records,
+                    version,
+                ))
+            }
+            // the inner route can't have a trampoline of its own
+            TRAMPOLINE_HOP | TRAMPOLINE_HOP_WITH_TLV if hop_flag == flag => {
+                let (next_hop_address, _, records) =
+                    self.parse_as_final_hop(flag == TRAMPOLINE_HOP_WITH_TLV)?;
+                Ok(ParsedRawRoutingInformation::TrampolineHop(
+                    NodeAddressBytes::from_bytes(next_hop_address.as_bytes()),
+                    records,
+                    version,
+                ))
+            }
+            _ => Err(Error::with_reason(
                 ErrorKind::InvalidRouting,
-                format!("tried to parse unknown routing flag: {}", flag),
+                ErrorReason::UnknownRoutingFlag { flag },
             )),
         }
     }
 
-    fn parse_as_forward_hop(self) -> ParsedRawRoutingInformation {
-        let mut i = 1;
-
-        let mut version: [u8; VERSION_LENGTH] = Default::default();
-        version.copy_from_slice(&self.value[i..i + VERSION_LENGTH]);
-        i += VERSION_LENGTH;
+    fn parse_as_forward_hop(
+        self,
+        params: &SphinxParams,
+        version: Version,
+        with_records: bool,
+    ) -> Result<ParsedRawRoutingInformation<C>> {
+        // the flag and the version have already been read
+        let mut i = 1 + VERSION_LENGTH;
+
+        let mut records_length = 0;
+        if with_records {
+            records_length = self.tlv_section_length()?;
+            i += TLV_SECTION_LENGTH_PREFIX_SIZE;
+        }
 
+        // shorter addresses are padded with zeroes
+        let node_address_length = params.node_address_length();
         let mut next_hop_address: [u8; NODE_ADDRESS_LENGTH] = Default::default();
-        next_hop_address.copy_from_slice(&self.value[i..i + NODE_ADDRESS_LENGTH]);
-        i += NODE_ADDRESS_LENGTH;
+        next_hop_address[..node_address_length]
+            .copy_from_slice(&self.value[i..i + node_address_length]);
+        i += node_address_length;
+
+        let delay = Delay::from_byte_slice(&self.value[i..i + params.delay_length()])?;
+        i += params.delay_length();
 
-        let mut delay_bytes: [u8; DELAY_LENGTH] = Default::default();
-        delay_bytes.copy_from_slice(&self.value[i..i + DELAY_LENGTH]);
-        i += DELAY_LENGTH;
+        let records = TlvStream::from_bytes(&self.value[i..i + records_length])?;
+        i += records_length;
 
-        // the next HEADER_INTEGRITY_MAC_SIZE bytes represent the integrity mac on the next hop
-        let mut next_hop_integrity_mac: [u8; HEADER_INTEGRITY_MAC_SIZE] = Default::default();
-        next_hop_integrity_mac.copy_from_slice(&self.value[i..i + HEADER_INTEGRITY_MAC_SIZE]);
-        i += HEADER_INTEGRITY_MAC_SIZE;
+        // the next header_integrity_mac_size bytes represent the integrity mac on the next hop
+        let mac_size = params.header_integrity_mac_size();
+        let next_hop_integrity_mac = HeaderIntegrityMac::from_bytes(&self.value[i..i + mac_size]);
+        i += mac_size;
 
-        // the next ENCRYPTED_ROUTING_INFO_SIZE bytes represent the routing information for the next hop
-        let mut next_hop_encrypted_routing_information = [0u8; ENCRYPTED_ROUTING_INFO_SIZE];
-        next_hop_encrypted_routing_information
-            .copy_from_slice(&self.value[i..i + ENCRYPTED_ROUTING_INFO_SIZE]);
+        // the next encrypted_routing_info_size bytes represent the routing information for the next hop
+        let next_hop_encrypted_routing_information =
+            self.value[i..i + params.encrypted_routing_info_size()].to_vec();
 
         let next_hop_encapsulated_routing_info = EncapsulatedRoutingInformation::encapsulate(
             EncryptedRoutingInformation::from_bytes(next_hop_encrypted_routing_information),
-            HeaderIntegrityMac::from_bytes(next_hop_integrity_mac),
+            next_hop_integrity_mac,
         );
 
-        ParsedRawRoutingInformation::ForwardHop(
+        Ok(ParsedRawRoutingInformation::ForwardHop(
             NodeAddressBytes::from_bytes(next_hop_address),
-            Delay::from_bytes(delay_bytes),
+            delay,
             Box::new(next_hop_encapsulated_routing_info),
-        )
+            records,
+            version,
+        ))
     }
 
     // TODO: this needs to be updated as a correct parse as final hop function!
-    fn parse_as_final_hop(self) -> ParsedRawRoutingInformation {
-        let mut i = 1;
-
-        let mut version: [u8; VERSION_LENGTH] = Default::default();
-        version.copy_from_slice(&self.value[i..i + VERSION_LENGTH]);
-        i += VERSION_LENGTH;
+    fn parse_as_final_hop(
+        self,
+        with_records: bool,
+    ) -> Result<(DestinationAddressBytes, SURBIdentifier, TlvStream)> {
+        // the flag and the version have already been read
+        let mut i = 1 + VERSION_LENGTH;
+
+        let mut records_length = 0;
+        if with_records {
+            records_length = self.tlv_section_length()?;
+            i += TLV_SECTION_LENGTH_PREFIX_SIZE;
+        }
 
         let mut destination_bytes: [u8; DESTINATION_ADDRESS_LENGTH] = Default::default();
         destination_bytes.copy_from_slice(&self.value[i..i + DESTINATION_ADDRESS_LENGTH]);
         i += DESTINATION_ADDRESS_LENGTH;
         let destination = DestinationAddressBytes::from_bytes(destination_bytes);
 
-        // the next HEADER_INTEGRITY_MAC_SIZE bytes represent the integrity mac on the next hop
-        let mut identifier: [u8; HEADER_INTEGRITY_MAC_SIZE] = Default::default();
-        identifier.copy_from_slice(&self.value[i..i + HEADER_INTEGRITY_MAC_SIZE]);
+        // the next IDENTIFIER_LENGTH bytes represent the SURB identifier
+        let mut identifier: SURBIdentifier = Default::default();
+        identifier.copy_from_slice(&self.value[i..i + IDENTIFIER_LENGTH]);
+        i += IDENTIFIER_LENGTH;
+
+        let records_bytes = self.value.get(i..i + records_length).ok_or_else(|| {
+            Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::TruncatedRoutingInformation,
+            )
+        })?;
+        let records = TlvStream::from_bytes(records_bytes)?;
+
+        Ok((destination, identifier, records))
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+pub(crate) enum ParsedRoutingInformationInPlace {
+    ForwardHop(NodeAddressBytes, Delay, TlvStream, Version),
+    FinalHop(DestinationAddressBytes, SURBIdentifier, TlvStream, Version),
+    TrampolineHop(NodeAddressBytes, TlvStream, Version),
+}
+
+fn routing_information_too_short_error() -> Error {
+    Error::with_reason(
+        ErrorKind::InvalidRouting,
+        ErrorReason::TruncatedRoutingInformation,
+    )
+}
 
-        ParsedRawRoutingInformation::FinalHop(destination, identifier)
+// the version is put right after the flag, and only the hops of the major version
+// of the params are laid out the way the params describe
+fn parse_version(routing_info: &[u8], params: &SphinxParams) -> Result<Version> {
+    let mut version_bytes: [u8; VERSION_LENGTH] = Default::default();
+    version_bytes.copy_from_slice(
+        routing_info
+            .get(1..1 + VERSION_LENGTH)
+            .ok_or_else(routing_information_too_short_error)?,
+    );
+    let version = Version::from_bytes(version_bytes);
+    if version.major() != params.version().major() {
+        return Err(Error::with_reason(
+            ErrorKind::InvalidRouting,
+            ErrorReason::UnsupportedVersion {
+                version: version.major(),
+            },
+        ));
     }
+    Ok(version)
+}
+
+/// Tells whether the hop belongs to the inner route, whose payload has been padded
+/// by the trampoline, by decrypting just the flag of its routing information.
+pub(crate) fn is_inner_route_hop<C: CipherSuite>(
+    enc_routing_info: &[u8],
+    stream_cipher_key: &C::StreamCipherKey,
+) -> bool {
+    let mut flag = [enc_routing_info[0]];
+    C::apply_keystream(stream_cipher_key, 0, &mut flag);
+    flag[0] & INNER_ROUTE_HOP != 0
 }
 
-// result of truncating encrypted beta before passing it to next 'layer'
-type TruncatedRoutingInformation = [u8; TRUNCATED_ROUTING_INFO_SIZE];
+/// Decrypts the routing information inside the buffer consisting of the integrity mac followed
+/// by the encrypted routing information. For forward hops, the buffer is then overwritten with
+/// the mac and the encrypted routing information of the next hop, exactly as they would be
+/// produced by `EncryptedRoutingInformation::unwrap`, but without allocating any of them.
+pub(crate) fn unwrap_in_place<C: CipherSuite>(
+    mac_and_routing_info: &mut [u8],
+    stream_cipher_key: &C::StreamCipherKey,
+    params: &SphinxParams,
+) -> Result<ParsedRoutingInformationInPlace> {
+    let mac_size = params.header_integrity_mac_size();
+    let routing_info_size = params.encrypted_routing_info_size();
+    assert_eq!(mac_size + routing_info_size, mac_and_routing_info.len());
+
+    let (mac, routing_info) = mac_and_routing_info.split_at_mut(mac_size);
+    C::apply_keystream(stream_cipher_key, 0, routing_info);
+
+    let version = parse_version(routing_info, params)?;
+    let flag = routing_info[0];
+    let hop_flag = flag & !INNER_ROUTE_HOP;
+    if flag != hop_flag && (hop_flag == TRAMPOLINE_HOP || hop_flag == TRAMPOLINE_HOP_WITH_TLV) {
+        return Err(Error::with_reason(
+            ErrorKind::InvalidRouting,
+            ErrorReason::UnknownRoutingFlag { flag },
+        ));
+    }
+    let mut i = 1 + VERSION_LENGTH;
+    let mut tlv_section_size = 0;
+    let mut records_length = 0;
+    if hop_flag == FORWARD_HOP_WITH_TLV
+        || hop_flag == FINAL_HOP_WITH_TLV
+        || hop_flag == TRAMPOLINE_HOP_WITH_TLV
+    {
+        records_length = routing_info
+            .get(i..i + TLV_SECTION_LENGTH_PREFIX_SIZE)
+            .map(|length_bytes| BigEndian::read_u16(length_bytes) as usize)
+            .ok_or_else(routing_information_too_short_error)?;
+        tlv_section_size = TLV_SECTION_LENGTH_PREFIX_SIZE + records_length;
+        i += TLV_SECTION_LENGTH_PREFIX_SIZE;
+    }
+
+    match hop_flag {
+        FORWARD_HOP | FORWARD_HOP_WITH_TLV => {
+            let hop_size = params.filler_step_size() + tlv_section_size;
+            if hop_size > routing_info_size {
+                return Err(Error::with_reason(
+                    ErrorKind::InvalidRouting,
+                    ErrorReason::RoutingInformationTooLong {
+                        required: hop_size,
+                        available: routing_info_size,
+                    },
+                ));
+            }
+
+            // shorter addresses are padded with zeroes
+            let node_address_length = params.node_address_length();
+            let mut next_hop_address: [u8; NODE_ADDRESS_LENGTH] = Default::default();
+            next_hop_address[..node_address_length]
+                .copy_from_slice(&routing_info[i..i + node_address_length]);
+            i += node_address_length;
+
+            let delay = Delay::from_byte_slice(&routing_info[i..i + params.delay_length()])?;
+            i += params.delay_length();
+
+            let records = TlvStream::from_bytes(&routing_info[i..i + records_length])?;
+            i += records_length;
+
+            // the mac on the next hop is followed by its routing information, which has to be
+            // shifted to the front and extended with the decrypted (zero) padding
+            mac.copy_from_slice(&routing_info[i..i + mac_size]);
+            routing_info.copy_within(hop_size.., 0);
+            let padding = &mut routing_info[routing_info_size - hop_size..];
+            padding.iter_mut().for_each(|b| *b = 0);
+            C::apply_keystream(stream_cipher_key, routing_info_size, padding);
+
+            Ok(ParsedRoutingInformationInPlace::ForwardHop(
+                NodeAddressBytes::from_bytes(next_hop_address),
+                delay,
+                records,
+                version,
+            ))
+        }
+        FINAL_HOP | FINAL_HOP_WITH_TLV | TRAMPOLINE_HOP | TRAMPOLINE_HOP_WITH_TLV => {
+            let mut destination_bytes: [u8; DESTINATION_ADDRESS_LENGTH] = Default::default();
+            destination_bytes.copy_from_slice(
+                routing_info
+                    .get(i..i + DESTINATION_ADDRESS_LENGTH)
+                    .ok_or_else(routing_information_too_short_error)?,
+            );
+            i += DESTINATION_ADDRESS_LENGTH;
+
+            let mut identifier: SURBIdentifier = Default::default();
+            identifier.copy_from_slice(
+                routing_info
+                    .get(i..i + IDENTIFIER_LENGTH)
+                    .ok_or_else(routing_information_too_short_error)?,
+            );
+            i += IDENTIFIER_LENGTH;
+
+            let records = TlvStream::from_bytes(
+                routing_info
+                    .get(i..i + records_length)
+                    .ok_or_else(routing_information_too_short_error)?,
+            )?;
+
+            if flag == TRAMPOLINE_HOP || flag == TRAMPOLINE_HOP_WITH_TLV {
+                return Ok(ParsedRoutingInformationInPlace::TrampolineHop(
+                    NodeAddressBytes::from_bytes(destination_bytes),
+                    records,
+                    version,
+                ));
+            }
+            Ok(ParsedRoutingInformationInPlace::FinalHop(
+                DestinationAddressBytes::from_bytes(destination_bytes),
+                identifier,
+                records,
+                version,
+            ))
+        }
+        _ => Err(Error::with_reason(
+            ErrorKind::InvalidRouting,
+            ErrorReason::UnknownRoutingFlag { flag },
+        )),
+    }
+}
 
 #[cfg(test)]
 mod preparing_header_layer {
     use super::*;
-    use crate::constants::HeaderIntegrityHmacAlgorithm;
+    use crate::constants::{HeaderIntegrityHmacAlgorithm, STREAM_CIPHER_OUTPUT_LENGTH};
+    use crate::crypto::{self, DefaultCipherSuite, STREAM_CIPHER_INIT_VECTOR};
+    use crate::header::routing::TRUNCATED_ROUTING_INFO_SIZE;
     use crate::{
         constants::HEADER_INTEGRITY_MAC_SIZE,
         test_utils::fixtures::{
@@ -302,7 +613,6 @@ mod preparing_header_layer {
             inner_layer_routing
                 .enc_routing_information
                 .value
-                .to_vec()
                 .iter()
                 .cloned()
                 .take(TRUNCATED_ROUTING_INFO_SIZE)
@@ -328,26 +638,41 @@ mod preparing_header_layer {
         let mut expected_routing_mac = expected_routing_mac.into_bytes().to_vec();
         expected_routing_mac.truncate(HEADER_INTEGRITY_MAC_SIZE);
 
-        let next_layer_routing = RoutingInformation::new(node_address, delay, inner_layer_routing)
-            .encrypt(previous_node_routing_keys.stream_cipher_key)
-            .encapsulate_with_mac(previous_node_routing_keys.header_integrity_hmac_key);
-
-        assert_eq!(
-            expected_encrypted_routing_info_vec,
-            next_layer_routing.enc_routing_information.value.to_vec()
-        );
-        assert_eq!(
-            expected_routing_mac,
-            next_layer_routing.integrity_mac.as_bytes().to_vec()
+        let params = SphinxParams::default();
+        let records = TlvStream::new();
+        let mut routing_info = inner_layer_routing.enc_routing_information.value;
+        let keystream = DefaultCipherSuite::generate_pseudorandom_bytes(
+            &previous_node_routing_keys.stream_cipher_key,
+            ENCRYPTED_ROUTING_INFO_SIZE,
         );
+        RoutingInformation::new(
+            node_address,
+            delay,
+            &records,
+            inner_layer_routing.integrity_mac,
+            &params,
+        )
+        .unwrap()
+        .encrypt_in_place(&mut routing_info, &keystream);
+        let routing_mac = HeaderIntegrityMac::compute::<DefaultCipherSuite>(
+            previous_node_routing_keys.header_integrity_hmac_key,
+            &routing_info,
+            params.header_integrity_mac_size(),
+        )
+        .unwrap();
+
+        assert_eq!(expected_encrypted_routing_info_vec, routing_info);
+        assert_eq!(expected_routing_mac, routing_mac.as_bytes().to_vec());
     }
 }
 
 #[cfg(test)]
 mod encrypting_routing_information {
     use super::*;
+    use crate::constants::STREAM_CIPHER_OUTPUT_LENGTH;
+    use crate::header::routing::TRUNCATED_ROUTING_INFO_SIZE;
     use crate::{
-        crypto::STREAM_CIPHER_KEY_SIZE,
+        crypto::{self, STREAM_CIPHER_INIT_VECTOR, STREAM_CIPHER_KEY_SIZE},
         test_utils::fixtures::{header_integrity_mac_fixture, node_address_fixture},
     };
 
@@ -358,7 +683,7 @@ mod encrypting_routing_information {
         let address = node_address_fixture();
         let delay = Delay::new_from_nanos(15);
         let mac = header_integrity_mac_fixture();
-        let next_routing = [8u8; TRUNCATED_ROUTING_INFO_SIZE];
+        let next_routing = vec![8u8; TRUNCATED_ROUTING_INFO_SIZE];
 
         let version = Version::new();
         let encryption_data = [
@@ -367,43 +692,66 @@ mod encrypting_routing_information {
             address.as_bytes().to_vec(),
             delay.to_bytes().to_vec(),
             mac.as_bytes().to_vec(),
-            next_routing.to_vec(),
+            next_routing.clone(),
         ]
         .concat();
 
+        let records = TlvStream::new();
         let routing_information = RoutingInformation {
             flag: FORWARD_HOP,
             version,
             node_address: address,
-            delay,
+            delay_bytes: delay.to_bytes().to_vec(),
+            records: &records,
             header_integrity_mac: mac,
-            next_routing_information: next_routing,
+            params: Default::default(),
         };
 
-        let encrypted_data = routing_information.encrypt(key);
-        let decryption_key_source = crypto::generate_pseudorandom_bytes(
+        let encryption_key_source = crypto::generate_pseudorandom_bytes(
             &key,
             &STREAM_CIPHER_INIT_VECTOR,
             STREAM_CIPHER_OUTPUT_LENGTH,
         );
-        let decryption_key = &decryption_key_source[..ENCRYPTED_ROUTING_INFO_SIZE];
-        let decrypted_data = utils::bytes::xor(&encrypted_data.value, decryption_key);
+        let encryption_key = &encryption_key_source[..ENCRYPTED_ROUTING_INFO_SIZE];
+        // the end of the next routing information gets truncated
+        let mut encrypted_data = [
+            next_routing,
+            vec![9u8; ENCRYPTED_ROUTING_INFO_SIZE - TRUNCATED_ROUTING_INFO_SIZE],
+        ]
+        .concat();
+        routing_information.encrypt_in_place(&mut encrypted_data, encryption_key);
+
+        let decrypted_data = utils::bytes::xor(&encrypted_data, encryption_key);
         assert_eq!(encryption_data, decrypted_data);
     }
 }
 
 #[cfg(test)]
 mod truncating_routing_information {
-    use crate::test_utils::fixtures::encrypted_routing_information_fixture;
+    use super::*;
+    use crate::header::filler::FILLER_STEP_SIZE_INCREASE;
+    use crate::test_utils::fixtures::{header_integrity_mac_fixture, node_address_fixture};
 
     #[test]
     fn it_does_not_change_prefixed_data() {
-        let encrypted_routing_info = encrypted_routing_information_fixture();
-        let routing_info_data_copy = encrypted_routing_info.value;
+        let routing_info_data: Vec<u8> =
+            (0..ENCRYPTED_ROUTING_INFO_SIZE).map(|i| i as u8).collect();
+        let mut routing_info = routing_info_data.clone();
+
+        let records = TlvStream::new();
+        RoutingInformation::new(
+            node_address_fixture(),
+            Delay::new_from_nanos(15),
+            &records,
+            header_integrity_mac_fixture(),
+            &Default::default(),
+        )
+        .unwrap()
+        .encrypt_in_place(&mut routing_info, &[0u8; ENCRYPTED_ROUTING_INFO_SIZE]);
 
-        let truncated_routing_info = encrypted_routing_info.truncate();
+        let truncated_routing_info = &routing_info[FILLER_STEP_SIZE_INCREASE..];
         for i in 0..truncated_routing_info.len() {
-            assert_eq!(truncated_routing_info[i], routing_info_data_copy[i]);
+            assert_eq!(truncated_routing_info[i], routing_info_data[i]);
         }
     }
 }
@@ -411,6 +759,7 @@ mod truncating_routing_information {
 #[cfg(test)]
 mod parse_decrypted_routing_information {
     use super::*;
+    use crate::header::tlv::TlvRecord;
     use crate::{
         header::routing::ENCRYPTED_ROUTING_INFO_SIZE,
         test_utils::fixtures::{header_integrity_mac_fixture, node_address_fixture},
@@ -435,14 +784,21 @@ mod parse_decrypted_routing_information {
         ]
         .concat();
 
-        let raw_routing_info = RawRoutingInformation { value: data };
+        let raw_routing_info: RawRoutingInformation = RawRoutingInformation {
+            value: data,
+            _cipher_suite: PhantomData,
+        };
 
-        match raw_routing_info.parse().unwrap() {
+        match raw_routing_info.parse(&Default::default()).unwrap() {
             ParsedRawRoutingInformation::ForwardHop(
                 next_address,
                 _delay,
                 encapsulated_routing_info,
+                records,
+                version,
             ) => {
+                assert!(records.is_empty());
+                assert_eq!(Version::new(), version);
                 assert_eq!(address_fixture, next_address);
                 assert_eq!(
                     integrity_mac.as_bytes().to_vec(),
@@ -456,7 +812,189 @@ mod parse_decrypted_routing_information {
                         .to_vec()
                 );
             }
-            ParsedRawRoutingInformation::FinalHop(_, _) => panic!(),
+            _ => panic!(),
         }
     }
+
+    #[test]
+    fn it_returns_tlv_records_attached_to_the_hop() {
+        let address_fixture = node_address_fixture();
+        let delay = Delay::new_from_nanos(10);
+        let integrity_mac = header_integrity_mac_fixture();
+        let next_routing_information = [1u8; ENCRYPTED_ROUTING_INFO_SIZE];
+        let records = TlvStream::from_records(vec![TlvRecord::new(42, vec![7u8; 10])]).unwrap();
+        let records_bytes = records.to_bytes();
+
+        let data = [
+            vec![FORWARD_HOP_WITH_TLV],
+            Version::new().to_bytes(),
+            (records_bytes.len() as u16).to_be_bytes().to_vec(),
+            address_fixture.as_bytes().to_vec(),
+            delay.to_bytes().to_vec(),
+            records_bytes,
+            integrity_mac.as_bytes().to_vec(),
+            next_routing_information.to_vec(),
+        ]
+        .concat();
+
+        let raw_routing_info: RawRoutingInformation = RawRoutingInformation {
+            value: data.clone(),
+            _cipher_suite: PhantomData,
+        };
+
+        match raw_routing_info.parse(&Default::default()).unwrap() {
+            ParsedRawRoutingInformation::ForwardHop(
+                next_address,
+                recovered_delay,
+                encapsulated_routing_info,
+                recovered_records,
+                _,
+            ) => {
+                assert_eq!(address_fixture, next_address);
+                assert_eq!(delay, recovered_delay);
+                assert_eq!(records, recovered_records);
+                assert_eq!(
+                    next_routing_information.to_vec(),
+                    encapsulated_routing_info
+                        .enc_routing_information
+                        .get_value_ref()
+                        .to_vec()
+                );
+            }
+            _ => panic!(),
+        }
+
+        // without the additional bytes shifted in, there is not enough data for the next hop
+        let truncated_raw_routing_info: RawRoutingInformation = RawRoutingInformation {
+            value: data[..data.len() - 1].to_vec(),
+            _cipher_suite: PhantomData,
+        };
+        assert!(truncated_raw_routing_info
+            .parse(&Default::default())
+            .is_err());
+    }
+
+    #[test]
+    fn it_returns_the_inner_first_hop_of_trampoline_hop() {
+        let address_fixture = node_address_fixture();
+        let data = [
+            vec![TRAMPOLINE_HOP],
+            Version::new().to_bytes(),
+            address_fixture.as_bytes().to_vec(),
+            vec![0u8; ENCRYPTED_ROUTING_INFO_SIZE],
+        ]
+        .concat();
+        let raw_routing_info: RawRoutingInformation = RawRoutingInformation {
+            value: data,
+            _cipher_suite: PhantomData,
+        };
+
+        match raw_routing_info.parse(&Default::default()).unwrap() {
+            ParsedRawRoutingInformation::TrampolineHop(next_address, records, _) => {
+                assert_eq!(address_fixture, next_address);
+                assert!(records.is_empty());
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn it_fails_with_unknown_routing_flag() {
+        let mut data = vec![0u8; ENCRYPTED_ROUTING_INFO_SIZE];
+        data[0] = 42;
+        let raw_routing_info: RawRoutingInformation = RawRoutingInformation {
+            value: data,
+            _cipher_suite: PhantomData,
+        };
+        match raw_routing_info.parse(&Default::default()) {
+            Err(err) => assert_eq!(
+                Some(&ErrorReason::UnknownRoutingFlag { flag: 42 }),
+                err.reason()
+            ),
+            Ok(_) => panic!("parsed routing information with unknown flag"),
+        }
+    }
+
+    #[test]
+    fn it_fails_with_trampoline_hop_of_the_inner_route() {
+        let mut data = vec![0u8; ENCRYPTED_ROUTING_INFO_SIZE];
+        data[0] = TRAMPOLINE_HOP | INNER_ROUTE_HOP;
+        let raw_routing_info: RawRoutingInformation = RawRoutingInformation {
+            value: data,
+            _cipher_suite: PhantomData,
+        };
+        match raw_routing_info.parse(&Default::default()) {
+            Err(err) => assert_eq!(
+                Some(&ErrorReason::UnknownRoutingFlag {
+                    flag: TRAMPOLINE_HOP | INNER_ROUTE_HOP
+                }),
+                err.reason()
+            ),
+            Ok(_) => panic!("parsed trampoline hop of the inner route"),
+        }
+    }
+
+    fn forward_hop_data(version: Version) -> Vec<u8> {
+        [
+            vec![FORWARD_HOP],
+            version.to_bytes(),
+            node_address_fixture().as_bytes().to_vec(),
+            Delay::new_from_nanos(10).to_bytes().to_vec(),
+            header_integrity_mac_fixture().as_bytes().to_vec(),
+            vec![1u8; ENCRYPTED_ROUTING_INFO_SIZE],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn it_returns_the_version_of_a_compatible_hop() {
+        let current = Version::new();
+        let newer = Version::from_parts(current.major(), current.minor() + 1, 7);
+        let raw_routing_info: RawRoutingInformation = RawRoutingInformation {
+            value: forward_hop_data(newer),
+            _cipher_suite: PhantomData,
+        };
+        match raw_routing_info.parse(&Default::default()).unwrap() {
+            ParsedRawRoutingInformation::ForwardHop(.., version) => assert_eq!(newer, version),
+            _ => panic!("parsed forward hop as the final one"),
+        }
+    }
+
+    #[test]
+    fn it_fails_with_unsupported_major_version() {
+        let other_major = Version::new().major() + 1;
+        let raw_routing_info: RawRoutingInformation = RawRoutingInformation {
+            value: forward_hop_data(Version::from_parts(other_major, 0, 0)),
+            _cipher_suite: PhantomData,
+        };
+        match raw_routing_info.parse(&Default::default()) {
+            Err(err) => {
+                assert_eq!(ErrorKind::InvalidRouting, err.kind());
+                assert_eq!(
+                    Some(&ErrorReason::UnsupportedVersion {
+                        version: other_major
+                    }),
+                    err.reason()
+                )
+            }
+            Ok(_) => panic!("parsed routing information of unsupported version"),
+        }
+    }
+
+    #[test]
+    fn it_parses_hops_of_the_version_of_the_params() {
+        let version = Version::from_parts(Version::new().major() + 1, 0, 0);
+        let params = SphinxParams::default().with_version(version);
+        let raw_routing_info: RawRoutingInformation = RawRoutingInformation {
+            value: forward_hop_data(version),
+            _cipher_suite: PhantomData,
+        };
+        assert!(raw_routing_info.parse(&params).is_ok());
+
+        let raw_routing_info: RawRoutingInformation = RawRoutingInformation {
+            value: forward_hop_data(Version::new()),
+            _cipher_suite: PhantomData,
+        };
+        assert!(raw_routing_info.parse(&params).is_err());
+    }
 }
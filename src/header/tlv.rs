@@ -0,0 +1,257 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::constants::TLV_SECTION_LENGTH_PREFIX_SIZE;
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+pub type TlvType = u64;
+
+/// Single type-length-value record attached to a hop of the route.
+/// The library does not interpret the records in any way, it's up to the mix operators
+/// to agree on their meaning.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlvRecord {
+    pub record_type: TlvType,
+    pub value: Vec<u8>,
+}
+
+impl TlvRecord {
+    pub fn new(record_type: TlvType, value: Vec<u8>) -> Self {
+        TlvRecord { record_type, value }
+    }
+
+    fn encoded_len(&self) -> usize {
+        bigsize_len(self.record_type) + bigsize_len(self.value.len() as u64) + self.value.len()
+    }
+}
+
+/// Records sent to a single hop, as in BOLT 4 of the Lightning Network they are sorted
+/// by strictly increasing type and both the types and lengths are encoded as `BigSize` integers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlvStream(Vec<TlvRecord>);
+
+impl TlvStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_records(mut records: Vec<TlvRecord>) -> Result<Self> {
+        records.sort_by_key(|record| record.record_type);
+        if records
+            .windows(2)
+            .any(|pair| pair[0].record_type == pair[1].record_type)
+        {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::MalformedRecords,
+            ));
+        }
+        Ok(TlvStream(records))
+    }
+
+    /// Inserts the record into the stream, replacing any existing record of the same type.
+    pub fn insert(&mut self, record: TlvRecord) {
+        match self
+            .0
+            .binary_search_by_key(&record.record_type, |r| r.record_type)
+        {
+            Ok(i) => self.0[i] = record,
+            Err(i) => self.0.insert(i, record),
+        }
+    }
+
+    pub fn get(&self, record_type: TlvType) -> Option<&[u8]> {
+        self.0
+            .binary_search_by_key(&record_type, |r| r.record_type)
+            .ok()
+            .map(|i| self.0[i].value.as_ref())
+    }
+
+    pub fn records(&self) -> &[TlvRecord] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        self.0.iter().map(TlvRecord::encoded_len).sum()
+    }
+
+    /// Number of bytes the records take in the routing information of a hop, i.e. including the
+    /// length prefix. Hops without any records use the legacy layout without the prefix.
+    pub(crate) fn section_len(&self) -> usize {
+        if self.is_empty() {
+            0
+        } else {
+            TLV_SECTION_LENGTH_PREFIX_SIZE + self.encoded_len()
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        for record in &self.0 {
+            write_bigsize(&mut bytes, record.record_type);
+            write_bigsize(&mut bytes, record.value.len() as u64);
+            bytes.extend_from_slice(&record.value);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut records: Vec<TlvRecord> = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let record_type = read_bigsize(bytes, &mut i)?;
+            if let Some(previous) = records.last() {
+                if previous.record_type >= record_type {
+                    return Err(Error::with_reason(
+                        ErrorKind::InvalidRouting,
+                        ErrorReason::MalformedRecords,
+                    ));
+                }
+            }
+
+            let length = read_bigsize(bytes, &mut i)?;
+            if length > (bytes.len() - i) as u64 {
+                return Err(Error::with_reason(
+                    ErrorKind::InvalidRouting,
+                    ErrorReason::MalformedRecords,
+                ));
+            }
+            let length = length as usize;
+            records.push(TlvRecord::new(record_type, bytes[i..i + length].to_vec()));
+            i += length;
+        }
+
+        Ok(TlvStream(records))
+    }
+}
+
+fn bigsize_len(value: u64) -> usize {
+    match value {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x10000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+fn write_bigsize(bytes: &mut Vec<u8>, value: u64) {
+    match bigsize_len(value) {
+        1 => bytes.push(value as u8),
+        3 => {
+            bytes.push(0xfd);
+            bytes.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        5 => {
+            bytes.push(0xfe);
+            bytes.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        _ => {
+            bytes.push(0xff);
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+fn read_bigsize(bytes: &[u8], i: &mut usize) -> Result<u64> {
+    let truncated = || Error::with_reason(ErrorKind::InvalidRouting, ErrorReason::MalformedRecords);
+
+    let prefix = *bytes.get(*i).ok_or_else(truncated)?;
+    let (value_len, minimum) = match prefix {
+        0xfd => (2, 0xfd),
+        0xfe => (4, 0x10000),
+        0xff => (8, 0x1_0000_0000),
+        _ => {
+            *i += 1;
+            return Ok(prefix as u64);
+        }
+    };
+
+    let value_bytes = bytes
+        .get(*i + 1..*i + 1 + value_len)
+        .ok_or_else(truncated)?;
+    let value = BigEndian::read_uint(value_bytes, value_len);
+    // every value has exactly one valid encoding
+    if value < minimum {
+        return Err(Error::with_reason(
+            ErrorKind::InvalidRouting,
+            ErrorReason::MalformedRecords,
+        ));
+    }
+    *i += 1 + value_len;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tlv_stream {
+    use super::*;
+
+    #[test]
+    fn it_is_possible_to_convert_it_to_and_from_bytes() {
+        let stream = TlvStream::from_records(vec![
+            TlvRecord::new(1_000_000, vec![]),
+            TlvRecord::new(2, vec![1, 2, 3]),
+            TlvRecord::new(254, vec![42u8; 300]),
+        ])
+        .unwrap();
+
+        let bytes = stream.to_bytes();
+        assert_eq!(stream.encoded_len(), bytes.len());
+        assert_eq!(stream, TlvStream::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn it_keeps_records_sorted_by_type() {
+        let mut stream = TlvStream::new();
+        stream.insert(TlvRecord::new(5, vec![5]));
+        stream.insert(TlvRecord::new(1, vec![1]));
+        stream.insert(TlvRecord::new(5, vec![6]));
+
+        let types: Vec<_> = stream.records().iter().map(|r| r.record_type).collect();
+        assert_eq!(vec![1, 5], types);
+        assert_eq!(Some([6u8].as_ref()), stream.get(5));
+        assert_eq!(None, stream.get(3));
+    }
+
+    #[test]
+    fn it_rejects_duplicate_types() {
+        assert!(TlvStream::from_records(vec![
+            TlvRecord::new(1, vec![]),
+            TlvRecord::new(1, vec![2])
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn it_uses_bigsize_encoding() {
+        let stream = TlvStream::from_records(vec![TlvRecord::new(0xfd, vec![7])]).unwrap();
+        assert_eq!(vec![0xfd, 0x00, 0xfd, 0x01, 7], stream.to_bytes());
+    }
+
+    #[test]
+    fn it_fails_to_recover_malformed_bytes() {
+        // unsorted types
+        assert!(TlvStream::from_bytes(&[2, 0, 1, 0]).is_err());
+        // value longer than the remaining data
+        assert!(TlvStream::from_bytes(&[1, 5, 0, 0]).is_err());
+        // non-canonical BigSize
+        assert!(TlvStream::from_bytes(&[0xfd, 0x00, 0x01, 0]).is_err());
+        // truncated BigSize
+        assert!(TlvStream::from_bytes(&[0xfe, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::{CipherSuite, DefaultCipherSuite, PrivateKey, SharedSecret};
+use crate::header::keys::RoutingKeys;
+use crate::header::SphinxHeader;
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use std::collections::{BTreeMap, HashMap};
+
+/// Bounded cache of routing keys derived by a mix node, keyed by the shared secret of the packet,
+/// which evicts the least recently used keys once it is full.
+///
+/// WARNING: shared secrets of honestly created packets are never repeated, so the cache is only
+/// useful in deployments that knowingly reuse initial secrets, e.g. in measurement loops.
+/// Otherwise it just keeps the key material of the processed packets in memory for longer.
+/// The keys are only valid for a single node key, so the cache has to be cleared whenever
+/// the node rotates its keys.
+pub struct RoutingKeysCache<C: CipherSuite = DefaultCipherSuite> {
+    capacity: usize,
+    entries: HashMap<SharedSecret<C::Group>, (RoutingKeys<C>, u64)>,
+    // shared secrets ordered by their last use
+    recently_used: BTreeMap<u64, SharedSecret<C::Group>>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl<C: CipherSuite> RoutingKeysCache<C> {
+    pub fn new(capacity: usize) -> Result<Self> {
+        if capacity == 0 {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidHeader,
+                ErrorReason::InvalidParameter {
+                    name: "routing keys cache capacity",
+                    value: capacity,
+                    minimum: 1,
+                    maximum: usize::MAX,
+                },
+            ));
+        }
+        Ok(RoutingKeysCache {
+            capacity,
+            entries: HashMap::with_capacity(capacity),
+            recently_used: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        })
+    }
+
+    /// Returns the cached routing keys for the shared secret or derives them
+    /// with `SphinxHeader::compute_routing_keys` and puts them in the cache.
+    pub fn get_or_compute(
+        &mut self,
+        shared_secret: &SharedSecret<C::Group>,
+        node_secret_key: &PrivateKey<C::Group>,
+    ) -> RoutingKeys<C> {
+        self.tick += 1;
+        if let Some((routing_keys, last_used)) = self.entries.get_mut(shared_secret) {
+            self.recently_used.remove(last_used);
+            self.recently_used.insert(self.tick, *shared_secret);
+            *last_used = self.tick;
+            self.hits += 1;
+            return routing_keys.clone();
+        }
+
+        self.misses += 1;
+        if self.entries.len() == self.capacity {
+            let least_recently_used = *self.recently_used.keys().next().unwrap();
+            let evicted = self.recently_used.remove(&least_recently_used).unwrap();
+            self.entries.remove(&evicted);
+        }
+        let routing_keys = SphinxHeader::compute_routing_keys(shared_secret, node_secret_key);
+        self.entries
+            .insert(*shared_secret, (routing_keys.clone(), self.tick));
+        self.recently_used.insert(self.tick, *shared_secret);
+        routing_keys
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of lookups that were answered from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of lookups that required deriving the keys.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Forgets all the keys, but keeps the counters.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recently_used.clear();
+    }
+}
+
+#[cfg(test)]
+mod routing_keys_cache {
+    use super::*;
+    use crate::crypto::PublicKey;
+
+    fn shared_secret() -> SharedSecret {
+        PublicKey::from(&PrivateKey::new())
+    }
+
+    #[test]
+    fn it_derives_the_same_keys_as_without_the_cache() {
+        let node_sk = PrivateKey::new();
+        let secret = shared_secret();
+        let mut cache = RoutingKeysCache::<DefaultCipherSuite>::new(2).unwrap();
+
+        let expected = SphinxHeader::<DefaultCipherSuite>::compute_routing_keys(&secret, &node_sk);
+        for _ in 0..2 {
+            let routing_keys = cache.get_or_compute(&secret, &node_sk);
+            assert_eq!(expected.replay_tag, routing_keys.replay_tag);
+            assert_eq!(expected.stream_cipher_key, routing_keys.stream_cipher_key);
+        }
+        assert_eq!(1, cache.hits());
+        assert_eq!(1, cache.misses());
+    }
+
+    #[test]
+    fn it_evicts_least_recently_used_keys() {
+        let node_sk = PrivateKey::new();
+        let secrets = [shared_secret(), shared_secret(), shared_secret()];
+        let mut cache = RoutingKeysCache::<DefaultCipherSuite>::new(2).unwrap();
+
+        cache.get_or_compute(&secrets[0], &node_sk);
+        cache.get_or_compute(&secrets[1], &node_sk);
+        // the first one becomes the most recently used, so the second one gets evicted
+        cache.get_or_compute(&secrets[0], &node_sk);
+        cache.get_or_compute(&secrets[2], &node_sk);
+        assert_eq!(2, cache.len());
+        assert_eq!(1, cache.hits());
+
+        cache.get_or_compute(&secrets[0], &node_sk);
+        assert_eq!(2, cache.hits());
+        cache.get_or_compute(&secrets[1], &node_sk);
+        assert_eq!(2, cache.hits());
+        assert_eq!(4, cache.misses());
+    }
+
+    #[test]
+    fn it_forgets_keys_when_cleared() {
+        let node_sk = PrivateKey::new();
+        let secret = shared_secret();
+        let mut cache = RoutingKeysCache::<DefaultCipherSuite>::new(2).unwrap();
+
+        cache.get_or_compute(&secret, &node_sk);
+        cache.clear();
+        assert!(cache.is_empty());
+        cache.get_or_compute(&secret, &node_sk);
+        assert_eq!(0, cache.hits());
+        assert_eq!(2, cache.misses());
+    }
+
+    #[test]
+    fn it_needs_room_for_at_least_a_single_entry() {
+        let err = RoutingKeysCache::<DefaultCipherSuite>::new(0)
+            .err()
+            .unwrap();
+        assert_eq!(
+            Some(&ErrorReason::InvalidParameter {
+                name: "routing keys cache capacity",
+                value: 0,
+                minimum: 1,
+                maximum: usize::MAX,
+            }),
+            err.reason()
+        );
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::{CipherSuite, DefaultCipherSuite, PrivateKey, PublicKey};
+use crate::header::keys::RoutingKeys;
+use crate::header::tlv::{TlvRecord, TlvType};
+use crate::header::SphinxHeader;
+use crate::packet::{ProcessedPacket, SphinxPacket};
+use crate::replay::{ExactReplayFilter, ReplayFilter};
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+pub type Epoch = u32;
+
+/// Type of the tlv record binding the routing information of a hop to the epoch of its key.
+pub const EPOCH_RECORD_TYPE: TlvType = 2;
+pub const EPOCH_RECORD_LENGTH: usize = 4;
+
+pub fn epoch_record(epoch: Epoch) -> TlvRecord {
+    TlvRecord::new(EPOCH_RECORD_TYPE, epoch.to_be_bytes().to_vec())
+}
+
+struct EpochKey<C: CipherSuite, F: ReplayFilter> {
+    epoch: Epoch,
+    private_key: PrivateKey<C::Group>,
+    // tags are derived from the shared secret, so they are only meaningful for a single key
+    replay_filter: F,
+}
+
+/// Keys of a mix node for the current epoch as well as for the previous one, so that
+/// packets created just before the rotation are still processed, and optionally the next one,
+/// which has already been published. Keys are erased as soon as they are dropped from the keyring.
+pub struct NodeKeyring<C: CipherSuite = DefaultCipherSuite, F: ReplayFilter = ExactReplayFilter> {
+    current: EpochKey<C, F>,
+    previous: Option<EpochKey<C, F>>,
+    next: Option<EpochKey<C, F>>,
+}
+
+impl<C: CipherSuite, F: ReplayFilter> NodeKeyring<C, F> {
+    pub fn new(epoch: Epoch, private_key: PrivateKey<C::Group>, replay_filter: F) -> Self {
+        NodeKeyring {
+            current: EpochKey {
+                epoch,
+                private_key,
+                replay_filter,
+            },
+            previous: None,
+            next: None,
+        }
+    }
+
+    pub fn current_epoch(&self) -> Epoch {
+        self.current.epoch
+    }
+
+    pub fn previous_epoch(&self) -> Option<Epoch> {
+        self.previous.as_ref().map(|key| key.epoch)
+    }
+
+    pub fn next_epoch(&self) -> Option<Epoch> {
+        self.next.as_ref().map(|key| key.epoch)
+    }
+
+    pub fn public_key(&self, epoch: Epoch) -> Option<PublicKey<C::Group>> {
+        self.keys()
+            .find(|key| key.epoch == epoch)
+            .map(|key| PublicKey::from(&key.private_key))
+    }
+
+    /// Sets the key of the upcoming epoch. Packets using it are accepted straight away
+    /// in case some sender starts using the key a bit earlier.
+    pub fn set_next(
+        &mut self,
+        epoch: Epoch,
+        private_key: PrivateKey<C::Group>,
+        replay_filter: F,
+    ) -> Result<()> {
+        if epoch <= self.current.epoch {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidKeyring,
+                ErrorReason::InvalidEpoch { epoch: Some(epoch) },
+            ));
+        }
+        self.next = Some(EpochKey {
+            epoch,
+            private_key,
+            replay_filter,
+        });
+        Ok(())
+    }
+
+    /// Makes the next key the current one. The current key is kept as the previous one
+    /// while the key of the previous epoch is erased.
+    pub fn rotate(&mut self) -> Result<()> {
+        let next = self.next.take().ok_or_else(|| {
+            Error::with_reason(
+                ErrorKind::InvalidKeyring,
+                ErrorReason::InvalidEpoch { epoch: None },
+            )
+        })?;
+        let current = std::mem::replace(&mut self.current, next);
+        self.previous = Some(current);
+        Ok(())
+    }
+
+    /// Erases the key of the previous epoch once no more packets are expected to use it.
+    pub fn expire_previous(&mut self) {
+        self.previous = None
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &EpochKey<C, F>> {
+        std::iter::once(&self.current)
+            .chain(self.next.as_ref())
+            .chain(self.previous.as_ref())
+    }
+
+    fn keys_mut(&mut self) -> impl Iterator<Item = &mut EpochKey<C, F>> {
+        std::iter::once(&mut self.current)
+            .chain(self.next.as_mut())
+            .chain(self.previous.as_mut())
+    }
+
+    /// Processes the packet with the key of whichever epoch the sender used and rejects
+    /// replays of packets that were already processed with that key.
+    ///
+    /// The header carries no cleartext hint of the epoch, so the key is found by trial:
+    /// the current key is tried first, then the next and the previous one, each at the cost
+    /// of a key exchange and a mac check. Packets of the previous epoch and junk packets
+    /// take up to three key exchanges, see `keyring processing` in the benchmarks.
+    /// The epoch the sender bound into the routing information does not select the key,
+    /// it is only checked against the epoch of the key that verified the mac.
+    pub fn process(&mut self, packet: SphinxPacket<C>) -> Result<ProcessedPacket<C>> {
+        let (epoch_key, routing_keys) = self
+            .keys_mut()
+            .find_map(|epoch_key| {
+                let routing_keys: RoutingKeys<C> = SphinxHeader::compute_routing_keys(
+                    &packet.header.shared_secret,
+                    &epoch_key.private_key,
+                );
+                if packet.header.has_valid_mac(&routing_keys) {
+                    Some((epoch_key, routing_keys))
+                } else {
+                    None
+                }
+            })
+            .ok_or_else(|| Error::with_reason(ErrorKind::InvalidHeader, ErrorReason::InvalidMac))?;
+
+        if epoch_key.replay_filter.contains(&routing_keys.replay_tag) {
+            return Err(Error::with_reason(ErrorKind::Replay, ErrorReason::Replayed));
+        }
+
+        let processed_packet =
+            packet.process_with_routing_keys(&epoch_key.private_key, &routing_keys)?;
+        if let Some(bound_epoch) = processed_packet.records().get(EPOCH_RECORD_TYPE) {
+            if bound_epoch.len() != EPOCH_RECORD_LENGTH
+                || BigEndian::read_u32(bound_epoch) != epoch_key.epoch
+            {
+                return Err(Error::with_reason(
+                    ErrorKind::InvalidRouting,
+                    ErrorReason::EpochMismatch {
+                        epoch: epoch_key.epoch,
+                    },
+                ));
+            }
+        }
+
+        epoch_key.replay_filter.insert(routing_keys.replay_tag);
+        Ok(processed_packet)
+    }
+}
+
+#[cfg(test)]
+mod node_keyring {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    fn keyring() -> NodeKeyring {
+        NodeKeyring::new(1, PrivateKey::new(), ExactReplayFilter::new())
+    }
+
+    #[test]
+    fn it_rotates_keys_of_subsequent_epochs() {
+        let mut keyring = keyring();
+        let next_key = PrivateKey::new();
+        let next_public_key = PublicKey::from(&next_key);
+        keyring
+            .set_next(2, next_key, ExactReplayFilter::new())
+            .unwrap();
+        assert_eq!(Some(next_public_key), keyring.public_key(2));
+
+        keyring.rotate().unwrap();
+        assert_eq!(2, keyring.current_epoch());
+        assert_eq!(Some(1), keyring.previous_epoch());
+        assert_eq!(None, keyring.next_epoch());
+        assert_eq!(Some(next_public_key), keyring.public_key(2));
+
+        keyring.expire_previous();
+        assert!(keyring.public_key(1).is_none());
+    }
+
+    #[test]
+    fn it_does_not_accept_next_key_for_past_epoch() {
+        let mut keyring = keyring();
+        assert!(keyring
+            .set_next(1, PrivateKey::new(), ExactReplayFilter::new())
+            .is_err());
+    }
+
+    #[test]
+    fn it_fails_to_rotate_without_next_key() {
+        let mut keyring = keyring();
+        assert_eq!(
+            ErrorKind::InvalidKeyring,
+            keyring.rotate().unwrap_err().kind()
+        );
+        assert_eq!(1, keyring.current_epoch());
+    }
+}
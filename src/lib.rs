@@ -12,11 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod blinded;
 pub mod constants;
 pub mod crypto;
+pub mod failure;
+pub mod fec;
+pub mod fragment;
 pub mod header;
+pub mod key_cache;
+pub mod keyring;
 pub mod packet;
+pub mod params;
 pub mod payload;
+pub mod recipient;
+pub mod replay;
 pub mod route;
 pub mod surb;
 mod utils;
@@ -25,6 +34,9 @@ mod utils;
 pub mod error;
 pub mod test_utils;
 
-pub use crate::error::{Error, ErrorKind, Result};
+pub use crate::error::{Error, ErrorKind, ErrorReason, Result};
+pub use crate::keyring::NodeKeyring;
 pub use crate::packet::{builder::SphinxPacketBuilder, ProcessedPacket, SphinxPacket};
-pub use crate::surb::{SURBMaterial, SURB};
+pub use crate::params::{HeaderLayouts, SphinxParams};
+pub use crate::surb::ack::{AckPacket, SURBAck};
+pub use crate::surb::{SURBDecryptionKeys, SURBMaterial, SURBSeed, SURB};
@@ -1,19 +1,41 @@
 use crate::{
-    crypto::EphemeralSecret,
-    header::{delays::Delay, SphinxHeader},
+    blinded::BlindedRoute,
+    constants::IDENTIFIER_LENGTH,
+    crypto::{CipherSuite, DefaultCipherSuite, EphemeralSecret, PublicKey, X25519},
+    failure::{FailureDecryptionKeys, FailureReport, FailureReturn, FailureSurbDeposit},
+    header::{
+        delays::Delay,
+        keys::PayloadKey,
+        pool::PreparedHeader,
+        routing::RouteKind,
+        tlv::{TlvRecord, TlvStream},
+        SphinxHeader,
+    },
+    keyring::{epoch_record, Epoch},
+    params::SphinxParams,
     payload::Payload,
-    route::{Destination, Node},
-    Result, SphinxPacket,
+    recipient,
+    route::{Destination, DestinationAddressBytes, Node, NodeAddressBytes, Route},
+    surb::ack::SURBAck,
+    Error, ErrorKind, ErrorReason, Result, SphinxPacket,
 };
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::marker::PhantomData;
 
 pub const DEFAULT_PAYLOAD_SIZE: usize = 1024;
 
-pub struct SphinxPacketBuilder<'a> {
+pub struct SphinxPacketBuilder<'a, C: CipherSuite = DefaultCipherSuite> {
     payload_size: usize,
-    initial_secret: Option<&'a EphemeralSecret>,
+    initial_secret: Option<&'a EphemeralSecret<C::Group>>,
+    params: SphinxParams,
+    hop_records: Vec<TlvStream>,
+    recipient_key: Option<PublicKey<X25519>>,
+    _cipher_suite: PhantomData<C>,
 }
 
-impl<'a> SphinxPacketBuilder<'a> {
+impl<'a, C: CipherSuite> SphinxPacketBuilder<'a, C> {
     pub fn new() -> Self {
         Self::default()
     }
@@ -23,35 +45,353 @@ impl<'a> SphinxPacketBuilder<'a> {
         self
     }
 
-    pub fn with_initial_secret(mut self, initial_secret: &'a EphemeralSecret) -> Self {
+    /// Size of payloads of the built packets, e.g. for splitting messages with
+    /// `fragment::split_message`.
+    pub fn payload_size(&self) -> usize {
+        self.payload_size
+    }
+
+    pub fn with_initial_secret(mut self, initial_secret: &'a EphemeralSecret<C::Group>) -> Self {
         self.initial_secret = Some(initial_secret);
         self
     }
 
+    pub fn with_params(mut self, params: SphinxParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Attaches tlv records to the routing information of the hop at the given position
+    /// of the route. Records for the last hop are delivered alongside the destination.
+    pub fn with_hop_records(mut self, hop_index: usize, records: TlvStream) -> Self {
+        if self.hop_records.len() <= hop_index {
+            self.hop_records.resize(hop_index + 1, TlvStream::new());
+        }
+        self.hop_records[hop_index] = records;
+        self
+    }
+
+    /// Binds the routing information of the hop to the key of the given epoch,
+    /// so that it would not be accepted by the node with a key of any other epoch.
+    /// Note that `with_hop_records` replaces all the records of the hop, including the epoch.
+    pub fn with_hop_epoch(mut self, hop_index: usize, epoch: Epoch) -> Self {
+        if self.hop_records.len() <= hop_index {
+            self.hop_records.resize(hop_index + 1, TlvStream::new());
+        }
+        self.hop_records[hop_index].insert(epoch_record(epoch));
+        self
+    }
+
+    /// Seals every message to the X25519 key of the recipient before it is put into the payload,
+    /// so that the final hop would only learn the destination and the ciphertext.
+    /// The recipient recovers the message with `recipient::open`. Note that sealing adds
+    /// `recipient::SEALED_MESSAGE_OVERHEAD` bytes to the message.
+    pub fn with_recipient_key(mut self, recipient_key: PublicKey<X25519>) -> Self {
+        self.recipient_key = Some(recipient_key);
+        self
+    }
+
+    fn seal<'m>(&self, message: &'m [u8]) -> Result<Cow<'m, [u8]>> {
+        match self.recipient_key.as_ref() {
+            Some(recipient_key) => Ok(Cow::Owned(recipient::seal(message, recipient_key)?)),
+            None => Ok(Cow::Borrowed(message)),
+        }
+    }
+
     pub fn build_packet<M: AsRef<[u8]>>(
         &self,
         message: M,
-        route: &[Node],
-        destination: &Destination,
-        delays: &[Delay],
-    ) -> Result<SphinxPacket> {
-        let (header, payload_keys) = match self.initial_secret.as_ref() {
-            Some(initial_secret) => SphinxHeader::new(initial_secret, route, delays, destination),
-            None => SphinxHeader::new(&EphemeralSecret::new(), route, delays, destination),
+        route: &Route<C::Group>,
+    ) -> Result<SphinxPacket<C>> {
+        let message = self.seal(message.as_ref())?;
+        let (packet, _) = self.build_packet_with_records(
+            &message,
+            route,
+            self.hop_records.clone(),
+            RouteKind::Regular,
+        )?;
+        Ok(packet)
+    }
+
+    fn build_packet_with_records(
+        &self,
+        message: &[u8],
+        route: &Route<C::Group>,
+        mut hop_records: Vec<TlvStream>,
+        kind: RouteKind,
+    ) -> Result<(SphinxPacket<C>, Vec<PayloadKey>)> {
+        if hop_records.len() > route.len() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::HopCountMismatch {
+                    hops: route.len(),
+                    provided: hop_records.len(),
+                },
+            ));
+        }
+        hop_records.resize(route.len(), TlvStream::new());
+
+        let fresh_secret;
+        let initial_secret = match self.initial_secret {
+            Some(initial_secret) => initial_secret,
+            None => {
+                fresh_secret = EphemeralSecret::new();
+                &fresh_secret
+            }
         };
+        let (header, payload_keys) =
+            SphinxHeader::new_of_kind(initial_secret, route, &hop_records, kind, &self.params)?;
 
+        // the inner packet has to fit in the payload of the packets of the builder
+        let payload_size = if kind == RouteKind::Inner {
+            self.inner_payload_size()?
+        } else {
+            self.payload_size
+        };
         // no need to check if plaintext has correct length as this check is already performed in payload encapsulation
+        let payload = Payload::encapsulate_message(message, &payload_keys, payload_size)?;
+        Ok((SphinxPacket { header, payload }, payload_keys))
+    }
+
+    /// Builds the packet using the header prepared ahead of time, e.g. taken from
+    /// `header::pool::HeaderPool`. The header already determines the route and the params,
+    /// so only the payload size of the builder is taken into account.
+    pub fn build_packet_with_header<M: AsRef<[u8]>>(
+        &self,
+        message: M,
+        prepared: PreparedHeader<C>,
+    ) -> Result<SphinxPacket<C>> {
+        let message = self.seal(message.as_ref())?;
         let payload =
-            Payload::encapsulate_message(message.as_ref(), &payload_keys, self.payload_size)?;
-        Ok(SphinxPacket { header, payload })
+            Payload::encapsulate_message(&message, &prepared.payload_keys, self.payload_size)?;
+        Ok(SphinxPacket {
+            header: prepared.header,
+            payload,
+        })
+    }
+
+    /// Builds a packet for each of the messages in parallel, spreading the key exchange
+    /// and the encryption across the available cores. Results are returned in the order of the messages.
+    /// Every packet gets a fresh initial secret, even if one was provided, as packets sharing it
+    /// would have identical headers, linking them together and failing the replay checks.
+    #[cfg(feature = "rayon")]
+    pub fn build_batch<M: AsRef<[u8]> + Sync>(
+        &self,
+        messages: &[M],
+        route: &Route<C::Group>,
+    ) -> Vec<Result<SphinxPacket<C>>> {
+        let builder = SphinxPacketBuilder {
+            payload_size: self.payload_size,
+            initial_secret: None,
+            params: self.params,
+            hop_records: self.hop_records.clone(),
+            recipient_key: self.recipient_key,
+            _cipher_suite: PhantomData,
+        };
+        messages
+            .par_iter()
+            .map(|message| builder.build_packet(message, route))
+            .collect()
+    }
+
+    /// Builds the packet with the ack put in front of the message, so that the final hop
+    /// could send it back using `surb::ack::extract_ack`. Note that the ack header is created
+    /// with its own params, which the final hop needs to know to extract it.
+    /// If the recipient key is set, only the message is sealed and the ack stays readable.
+    pub fn build_packet_with_ack<M: AsRef<[u8]>>(
+        &self,
+        message: M,
+        route: &Route<C::Group>,
+        ack: &SURBAck<C>,
+    ) -> Result<SphinxPacket<C>> {
+        let message = ack.prepend_to_message(&self.seal(message.as_ref())?);
+        let (packet, _) = self.build_packet_with_records(
+            &message,
+            route,
+            self.hop_records.clone(),
+            RouteKind::Regular,
+        )?;
+        Ok(packet)
+    }
+
+    /// Builds the packet going through the nodes chosen by the sender up to the introduction node
+    /// of the blinded route published by the recipient, and then through the rest of its hops.
+    /// The delays are given for all the hops, including the blinded ones. Records of the builder
+    /// are attached to the respective hops as usual, alongside the records of the blinded route.
+    pub fn build_packet_with_blinded_route<M: AsRef<[u8]>>(
+        &self,
+        message: M,
+        prefix: &[Node<C::Group>],
+        blinded_route: &BlindedRoute<C::Group>,
+        delays: Vec<Delay>,
+    ) -> Result<SphinxPacket<C>> {
+        let (route, blinded_records) =
+            blinded_route.route_with_prefix(prefix, delays, &self.params)?;
+
+        let mut hop_records = self.hop_records.clone();
+        if hop_records.len() < route.len() {
+            hop_records.resize(route.len(), TlvStream::new());
+        }
+        for (records, blinded_records) in hop_records.iter_mut().zip(blinded_records) {
+            for record in blinded_records.records() {
+                records.insert(record.clone());
+            }
+        }
+
+        let message = self.seal(message.as_ref())?;
+        let (packet, _) =
+            self.build_packet_with_records(&message, &route, hop_records, RouteKind::Regular)?;
+        Ok(packet)
+    }
+
+    /// Builds the packet with `FailureReturn` attached to the records of its first hop,
+    /// so that the failure of any of the hops could be reported back to the sender.
+    /// The returned keys are needed to read the report, see `failure::FailureRelay`.
+    pub fn build_packet_with_failure_return<M: AsRef<[u8]>>(
+        &self,
+        message: M,
+        route: &Route<C::Group>,
+        failure_return: &FailureReturn,
+    ) -> Result<(SphinxPacket<C>, FailureDecryptionKeys<C>)> {
+        let mut hop_records = self.hop_records.clone();
+        if hop_records.is_empty() {
+            hop_records.push(TlvStream::new());
+        }
+        hop_records[0].insert(failure_return.to_record());
+
+        let message = self.seal(message.as_ref())?;
+        let (packet, payload_keys) =
+            self.build_packet_with_records(&message, route, hop_records, RouteKind::Regular)?;
+        Ok((packet, FailureDecryptionKeys::new(&payload_keys)))
+    }
+
+    /// Builds the packet passing the failure report back to the previous hop, which recovers it
+    /// with `FailureReport::from_final_hop`. Apart from its records, the packet is built
+    /// like any other, so the report is not distinguishable from the rest of the traffic.
+    pub fn build_failure_report(
+        &self,
+        report: &FailureReport,
+        previous_hop: &Node<C::Group>,
+    ) -> Result<SphinxPacket<C>> {
+        self.build_packet_for_node(
+            report.message().as_bytes(),
+            previous_hop,
+            report.to_record(),
+        )
+    }
+
+    /// Builds the packet depositing the SURB with the first hop of the packets built with
+    /// `build_packet_with_failure_return`, which recovers it with `FailureSurbDeposit::from_final_hop`.
+    /// The SURB has to be built with the params of the builder.
+    pub fn build_failure_surb_deposit(
+        &self,
+        deposit: &FailureSurbDeposit<C>,
+        first_hop: &Node<C::Group>,
+    ) -> Result<SphinxPacket<C>> {
+        self.build_packet_for_node(&deposit.surb().to_bytes()?, first_hop, deposit.to_record())
+    }
+
+    // single hop packet for the node itself, with the record attached to its only hop
+    fn build_packet_for_node(
+        &self,
+        message: &[u8],
+        node: &Node<C::Group>,
+        record: TlvRecord,
+    ) -> Result<SphinxPacket<C>> {
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes(node.address.as_bytes()),
+            [0u8; IDENTIFIER_LENGTH],
+        );
+        let route = Route::new_with_params(
+            vec![node.clone()],
+            vec![Delay::new_from_nanos(0)],
+            destination,
+            &self.params,
+        )?;
+        let mut records = TlvStream::new();
+        records.insert(record);
+
+        let (packet, _) =
+            self.build_packet_with_records(message, &route, vec![records], RouteKind::Regular)?;
+        Ok(packet)
+    }
+
+    /// Size of payloads of the packets built with `build_inner_packet`. It is smaller than
+    /// the payload size of the builder by the size of the header and `PAYLOAD_OVERHEAD_SIZE`,
+    /// so that the whole inner packet fits in the payload of any other packet.
+    pub fn inner_payload_size(&self) -> Result<usize> {
+        super::inner_payload_size::<C>(self.payload_size, &self.params)
+    }
+
+    /// Builds the packet to be carried by the packet built with `build_packet_with_inner_packet`.
+    /// Its payload is of `inner_payload_size` and gets padded by the trampoline, which its hops
+    /// know of, so that along both of the routes the packet is of the same size as any other
+    /// packet of the builder.
+    pub fn build_inner_packet<M: AsRef<[u8]>>(
+        &self,
+        message: M,
+        route: &Route<C::Group>,
+    ) -> Result<SphinxPacket<C>> {
+        let message = self.seal(message.as_ref())?;
+        let (packet, _) = self.build_packet_with_records(
+            &message,
+            route,
+            self.hop_records.clone(),
+            RouteKind::Inner,
+        )?;
+        Ok(packet)
+    }
+
+    /// Builds the packet carrying the inner packet in its payload, so that the final of the nodes
+    /// would forward it to `inner_first_hop` instead of treating it as a message. This lets
+    /// the packet traverse two independently built routes, each up to the maximum path length.
+    /// The inner packet has to be built with `build_inner_packet` of a builder with the same
+    /// params and payload size, so that both the built and the forwarded packet are of the same
+    /// size as any other packet.
+    /// The inner packet is never sealed to the recipient key.
+    pub fn build_packet_with_inner_packet(
+        &self,
+        inner_packet: &SphinxPacket<C>,
+        inner_first_hop: NodeAddressBytes,
+        nodes: &[Node<C::Group>],
+        delays: Vec<Delay>,
+    ) -> Result<SphinxPacket<C>> {
+        let packet_size = self.params.header_size::<C::Group>() + self.inner_payload_size()?;
+        if inner_packet.len() != packet_size {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPacket,
+                ErrorReason::UnexpectedLength {
+                    expected: packet_size,
+                    actual: inner_packet.len(),
+                },
+            ));
+        }
+
+        // the final hop finds the address of the inner first hop in place of the destination
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes(inner_first_hop.as_bytes()),
+            [0u8; IDENTIFIER_LENGTH],
+        );
+        let route = Route::new_with_params(nodes.to_vec(), delays, destination, &self.params)?;
+        let (packet, _) = self.build_packet_with_records(
+            &inner_packet.to_bytes(),
+            &route,
+            self.hop_records.clone(),
+            RouteKind::Trampoline,
+        )?;
+        Ok(packet)
     }
 }
 
-impl<'a> Default for SphinxPacketBuilder<'a> {
+impl<'a, C: CipherSuite> Default for SphinxPacketBuilder<'a, C> {
     fn default() -> Self {
         SphinxPacketBuilder {
             payload_size: DEFAULT_PAYLOAD_SIZE,
             initial_secret: None,
+            params: Default::default(),
+            hop_records: Vec::new(),
+            recipient_key: None,
+            _cipher_suite: PhantomData,
         }
     }
 }
@@ -1,57 +1,136 @@
+use crate::blinded;
 use crate::crypto::keys::SharedSecret;
+use crate::crypto::{CipherSuite, DefaultCipherSuite, SphinxGroup};
 use crate::header::keys::RoutingKeys;
+use crate::header::routing::nodes::{self, ParsedRoutingInformationInPlace};
+use crate::key_cache::RoutingKeysCache;
 use crate::{
     crypto::PrivateKey,
-    header::{self, delays::Delay, HEADER_SIZE},
+    header::{self, delays::Delay, routing::Version, tlv::TlvStream},
+    params::{HeaderLayouts, SphinxParams},
     payload::{Payload, PAYLOAD_OVERHEAD_SIZE},
-    route::{Destination, DestinationAddressBytes, Node, NodeAddressBytes, SURBIdentifier},
-    Error, ErrorKind, Result,
+    replay::ReplayFilter,
+    route::{DestinationAddressBytes, NodeAddressBytes, Route, SURBIdentifier},
+    Error, ErrorKind, ErrorReason, Result,
 };
 use builder::SphinxPacketBuilder;
 use header::{ProcessedHeader, SphinxHeader};
+use rand::rngs::OsRng;
+use rand::RngCore;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 
 pub mod builder;
 
-pub enum ProcessedPacket {
+pub enum ProcessedPacket<C: CipherSuite = DefaultCipherSuite> {
     // TODO: considering fields sizes here (`SphinxPacket` and `Payload`), we perhaps
     // should follow clippy recommendation and box it
-    ForwardHop(Box<SphinxPacket>, NodeAddressBytes, Delay),
-    FinalHop(DestinationAddressBytes, SURBIdentifier, Payload),
+    ForwardHop(
+        Box<SphinxPacket<C>>,
+        NodeAddressBytes,
+        Delay,
+        TlvStream,
+        Version,
+    ),
+    FinalHop(
+        DestinationAddressBytes,
+        SURBIdentifier,
+        Payload<C>,
+        TlvStream,
+        Version,
+    ),
+    /// Inner packet recovered from the payload, to be forwarded to its first hop.
+    TrampolineHop(Box<SphinxPacket<C>>, NodeAddressBytes, TlvStream, Version),
 }
 
-impl ProcessedPacket {
-    pub fn shared_secret(&self) -> Option<SharedSecret> {
+impl<C: CipherSuite> ProcessedPacket<C> {
+    pub fn shared_secret(&self) -> Option<SharedSecret<C::Group>> {
         match self {
             ProcessedPacket::ForwardHop(packet, ..) => Some(packet.shared_secret()),
             ProcessedPacket::FinalHop(..) => None,
+            ProcessedPacket::TrampolineHop(packet, ..) => Some(packet.shared_secret()),
+        }
+    }
+
+    /// Tlv records the sender attached for this hop.
+    pub fn records(&self) -> &TlvStream {
+        match self {
+            ProcessedPacket::ForwardHop(.., records, _) => records,
+            ProcessedPacket::FinalHop(.., records, _) => records,
+            ProcessedPacket::TrampolineHop(.., records, _) => records,
+        }
+    }
+
+    /// Version of the header layout the sender wrote for this hop.
+    pub fn version(&self) -> Version {
+        match self {
+            ProcessedPacket::ForwardHop(.., version) => *version,
+            ProcessedPacket::FinalHop(.., version) => *version,
+            ProcessedPacket::TrampolineHop(.., version) => *version,
         }
     }
 }
 
-pub struct SphinxPacket {
-    pub header: header::SphinxHeader,
-    pub payload: Payload,
+/// Result of processing the packet inside the caller's buffer. The forward hop borrows
+/// the whole buffer, which now holds the packet for the next hop, while the final hop
+/// borrows just the decrypted payload. So does the trampoline hop, whose payload
+/// carries the inner packet.
+pub enum ProcessedPacketInPlace<'a> {
+    ForwardHop(&'a [u8], NodeAddressBytes, Delay, TlvStream, Version),
+    FinalHop(
+        DestinationAddressBytes,
+        SURBIdentifier,
+        &'a [u8],
+        TlvStream,
+        Version,
+    ),
+    TrampolineHop(NodeAddressBytes, &'a [u8], TlvStream, Version),
+}
+
+/// Size of the actual payload of the hops of the inner route, which is padded by the trampoline
+/// to the size of the payload of the outer packet, so that the inner packet would fit in it.
+pub(crate) fn inner_payload_size<C: CipherSuite>(
+    payload_size: usize,
+    params: &SphinxParams,
+) -> Result<usize> {
+    let overhead = params.header_size::<C::Group>() + PAYLOAD_OVERHEAD_SIZE;
+    payload_size
+        .checked_sub(overhead)
+        .filter(|&size| size > PAYLOAD_OVERHEAD_SIZE)
+        .ok_or_else(|| {
+            Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::PayloadTooSmall {
+                    size: payload_size,
+                    minimum: overhead + PAYLOAD_OVERHEAD_SIZE + 1,
+                },
+            )
+        })
+}
+
+#[derive(Clone)]
+pub struct SphinxPacket<C: CipherSuite = DefaultCipherSuite> {
+    pub header: header::SphinxHeader<C>,
+    pub payload: Payload<C>,
 }
 
-#[allow(clippy::len_without_is_empty)]
 impl SphinxPacket {
     // `new` works as before and does not care about changes made; it uses default values everywhere
-    pub fn new(
-        message: Vec<u8>,
-        route: &[Node],
-        destination: &Destination,
-        delays: &[Delay],
-    ) -> Result<SphinxPacket> {
-        SphinxPacketBuilder::default().build_packet(message, route, destination, delays)
+    // (including the cipher suite). Use `SphinxPacketBuilder` for anything else.
+    pub fn new(message: Vec<u8>, route: &Route) -> Result<SphinxPacket> {
+        SphinxPacketBuilder::default().build_packet(message, route)
     }
+}
 
-    pub fn shared_secret(&self) -> SharedSecret {
+#[allow(clippy::len_without_is_empty)]
+impl<C: CipherSuite> SphinxPacket<C> {
+    pub fn shared_secret(&self) -> SharedSecret<C::Group> {
         self.header.shared_secret
     }
 
     pub fn len(&self) -> usize {
         // header always has constant size
-        HEADER_SIZE + self.payload.len()
+        self.header.params().header_size::<C::Group>() + self.payload.len()
     }
 
     /// Processes the header with the provided derived keys.
@@ -59,45 +138,273 @@ impl SphinxPacket {
     /// and we could cache processing results.
     ///
     /// However, unless you know exactly what you are doing, you should NEVER use this method!
-    /// Prefer normal [process] instead.
+    /// Prefer normal [process] instead. Note that the hops of blinded routes can not be processed
+    /// this way, as they require the secret key of the node.
     pub fn process_with_derived_keys(
         self,
-        new_blinded_secret: &Option<SharedSecret>,
-        routing_keys: &RoutingKeys,
-    ) -> Result<ProcessedPacket> {
+        new_blinded_secret: &Option<SharedSecret<C::Group>>,
+        routing_keys: &RoutingKeys<C>,
+    ) -> Result<ProcessedPacket<C>> {
+        let params = *self.header.params();
+        let inner_route_hop = self.header.is_inner_route_hop(routing_keys);
         let unwrapped_header = self
             .header
             .process_with_derived_keys(new_blinded_secret, routing_keys)?;
-        match unwrapped_header {
-            ProcessedHeader::ForwardHop(new_header, next_hop_address, delay, payload_key) => {
-                let new_payload = self.payload.unwrap(&payload_key)?;
-                let new_packet = SphinxPacket {
-                    header: *new_header,
-                    payload: new_payload,
-                };
-                Ok(ProcessedPacket::ForwardHop(
-                    Box::new(new_packet),
+        Self::unwrap_payload(self.payload, unwrapped_header, inner_route_hop, &params)
+    }
+
+    pub fn process(self, node_secret_key: &PrivateKey<C::Group>) -> Result<ProcessedPacket<C>> {
+        let routing_keys =
+            SphinxHeader::<C>::compute_routing_keys(&self.header.shared_secret, node_secret_key);
+        self.process_with_routing_keys(node_secret_key, &routing_keys)
+    }
+
+    /// Processes the packet like [process], but fails with `ErrorKind::Replay` if a packet
+    /// with the same shared secret has already been processed.
+    pub fn process_with_replay_check<F: ReplayFilter>(
+        self,
+        node_secret_key: &PrivateKey<C::Group>,
+        replay_filter: &mut F,
+    ) -> Result<ProcessedPacket<C>> {
+        let routing_keys =
+            SphinxHeader::compute_routing_keys(&self.header.shared_secret, node_secret_key);
+        if replay_filter.contains(&routing_keys.replay_tag) {
+            return Err(Error::with_reason(ErrorKind::Replay, ErrorReason::Replayed));
+        }
+
+        let processed_packet = self.process_with_routing_keys(node_secret_key, &routing_keys)?;
+        // only remember packets that were processed correctly, otherwise anyone could get
+        // a valid packet dropped by sending a malformed copy of it first
+        replay_filter.insert(routing_keys.replay_tag);
+        Ok(processed_packet)
+    }
+
+    /// Processes the packet like [process], but takes the routing keys from the cache
+    /// if a packet with the same shared secret has already been processed.
+    ///
+    /// However, unless you knowingly reuse initial secrets, e.g. in measurement loops,
+    /// you should NEVER use this method! See `RoutingKeysCache` for details.
+    pub fn process_with_key_cache(
+        self,
+        node_secret_key: &PrivateKey<C::Group>,
+        cache: &mut RoutingKeysCache<C>,
+    ) -> Result<ProcessedPacket<C>> {
+        let routing_keys = cache.get_or_compute(&self.header.shared_secret, node_secret_key);
+        self.process_with_routing_keys(node_secret_key, &routing_keys)
+    }
+
+    /// Recovers and processes the packet using whichever of the header layouts it was created
+    /// with, so that a node could accept packets of more than one version at the same time.
+    /// The layout is recognised by its integrity mac, which only verifies for the right one,
+    /// and among the layouts of the same size by the version of the hop.
+    /// Packets of the next hop keep the layout they came with.
+    pub fn process_with_layouts(
+        bytes: &[u8],
+        node_secret_key: &PrivateKey<C::Group>,
+        layouts: &HeaderLayouts,
+    ) -> Result<ProcessedPacket<C>> {
+        // the shared secret comes first in all of the layouts
+        let element_size = <C::Group as SphinxGroup>::ELEMENT_SIZE;
+        let shared_secret_bytes = bytes.get(..element_size).ok_or_else(|| {
+            Error::with_reason(
+                ErrorKind::InvalidPacket,
+                ErrorReason::TooShort {
+                    minimum: element_size,
+                    actual: bytes.len(),
+                },
+            )
+        })?;
+        let shared_secret = SharedSecret::<C::Group>::try_from_byte_slice(shared_secret_bytes)?;
+        let routing_keys = SphinxHeader::<C>::compute_routing_keys(&shared_secret, node_secret_key);
+
+        let mut last_error = None;
+        for params in layouts.iter() {
+            let packet = match Self::from_bytes_with_params(bytes, params) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    last_error.get_or_insert(err);
+                    continue;
+                }
+            };
+            if !packet.header.has_valid_mac(&routing_keys) {
+                last_error = Some(Error::with_reason(
+                    ErrorKind::InvalidHeader,
+                    ErrorReason::InvalidMac,
+                ));
+                continue;
+            }
+            match packet.process_with_routing_keys(node_secret_key, &routing_keys) {
+                // the mac verifies for all the layouts of the same size,
+                // so only the version written by the sender tells them apart
+                Err(err)
+                    if matches!(err.reason(), Some(ErrorReason::UnsupportedVersion { .. })) =>
+                {
+                    last_error = Some(err);
+                }
+                processed => return processed,
+            }
+        }
+        // there is always at least a single layout
+        Err(last_error.unwrap())
+    }
+
+    /// Processes the packet serialized in the buffer without recovering it first,
+    /// assuming it was created using the default `SphinxParams`.
+    pub fn process_in_place<'a>(
+        buffer: &'a mut [u8],
+        node_secret_key: &PrivateKey<C::Group>,
+    ) -> Result<ProcessedPacketInPlace<'a>> {
+        Self::process_in_place_with_params(buffer, node_secret_key, &SphinxParams::default())
+    }
+
+    /// Processes the packet serialized in the buffer, which is then overwritten with the packet
+    /// for the next hop, or with the decrypted payload for the final hop. Unlike [process],
+    /// it does not allocate, unless the hop carries tlv records.
+    pub fn process_in_place_with_params<'a>(
+        buffer: &'a mut [u8],
+        node_secret_key: &PrivateKey<C::Group>,
+        params: &SphinxParams,
+    ) -> Result<ProcessedPacketInPlace<'a>> {
+        let header_size = params.header_size::<C::Group>();
+        if buffer.len() < header_size + PAYLOAD_OVERHEAD_SIZE {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPacket,
+                ErrorReason::TooShort {
+                    minimum: header_size + PAYLOAD_OVERHEAD_SIZE,
+                    actual: buffer.len(),
+                },
+            ));
+        }
+
+        let (header, payload) = buffer.split_at_mut(header_size);
+        let element_size = <C::Group as SphinxGroup>::ELEMENT_SIZE;
+        let shared_secret = SharedSecret::<C::Group>::try_from_byte_slice(&header[..element_size])?;
+        let routing_keys = SphinxHeader::<C>::compute_routing_keys(&shared_secret, node_secret_key);
+        let inner_route_hop = nodes::is_inner_route_hop::<C>(
+            &header[element_size + params.header_integrity_mac_size()..],
+            &routing_keys.stream_cipher_key,
+        );
+        let processed_header =
+            SphinxHeader::<C>::process_in_place(header, &shared_secret, &routing_keys, params)?;
+        // the padding of the payload of the inner route is replaced, as in `Payload::unwrap_padded`
+        let payload_size = if inner_route_hop {
+            inner_payload_size::<C>(payload.len(), params)?
+        } else {
+            payload.len()
+        };
+        C::decrypt_payload(&routing_keys.payload_key, &mut payload[..payload_size])?;
+        OsRng.fill_bytes(&mut payload[payload_size..]);
+
+        Ok(match processed_header {
+            ParsedRoutingInformationInPlace::ForwardHop(
+                next_hop_address,
+                delay,
+                records,
+                version,
+            ) => {
+                let next_hop_address =
+                    match blinded::unblind_forward_hop(&records, node_secret_key)? {
+                        Some((next_hop_address, blinding_factor)) => {
+                            let shared_secret_bytes =
+                                &mut header[..<C::Group as SphinxGroup>::ELEMENT_SIZE];
+                            let next_shared_secret =
+                                SharedSecret::<C::Group>::try_from_byte_slice(shared_secret_bytes)?
+                                    .blind(&blinding_factor);
+                            shared_secret_bytes
+                                .copy_from_slice(next_shared_secret.as_bytes().as_ref());
+                            next_hop_address
+                        }
+                        None => next_hop_address,
+                    };
+                ProcessedPacketInPlace::ForwardHop(
+                    buffer,
                     next_hop_address,
                     delay,
-                ))
+                    records,
+                    version,
+                )
             }
-            ProcessedHeader::FinalHop(destination, identifier, payload_key) => {
-                let new_payload = self.payload.unwrap(&payload_key)?;
-                Ok(ProcessedPacket::FinalHop(
+            ParsedRoutingInformationInPlace::FinalHop(
+                destination,
+                identifier,
+                records,
+                version,
+            ) => {
+                let (destination, identifier) =
+                    blinded::unblind_final_hop(&records, node_secret_key)?
+                        .unwrap_or((destination, identifier));
+                ProcessedPacketInPlace::FinalHop(
                     destination,
                     identifier,
-                    new_payload,
-                ))
+                    &buffer[header_size..header_size + payload_size],
+                    records,
+                    version,
+                )
             }
-        }
+            ParsedRoutingInformationInPlace::TrampolineHop(next_hop_address, records, version) => {
+                ProcessedPacketInPlace::TrampolineHop(
+                    next_hop_address,
+                    &buffer[header_size..],
+                    records,
+                    version,
+                )
+            }
+        })
     }
 
-    // TODO: we should have some list of 'seen shared_keys' for replay detection, but this should be handled by a mix node
-    pub fn process(self, node_secret_key: &PrivateKey) -> Result<ProcessedPacket> {
-        let unwrapped_header = self.header.process(node_secret_key)?;
+    /// Processes the packets in parallel, spreading the key exchange and the decryption
+    /// of all of them across the available cores. Results are returned in the order of the packets.
+    #[cfg(feature = "rayon")]
+    pub fn process_batch(
+        packets: &[SphinxPacket<C>],
+        node_secret_key: &PrivateKey<C::Group>,
+    ) -> Vec<Result<ProcessedPacket<C>>> {
+        packets
+            .par_iter()
+            // processing consumes the packet, so we have to work on its copy
+            .map(|packet| packet.clone().process(node_secret_key))
+            .collect()
+    }
+
+    pub(crate) fn process_with_routing_keys(
+        self,
+        node_secret_key: &PrivateKey<C::Group>,
+        routing_keys: &RoutingKeys<C>,
+    ) -> Result<ProcessedPacket<C>> {
+        let params = *self.header.params();
+        let inner_route_hop = self.header.is_inner_route_hop(routing_keys);
+        let unwrapped_header = self
+            .header
+            .process_with_routing_keys(routing_keys)?
+            .resolve_blinded_hop(node_secret_key)?;
+        Self::unwrap_payload(self.payload, unwrapped_header, inner_route_hop, &params)
+    }
+
+    fn unwrap_payload(
+        payload: Payload<C>,
+        unwrapped_header: ProcessedHeader<C>,
+        inner_route_hop: bool,
+        params: &SphinxParams,
+    ) -> Result<ProcessedPacket<C>> {
+        let payload_size = if inner_route_hop {
+            inner_payload_size::<C>(payload.len(), params)?
+        } else {
+            payload.len()
+        };
         match unwrapped_header {
-            ProcessedHeader::ForwardHop(new_header, next_hop_address, delay, payload_key) => {
-                let new_payload = self.payload.unwrap(&payload_key)?;
+            ProcessedHeader::ForwardHop(
+                new_header,
+                next_hop_address,
+                delay,
+                payload_key,
+                records,
+                version,
+            ) => {
+                let new_payload = if inner_route_hop {
+                    payload.unwrap_padded(&payload_key, payload_size)?
+                } else {
+                    payload.unwrap(&payload_key)?
+                };
                 let new_packet = SphinxPacket {
                     header: *new_header,
                     payload: new_payload,
@@ -106,19 +413,58 @@ impl SphinxPacket {
                     Box::new(new_packet),
                     next_hop_address,
                     delay,
+                    records,
+                    version,
                 ))
             }
-            ProcessedHeader::FinalHop(destination, identifier, payload_key) => {
-                let new_payload = self.payload.unwrap(&payload_key)?;
+            ProcessedHeader::FinalHop(destination, identifier, payload_key, records, version) => {
+                let new_payload = payload.truncate(payload_size).unwrap(&payload_key)?;
                 Ok(ProcessedPacket::FinalHop(
                     destination,
                     identifier,
                     new_payload,
+                    records,
+                    version,
+                ))
+            }
+            ProcessedHeader::TrampolineHop(next_hop_address, payload_key, records, version) => {
+                let inner_packet =
+                    Self::recover_inner_packet(payload.unwrap(&payload_key)?, params)?;
+                Ok(ProcessedPacket::TrampolineHop(
+                    Box::new(inner_packet),
+                    next_hop_address,
+                    records,
+                    version,
                 ))
             }
         }
     }
 
+    /// Recovers the inner packet from the decrypted payload of the trampoline hop, e.g. the one
+    /// borrowed by `ProcessedPacketInPlace::TrampolineHop`. The inner packet is laid out the same
+    /// way as the outer one, and its payload gets padded with random bytes to the size of the
+    /// outer payload, so that the forwarded packet is as large as any other packet.
+    pub fn recover_inner_packet(payload: Payload<C>, params: &SphinxParams) -> Result<Self> {
+        let payload_size = payload.len();
+        let inner_packet_bytes = payload.recover_plaintext()?;
+        let inner_packet = SphinxPacket::<C>::from_bytes_with_params(&inner_packet_bytes, params)?;
+
+        let inner_size = inner_payload_size::<C>(payload_size, params)?;
+        if inner_packet.payload.len() != inner_size {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPayload,
+                ErrorReason::UnexpectedLength {
+                    expected: inner_size,
+                    actual: inner_packet.payload.len(),
+                },
+            ));
+        }
+        Ok(SphinxPacket {
+            header: inner_packet.header,
+            payload: inner_packet.payload.pad(payload_size),
+        })
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         self.header
             .to_bytes()
@@ -128,23 +474,28 @@ impl SphinxPacket {
             .collect()
     }
 
+    /// Recovers the packet assuming it was created using the default `SphinxParams`.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_bytes_with_params(bytes, &SphinxParams::default())
+    }
+
+    pub fn from_bytes_with_params(bytes: &[u8], params: &SphinxParams) -> Result<Self> {
         // with payloads being dynamic in size, the only thing we can do
         // is to check if it at least is longer than the minimum length
-        if bytes.len() < HEADER_SIZE + PAYLOAD_OVERHEAD_SIZE {
-            return Err(Error::new(
+        let header_size = params.header_size::<C::Group>();
+        if bytes.len() < header_size + PAYLOAD_OVERHEAD_SIZE {
+            return Err(Error::with_reason(
                 ErrorKind::InvalidPacket,
-                format!(
-                    "tried to recover sphinx packet using {} bytes, expected at least {}",
-                    bytes.len(),
-                    HEADER_SIZE + PAYLOAD_OVERHEAD_SIZE
-                ),
+                ErrorReason::TooShort {
+                    minimum: header_size + PAYLOAD_OVERHEAD_SIZE,
+                    actual: bytes.len(),
+                },
             ));
         }
 
-        let header_bytes = &bytes[..HEADER_SIZE];
-        let payload_bytes = &bytes[HEADER_SIZE..];
-        let header = SphinxHeader::from_bytes(header_bytes)?;
+        let header_bytes = &bytes[..header_size];
+        let payload_bytes = &bytes[header_size..];
+        let header = SphinxHeader::from_bytes(header_bytes, params)?;
         let payload = Payload::from_bytes(payload_bytes)?;
 
         Ok(SphinxPacket { header, payload })
@@ -159,9 +510,187 @@ mod test_building_packet_from_bytes {
     fn from_bytes_returns_error_if_bytes_are_too_short() {
         let bytes = [0u8; 1];
         let expected = ErrorKind::InvalidPacket;
-        match SphinxPacket::from_bytes(&bytes) {
+        match SphinxPacket::<DefaultCipherSuite>::from_bytes(&bytes) {
             Err(err) => assert_eq!(expected, err.kind()),
             _ => panic!("Should have returned an error when packet bytes too short"),
         };
     }
 }
+
+#[cfg(test)]
+mod processing_packet_in_place {
+    use super::*;
+    use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::crypto::{ChaCha20Blake2bSuite, EphemeralSecret};
+    use crate::header::delays;
+    use crate::header::tlv::TlvRecord;
+    use crate::route::Node;
+    use crate::test_utils::fixtures::destination_fixture;
+    use std::time::Duration;
+
+    fn packet_and_keys<C: CipherSuite>() -> (SphinxPacket<C>, Vec<PrivateKey<C::Group>>) {
+        let mut node_sks = Vec::new();
+        let mut route = Vec::new();
+        for i in 0..4 {
+            let node_sk = PrivateKey::<C::Group>::new();
+            route.push(Node::new(
+                NodeAddressBytes::from_bytes([i; NODE_ADDRESS_LENGTH]),
+                (&node_sk).into(),
+            ));
+            node_sks.push(node_sk);
+        }
+        let delays = delays::generate_from_average_duration(4, Duration::from_millis(10));
+        let mut records = TlvStream::new();
+        records.insert(TlvRecord::new(5, vec![42u8; 20]));
+
+        let initial_secret = EphemeralSecret::new();
+        let route = Route::new(route.to_vec(), delays, destination_fixture()).unwrap();
+        let packet = SphinxPacketBuilder::<C>::new()
+            .with_initial_secret(&initial_secret)
+            .with_hop_records(1, records.clone())
+            .with_hop_records(3, records)
+            .build_packet(b"foomp", &route)
+            .unwrap();
+        (packet, node_sks)
+    }
+
+    fn it_produces_the_same_packets_as_regular_processing<C: CipherSuite>() {
+        let (mut packet, node_sks) = packet_and_keys::<C>();
+        let mut buffer = packet.to_bytes();
+
+        for node_sk in &node_sks {
+            let processed_in_place =
+                SphinxPacket::<C>::process_in_place(&mut buffer, node_sk).unwrap();
+            match (packet.process(node_sk).unwrap(), processed_in_place) {
+                (
+                    ProcessedPacket::ForwardHop(next_packet, address, delay, records, version),
+                    ProcessedPacketInPlace::ForwardHop(
+                        next_packet_bytes,
+                        address_in_place,
+                        delay_in_place,
+                        records_in_place,
+                        version_in_place,
+                    ),
+                ) => {
+                    assert_eq!(next_packet.to_bytes(), next_packet_bytes);
+                    assert_eq!(address, address_in_place);
+                    assert_eq!(delay, delay_in_place);
+                    assert_eq!(records, records_in_place);
+                    assert_eq!(version, version_in_place);
+                    packet = *next_packet;
+                }
+                (
+                    ProcessedPacket::FinalHop(destination, identifier, payload, records, version),
+                    ProcessedPacketInPlace::FinalHop(
+                        destination_in_place,
+                        identifier_in_place,
+                        payload_in_place,
+                        records_in_place,
+                        version_in_place,
+                    ),
+                ) => {
+                    assert_eq!(destination, destination_in_place);
+                    assert_eq!(identifier, identifier_in_place);
+                    assert_eq!(payload.as_bytes(), payload_in_place);
+                    assert_eq!(records, records_in_place);
+                    assert_eq!(version, version_in_place);
+                    return;
+                }
+                _ => panic!("processing in place resulted in a different kind of hop"),
+            }
+        }
+        panic!("packet has not reached its destination")
+    }
+
+    #[test]
+    fn it_produces_the_same_packets_as_regular_processing_with_default_suite() {
+        it_produces_the_same_packets_as_regular_processing::<DefaultCipherSuite>()
+    }
+
+    #[test]
+    fn it_produces_the_same_packets_as_regular_processing_with_chacha20_blake2b_suite() {
+        it_produces_the_same_packets_as_regular_processing::<ChaCha20Blake2bSuite>()
+    }
+
+    #[test]
+    fn it_fails_for_packet_with_tampered_header() {
+        let (packet, node_sks) = packet_and_keys::<DefaultCipherSuite>();
+        let mut buffer = packet.to_bytes();
+        buffer[40] ^= 1;
+        assert!(
+            SphinxPacket::<DefaultCipherSuite>::process_in_place(&mut buffer, &node_sks[0])
+                .is_err()
+        );
+        assert!(
+            SphinxPacket::<DefaultCipherSuite>::process_in_place(&mut [0u8; 10], &node_sks[0])
+                .is_err()
+        );
+    }
+}
+
+#[cfg(all(test, feature = "rayon"))]
+mod processing_packets_in_batch {
+    use super::*;
+    use crate::constants::NODE_ADDRESS_LENGTH;
+    use crate::crypto::EphemeralSecret;
+    use crate::header::delays;
+    use crate::route::Node;
+    use crate::test_utils::fixtures::destination_fixture;
+    use std::time::Duration;
+
+    #[test]
+    fn it_returns_results_in_order_of_the_packets() {
+        let node_sk = PrivateKey::new();
+        let route = [Node::new(
+            NodeAddressBytes::from_bytes([1u8; NODE_ADDRESS_LENGTH]),
+            (&node_sk).into(),
+        )];
+        let delays = delays::generate_from_average_duration(1, Duration::from_millis(10));
+        let messages: Vec<_> = (0..8u8).map(|i| vec![i; 10]).collect();
+
+        let route = Route::new(route.to_vec(), delays, destination_fixture()).unwrap();
+        let mut packets: Vec<SphinxPacket> = SphinxPacketBuilder::new()
+            .build_batch(&messages, &route)
+            .into_iter()
+            .collect::<Result<_>>()
+            .unwrap();
+        // make sure a failure of one packet does not affect the others
+        packets[3].header.routing_info.integrity_mac =
+            packets[4].header.routing_info.integrity_mac.clone();
+
+        let results = SphinxPacket::process_batch(&packets, &node_sk);
+        assert_eq!(messages.len(), results.len());
+        for (i, (message, result)) in messages.iter().zip(results).enumerate() {
+            match result {
+                Ok(ProcessedPacket::FinalHop(_, _, payload, _, _)) => {
+                    assert_eq!(message, &payload.recover_plaintext().unwrap())
+                }
+                Err(err) if i == 3 => assert_eq!(ErrorKind::InvalidHeader, err.kind()),
+                _ => panic!("unexpected processing result of packet {}", i),
+            }
+        }
+    }
+
+    #[test]
+    fn packets_of_the_batch_do_not_share_the_initial_secret() {
+        let node_sk = PrivateKey::new();
+        let route = [Node::new(
+            NodeAddressBytes::from_bytes([1u8; NODE_ADDRESS_LENGTH]),
+            (&node_sk).into(),
+        )];
+        let delays = delays::generate_from_average_duration(1, Duration::from_millis(10));
+        let route = Route::new(route.to_vec(), delays, destination_fixture()).unwrap();
+
+        let initial_secret = EphemeralSecret::new();
+        let packets: Vec<SphinxPacket> = SphinxPacketBuilder::new()
+            .with_initial_secret(&initial_secret)
+            .build_batch(&[vec![42u8; 10], vec![42u8; 10]], &route)
+            .into_iter()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_ne!(
+            packets[0].header.shared_secret.as_bytes(),
+            packets[1].header.shared_secret.as_bytes()
+        );
+    }
+}
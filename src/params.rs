@@ -0,0 +1,372 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::constants::{
+    DELAY_LENGTH, FINAL_NODE_META_INFO_LENGTH, FLAG_LENGTH, HEADER_INTEGRITY_MAC_SIZE,
+    MAX_PATH_LENGTH, NODE_ADDRESS_LENGTH, VERSION_LENGTH,
+};
+use crate::crypto::SphinxGroup;
+use crate::header::routing::Version;
+use crate::{Error, ErrorKind, ErrorReason, Result};
+
+/// Maximum size of the header integrity mac that all of the cipher suites are able to produce.
+pub const MAX_HEADER_INTEGRITY_MAC_SIZE: usize = 32;
+
+/// Runtime parameters determining the layout of the sphinx header.
+///
+/// All the packets exchanged within a single network must be created and processed using
+/// the same parameters. `SphinxParams::default()` corresponds to the values defined in `constants`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SphinxParams {
+    max_path_length: usize,
+    node_address_length: usize,
+    header_integrity_mac_size: usize,
+    delay_length: usize,
+    version: Version,
+}
+
+impl SphinxParams {
+    /// Note that node addresses are still represented by `NodeAddressBytes`, and hence the
+    /// `node_address_length` can't be greater than `NODE_ADDRESS_LENGTH`. Shorter addresses
+    /// are expected to occupy the beginning of `NodeAddressBytes` and be followed by zeroes.
+    /// Similarly, delays are always represented by `u64` values so `delay_length` can't be
+    /// greater than `DELAY_LENGTH`.
+    pub fn new(
+        max_path_length: usize,
+        node_address_length: usize,
+        header_integrity_mac_size: usize,
+        delay_length: usize,
+    ) -> Result<Self> {
+        let params = SphinxParams {
+            max_path_length,
+            node_address_length,
+            header_integrity_mac_size,
+            delay_length,
+            version: Version::new(),
+        };
+        params.validate()?;
+        Ok(params)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.max_path_length == 0 {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidHeader,
+                ErrorReason::InvalidParameter {
+                    name: "maximum path length",
+                    value: self.max_path_length,
+                    minimum: 1,
+                    maximum: usize::MAX,
+                },
+            ));
+        }
+        if self.node_address_length == 0 || self.node_address_length > NODE_ADDRESS_LENGTH {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidHeader,
+                ErrorReason::InvalidParameter {
+                    name: "node address length",
+                    value: self.node_address_length,
+                    minimum: 1,
+                    maximum: NODE_ADDRESS_LENGTH,
+                },
+            ));
+        }
+        if self.header_integrity_mac_size == 0
+            || self.header_integrity_mac_size > MAX_HEADER_INTEGRITY_MAC_SIZE
+        {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidHeader,
+                ErrorReason::InvalidParameter {
+                    name: "header integrity mac size",
+                    value: self.header_integrity_mac_size,
+                    minimum: 1,
+                    maximum: MAX_HEADER_INTEGRITY_MAC_SIZE,
+                },
+            ));
+        }
+        if self.delay_length == 0 || self.delay_length > DELAY_LENGTH {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidHeader,
+                ErrorReason::InvalidParameter {
+                    name: "delay length",
+                    value: self.delay_length,
+                    minimum: 1,
+                    maximum: DELAY_LENGTH,
+                },
+            ));
+        }
+        // the final hop of a route of maximum length has only a single 'step' available
+        if self.filler_step_size() < FINAL_NODE_META_INFO_LENGTH {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidHeader,
+                ErrorReason::RoutingInformationTooLong {
+                    required: FINAL_NODE_META_INFO_LENGTH,
+                    available: self.filler_step_size(),
+                },
+            ));
+        }
+        Ok(())
+    }
+
+    /// Tags the layout with the given version, which gets written into the routing information
+    /// of all the hops. Layouts that differ in any of the other parameters should use
+    /// a different major version, so that they could be processed by the same node.
+    pub fn with_version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// r in the Sphinx paper
+    pub fn max_path_length(&self) -> usize {
+        self.max_path_length
+    }
+
+    pub fn node_address_length(&self) -> usize {
+        self.node_address_length
+    }
+
+    pub fn header_integrity_mac_size(&self) -> usize {
+        self.header_integrity_mac_size
+    }
+
+    pub fn delay_length(&self) -> usize {
+        self.delay_length
+    }
+
+    // the meta info is all the information from sender to the node like: where to forward the packet, what is the delay etc
+    pub fn node_meta_info_size(&self) -> usize {
+        self.node_address_length + FLAG_LENGTH + self.delay_length + VERSION_LENGTH
+    }
+
+    /// Size of the routing information of a single hop, i.e. also the size by which the filler
+    /// grows with each hop.
+    pub fn filler_step_size(&self) -> usize {
+        self.node_meta_info_size() + self.header_integrity_mac_size
+    }
+
+    pub fn encrypted_routing_info_size(&self) -> usize {
+        self.filler_step_size() * self.max_path_length
+    }
+
+    pub fn truncated_routing_info_size(&self) -> usize {
+        self.encrypted_routing_info_size() - self.filler_step_size()
+    }
+
+    pub fn stream_cipher_output_length(&self) -> usize {
+        self.filler_step_size() * (self.max_path_length + 1)
+    }
+
+    /// Size of the serialized header, which also depends on the size of the group element.
+    pub fn header_size<G: SphinxGroup>(&self) -> usize {
+        G::ELEMENT_SIZE + self.header_integrity_mac_size + self.encrypted_routing_info_size()
+    }
+}
+
+impl Default for SphinxParams {
+    fn default() -> Self {
+        SphinxParams {
+            max_path_length: MAX_PATH_LENGTH,
+            node_address_length: NODE_ADDRESS_LENGTH,
+            header_integrity_mac_size: HEADER_INTEGRITY_MAC_SIZE,
+            delay_length: DELAY_LENGTH,
+            version: Version::new(),
+        }
+    }
+}
+
+/// Header layouts processed side by side by a single node, so that a new layout could be rolled
+/// out node by node. Each layout is identified by the major version of its params.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderLayouts {
+    layouts: Vec<SphinxParams>,
+}
+
+impl HeaderLayouts {
+    pub fn new(layouts: Vec<SphinxParams>) -> Result<Self> {
+        if layouts.is_empty() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidHeader,
+                ErrorReason::InvalidParameter {
+                    name: "number of header layouts",
+                    value: 0,
+                    minimum: 1,
+                    maximum: usize::MAX,
+                },
+            ));
+        }
+        for (i, params) in layouts.iter().enumerate() {
+            let major = params.version().major();
+            if layouts[..i]
+                .iter()
+                .any(|other| other.version().major() == major)
+            {
+                return Err(Error::with_reason(
+                    ErrorKind::InvalidHeader,
+                    ErrorReason::DuplicateVersion { version: major },
+                ));
+            }
+        }
+        Ok(HeaderLayouts { layouts })
+    }
+
+    /// Params of the layout with the given major version.
+    pub fn get(&self, major: u8) -> Option<&SphinxParams> {
+        self.layouts
+            .iter()
+            .find(|params| params.version().major() == major)
+    }
+
+    pub fn is_supported(&self, version: Version) -> bool {
+        self.get(version.major()).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SphinxParams> {
+        self.layouts.iter()
+    }
+}
+
+impl Default for HeaderLayouts {
+    fn default() -> Self {
+        HeaderLayouts {
+            layouts: vec![SphinxParams::default()],
+        }
+    }
+}
+
+#[cfg(test)]
+mod sphinx_params {
+    use super::*;
+    use crate::constants::STREAM_CIPHER_OUTPUT_LENGTH;
+    use crate::crypto::{DefaultGroup, X448};
+    use crate::header::filler::FILLER_STEP_SIZE_INCREASE;
+    use crate::header::routing::{ENCRYPTED_ROUTING_INFO_SIZE, TRUNCATED_ROUTING_INFO_SIZE};
+    use crate::header::HEADER_SIZE;
+
+    #[test]
+    fn default_values_are_consistent_with_defined_constants() {
+        let params = SphinxParams::default();
+        assert_eq!(FILLER_STEP_SIZE_INCREASE, params.filler_step_size());
+        assert_eq!(
+            ENCRYPTED_ROUTING_INFO_SIZE,
+            params.encrypted_routing_info_size()
+        );
+        assert_eq!(
+            TRUNCATED_ROUTING_INFO_SIZE,
+            params.truncated_routing_info_size()
+        );
+        assert_eq!(
+            STREAM_CIPHER_OUTPUT_LENGTH,
+            params.stream_cipher_output_length()
+        );
+        assert_eq!(HEADER_SIZE, params.header_size::<DefaultGroup>());
+    }
+
+    #[test]
+    fn default_values_pass_validation() {
+        let params = SphinxParams::default();
+        assert_eq!(
+            params,
+            SphinxParams::new(
+                params.max_path_length(),
+                params.node_address_length(),
+                params.header_integrity_mac_size(),
+                params.delay_length()
+            )
+            .unwrap()
+        )
+    }
+
+    #[test]
+    fn header_size_grows_with_maximum_path_length() {
+        let params = SphinxParams::new(7, NODE_ADDRESS_LENGTH, 16, 8).unwrap();
+        assert_eq!(
+            HEADER_SIZE + 2 * FILLER_STEP_SIZE_INCREASE,
+            params.header_size::<DefaultGroup>()
+        );
+        assert_eq!(
+            HEADER_SIZE + 2 * FILLER_STEP_SIZE_INCREASE + 24,
+            params.header_size::<X448>()
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_values() {
+        assert!(SphinxParams::new(0, NODE_ADDRESS_LENGTH, 16, 8).is_err());
+        assert!(SphinxParams::new(5, NODE_ADDRESS_LENGTH + 1, 16, 8).is_err());
+        assert!(SphinxParams::new(5, NODE_ADDRESS_LENGTH, 0, 8).is_err());
+        assert!(SphinxParams::new(5, NODE_ADDRESS_LENGTH, 33, 8).is_err());
+        assert!(SphinxParams::new(5, NODE_ADDRESS_LENGTH, 16, 9).is_err());
+        // not enough space for the final hop
+        assert!(SphinxParams::new(5, 8, 8, 2).is_err());
+    }
+
+    #[test]
+    fn versions_do_not_affect_the_layout_size() {
+        let params = SphinxParams::default();
+        let versioned = params.with_version(Version::from_parts(7, 0, 0));
+        assert_eq!(Version::from_parts(7, 0, 0), versioned.version());
+        assert_ne!(params, versioned);
+        assert_eq!(
+            params.header_size::<DefaultGroup>(),
+            versioned.header_size::<DefaultGroup>()
+        );
+    }
+}
+
+#[cfg(test)]
+mod header_layouts {
+    use super::*;
+
+    #[test]
+    fn it_finds_layouts_by_major_version() {
+        let current = SphinxParams::default();
+        let next = SphinxParams::new(7, NODE_ADDRESS_LENGTH, 16, 8)
+            .unwrap()
+            .with_version(Version::from_parts(current.version().major() + 1, 0, 0));
+        let layouts = HeaderLayouts::new(vec![current, next]).unwrap();
+
+        assert_eq!(Some(&current), layouts.get(current.version().major()));
+        assert_eq!(Some(&next), layouts.get(next.version().major()));
+        assert!(layouts.get(current.version().major() + 2).is_none());
+        assert!(layouts.is_supported(Version::from_parts(current.version().major(), 42, 42)));
+    }
+
+    #[test]
+    fn it_rejects_empty_layouts() {
+        let err = HeaderLayouts::new(Vec::new()).unwrap_err();
+        assert!(matches!(
+            err.reason(),
+            Some(ErrorReason::InvalidParameter { value: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn it_rejects_layouts_with_the_same_major_version() {
+        let current = SphinxParams::default();
+        let other = SphinxParams::new(7, NODE_ADDRESS_LENGTH, 16, 8)
+            .unwrap()
+            .with_version(Version::from_parts(current.version().major(), 1, 0));
+        let err = HeaderLayouts::new(vec![current, other]).unwrap_err();
+        assert_eq!(
+            Some(&ErrorReason::DuplicateVersion {
+                version: current.version().major()
+            }),
+            err.reason()
+        );
+    }
+}
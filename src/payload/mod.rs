@@ -13,12 +13,12 @@
 // limitations under the License.
 
 use crate::constants::SECURITY_PARAMETER;
+use crate::crypto::{CipherSuite, DefaultCipherSuite};
 use crate::header::keys::PayloadKey;
-use crate::{Error, ErrorKind, Result};
-use arrayref::array_ref;
-use blake2::VarBlake2b;
-use chacha::ChaCha; // we might want to swap this one with a different implementation
-use lioness::Lioness;
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::marker::PhantomData;
 
 // payload consists of security parameter long zero-padding, plaintext and '1' byte to indicate start of padding
 // (it can optionally be followed by zero-padding
@@ -26,13 +26,13 @@ pub const PAYLOAD_OVERHEAD_SIZE: usize = SECURITY_PARAMETER + 1;
 
 // TODO: question: is padding to some pre-defined length a sphinx-specific thing or rather
 // something for our particular use case?
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 #[cfg_attr(test, derive(PartialEq))]
-pub struct Payload(Vec<u8>);
+pub struct Payload<C: CipherSuite = DefaultCipherSuite>(Vec<u8>, PhantomData<C>);
 
 // is_empty does not make sense in this context, as you can't construct an empty Payload
 #[allow(clippy::len_without_is_empty)]
-impl Payload {
+impl<C: CipherSuite> Payload<C> {
     /// Tries to encapsulate provided plaintext message inside a sphinx payload adding
     /// as many layers of encryption as there are keys provided.
     /// Note that the encryption layers are going to be added in *reverse* order!
@@ -53,33 +53,38 @@ impl Payload {
     }
 
     /// Ensures the desires payload_size is longer than the required overhead as well
-    /// as the blocksize of lioness encryption.
+    /// as the blocksize of the payload encryption of the cipher suite.
     /// It also checks if the plaintext can fit in the specified payload [size].
     fn validate_parameters(payload_size: usize, plaintext_len: usize) -> Result<()> {
         if payload_size < PAYLOAD_OVERHEAD_SIZE {
-            return Err(Error::new(
+            return Err(Error::with_reason(
                 ErrorKind::InvalidPayload,
-                "specified payload_size is smaller than the required overhead",
+                ErrorReason::PayloadTooSmall {
+                    size: payload_size,
+                    minimum: PAYLOAD_OVERHEAD_SIZE,
+                },
             ));
         // lioness blocksize is 32 bytes (in this implementation)
         // Technically this check shouldn't happen if you're not going to add any
         // encryption layers to the payload, but then why are you even using sphinx?
-        } else if payload_size < lioness::DIGEST_RESULT_SIZE {
-            return Err(Error::new(
+        } else if payload_size < C::MIN_PAYLOAD_BLOCK_SIZE {
+            return Err(Error::with_reason(
                 ErrorKind::InvalidPayload,
-                "specified payload_size is smaller lioness block size",
+                ErrorReason::PayloadTooSmall {
+                    size: payload_size,
+                    minimum: C::MIN_PAYLOAD_BLOCK_SIZE,
+                },
             ));
         }
 
         let maximum_plaintext_length = payload_size - PAYLOAD_OVERHEAD_SIZE;
         if plaintext_len > maximum_plaintext_length {
-            return Err(Error::new(
+            return Err(Error::with_reason(
                 ErrorKind::InvalidPayload,
-                format!(
-                    "too long message provided. Message was: {}B long, maximum_plaintext_length is: {}B",
-                    plaintext_len,
-                    maximum_plaintext_length
-                ),
+                ErrorReason::PayloadTooLarge {
+                    size: plaintext_len,
+                    maximum: maximum_plaintext_length,
+                },
             ));
         }
         Ok(())
@@ -97,42 +102,47 @@ impl Payload {
             .take(payload_size) // take however much we need (remember, iterators are lazy)
             .collect();
 
-        Payload(final_payload)
+        Payload(final_payload, PhantomData)
     }
 
     /// Tries to add an additional layer of encryption onto self.
-    fn add_encryption_layer(mut self, payload_enc_key: &PayloadKey) -> Result<Self> {
-        let lioness_cipher = Lioness::<VarBlake2b, ChaCha>::new_raw(array_ref!(
-            payload_enc_key,
-            0,
-            lioness::RAW_KEY_SIZE
-        ));
-
-        if let Err(err) = lioness_cipher.encrypt(&mut self.0) {
-            return Err(Error::new(
-                ErrorKind::InvalidPayload,
-                format!("error while encrypting payload - {}", err),
-            ));
-        };
+    pub(crate) fn add_encryption_layer(mut self, payload_enc_key: &PayloadKey) -> Result<Self> {
+        C::encrypt_payload(payload_enc_key, &mut self.0)?;
         Ok(self)
     }
 
     /// Tries to remove single layer of encryption from self.
     pub fn unwrap(mut self, payload_key: &PayloadKey) -> Result<Self> {
-        let lioness_cipher = Lioness::<VarBlake2b, ChaCha>::new_raw(array_ref!(
-            payload_key,
-            0,
-            lioness::RAW_KEY_SIZE
-        ));
-        if let Err(err) = lioness_cipher.decrypt(&mut self.0) {
-            return Err(Error::new(
-                ErrorKind::InvalidPayload,
-                format!("error while unwrapping payload - {}", err),
-            ));
-        };
+        C::decrypt_payload(payload_key, &mut self.0)?;
         Ok(self)
     }
 
+    /// Removes a single layer of encryption from the payload of a hop of the inner route, of which
+    /// only the first `size` bytes are the actual payload. The rest is the padding added by the
+    /// trampoline, which is replaced with fresh random bytes, so that it could not be used to link
+    /// the packet across the hops.
+    pub(crate) fn unwrap_padded(mut self, payload_key: &PayloadKey, size: usize) -> Result<Self> {
+        C::decrypt_payload(payload_key, &mut self.0[..size])?;
+        OsRng.fill_bytes(&mut self.0[size..]);
+        Ok(self)
+    }
+
+    /// Extends the payload with random bytes up to the provided size.
+    pub(crate) fn pad(mut self, size: usize) -> Self {
+        let length = self.0.len();
+        if size > length {
+            self.0.resize(size, 0);
+            OsRng.fill_bytes(&mut self.0[length..]);
+        }
+        self
+    }
+
+    /// Drops the padding following the first `size` bytes of the payload.
+    pub(crate) fn truncate(mut self, size: usize) -> Self {
+        self.0.truncate(size);
+        self
+    }
+
     /// After calling [`unwrap`] required number of times with correct `payload_keys`, tries to parse
     /// the resultant payload content into original encapsulated plaintext message.
     pub fn recover_plaintext(self) -> Result<Vec<u8>> {
@@ -149,9 +159,9 @@ impl Payload {
         // and finally remove the first 1. The result should be our plaintext.
         // However, we must check if first SECURITY_PARAMETER bytes are actually 0
         if !self.0.iter().take(SECURITY_PARAMETER).all(|b| *b == 0) {
-            return Err(Error::new(
+            return Err(Error::with_reason(
                 ErrorKind::InvalidPayload,
-                "malformed payload - no leading zero padding present",
+                ErrorReason::MalformedPadding,
             ));
         }
 
@@ -170,9 +180,9 @@ impl Payload {
         }
 
         // our plaintext is invalid
-        Err(Error::new(
+        Err(Error::with_reason(
             ErrorKind::InvalidPayload,
-            "malformed payload - invalid trailing padding",
+            ErrorReason::MalformedPadding,
         ))
     }
 
@@ -203,13 +213,16 @@ impl Payload {
         // with payloads being dynamic in size, the only thing we can do
         // is to check if it at least is longer than the minimum length
         if bytes.len() < PAYLOAD_OVERHEAD_SIZE {
-            return Err(Error::new(
+            return Err(Error::with_reason(
                 ErrorKind::InvalidPayload,
-                "too short payload provided",
+                ErrorReason::TooShort {
+                    minimum: PAYLOAD_OVERHEAD_SIZE,
+                    actual: bytes.len(),
+                },
             ));
         }
 
-        Ok(Payload(bytes.to_vec()))
+        Ok(Payload(bytes.to_vec(), PhantomData))
     }
 }
 
@@ -221,7 +234,7 @@ mod building_payload_from_bytes {
     fn from_bytes_returns_error_if_bytes_are_too_short() {
         let bytes = [0u8; 1].to_vec();
         let expected = ErrorKind::InvalidPayload;
-        match Payload::from_bytes(&bytes) {
+        match Payload::<DefaultCipherSuite>::from_bytes(&bytes) {
             Err(err) => assert_eq!(expected, err.kind()),
             _ => panic!("Should have returned an error when packet bytes too short"),
         };
@@ -234,19 +247,30 @@ mod parameter_verification {
 
     #[test]
     fn it_returns_an_error_if_payload_size_is_smaller_than_the_overhead() {
-        assert!(Payload::validate_parameters(PAYLOAD_OVERHEAD_SIZE - 1, 16).is_err());
+        assert!(
+            Payload::<DefaultCipherSuite>::validate_parameters(PAYLOAD_OVERHEAD_SIZE - 1, 16)
+                .is_err()
+        );
     }
 
     #[test]
     fn it_returns_an_error_if_payload_size_is_smaller_than_the_lioness_blocklen() {
-        assert!(Payload::validate_parameters(lioness::DIGEST_RESULT_SIZE - 1, 16).is_err());
+        assert!(Payload::<DefaultCipherSuite>::validate_parameters(
+            lioness::DIGEST_RESULT_SIZE - 1,
+            16
+        )
+        .is_err());
     }
 
     #[test]
     fn it_returns_an_error_if_message_is_longer_than_maximum_allowed_length() {
         let payload_length = 100;
         let max_allowed_length = payload_length - PAYLOAD_OVERHEAD_SIZE;
-        assert!(Payload::validate_parameters(payload_length, max_allowed_length + 1).is_err());
+        assert!(Payload::<DefaultCipherSuite>::validate_parameters(
+            payload_length,
+            max_allowed_length + 1
+        )
+        .is_err());
     }
 }
 
@@ -260,7 +284,7 @@ mod final_payload_setting {
         for plaintext_length in plaintext_lengths {
             // ensure payload always has correct length, because we're not testing for that
             let payload_size = plaintext_length + lioness::DIGEST_RESULT_SIZE;
-            let final_payload =
+            let final_payload: Payload =
                 Payload::set_final_payload(&vec![42u8; plaintext_length], payload_size);
             let final_payload_inner = final_payload.into_inner();
 
@@ -298,7 +322,7 @@ mod test_encapsulating_payload {
     fn can_be_encapsulated_without_encryption() {
         let message = vec![1u8, 16];
         let payload_size = 512;
-        let unencrypted_message =
+        let unencrypted_message: Payload =
             Payload::encapsulate_message(&message, &[], payload_size).unwrap();
 
         // should be equivalent to just setting final payload
@@ -314,7 +338,12 @@ mod test_encapsulating_payload {
         let payload_size = 512;
         let payload_key_1 = [3u8; PAYLOAD_KEY_SIZE];
 
-        assert!(Payload::encapsulate_message(&message, &[payload_key_1], payload_size).is_ok())
+        assert!(Payload::<DefaultCipherSuite>::encapsulate_message(
+            &message,
+            &[payload_key_1],
+            payload_size
+        )
+        .is_ok())
     }
 
     #[test]
@@ -327,7 +356,7 @@ mod test_encapsulating_payload {
         let payload_key_4 = [6u8; PAYLOAD_KEY_SIZE];
         let payload_key_5 = [7u8; PAYLOAD_KEY_SIZE];
 
-        assert!(Payload::encapsulate_message(
+        assert!(Payload::<DefaultCipherSuite>::encapsulate_message(
             &message,
             &[
                 payload_key_1,
@@ -356,7 +385,7 @@ mod test_unwrapping_payload {
         let payload_key_3 = [5u8; PAYLOAD_KEY_SIZE];
         let payload_keys = [payload_key_1, payload_key_2, payload_key_3];
 
-        let encrypted_payload =
+        let encrypted_payload: Payload =
             Payload::encapsulate_message(&message, &payload_keys, DEFAULT_PAYLOAD_SIZE).unwrap();
 
         let unwrapped_payload = payload_keys
@@ -388,7 +417,7 @@ mod plaintext_recovery {
         let payload_key_3 = [5u8; PAYLOAD_KEY_SIZE];
         let payload_keys = [payload_key_1, payload_key_2, payload_key_3];
 
-        let encrypted_payload =
+        let encrypted_payload: Payload =
             Payload::encapsulate_message(&message, &payload_keys, DEFAULT_PAYLOAD_SIZE).unwrap();
 
         let unwrapped_payload = payload_keys
@@ -412,7 +441,7 @@ mod plaintext_recovery {
         let payload_key_3 = [5u8; PAYLOAD_KEY_SIZE];
         let payload_keys = [payload_key_1, payload_key_2, payload_key_3];
 
-        let encrypted_payload =
+        let encrypted_payload: Payload =
             Payload::encapsulate_message(&message, &payload_keys, DEFAULT_PAYLOAD_SIZE).unwrap();
 
         let unwrapped_payload = payload_keys
@@ -436,7 +465,7 @@ mod plaintext_recovery {
         let payload_key_3 = [5u8; PAYLOAD_KEY_SIZE];
         let payload_keys = [payload_key_1, payload_key_2, payload_key_3];
 
-        let encrypted_payload =
+        let encrypted_payload: Payload =
             Payload::encapsulate_message(&message, &payload_keys, DEFAULT_PAYLOAD_SIZE).unwrap();
 
         let unwrapped_payload = payload_keys
@@ -459,7 +488,7 @@ mod plaintext_recovery {
         let payload_key_3 = [5u8; PAYLOAD_KEY_SIZE];
         let payload_keys = [payload_key_1, payload_key_2, payload_key_3];
 
-        let encrypted_payload =
+        let encrypted_payload: Payload =
             Payload::encapsulate_message(&message, &payload_keys, DEFAULT_PAYLOAD_SIZE).unwrap();
 
         let unwrapped_payload = payload_keys
@@ -474,7 +503,7 @@ mod plaintext_recovery {
 
     #[test]
     fn it_fails_to_recover_plaintext_from_incorrectly_constructed_payload() {
-        let zero_payload = Payload(vec![0u8; DEFAULT_PAYLOAD_SIZE]);
+        let zero_payload: Payload = Payload(vec![0u8; DEFAULT_PAYLOAD_SIZE], PhantomData);
 
         assert!(zero_payload.recover_plaintext().is_err());
     }
@@ -0,0 +1,150 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::{PrivateKey, PublicKey, PUBLIC_KEY_SIZE};
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+pub const AEAD_TAG_SIZE: usize = 16;
+
+/// Number of bytes by which sealing grows the message, i.e. the ephemeral key of the sender
+/// followed by the authentication tag. It has to be accounted for in the payload size.
+pub const SEALED_MESSAGE_OVERHEAD: usize = PUBLIC_KEY_SIZE + AEAD_TAG_SIZE;
+
+const RECIPIENT_KDF_INFO: &[u8] = b"sphinx-recipient/";
+
+// every message is sealed under a fresh ephemeral key, so the derived key is never reused
+// and the nonce does not have to be unique
+const NONCE: [u8; 12] = [0u8; 12];
+
+fn message_key(
+    shared_secret: &PublicKey,
+    ephemeral_key: &PublicKey,
+    recipient_key: &PublicKey,
+) -> Result<Key> {
+    // low order points of the recipient would result in the shared secret known to everyone
+    if shared_secret.as_bytes().iter().all(|b| *b == 0) {
+        return Err(Error::with_reason(
+            ErrorKind::InvalidPayload,
+            ErrorReason::InvalidGroupElement,
+        ));
+    }
+
+    let info: Vec<u8> = RECIPIENT_KDF_INFO
+        .iter()
+        .chain(ephemeral_key.as_bytes())
+        .chain(recipient_key.as_bytes())
+        .cloned()
+        .collect();
+    let mut key = Key::default();
+    // this can only fail if we requested more than 255 * 32 bytes, which we never do
+    Hkdf::<Sha256>::new(None, shared_secret.as_bytes())
+        .expand(&info, &mut key)
+        .unwrap();
+    Ok(key)
+}
+
+/// Encrypts the message to the X25519 key of the recipient, so that the final hop of the packet
+/// would only see the ciphertext. The result is the ephemeral key of the sender followed
+/// by the ChaCha20-Poly1305 ciphertext.
+pub fn seal(message: &[u8], recipient_key: &PublicKey) -> Result<Vec<u8>> {
+    let ephemeral_secret = PrivateKey::new();
+    let ephemeral_key = PublicKey::from(&ephemeral_secret);
+    let shared_secret = ephemeral_secret.diffie_hellman(recipient_key);
+    let key = message_key(&shared_secret, &ephemeral_key, recipient_key)?;
+
+    let ciphertext = ChaCha20Poly1305::new(&key)
+        .encrypt(Nonce::from_slice(&NONCE), message)
+        .map_err(|_| {
+            Error::with_reason(ErrorKind::InvalidPayload, ErrorReason::PayloadCipherFailure)
+        })?;
+
+    let mut sealed = ephemeral_key.as_bytes().to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Recovers the message sealed to the recipient, e.g. the plaintext of the payload
+/// the final hop passed on.
+pub fn open(sealed: &[u8], recipient_secret: &PrivateKey) -> Result<Vec<u8>> {
+    if sealed.len() < SEALED_MESSAGE_OVERHEAD {
+        return Err(Error::with_reason(
+            ErrorKind::InvalidPayload,
+            ErrorReason::TooShort {
+                minimum: SEALED_MESSAGE_OVERHEAD,
+                actual: sealed.len(),
+            },
+        ));
+    }
+
+    let (ephemeral_key_bytes, ciphertext) = sealed.split_at(PUBLIC_KEY_SIZE);
+    let ephemeral_key = PublicKey::try_from_byte_slice(ephemeral_key_bytes)?;
+    let shared_secret = recipient_secret.diffie_hellman(&ephemeral_key);
+    let key = message_key(
+        &shared_secret,
+        &ephemeral_key,
+        &PublicKey::from(recipient_secret),
+    )?;
+
+    ChaCha20Poly1305::new(&key)
+        .decrypt(Nonce::from_slice(&NONCE), ciphertext)
+        .map_err(|_| Error::with_reason(ErrorKind::InvalidPayload, ErrorReason::InvalidMac))
+}
+
+#[cfg(test)]
+mod sealing_message_to_recipient {
+    use super::*;
+    use crate::crypto::keygen;
+
+    #[test]
+    fn recipient_recovers_the_sealed_message() {
+        let (recipient_sk, recipient_pk) = keygen();
+        let message = vec![42u8; 100];
+        let sealed = seal(&message, &recipient_pk).unwrap();
+        assert_eq!(message.len() + SEALED_MESSAGE_OVERHEAD, sealed.len());
+        assert_ne!(&message[..], &sealed[PUBLIC_KEY_SIZE..][..message.len()]);
+        assert_eq!(message, open(&sealed, &recipient_sk).unwrap());
+    }
+
+    #[test]
+    fn messages_are_sealed_under_different_keys() {
+        let (_, recipient_pk) = keygen();
+        assert_ne!(
+            seal(b"foomp", &recipient_pk).unwrap(),
+            seal(b"foomp", &recipient_pk).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_can_not_be_opened_with_a_different_key() {
+        let (_, recipient_pk) = keygen();
+        let (other_sk, _) = keygen();
+        let sealed = seal(b"foomp", &recipient_pk).unwrap();
+        let err = open(&sealed, &other_sk).unwrap_err();
+        assert_eq!(Some(&ErrorReason::InvalidMac), err.reason());
+    }
+
+    #[test]
+    fn tampered_message_is_rejected() {
+        let (recipient_sk, recipient_pk) = keygen();
+        let mut sealed = seal(b"foomp", &recipient_pk).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(open(&sealed, &recipient_sk).is_err());
+        assert!(open(&sealed[..SEALED_MESSAGE_OVERHEAD - 1], &recipient_sk).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::header::keys::ReplayTag;
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashSet;
+
+/// Set of replay tags of the packets a mix node has already processed.
+/// The tags are derived from the shared secret of the node and the packet, so the filter has to
+/// be cleared whenever the node rotates its keys.
+pub trait ReplayFilter {
+    fn contains(&self, tag: &ReplayTag) -> bool;
+
+    fn insert(&mut self, tag: ReplayTag);
+}
+
+/// Remembers every single tag, hence it never reports false positives, but its memory usage
+/// grows with each processed packet.
+#[derive(Debug, Default)]
+pub struct ExactReplayFilter {
+    seen: HashSet<ReplayTag>,
+}
+
+impl ExactReplayFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.seen.clear()
+    }
+}
+
+impl ReplayFilter for ExactReplayFilter {
+    fn contains(&self, tag: &ReplayTag) -> bool {
+        self.seen.contains(tag)
+    }
+
+    fn insert(&mut self, tag: ReplayTag) {
+        self.seen.insert(tag);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    items: usize,
+}
+
+impl BloomFilter {
+    fn new(num_bits: u64, num_hashes: u32) -> Self {
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+            items: 0,
+        }
+    }
+
+    /// Sizes the filter for the expected number of items, which have to be positive, and the
+    /// false positive rate, which has to be strictly between 0 and 1.
+    pub(crate) fn with_false_positive_rate(items: usize, false_positive_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(items as f64) * false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / items as f64) * ln2).round().max(1.0) as u32;
+        BloomFilter::new(num_bits, num_hashes)
+    }
+
+    // items are outputs of a KDF or a hash function, so rather than hashing them again,
+    // we just use their first 16 bytes for the double hashing
+    fn bit_indices(&self, tag: &[u8]) -> impl Iterator<Item = u64> {
+        let h1 = LittleEndian::read_u64(&tag[..8]);
+        let h2 = LittleEndian::read_u64(&tag[8..16]) | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    pub(crate) fn contains(&self, tag: &[u8]) -> bool {
+        self.bit_indices(tag)
+            .all(|i| self.bits[(i / 64) as usize] & (1 << (i % 64)) != 0)
+    }
+
+    pub(crate) fn insert(&mut self, tag: &[u8]) {
+        let indices: Vec<_> = self.bit_indices(tag).collect();
+        for i in indices {
+            self.bits[(i / 64) as usize] |= 1 << (i % 64);
+        }
+        self.items += 1;
+    }
+}
+
+/// Bloom filter with constant memory usage consisting of two generations.
+/// Once the current generation holds the expected number of tags, it becomes the previous one
+/// and the oldest tags are forgotten, so every tag is remembered for at least
+/// `items_per_generation` insertions. Since both generations are checked, the false positive
+/// rate is at most twice the one requested.
+#[derive(Debug, Clone)]
+pub struct BloomReplayFilter {
+    current: BloomFilter,
+    previous: BloomFilter,
+    items_per_generation: usize,
+}
+
+impl BloomReplayFilter {
+    pub fn new(items_per_generation: usize, false_positive_rate: f64) -> Result<Self> {
+        if items_per_generation == 0 {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPacket,
+                ErrorReason::InvalidParameter {
+                    name: "items per generation",
+                    value: items_per_generation,
+                    minimum: 1,
+                    maximum: usize::MAX,
+                },
+            ));
+        }
+        // also rejects NaN
+        if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidPacket,
+                ErrorReason::InvalidFalsePositiveRate,
+            ));
+        }
+
+        let current =
+            BloomFilter::with_false_positive_rate(items_per_generation, false_positive_rate);
+        Ok(BloomReplayFilter {
+            previous: current.clone(),
+            current,
+            items_per_generation,
+        })
+    }
+
+    /// Forgets the previous generation and starts a new one. It should be called explicitly
+    /// alongside key rotation of the node.
+    pub fn rotate(&mut self) {
+        let fresh = BloomFilter::new(self.current.num_bits, self.current.num_hashes);
+        self.previous = std::mem::replace(&mut self.current, fresh);
+    }
+}
+
+impl ReplayFilter for BloomReplayFilter {
+    fn contains(&self, tag: &ReplayTag) -> bool {
+        self.current.contains(tag) || self.previous.contains(tag)
+    }
+
+    fn insert(&mut self, tag: ReplayTag) {
+        if self.current.items >= self.items_per_generation {
+            self.rotate()
+        }
+        self.current.insert(&tag)
+    }
+}
+
+#[cfg(test)]
+mod exact_replay_filter {
+    use super::*;
+
+    #[test]
+    fn it_detects_previously_inserted_tags() {
+        let mut filter = ExactReplayFilter::new();
+        assert!(!filter.contains(&[1u8; 32]));
+        filter.insert([1u8; 32]);
+        assert!(filter.contains(&[1u8; 32]));
+        assert!(!filter.contains(&[2u8; 32]));
+        assert_eq!(1, filter.len());
+    }
+}
+
+#[cfg(test)]
+mod bloom_replay_filter {
+    use super::*;
+    use crate::constants::REPLAY_TAG_SIZE;
+    use crate::utils;
+    use rand::rngs::OsRng;
+
+    fn random_tag() -> ReplayTag {
+        let mut tag = ReplayTag::default();
+        tag.copy_from_slice(&utils::bytes::random(&mut OsRng, REPLAY_TAG_SIZE));
+        tag
+    }
+
+    #[test]
+    fn it_detects_tags_of_the_current_and_previous_generation() {
+        let mut filter = BloomReplayFilter::new(100, 0.001).unwrap();
+        let tags: Vec<_> = (0..150).map(|_| random_tag()).collect();
+        for tag in &tags {
+            filter.insert(*tag);
+        }
+        for tag in &tags {
+            assert!(filter.contains(tag));
+        }
+    }
+
+    #[test]
+    fn it_forgets_tags_after_two_rotations() {
+        let mut filter = BloomReplayFilter::new(100, 0.001).unwrap();
+        let tag = random_tag();
+        filter.insert(tag);
+        filter.rotate();
+        assert!(filter.contains(&tag));
+        filter.rotate();
+        assert!(!filter.contains(&tag));
+    }
+
+    #[test]
+    fn it_rarely_reports_false_positives() {
+        let mut filter = BloomReplayFilter::new(1000, 0.01).unwrap();
+        for _ in 0..1000 {
+            filter.insert(random_tag());
+        }
+        let false_positives = (0..10000)
+            .filter(|_| filter.contains(&random_tag()))
+            .count();
+        // expected rate is 1%, leave plenty of room not to make the test flaky
+        assert!(false_positives < 500);
+    }
+
+    #[test]
+    fn it_rejects_invalid_parameters() {
+        let err = BloomReplayFilter::new(0, 0.01).unwrap_err();
+        assert!(matches!(
+            err.reason(),
+            Some(ErrorReason::InvalidParameter { value: 0, .. })
+        ));
+
+        for rate in [0.0, 1.0, -0.5, f64::NAN] {
+            let err = BloomReplayFilter::new(100, rate).unwrap_err();
+            assert_eq!(Some(&ErrorReason::InvalidFalsePositiveRate), err.reason());
+        }
+    }
+}
@@ -13,8 +13,11 @@
 // limitations under the License.
 
 use crate::constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH};
-use crate::crypto;
-use crate::{Error, ErrorKind, Result};
+use crate::crypto::{self, DefaultGroup, SphinxGroup};
+use crate::header::delays::Delay;
+use crate::header::routing::destination::max_destination_length;
+use crate::params::SphinxParams;
+use crate::{Error, ErrorKind, ErrorReason, Result};
 use std::fmt::{self, Display, Formatter};
 
 // in paper delta
@@ -29,18 +32,21 @@ impl DestinationAddressBytes {
     pub fn try_from_base58_string<S: Into<String>>(val: S) -> Result<Self> {
         let decoded = match bs58::decode(val.into()).into_vec() {
             Ok(decoded) => decoded,
-            Err(e) => {
-                return Err(Error::new(
+            Err(_) => {
+                return Err(Error::with_reason(
                     ErrorKind::InvalidRouting,
-                    format!("failed to decode destination from b58 string: {:?}", e),
+                    ErrorReason::MalformedAddress,
                 ))
             }
         };
 
         if decoded.len() != DESTINATION_ADDRESS_LENGTH {
-            return Err(Error::new(
+            return Err(Error::with_reason(
                 ErrorKind::InvalidRouting,
-                "decoded destination address has invalid length",
+                ErrorReason::UnexpectedLength {
+                    expected: DESTINATION_ADDRESS_LENGTH,
+                    actual: decoded.len(),
+                },
             ));
         }
 
@@ -56,9 +62,12 @@ impl DestinationAddressBytes {
 
     pub fn try_from_byte_slice(b: &[u8]) -> Result<Self> {
         if b.len() != DESTINATION_ADDRESS_LENGTH {
-            return Err(Error::new(
+            return Err(Error::with_reason(
                 ErrorKind::InvalidRouting,
-                "received bytes got invalid length",
+                ErrorReason::UnexpectedLength {
+                    expected: DESTINATION_ADDRESS_LENGTH,
+                    actual: b.len(),
+                },
             ));
         }
 
@@ -97,18 +106,21 @@ impl NodeAddressBytes {
     pub fn try_from_base58_string<S: Into<String>>(val: S) -> Result<Self> {
         let decoded = match bs58::decode(val.into()).into_vec() {
             Ok(decoded) => decoded,
-            Err(e) => {
-                return Err(Error::new(
+            Err(_) => {
+                return Err(Error::with_reason(
                     ErrorKind::InvalidRouting,
-                    format!("failed to decode node address from b58 string: {:?}", e),
+                    ErrorReason::MalformedAddress,
                 ))
             }
         };
 
         if decoded.len() != NODE_ADDRESS_LENGTH {
-            return Err(Error::new(
+            return Err(Error::with_reason(
                 ErrorKind::InvalidRouting,
-                "decoded node address has invalid length",
+                ErrorReason::UnexpectedLength {
+                    expected: NODE_ADDRESS_LENGTH,
+                    actual: decoded.len(),
+                },
             ));
         }
 
@@ -120,9 +132,12 @@ impl NodeAddressBytes {
 
     pub fn try_from_byte_slice(b: &[u8]) -> Result<Self> {
         if b.len() != NODE_ADDRESS_LENGTH {
-            return Err(Error::new(
+            return Err(Error::with_reason(
                 ErrorKind::InvalidRouting,
-                "received bytes got invalid length",
+                ErrorReason::UnexpectedLength {
+                    expected: NODE_ADDRESS_LENGTH,
+                    actual: b.len(),
+                },
             ));
         }
 
@@ -174,17 +189,125 @@ impl Destination {
 }
 
 #[derive(Clone, Debug)]
-pub struct Node {
+pub struct Node<G: SphinxGroup = DefaultGroup> {
     pub address: NodeAddressBytes,
-    pub pub_key: crypto::PublicKey,
+    pub pub_key: crypto::PublicKey<G>,
 }
 
-impl Node {
-    pub fn new(address: NodeAddressBytes, pub_key: crypto::PublicKey) -> Self {
+impl<G: SphinxGroup> Node<G> {
+    pub fn new(address: NodeAddressBytes, pub_key: crypto::PublicKey<G>) -> Self {
         Self { address, pub_key }
     }
 }
 
+/// Nodes of the route together with their delays and the destination of the final hop,
+/// checked to be non-empty and to fit in the header with the given params.
+#[derive(Clone, Debug)]
+pub struct Route<G: SphinxGroup = DefaultGroup> {
+    nodes: Vec<Node<G>>,
+    delays: Vec<Delay>,
+    destination: Destination,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl<G: SphinxGroup> Route<G> {
+    /// Creates the route fitting in the header with the default params.
+    pub fn new(nodes: Vec<Node<G>>, delays: Vec<Delay>, destination: Destination) -> Result<Self> {
+        Self::new_with_params(nodes, delays, destination, &Default::default())
+    }
+
+    pub fn new_with_params(
+        nodes: Vec<Node<G>>,
+        delays: Vec<Delay>,
+        destination: Destination,
+        params: &SphinxParams,
+    ) -> Result<Self> {
+        if nodes.is_empty() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::EmptyRoute,
+            ));
+        }
+        if nodes.len() > params.max_path_length() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::RouteTooLong {
+                    length: nodes.len(),
+                    maximum: params.max_path_length(),
+                },
+            ));
+        }
+        if delays.len() != nodes.len() {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::HopCountMismatch {
+                    hops: nodes.len(),
+                    provided: delays.len(),
+                },
+            ));
+        }
+
+        let filler_len = (nodes.len() - 1) * params.filler_step_size();
+        let destination_length = destination.address.as_bytes_ref().len();
+        let available = max_destination_length(filler_len, params);
+        if destination_length > available {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::RoutingInformationTooLong {
+                    required: destination_length,
+                    available,
+                },
+            ));
+        }
+
+        Ok(Route {
+            nodes,
+            delays,
+            destination,
+        })
+    }
+
+    /// Creates the route like `new_with_params`, but additionally rejects routes
+    /// going through the same node twice in a row.
+    pub fn new_with_distinct_hops(
+        nodes: Vec<Node<G>>,
+        delays: Vec<Delay>,
+        destination: Destination,
+        params: &SphinxParams,
+    ) -> Result<Self> {
+        if let Some(index) = nodes
+            .windows(2)
+            .position(|pair| pair[0].address == pair[1].address)
+        {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidRouting,
+                ErrorReason::RepeatedHop { index: index + 1 },
+            ));
+        }
+        Self::new_with_params(nodes, delays, destination, params)
+    }
+
+    pub fn nodes(&self) -> &[Node<G>] {
+        &self.nodes
+    }
+
+    pub fn delays(&self) -> &[Delay] {
+        &self.delays
+    }
+
+    pub fn destination(&self) -> &Destination {
+        &self.destination
+    }
+
+    pub fn first_hop(&self) -> &Node<G> {
+        &self.nodes[0]
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
 #[cfg(test)]
 mod address_encoding {
     use super::*;
@@ -197,3 +320,87 @@ mod address_encoding {
         assert_eq!(dummy_address, recovered)
     }
 }
+
+#[cfg(test)]
+mod creating_route {
+    use super::*;
+    use crate::constants::MAX_PATH_LENGTH;
+    use crate::test_utils::fixtures::destination_fixture;
+    use crate::test_utils::random_node;
+
+    fn delays(n: usize) -> Vec<Delay> {
+        vec![Delay::new_from_millis(10); n]
+    }
+
+    fn reason(result: Result<Route>) -> ErrorReason {
+        result.unwrap_err().reason().unwrap().clone()
+    }
+
+    #[test]
+    fn it_accepts_route_of_maximum_length() {
+        let nodes: Vec<_> = (0..MAX_PATH_LENGTH).map(|_| random_node()).collect();
+        let route = Route::new(nodes, delays(MAX_PATH_LENGTH), destination_fixture()).unwrap();
+        assert_eq!(MAX_PATH_LENGTH, route.len());
+    }
+
+    #[test]
+    fn it_fails_for_empty_route() {
+        assert_eq!(
+            ErrorReason::EmptyRoute,
+            reason(Route::new(vec![], vec![], destination_fixture()))
+        );
+    }
+
+    #[test]
+    fn it_fails_for_route_longer_than_maximum_path_length() {
+        let nodes: Vec<_> = (0..MAX_PATH_LENGTH + 1).map(|_| random_node()).collect();
+        assert_eq!(
+            ErrorReason::RouteTooLong {
+                length: MAX_PATH_LENGTH + 1,
+                maximum: MAX_PATH_LENGTH
+            },
+            reason(Route::new(
+                nodes,
+                delays(MAX_PATH_LENGTH + 1),
+                destination_fixture()
+            ))
+        );
+    }
+
+    #[test]
+    fn it_fails_for_different_number_of_delays() {
+        let nodes = vec![random_node(), random_node()];
+        assert_eq!(
+            ErrorReason::HopCountMismatch {
+                hops: 2,
+                provided: 1
+            },
+            reason(Route::new(nodes, delays(1), destination_fixture()))
+        );
+    }
+
+    #[test]
+    fn it_rejects_repeated_hops_only_if_requested() {
+        let node = |byte| {
+            Node::new(
+                NodeAddressBytes([byte; NODE_ADDRESS_LENGTH]),
+                random_node().pub_key,
+            )
+        };
+        let nodes = vec![node(1), node(2), node(2)];
+        let params = SphinxParams::default();
+        assert!(
+            Route::new_with_params(nodes.clone(), delays(3), destination_fixture(), &params)
+                .is_ok()
+        );
+        assert_eq!(
+            ErrorReason::RepeatedHop { index: 2 },
+            reason(Route::new_with_distinct_hops(
+                nodes,
+                delays(3),
+                destination_fixture(),
+                &params
+            ))
+        );
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::constants::NODE_ADDRESS_LENGTH;
+use crate::crypto::{CipherSuite, DefaultCipherSuite, EphemeralSecret, PrivateKey};
+use crate::header::delays::Delay;
+use crate::header::routing::TRAMPOLINE_HOP;
+use crate::header::tlv::TlvStream;
+use crate::header::{ProcessedHeader, SphinxHeader};
+use crate::params::SphinxParams;
+use crate::payload::Payload;
+use crate::replay::ReplayFilter;
+use crate::route::{DestinationAddressBytes, NodeAddressBytes, SURBIdentifier};
+use crate::surb::SURBMaterial;
+use crate::{Error, ErrorKind, ErrorReason, Result};
+
+/// Pre-built header of an acknowledgement routed back to the sender of a packet, together with
+/// the address of its first hop. It is put at the beginning of the plaintext of the packet,
+/// so that the final hop could send the ack back once the packet was delivered.
+/// The sender learns which packet was delivered from the identifier of the ack destination.
+pub struct SURBAck<C: CipherSuite = DefaultCipherSuite> {
+    ack_header: SphinxHeader<C>,
+    first_hop_address: NodeAddressBytes,
+}
+
+impl<C: CipherSuite> SURBAck<C> {
+    pub fn new(
+        initial_secret: &EphemeralSecret<C::Group>,
+        ack_material: SURBMaterial<C::Group>,
+    ) -> Result<Self> {
+        // payload keys are not needed as acks do not carry any payload
+        let (ack_header, _) = SphinxHeader::new(
+            initial_secret,
+            &ack_material.surb_route,
+            &ack_material.surb_params,
+        )?;
+
+        Ok(SURBAck {
+            ack_header,
+            first_hop_address: ack_material.surb_route.first_hop().address,
+        })
+    }
+
+    /// Number of bytes the ack takes in the plaintext of the packet.
+    pub fn len(params: &SphinxParams) -> usize {
+        NODE_ADDRESS_LENGTH + params.header_size::<C::Group>()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.first_hop_address
+            .as_bytes()
+            .iter()
+            .cloned()
+            .chain(self.ack_header.to_bytes())
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8], params: &SphinxParams) -> Result<Self> {
+        if bytes.len() != Self::len(params) {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidSURB,
+                ErrorReason::UnexpectedLength {
+                    expected: Self::len(params),
+                    actual: bytes.len(),
+                },
+            ));
+        }
+
+        let first_hop_address =
+            NodeAddressBytes::try_from_byte_slice(&bytes[..NODE_ADDRESS_LENGTH])?;
+        let ack_header = SphinxHeader::from_bytes(&bytes[NODE_ADDRESS_LENGTH..], params)?;
+
+        Ok(SURBAck {
+            ack_header,
+            first_hop_address,
+        })
+    }
+
+    /// Prepends the ack to the message given to `Payload::encapsulate_message`.
+    pub fn prepend_to_message(&self, message: &[u8]) -> Vec<u8> {
+        let mut plaintext = self.to_bytes();
+        plaintext.extend_from_slice(message);
+        plaintext
+    }
+
+    /// Turns the ack into the packet that should be sent to the returned first hop.
+    pub fn into_ack_packet(self) -> (AckPacket<C>, NodeAddressBytes) {
+        (
+            AckPacket {
+                header: self.ack_header,
+            },
+            self.first_hop_address,
+        )
+    }
+}
+
+/// Recovers the plaintext of the payload received at the final hop, splits the ack from
+/// the actual message and returns the ack packet ready to be sent to its first hop.
+pub fn extract_ack<C: CipherSuite>(
+    payload: Payload<C>,
+    params: &SphinxParams,
+) -> Result<(AckPacket<C>, NodeAddressBytes, Vec<u8>)> {
+    let plaintext = payload.recover_plaintext()?;
+    let ack_len = SURBAck::<C>::len(params);
+    if plaintext.len() < ack_len {
+        return Err(Error::with_reason(
+            ErrorKind::InvalidPayload,
+            ErrorReason::TooShort {
+                minimum: ack_len,
+                actual: plaintext.len(),
+            },
+        ));
+    }
+
+    let (ack_packet, first_hop_address) =
+        SURBAck::<C>::from_bytes(&plaintext[..ack_len], params)?.into_ack_packet();
+    Ok((ack_packet, first_hop_address, plaintext[ack_len..].to_vec()))
+}
+
+pub enum ProcessedAckPacket<C: CipherSuite = DefaultCipherSuite> {
+    ForwardHop(Box<AckPacket<C>>, NodeAddressBytes, Delay, TlvStream),
+    FinalHop(DestinationAddressBytes, SURBIdentifier, TlvStream),
+}
+
+/// Acknowledgement consisting of just the Sphinx header, as it does not carry any payload.
+pub struct AckPacket<C: CipherSuite = DefaultCipherSuite> {
+    pub header: SphinxHeader<C>,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl<C: CipherSuite> AckPacket<C> {
+    pub fn len(&self) -> usize {
+        self.header.params().header_size::<C::Group>()
+    }
+
+    pub fn process(self, node_secret_key: &PrivateKey<C::Group>) -> Result<ProcessedAckPacket<C>> {
+        Self::from_processed_header(self.header.process(node_secret_key)?)
+    }
+
+    /// Processes the ack like [process], but fails with `ErrorKind::Replay` if a packet
+    /// with the same shared secret has already been processed.
+    pub fn process_with_replay_check<F: ReplayFilter>(
+        self,
+        node_secret_key: &PrivateKey<C::Group>,
+        replay_filter: &mut F,
+    ) -> Result<ProcessedAckPacket<C>> {
+        let routing_keys =
+            SphinxHeader::compute_routing_keys(&self.header.shared_secret, node_secret_key);
+        if replay_filter.contains(&routing_keys.replay_tag) {
+            return Err(Error::with_reason(ErrorKind::Replay, ErrorReason::Replayed));
+        }
+
+        let processed_header = self.header.process_with_routing_keys(&routing_keys)?;
+        replay_filter.insert(routing_keys.replay_tag);
+        Self::from_processed_header(processed_header)
+    }
+
+    fn from_processed_header(
+        processed_header: ProcessedHeader<C>,
+    ) -> Result<ProcessedAckPacket<C>> {
+        Ok(match processed_header {
+            ProcessedHeader::ForwardHop(header, next_hop_address, delay, _, records, _) => {
+                ProcessedAckPacket::ForwardHop(
+                    Box::new(AckPacket { header: *header }),
+                    next_hop_address,
+                    delay,
+                    records,
+                )
+            }
+            ProcessedHeader::FinalHop(destination, identifier, _, records, _) => {
+                ProcessedAckPacket::FinalHop(destination, identifier, records)
+            }
+            // acks have no payload which could carry the inner packet
+            ProcessedHeader::TrampolineHop(..) => {
+                return Err(Error::with_reason(
+                    ErrorKind::InvalidSURB,
+                    ErrorReason::UnknownRoutingFlag {
+                        flag: TRAMPOLINE_HOP,
+                    },
+                ))
+            }
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.header.to_bytes()
+    }
+
+    /// Recovers the ack assuming it was created using the default `SphinxParams`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_bytes_with_params(bytes, &SphinxParams::default())
+    }
+
+    pub fn from_bytes_with_params(bytes: &[u8], params: &SphinxParams) -> Result<Self> {
+        Ok(AckPacket {
+            header: SphinxHeader::from_bytes(bytes, params)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod surb_ack {
+    use super::*;
+    use crate::crypto;
+    use crate::header::delays;
+    use crate::route::{Node, Route};
+    use crate::test_utils::fixtures::destination_fixture;
+    use std::time::Duration;
+
+    fn ack_fixture() -> SURBAck {
+        let (_, node_pk) = crypto::keygen();
+        let node = Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node_pk,
+        );
+        let delays = delays::generate_from_average_duration(1, Duration::from_secs(1));
+        SURBAck::new(
+            &EphemeralSecret::new(),
+            SURBMaterial::new(Route::new(vec![node], delays, destination_fixture()).unwrap()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_is_recovered_from_the_plaintext_of_the_payload() {
+        let ack = ack_fixture();
+        let expected_header = ack.ack_header.to_bytes();
+        let plaintext = ack.prepend_to_message(b"foomp");
+        let payload: Payload = Payload::encapsulate_message(&plaintext, &[], 1024).unwrap();
+
+        let (ack_packet, first_hop, message) =
+            extract_ack(payload, &SphinxParams::default()).unwrap();
+        assert_eq!(expected_header, ack_packet.to_bytes());
+        assert_eq!(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            first_hop
+        );
+        assert_eq!(b"foomp".to_vec(), message);
+    }
+
+    #[test]
+    fn it_is_not_recovered_from_too_short_plaintext() {
+        let plaintext = ack_fixture().to_bytes();
+        let payload: Payload =
+            Payload::encapsulate_message(&plaintext[..plaintext.len() - 1], &[], 1024).unwrap();
+        assert!(extract_ack(payload, &SphinxParams::default()).is_err());
+    }
+}
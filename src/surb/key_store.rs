@@ -0,0 +1,525 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::constants::HeaderIntegrityHmacAlgorithm;
+use crate::crypto::{self, CipherSuite, STREAM_CIPHER_KEY_SIZE};
+use crate::payload::Payload;
+use crate::replay::BloomFilter;
+use crate::route::SURBIdentifier;
+use crate::surb::SURBDecryptionKeys;
+use crate::utils;
+use crate::{Error, ErrorKind, ErrorReason, Result};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use subtle::ConstantTimeEq;
+
+pub const FILE_KEY_STORE_SECRET_SIZE: usize = 32;
+const FILE_KEY_STORE_MAC_KEY_SIZE: usize = 32;
+const FILE_KEY_STORE_MAC_SIZE: usize = 32;
+const FILE_KEY_STORE_KDF_INFO: &[u8] = b"sphinx-surb-key-store";
+
+const KEYS_FILE_EXTENSION: &str = "surb";
+const USED_FILE_EXTENSION: &str = "used";
+
+/// Keeps the decryption keys of SURBs handed out by the client until the replies arrive.
+/// Every SURB can be used only once, so once the reply was recovered with its keys,
+/// any further reply with the same identifier is rejected.
+pub trait SurbKeyStore {
+    /// Stores keys of a newly created SURB. It fails if the identifier has already been used.
+    fn insert(&mut self, keys: SURBDecryptionKeys) -> Result<()>;
+
+    /// Returns the keys of the SURB which has not been used yet, without marking it as used,
+    /// so that a reply which can not be recovered would not burn the SURB.
+    fn get(&self, identifier: &SURBIdentifier) -> Result<SURBDecryptionKeys>;
+
+    /// Removes the keys from the store and marks the identifier as used.
+    fn commit(&mut self, identifier: &SURBIdentifier) -> Result<()>;
+
+    /// Removes the keys from the store and marks the identifier as used, regardless of
+    /// whether the reply is going to be recovered with them.
+    fn take(&mut self, identifier: &SURBIdentifier) -> Result<SURBDecryptionKeys> {
+        let keys = self.get(identifier)?;
+        self.commit(identifier)?;
+        Ok(keys)
+    }
+
+    /// Recovers the reply received at the final hop under the provided SURB identifier.
+    /// The SURB is marked as used only once the reply has been recovered.
+    fn recover_reply<C: CipherSuite>(
+        &mut self,
+        identifier: &SURBIdentifier,
+        payload: Payload<C>,
+    ) -> Result<Vec<u8>> {
+        let reply = self.get(identifier)?.recover_reply(payload)?;
+        self.commit(identifier)?;
+        Ok(reply)
+    }
+}
+
+fn already_used_error() -> Error {
+    Error::with_reason(ErrorKind::InvalidSURB, ErrorReason::SURBAlreadyUsed)
+}
+
+fn unknown_surb_error() -> Error {
+    Error::with_reason(ErrorKind::InvalidSURB, ErrorReason::UnknownSURB)
+}
+
+/// Default number of identifiers of used SURBs remembered exactly by `InMemorySurbKeyStore`.
+pub const DEFAULT_USED_SURBS_CAPACITY: usize = 1 << 16;
+
+// the tombstones of the identifiers evicted from the exact set are sized for this many times
+// the capacity of the set
+const TOMBSTONES_PER_USED_SURB: usize = 8;
+const TOMBSTONES_FALSE_POSITIVE_RATE: f64 = 1e-4;
+
+/// Only the most recently used identifiers are remembered exactly, up to the capacity.
+/// The older ones are moved to a Bloom filter of constant size, so an identifier is never
+/// accepted again once it has been used. The filter is sized for `8 * used_capacity` identifiers
+/// with a false positive rate of 0.01%; past that the rate grows, and inserting keys under fresh
+/// identifiers fails more and more often with `SURBAlreadyUsed`. Keys that have already been
+/// inserted are never affected, so at worst the store has to be replaced by a new one, while
+/// a used SURB is never accepted twice.
+#[derive(Debug)]
+pub struct InMemorySurbKeyStore {
+    keys: HashMap<SURBIdentifier, SURBDecryptionKeys>,
+    used: HashSet<SURBIdentifier>,
+    // order in which the identifiers were used, so that the oldest ones are evicted first
+    used_order: VecDeque<SURBIdentifier>,
+    used_capacity: usize,
+    // hashes of the identifiers evicted from the exact set
+    tombstones: BloomFilter,
+}
+
+impl Default for InMemorySurbKeyStore {
+    fn default() -> Self {
+        Self::with_used_capacity(DEFAULT_USED_SURBS_CAPACITY)
+    }
+}
+
+impl InMemorySurbKeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_used_capacity(used_capacity: usize) -> Self {
+        InMemorySurbKeyStore {
+            keys: HashMap::new(),
+            used: HashSet::new(),
+            used_order: VecDeque::new(),
+            used_capacity,
+            tombstones: BloomFilter::with_false_positive_rate(
+                used_capacity
+                    .saturating_mul(TOMBSTONES_PER_USED_SURB)
+                    .max(1),
+                TOMBSTONES_FALSE_POSITIVE_RATE,
+            ),
+        }
+    }
+
+    fn is_used(&self, identifier: &SURBIdentifier) -> bool {
+        self.used.contains(identifier) || self.tombstones.contains(&tombstone(identifier))
+    }
+
+    /// Number of SURBs still waiting for the reply.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+fn tombstone(identifier: &SURBIdentifier) -> [u8; 32] {
+    // identifiers might be chosen by the caller, so they are hashed to spread them evenly
+    // across the bits of the filter
+    Sha256::digest(identifier).into()
+}
+
+impl SurbKeyStore for InMemorySurbKeyStore {
+    fn insert(&mut self, keys: SURBDecryptionKeys) -> Result<()> {
+        if self.keys.contains_key(keys.identifier()) || self.is_used(keys.identifier()) {
+            return Err(already_used_error());
+        }
+        self.keys.insert(*keys.identifier(), keys);
+        Ok(())
+    }
+
+    // keys are removed once used and never inserted for a used identifier, so the stored keys
+    // are always valid, even if their identifier is a false positive of the tombstones
+    fn get(&self, identifier: &SURBIdentifier) -> Result<SURBDecryptionKeys> {
+        match self.keys.get(identifier) {
+            Some(keys) => Ok(keys.clone()),
+            None if self.is_used(identifier) => Err(already_used_error()),
+            None => Err(unknown_surb_error()),
+        }
+    }
+
+    fn commit(&mut self, identifier: &SURBIdentifier) -> Result<()> {
+        if self.keys.remove(identifier).is_none() {
+            return Err(if self.is_used(identifier) {
+                already_used_error()
+            } else {
+                unknown_surb_error()
+            });
+        }
+
+        if self.used_order.len() >= self.used_capacity {
+            if let Some(oldest) = self.used_order.pop_front() {
+                self.used.remove(&oldest);
+                self.tombstones.insert(&tombstone(&oldest));
+            }
+        }
+        if self.used_capacity > 0 {
+            self.used.insert(*identifier);
+            self.used_order.push_back(*identifier);
+        } else {
+            self.tombstones.insert(&tombstone(identifier));
+        }
+        Ok(())
+    }
+}
+
+/// Stores keys of every SURB in a separate file inside the directory, encrypted with AES-128-CTR
+/// and authenticated with HMAC-SHA256 under keys derived from the store secret.
+/// File names are derived from the identifiers with a keyed hash, so they do not reveal them.
+/// Once a SURB is used, its file is replaced with an empty marker, which is kept
+/// until `expire_used` removes it.
+pub struct FileSurbKeyStore {
+    directory: PathBuf,
+    encryption_key: [u8; STREAM_CIPHER_KEY_SIZE],
+    mac_key: [u8; FILE_KEY_STORE_MAC_KEY_SIZE],
+}
+
+impl FileSurbKeyStore {
+    pub fn new<P: AsRef<Path>>(
+        directory: P,
+        secret: &[u8; FILE_KEY_STORE_SECRET_SIZE],
+    ) -> Result<Self> {
+        fs::create_dir_all(directory.as_ref()).map_err(io_error)?;
+
+        let mut okm = [0u8; STREAM_CIPHER_KEY_SIZE + FILE_KEY_STORE_MAC_KEY_SIZE];
+        Hkdf::<Sha256>::new(None, secret)
+            .expand(FILE_KEY_STORE_KDF_INFO, &mut okm)
+            .unwrap();
+        let mut encryption_key = [0u8; STREAM_CIPHER_KEY_SIZE];
+        encryption_key.copy_from_slice(&okm[..STREAM_CIPHER_KEY_SIZE]);
+        let mut mac_key = [0u8; FILE_KEY_STORE_MAC_KEY_SIZE];
+        mac_key.copy_from_slice(&okm[STREAM_CIPHER_KEY_SIZE..]);
+
+        Ok(FileSurbKeyStore {
+            directory: directory.as_ref().to_path_buf(),
+            encryption_key,
+            mac_key,
+        })
+    }
+
+    /// Removes markers of SURBs used longer than `max_age` ago. Replies to them are still
+    /// rejected, as their keys are gone, but they are reported as unknown rather than used.
+    /// Returns the number of removed markers.
+    pub fn expire_used(&self, max_age: Duration) -> Result<usize> {
+        let now = SystemTime::now();
+        let mut expired = 0;
+        for entry in fs::read_dir(&self.directory).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            if path.extension() != Some(OsStr::new(USED_FILE_EXTENSION)) {
+                continue;
+            }
+            let modified = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .map_err(io_error)?;
+            // markers from the future are kept
+            if now.duration_since(modified).unwrap_or_default() > max_age {
+                fs::remove_file(&path).map_err(io_error)?;
+                expired += 1;
+            }
+        }
+        Ok(expired)
+    }
+
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        crypto::compute_keyed_hmac::<HeaderIntegrityHmacAlgorithm>(&self.mac_key, data)
+            .into_bytes()
+            .to_vec()
+    }
+
+    fn path(&self, identifier: &SURBIdentifier, extension: &str) -> PathBuf {
+        let name: String = self.mac(identifier)[..16]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.directory.join(name).with_extension(extension)
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut iv = [0u8; STREAM_CIPHER_KEY_SIZE];
+        iv.copy_from_slice(&utils::bytes::random(&mut OsRng, STREAM_CIPHER_KEY_SIZE));
+        let keystream =
+            crypto::generate_pseudorandom_bytes(&self.encryption_key, &iv, plaintext.len());
+
+        let mut record = iv.to_vec();
+        record.extend(utils::bytes::xor(plaintext, &keystream));
+        let tag = self.mac(&record);
+        record.extend(tag);
+        record
+    }
+
+    fn decrypt(&self, record: &[u8]) -> Result<Vec<u8>> {
+        if record.len() < STREAM_CIPHER_KEY_SIZE + FILE_KEY_STORE_MAC_SIZE {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidSURB,
+                ErrorReason::TooShort {
+                    minimum: STREAM_CIPHER_KEY_SIZE + FILE_KEY_STORE_MAC_SIZE,
+                    actual: record.len(),
+                },
+            ));
+        }
+        let (data, tag) = record.split_at(record.len() - FILE_KEY_STORE_MAC_SIZE);
+        if !bool::from(self.mac(data).ct_eq(tag)) {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidSURB,
+                ErrorReason::InvalidMac,
+            ));
+        }
+
+        let (iv, ciphertext) = data.split_at(STREAM_CIPHER_KEY_SIZE);
+        let mut iv_bytes = [0u8; STREAM_CIPHER_KEY_SIZE];
+        iv_bytes.copy_from_slice(iv);
+        let keystream =
+            crypto::generate_pseudorandom_bytes(&self.encryption_key, &iv_bytes, ciphertext.len());
+        Ok(utils::bytes::xor(ciphertext, &keystream))
+    }
+}
+
+fn io_error(err: io::Error) -> Error {
+    Error::with_reason(
+        ErrorKind::InvalidSURB,
+        ErrorReason::KeyStoreIo { kind: err.kind() },
+    )
+}
+
+impl SurbKeyStore for FileSurbKeyStore {
+    fn insert(&mut self, keys: SURBDecryptionKeys) -> Result<()> {
+        let keys_path = self.path(keys.identifier(), KEYS_FILE_EXTENSION);
+        if self.path(keys.identifier(), USED_FILE_EXTENSION).exists() || keys_path.exists() {
+            return Err(already_used_error());
+        }
+        fs::write(keys_path, self.encrypt(&keys.to_bytes())).map_err(io_error)
+    }
+
+    fn get(&self, identifier: &SURBIdentifier) -> Result<SURBDecryptionKeys> {
+        if self.path(identifier, USED_FILE_EXTENSION).exists() {
+            return Err(already_used_error());
+        }
+
+        let record = match fs::read(self.path(identifier, KEYS_FILE_EXTENSION)) {
+            Ok(record) => record,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(unknown_surb_error()),
+            Err(err) => return Err(io_error(err)),
+        };
+        let keys = SURBDecryptionKeys::from_bytes(&self.decrypt(&record)?)?;
+        if keys.identifier() != identifier {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidSURB,
+                ErrorReason::MalformedSURB,
+            ));
+        }
+        Ok(keys)
+    }
+
+    fn commit(&mut self, identifier: &SURBIdentifier) -> Result<()> {
+        let used_path = self.path(identifier, USED_FILE_EXTENSION);
+        if used_path.exists() {
+            return Err(already_used_error());
+        }
+        let keys_path = self.path(identifier, KEYS_FILE_EXTENSION);
+        if !keys_path.exists() {
+            return Err(unknown_surb_error());
+        }
+
+        // mark it as used before removing the keys so that a crash in between
+        // could not make the SURB usable again
+        fs::write(used_path, []).map_err(io_error)?;
+        fs::remove_file(keys_path).map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod surb_key_stores {
+    use super::*;
+    use crate::constants::{IDENTIFIER_LENGTH, PAYLOAD_KEY_SIZE};
+
+    fn keys_fixture(identifier: u8) -> SURBDecryptionKeys {
+        let bytes: Vec<u8> = std::iter::repeat(identifier)
+            .take(IDENTIFIER_LENGTH)
+            .chain(std::iter::repeat(1u8).take(PAYLOAD_KEY_SIZE * 3))
+            .collect();
+        SURBDecryptionKeys::from_bytes(&bytes).unwrap()
+    }
+
+    fn enforces_single_use<S: SurbKeyStore>(store: &mut S) {
+        store.insert(keys_fixture(1)).unwrap();
+        store.insert(keys_fixture(2)).unwrap();
+        assert!(store.insert(keys_fixture(1)).is_err());
+
+        let keys = store.take(&[1u8; IDENTIFIER_LENGTH]).unwrap();
+        assert_eq!(keys_fixture(1).to_bytes(), keys.to_bytes());
+        assert!(store.take(&[1u8; IDENTIFIER_LENGTH]).is_err());
+        assert!(store.insert(keys_fixture(1)).is_err());
+
+        assert!(store.take(&[3u8; IDENTIFIER_LENGTH]).is_err());
+        assert!(store.take(&[2u8; IDENTIFIER_LENGTH]).is_ok());
+    }
+
+    fn temp_directory() -> PathBuf {
+        let name: String = utils::bytes::random(&mut OsRng, 8)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        std::env::temp_dir().join(format!("sphinx-surb-key-store-{}", name))
+    }
+
+    #[test]
+    fn in_memory_store_enforces_single_use() {
+        let mut store = InMemorySurbKeyStore::new();
+        enforces_single_use(&mut store);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn keys_are_kept_until_committed() {
+        let mut store = InMemorySurbKeyStore::new();
+        store.insert(keys_fixture(1)).unwrap();
+        assert!(store.get(&[1u8; IDENTIFIER_LENGTH]).is_ok());
+        assert!(store.get(&[1u8; IDENTIFIER_LENGTH]).is_ok());
+
+        store.commit(&[1u8; IDENTIFIER_LENGTH]).unwrap();
+        assert_eq!(
+            Some(&ErrorReason::SURBAlreadyUsed),
+            store.get(&[1u8; IDENTIFIER_LENGTH]).unwrap_err().reason()
+        );
+        assert!(store.commit(&[1u8; IDENTIFIER_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn in_memory_store_keeps_rejecting_used_surbs_over_capacity() {
+        for used_capacity in [0, 2] {
+            let mut store = InMemorySurbKeyStore::with_used_capacity(used_capacity);
+            for identifier in 1..=3 {
+                store.insert(keys_fixture(identifier)).unwrap();
+                store.take(&[identifier; IDENTIFIER_LENGTH]).unwrap();
+            }
+
+            for identifier in 1..=3 {
+                assert_eq!(
+                    Some(&ErrorReason::SURBAlreadyUsed),
+                    store
+                        .get(&[identifier; IDENTIFIER_LENGTH])
+                        .unwrap_err()
+                        .reason()
+                );
+                assert_eq!(
+                    Some(&ErrorReason::SURBAlreadyUsed),
+                    store.insert(keys_fixture(identifier)).unwrap_err().reason()
+                );
+            }
+            assert!(store.insert(keys_fixture(4)).is_ok());
+        }
+    }
+
+    #[test]
+    fn file_store_expires_markers_of_used_surbs() {
+        let directory = temp_directory();
+        let mut store =
+            FileSurbKeyStore::new(&directory, &[7u8; FILE_KEY_STORE_SECRET_SIZE]).unwrap();
+        store.insert(keys_fixture(1)).unwrap();
+        store.insert(keys_fixture(2)).unwrap();
+        store.take(&[1u8; IDENTIFIER_LENGTH]).unwrap();
+
+        assert_eq!(0, store.expire_used(Duration::from_secs(3600)).unwrap());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(1, store.expire_used(Duration::from_millis(10)).unwrap());
+        assert_eq!(
+            Some(&ErrorReason::UnknownSURB),
+            store.get(&[1u8; IDENTIFIER_LENGTH]).unwrap_err().reason()
+        );
+        assert!(store.get(&[2u8; IDENTIFIER_LENGTH]).is_ok());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn file_store_enforces_single_use_across_instances() {
+        let directory = temp_directory();
+        let secret = [7u8; FILE_KEY_STORE_SECRET_SIZE];
+        enforces_single_use(&mut FileSurbKeyStore::new(&directory, &secret).unwrap());
+
+        let mut reopened = FileSurbKeyStore::new(&directory, &secret).unwrap();
+        assert!(reopened.take(&[1u8; IDENTIFIER_LENGTH]).is_err());
+        assert!(reopened.insert(keys_fixture(2)).is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn file_store_does_not_recover_keys_with_different_secret() {
+        let directory = temp_directory();
+        FileSurbKeyStore::new(&directory, &[7u8; FILE_KEY_STORE_SECRET_SIZE])
+            .unwrap()
+            .insert(keys_fixture(1))
+            .unwrap();
+
+        let mut other_store =
+            FileSurbKeyStore::new(&directory, &[8u8; FILE_KEY_STORE_SECRET_SIZE]).unwrap();
+        assert!(other_store.take(&[1u8; IDENTIFIER_LENGTH]).is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn file_store_does_not_keep_keys_in_plaintext() {
+        let directory = temp_directory();
+        let keys = keys_fixture(1);
+        FileSurbKeyStore::new(&directory, &[7u8; FILE_KEY_STORE_SECRET_SIZE])
+            .unwrap()
+            .insert(keys_fixture(1))
+            .unwrap();
+
+        for entry in fs::read_dir(&directory).unwrap() {
+            let content = fs::read(entry.unwrap().path()).unwrap();
+            assert!(!content
+                .windows(PAYLOAD_KEY_SIZE)
+                .any(|window| window == &keys.to_bytes()[IDENTIFIER_LENGTH..][..PAYLOAD_KEY_SIZE]));
+        }
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn file_store_reports_io_failures() {
+        // a regular file can't be used as the directory of the store
+        let path = temp_directory();
+        fs::write(&path, []).unwrap();
+        let err = match FileSurbKeyStore::new(&path, &[7u8; FILE_KEY_STORE_SECRET_SIZE]) {
+            Ok(_) => panic!("opened a store in a regular file"),
+            Err(err) => err,
+        };
+        assert_eq!(ErrorKind::InvalidSURB, err.kind());
+        assert!(matches!(err.reason(), Some(ErrorReason::KeyStoreIo { .. })));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,105 +1,380 @@
-use crate::constants::{NODE_ADDRESS_LENGTH, PAYLOAD_KEY_SIZE};
-use crate::header::delays::Delay;
+use crate::constants::{IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH, PAYLOAD_KEY_SIZE};
+use crate::crypto::{CipherSuite, DefaultCipherSuite, DefaultGroup, SphinxGroup};
 use crate::header::keys::PayloadKey;
+use crate::params::SphinxParams;
 use crate::payload::Payload;
-use crate::route::{Destination, Node, NodeAddressBytes};
-use crate::{crypto::EphemeralSecret, Error, ErrorKind, Result};
+use crate::route::{Node, NodeAddressBytes, Route, SURBIdentifier};
+use crate::{crypto::EphemeralSecret, crypto::PublicKey, Error, ErrorKind, ErrorReason, Result};
 use crate::{header, SphinxPacket};
-use header::{SphinxHeader, HEADER_SIZE};
+use header::keys::KeyMaterial;
+use header::SphinxHeader;
+use hkdf::Hkdf;
+use key_store::SurbKeyStore;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use sha2::Sha256;
 use std::fmt;
 
+pub const SURB_SEED_SIZE: usize = 32;
+
+/// Version of the SURB encoding produced by `SURB::to_bytes`. It carries just a single payload key,
+/// so its length does not depend on the length of the route. Unversioned bytes are treated as
+/// the legacy encoding with one payload key per hop.
+pub const SURB_FORMAT_V1: u8 = 1;
+const SURB_SEED_KDF_INFO: &[u8] = b"sphinx-surb-seed/";
+
+pub mod ack;
+pub mod key_store;
+
 /// A Single Use Reply Block (SURB) must have a pre-aggregated Sphinx header,
-/// the address of the first hop in the route of the SURB, and the key material
-/// used to layer encrypt the payload.
+/// the address of the first hop in the route of the SURB, and the key used to encrypt
+/// the payload. As in the Sphinx paper, it does not contain the keys of the individual hops,
+/// so whoever uses the SURB is not able to decrypt the reply once it has been sent.
+/// SURBs recovered from the legacy encoding might still carry the key of every hop.
 #[allow(non_snake_case)]
-pub struct SURB {
-    SURB_header: header::SphinxHeader,
+pub struct SURB<C: CipherSuite = DefaultCipherSuite> {
+    SURB_header: header::SphinxHeader<C>,
     first_hop_address: NodeAddressBytes,
     payload_keys: Vec<PayloadKey>,
 }
 
-impl fmt::Debug for SURB {
+impl<C: CipherSuite> fmt::Debug for SURB<C> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut formatted_keys_inner = Vec::with_capacity(self.payload_keys.len());
-        for payload_key in &self.payload_keys {
-            formatted_keys_inner.push(format!("{{ payload_key: {:?} }}", payload_key.to_vec()))
+        write!(
+            f,
+            "SURB: {{ SURB_header: {:?}, first_hop_address: {:?}, payload_keys: {:?} }}",
+            self.SURB_header,
+            self.first_hop_address,
+            self.payload_keys
+                .iter()
+                .map(|payload_key| payload_key.to_vec())
+                .collect::<Vec<_>>()
+        )
+    }
+}
+
+/// Keys the creator of the SURB has to keep in order to read the reply.
+/// The reply arrives at the final hop with the SURB identifier, which should be used to find them.
+#[derive(Clone)]
+pub struct SURBDecryptionKeys {
+    identifier: SURBIdentifier,
+    // the key that was given away alongside the SURB
+    surb_payload_key: PayloadKey,
+    // keys of all the hops of the SURB route
+    payload_keys: Vec<PayloadKey>,
+}
+
+impl SURBDecryptionKeys {
+    /// Regenerates the keys of the SURB created with `SURB::new_from_seed` from the seed and
+    /// the record of the SURB.
+    pub fn from_seed<C: CipherSuite>(seed: &SURBSeed, record: &SURBSeedRecord<C::Group>) -> Self {
+        let identifier = record.identifier;
+        let key_material =
+            KeyMaterial::<C>::derive(&record.surb_route, &seed.initial_secret(&identifier));
+        SURBDecryptionKeys {
+            identifier,
+            surb_payload_key: seed.surb_payload_key(&identifier),
+            payload_keys: key_material
+                .routing_keys
+                .iter()
+                .map(|routing_keys| routing_keys.payload_key)
+                .collect(),
+        }
+    }
+
+    pub fn identifier(&self) -> &SURBIdentifier {
+        &self.identifier
+    }
+
+    /// Strips all layers of encryption from the payload received at the final hop of the SURB
+    /// route and recovers the reply message.
+    pub fn recover_reply<C: CipherSuite>(&self, payload: Payload<C>) -> Result<Vec<u8>> {
+        // every hop has 'unwrapped' a layer of the payload using its key, hence we have to
+        // redo that in reverse order before removing the single layer added by the SURB user
+        let mut payload = payload;
+        for payload_key in self.payload_keys.iter().rev() {
+            payload = payload.add_encryption_layer(payload_key)?;
+        }
+        payload.unwrap(&self.surb_payload_key)?.recover_plaintext()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.identifier
+            .iter()
+            .chain(self.surb_payload_key.iter())
+            .chain(self.payload_keys.iter().flat_map(|key| key.iter()))
+            .cloned()
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let keys_offset = IDENTIFIER_LENGTH + PAYLOAD_KEY_SIZE;
+        // there has to be key of at least a single hop
+        if bytes.len() <= keys_offset || (bytes.len() - keys_offset) % PAYLOAD_KEY_SIZE != 0 {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidSURB,
+                ErrorReason::MalformedSURB,
+            ));
         }
-        let formatted_keys = format!("{{ {} }}", formatted_keys_inner.join(", "));
 
+        let mut identifier = [0u8; IDENTIFIER_LENGTH];
+        identifier.copy_from_slice(&bytes[..IDENTIFIER_LENGTH]);
+        let mut surb_payload_key = [0u8; PAYLOAD_KEY_SIZE];
+        surb_payload_key.copy_from_slice(&bytes[IDENTIFIER_LENGTH..keys_offset]);
+        let payload_keys = bytes[keys_offset..]
+            .chunks(PAYLOAD_KEY_SIZE)
+            .map(|chunk| {
+                let mut payload_key = [0u8; PAYLOAD_KEY_SIZE];
+                payload_key.copy_from_slice(chunk);
+                payload_key
+            })
+            .collect();
+
+        Ok(SURBDecryptionKeys {
+            identifier,
+            surb_payload_key,
+            payload_keys,
+        })
+    }
+}
+
+impl fmt::Debug for SURBDecryptionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // do not leak the keys
         write!(
             f,
-            "SURB: {{ SURB_header: {:?}, first_hop_address: {:?}, payload_keys: {:?} }}",
-            self.SURB_header, self.first_hop_address, formatted_keys
+            "SURBDecryptionKeys: {{ identifier: {:?}, hops: {} }}",
+            self.identifier,
+            self.payload_keys.len()
         )
     }
 }
 
-pub struct SURBMaterial {
-    surb_route: Vec<Node>,
-    surb_delays: Vec<Delay>,
-    surb_destination: Destination,
+/// Short secret from which all the keys of a single SURB are derived.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SURBSeed([u8; SURB_SEED_SIZE]);
+
+impl SURBSeed {
+    pub fn new() -> Self {
+        let mut seed = [0u8; SURB_SEED_SIZE];
+        OsRng.fill_bytes(&mut seed);
+        SURBSeed(seed)
+    }
+
+    pub fn from_bytes(bytes: [u8; SURB_SEED_SIZE]) -> Self {
+        SURBSeed(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; SURB_SEED_SIZE] {
+        &self.0
+    }
+
+    // the identifier is part of the derivation, so the same seed can't be used to produce
+    // the same keys for two different SURBs
+    fn expand(&self, identifier: &SURBIdentifier, domain: &[u8], output: &mut [u8]) {
+        let info: Vec<u8> = SURB_SEED_KDF_INFO
+            .iter()
+            .chain(domain)
+            .chain(identifier.iter())
+            .cloned()
+            .collect();
+        // this can only fail if we requested more than 255 * 32 bytes, which we never do
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand(&info, output)
+            .unwrap();
+    }
+
+    fn initial_secret<G: SphinxGroup>(&self, identifier: &SURBIdentifier) -> EphemeralSecret<G> {
+        let mut rng_seed = [0u8; 32];
+        self.expand(identifier, b"initial-secret", &mut rng_seed);
+        EphemeralSecret::new_with_rng(&mut ChaCha20Rng::from_seed(rng_seed))
+    }
+
+    fn surb_payload_key(&self, identifier: &SURBIdentifier) -> PayloadKey {
+        let mut payload_key = [0u8; PAYLOAD_KEY_SIZE];
+        self.expand(identifier, b"payload-key", &mut payload_key);
+        payload_key
+    }
+}
+
+/// What has to be kept for every SURB created with `SURB::new_from_seed`, alongside the seed
+/// shared by all of them. The seed alone is not enough to regenerate the keys: while the initial
+/// secret is derived from it, the keys of the hops come from the secrets shared with the nodes
+/// of the SURB route, which can't be computed without their public keys.
+#[derive(Clone, Debug)]
+pub struct SURBSeedRecord<G: SphinxGroup = DefaultGroup> {
+    identifier: SURBIdentifier,
+    surb_route: Vec<Node<G>>,
+}
+
+impl<G: SphinxGroup> SURBSeedRecord<G> {
+    pub fn identifier(&self) -> &SURBIdentifier {
+        &self.identifier
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.identifier
+            .iter()
+            .chain(self.surb_route.iter().flat_map(|node| {
+                node.address
+                    .as_bytes_ref()
+                    .iter()
+                    .chain(node.pub_key.as_bytes().as_ref())
+            }))
+            .cloned()
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let node_length = NODE_ADDRESS_LENGTH + G::ELEMENT_SIZE;
+        // the route has at least a single hop
+        if bytes.len() <= IDENTIFIER_LENGTH || (bytes.len() - IDENTIFIER_LENGTH) % node_length != 0
+        {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidSURB,
+                ErrorReason::MalformedSURB,
+            ));
+        }
+
+        let mut identifier = [0u8; IDENTIFIER_LENGTH];
+        identifier.copy_from_slice(&bytes[..IDENTIFIER_LENGTH]);
+        let surb_route = bytes[IDENTIFIER_LENGTH..]
+            .chunks(node_length)
+            .map(|node_bytes| {
+                Ok(Node::new(
+                    NodeAddressBytes::try_from_byte_slice(&node_bytes[..NODE_ADDRESS_LENGTH])?,
+                    PublicKey::try_from_byte_slice(&node_bytes[NODE_ADDRESS_LENGTH..])?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(SURBSeedRecord {
+            identifier,
+            surb_route,
+        })
+    }
+}
+
+impl Default for SURBSeed {
+    fn default() -> Self {
+        SURBSeed::new()
+    }
+}
+
+impl fmt::Debug for SURBSeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // do not leak the seed
+        write!(f, "SURBSeed")
+    }
+}
+
+pub struct SURBMaterial<G: SphinxGroup = DefaultGroup> {
+    pub(crate) surb_route: Route<G>,
+    pub(crate) surb_params: SphinxParams,
 }
 
-impl SURBMaterial {
-    pub fn new(route: Vec<Node>, delays: Vec<Delay>, destination: Destination) -> Self {
+impl<G: SphinxGroup> SURBMaterial<G> {
+    pub fn new(route: Route<G>) -> Self {
         SURBMaterial {
             surb_route: route,
-            surb_delays: delays,
-            surb_destination: destination,
+            surb_params: Default::default(),
         }
     }
 
+    pub fn with_params(mut self, params: SphinxParams) -> Self {
+        self.surb_params = params;
+        self
+    }
+
     #[allow(non_snake_case)]
-    pub fn construct_SURB(self) -> Result<SURB> {
+    pub fn construct_SURB<C: CipherSuite<Group = G>>(
+        self,
+    ) -> Result<(SURB<C>, SURBDecryptionKeys)> {
         let surb_initial_secret = EphemeralSecret::new();
         SURB::new(surb_initial_secret, self)
     }
+
+    /// Constructs the SURB and puts its decryption keys straight into the provided store.
+    #[allow(non_snake_case)]
+    pub fn construct_SURB_with_key_store<C: CipherSuite<Group = G>, S: SurbKeyStore>(
+        self,
+        key_store: &mut S,
+    ) -> Result<SURB<C>> {
+        let (surb, surb_keys) = self.construct_SURB()?;
+        key_store.insert(surb_keys)?;
+        Ok(surb)
+    }
 }
 
 #[allow(non_snake_case)]
-impl SURB {
-    pub fn new(surb_initial_secret: EphemeralSecret, surb_material: SURBMaterial) -> Result<Self> {
-        let surb_route = surb_material.surb_route;
-        let surb_delays = surb_material.surb_delays;
-        let surb_destination = surb_material.surb_destination;
-
-        /* Pre-computes the header of the Sphinx packet which will be used as SURB
-        and encapsulates it into struct together with the address of the first hop in the route of the SURB, and the key material
-        which should be used to layer encrypt the payload. */
-        if surb_route.is_empty() {
-            return Err(Error::new(
-                ErrorKind::InvalidSURB,
-                "tried to create SURB for an empty route",
-            ));
-        }
-        if surb_route.len() != surb_delays.len() {
-            return Err(Error::new(ErrorKind::InvalidSURB, format!("creating SURB for contradictory data: route has len {} while there are {} delays generated", surb_route.len(), surb_delays.len())));
-        }
+impl<C: CipherSuite> SURB<C> {
+    pub fn new(
+        surb_initial_secret: EphemeralSecret<C::Group>,
+        surb_material: SURBMaterial<C::Group>,
+    ) -> Result<(Self, SURBDecryptionKeys)> {
+        let mut surb_payload_key = [0u8; PAYLOAD_KEY_SIZE];
+        OsRng.fill_bytes(&mut surb_payload_key);
+        Self::new_with_payload_key(surb_initial_secret, surb_payload_key, surb_material)
+    }
 
-        let first_hop = surb_route.first().unwrap();
+    /// Creates the SURB with all of its keys derived from the seed and the SURB identifier,
+    /// so that instead of the keys the creator can keep the returned record, which holds
+    /// no secrets, and later recover the keys with `SURBDecryptionKeys::from_seed`.
+    pub fn new_from_seed(
+        seed: &SURBSeed,
+        surb_material: SURBMaterial<C::Group>,
+    ) -> Result<(Self, SURBSeedRecord<C::Group>)> {
+        let identifier = surb_material.surb_route.destination().identifier;
+        let record = SURBSeedRecord {
+            identifier,
+            surb_route: surb_material.surb_route.nodes().to_vec(),
+        };
+        let (surb, _) = Self::new_with_payload_key(
+            seed.initial_secret(&identifier),
+            seed.surb_payload_key(&identifier),
+            surb_material,
+        )?;
+        Ok((surb, record))
+    }
 
-        let (header, payload_keys) = header::SphinxHeader::new(
-            &surb_initial_secret,
-            &surb_route,
-            &surb_delays,
-            &surb_destination,
-        );
+    fn new_with_payload_key(
+        surb_initial_secret: EphemeralSecret<C::Group>,
+        surb_payload_key: PayloadKey,
+        surb_material: SURBMaterial<C::Group>,
+    ) -> Result<(Self, SURBDecryptionKeys)> {
+        let surb_route = surb_material.surb_route;
+        let surb_params = surb_material.surb_params;
 
-        Ok(SURB {
-            SURB_header: header,
-            first_hop_address: first_hop.address,
-            payload_keys,
-        })
+        /* Pre-computes the header of the Sphinx packet which will be used as SURB
+        and encapsulates it into struct together with the address of the first hop in the route of the SURB, and the key
+        which should be used to encrypt the payload. */
+        let (header, payload_keys) =
+            header::SphinxHeader::new(&surb_initial_secret, &surb_route, &surb_params)?;
+
+        Ok((
+            SURB {
+                SURB_header: header,
+                first_hop_address: surb_route.first_hop().address,
+                payload_keys: vec![surb_payload_key],
+            },
+            SURBDecryptionKeys {
+                identifier: surb_route.destination().identifier,
+                surb_payload_key,
+                payload_keys,
+            },
+        ))
     }
 
-    /// Function takes the precomputed surb header, layer encrypts the plaintext payload content
-    /// using the precomputed payload key material and returns the full Sphinx packet
+    /// Function takes the precomputed surb header, encrypts the plaintext payload content
+    /// using the SURB payload keys and returns the full Sphinx packet
     /// together with the address of first hop to which it should be forwarded.
     pub fn use_surb(
         self,
         plaintext_message: &[u8],
         payload_size: usize,
-    ) -> Result<(SphinxPacket, NodeAddressBytes)> {
+    ) -> Result<(SphinxPacket<C>, NodeAddressBytes)> {
         let header = self.SURB_header;
 
         // Note that Payload::encapsulate_message performs checks to verify whether the plaintext
@@ -110,48 +385,82 @@ impl SURB {
         Ok((SphinxPacket { header, payload }, self.first_hop_address))
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// Serializes the SURB using the versioned encoding, whose length is the same for all routes.
+    /// It fails for SURBs recovered from the legacy encoding carrying more than a single key,
+    /// as those can only be serialized with `to_legacy_bytes`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.payload_keys.len() != 1 {
+            return Err(Error::with_reason(
+                ErrorKind::InvalidSURB,
+                ErrorReason::MalformedSURB,
+            ));
+        }
+        Ok(std::iter::once(SURB_FORMAT_V1)
+            .chain(self.to_legacy_bytes())
+            .collect())
+    }
+
+    /// Serializes the SURB using the legacy encoding with all of its payload keys appended,
+    /// so that it could be used by parties not supporting the versioned one.
+    pub fn to_legacy_bytes(&self) -> Vec<u8> {
         self.SURB_header
             .to_bytes()
             .into_iter()
             .chain(self.first_hop_address.as_bytes().iter().cloned())
-            .chain(self.payload_keys.iter().flat_map(|x| x.iter()).cloned())
+            .chain(self.payload_keys.iter().flat_map(|key| key.iter().cloned()))
             .collect()
     }
 
+    /// Recovers the SURB assuming its header was created using the default `SphinxParams`.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        // SURB needs to contain AT LEAST a single payload key
-        if bytes.len() < HEADER_SIZE + NODE_ADDRESS_LENGTH + PAYLOAD_KEY_SIZE {
-            return Err(Error::new(
-                ErrorKind::InvalidSURB,
-                "not enough bytes provided to try to recover a SURB",
-            ));
+        Self::from_bytes_with_params(bytes, &SphinxParams::default())
+    }
+
+    /// Recovers the SURB from either the versioned or the legacy encoding. Their lengths
+    /// never collide, since the version adds a single byte while keys are much longer.
+    pub fn from_bytes_with_params(bytes: &[u8], params: &SphinxParams) -> Result<Self> {
+        let header_size = params.header_size::<C::Group>();
+        let prefix_size = header_size + NODE_ADDRESS_LENGTH;
+        let versioned_size = 1 + prefix_size + PAYLOAD_KEY_SIZE;
+
+        if bytes.len() == versioned_size {
+            if bytes[0] != SURB_FORMAT_V1 {
+                return Err(Error::with_reason(
+                    ErrorKind::InvalidSURB,
+                    ErrorReason::UnsupportedVersion { version: bytes[0] },
+                ));
+            }
+            return Self::from_legacy_bytes(&bytes[1..], params);
         }
+        Self::from_legacy_bytes(bytes, params)
+    }
 
-        let header_bytes = &bytes[..HEADER_SIZE];
-        let first_hop_bytes = &bytes[HEADER_SIZE..HEADER_SIZE + NODE_ADDRESS_LENGTH];
-        let payload_keys_bytes = &bytes[HEADER_SIZE + NODE_ADDRESS_LENGTH..];
-        // make sure that bytes of valid length were sent
-        if payload_keys_bytes.len() % PAYLOAD_KEY_SIZE != 0 {
-            return Err(Error::new(
+    fn from_legacy_bytes(bytes: &[u8], params: &SphinxParams) -> Result<Self> {
+        let header_size = params.header_size::<C::Group>();
+        let prefix_size = header_size + NODE_ADDRESS_LENGTH;
+        if bytes.len() < prefix_size + PAYLOAD_KEY_SIZE
+            || (bytes.len() - prefix_size) % PAYLOAD_KEY_SIZE != 0
+        {
+            return Err(Error::with_reason(
                 ErrorKind::InvalidSURB,
-                "bytes of invalid length provided",
+                ErrorReason::MalformedSURB,
             ));
         }
 
-        let SURB_header = SphinxHeader::from_bytes(header_bytes)?;
-        let first_hop_address = NodeAddressBytes::try_from_byte_slice(first_hop_bytes)?;
+        let header_bytes = &bytes[..header_size];
+        let first_hop_bytes = &bytes[header_size..prefix_size];
 
-        let key_count = payload_keys_bytes.len() / PAYLOAD_KEY_SIZE;
-        let mut payload_keys = Vec::with_capacity(key_count);
+        let SURB_header = SphinxHeader::from_bytes(header_bytes, params)?;
+        let first_hop_address = NodeAddressBytes::try_from_byte_slice(first_hop_bytes)?;
 
-        for i in 0..key_count {
-            let mut payload_key = [0u8; PAYLOAD_KEY_SIZE];
-            payload_key.copy_from_slice(
-                &payload_keys_bytes[i * PAYLOAD_KEY_SIZE..(i + 1) * PAYLOAD_KEY_SIZE],
-            );
-            payload_keys.push(payload_key);
-        }
+        let payload_keys = bytes[prefix_size..]
+            .chunks_exact(PAYLOAD_KEY_SIZE)
+            .map(|chunk| {
+                let mut payload_key = [0u8; PAYLOAD_KEY_SIZE];
+                payload_key.copy_from_slice(chunk);
+                payload_key
+            })
+            .collect();
 
         Ok(SURB {
             SURB_header,
@@ -166,6 +475,7 @@ mod prepare_and_use_process_surb {
     use super::*;
     use crate::constants::NODE_ADDRESS_LENGTH;
     use crate::crypto;
+    use crate::crypto::PUBLIC_KEY_SIZE;
     use crate::header::{delays, HEADER_SIZE};
     use crate::{packet::builder::DEFAULT_PAYLOAD_SIZE, test_utils::fixtures::destination_fixture};
     use std::time::Duration;
@@ -196,25 +506,21 @@ mod prepare_and_use_process_surb {
 
         SURB::new(
             surb_initial_secret,
-            SURBMaterial::new(surb_route, surb_delays, surb_destination),
+            SURBMaterial::new(Route::new(surb_route, surb_delays, surb_destination).unwrap()),
         )
         .unwrap()
+        .0
     }
 
     #[test]
     fn returns_error_if_surb_route_empty() {
-        let surb_route = Vec::new();
-        let surb_destination = destination_fixture();
-        let surb_initial_secret = EphemeralSecret::new();
+        // empty routes are rejected before any SURB material could be created
+        let surb_route: Vec<Node> = Vec::new();
         let surb_delays =
             delays::generate_from_average_duration(surb_route.len(), Duration::from_secs(3));
-        let expected = ErrorKind::InvalidSURB;
 
-        match SURB::new(
-            surb_initial_secret,
-            SURBMaterial::new(surb_route, surb_delays, surb_destination),
-        ) {
-            Err(err) => assert_eq!(expected, err.kind()),
+        match Route::new(surb_route, surb_delays, destination_fixture()) {
+            Err(err) => assert_eq!(Some(&ErrorReason::EmptyRoute), err.reason()),
             _ => panic!("Should have returned an error when route empty"),
         };
     }
@@ -229,18 +535,63 @@ mod prepare_and_use_process_surb {
     fn to_bytes_returns_correct_value() {
         let pre_surb = SURB_fixture();
 
-        let pre_surb_bytes = pre_surb.to_bytes();
+        let pre_surb_bytes = pre_surb.to_bytes().unwrap();
         let expected = [
+            vec![SURB_FORMAT_V1],
             pre_surb.SURB_header.to_bytes(),
             [5u8; NODE_ADDRESS_LENGTH].to_vec(),
             pre_surb.payload_keys[0].to_vec(),
-            pre_surb.payload_keys[1].to_vec(),
-            pre_surb.payload_keys[2].to_vec(),
         ]
         .concat();
         assert_eq!(pre_surb_bytes, expected);
     }
 
+    #[test]
+    fn versioned_encoding_has_the_same_length_for_all_routes() {
+        let (_, node_pk) = crypto::keygen();
+        let node = Node {
+            address: NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node_pk,
+        };
+        let surb_delays = delays::generate_from_average_duration(1, Duration::from_secs(3));
+        let (short_surb, _): (SURB, _) = SURB::new(
+            EphemeralSecret::new(),
+            SURBMaterial::new(Route::new(vec![node], surb_delays, destination_fixture()).unwrap()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            short_surb.to_bytes().unwrap().len(),
+            SURB_fixture().to_bytes().unwrap().len()
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn can_be_recovered_from_legacy_bytes_with_key_per_hop() {
+        let dummy_SURB = SURB_fixture();
+        let legacy_bytes = [
+            dummy_SURB.SURB_header.to_bytes(),
+            [5u8; NODE_ADDRESS_LENGTH].to_vec(),
+            [1u8; PAYLOAD_KEY_SIZE * 3].to_vec(),
+        ]
+        .concat();
+
+        let recovered_SURB: SURB = SURB::from_bytes(&legacy_bytes).unwrap();
+        assert_eq!(3, recovered_SURB.payload_keys.len());
+        assert_eq!(legacy_bytes, recovered_SURB.to_legacy_bytes());
+        assert!(recovered_SURB.to_bytes().is_err());
+    }
+
+    #[test]
+    fn fails_to_recover_from_bytes_of_invalid_length_or_version() {
+        let mut bytes = SURB_fixture().to_bytes().unwrap();
+        assert!(SURB::<DefaultCipherSuite>::from_bytes(&bytes[..bytes.len() - 2]).is_err());
+
+        bytes[0] = SURB_FORMAT_V1 + 1;
+        assert!(SURB::<DefaultCipherSuite>::from_bytes(&bytes).is_err());
+    }
+
     #[test]
     fn returns_error_is_payload_too_large() {
         let pre_surb = SURB_fixture();
@@ -257,19 +608,14 @@ mod prepare_and_use_process_surb {
     #[allow(non_snake_case)]
     fn can_be_converted_to_and_from_bytes() {
         let dummy_SURB = SURB_fixture();
-        let bytes = dummy_SURB.to_bytes();
-        let recovered_SURB = SURB::from_bytes(&bytes).unwrap();
+        let bytes = dummy_SURB.to_bytes().unwrap();
+        let recovered_SURB: SURB = SURB::from_bytes(&bytes).unwrap();
 
         assert_eq!(
             dummy_SURB.first_hop_address,
             recovered_SURB.first_hop_address
         );
-        for i in 0..dummy_SURB.payload_keys.len() {
-            assert_eq!(
-                dummy_SURB.payload_keys[i].to_vec(),
-                recovered_SURB.payload_keys[i].to_vec()
-            )
-        }
+        assert_eq!(dummy_SURB.payload_keys, recovered_SURB.payload_keys);
 
         // TODO: saner way of comparing headers...
         assert_eq!(
@@ -277,4 +623,91 @@ mod prepare_and_use_process_surb {
             dummy_SURB.SURB_header.to_bytes()
         );
     }
+
+    #[test]
+    fn reply_can_only_be_recovered_by_the_surb_creator() {
+        let (node_sk, node_pk) = crypto::keygen();
+        let surb_route = vec![Node {
+            address: NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node_pk,
+        }];
+        let surb_delays =
+            delays::generate_from_average_duration(surb_route.len(), Duration::from_secs(3));
+        let (surb, surb_keys): (SURB, _) =
+            SURBMaterial::new(Route::new(surb_route, surb_delays, destination_fixture()).unwrap())
+                .construct_SURB()
+                .unwrap();
+
+        let plaintext_message = vec![42u8; 160];
+        let (packet, _) = surb
+            .use_surb(&plaintext_message, DEFAULT_PAYLOAD_SIZE)
+            .unwrap();
+        let payload = match packet.process(&node_sk).unwrap() {
+            crate::ProcessedPacket::FinalHop(_, _, payload, _, _) => payload,
+            _ => panic!("expected final hop"),
+        };
+
+        let payload_bytes = payload.as_bytes().to_vec();
+        assert!(Payload::<DefaultCipherSuite>::from_bytes(&payload_bytes)
+            .unwrap()
+            .recover_plaintext()
+            .is_err());
+        assert_eq!(plaintext_message, surb_keys.recover_reply(payload).unwrap());
+    }
+
+    #[test]
+    fn keys_derived_from_seed_can_be_regenerated_by_the_creator() {
+        let (node_sk, node_pk) = crypto::keygen();
+        let surb_route = vec![Node {
+            address: NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node_pk,
+        }];
+        let surb_delays =
+            delays::generate_from_average_duration(surb_route.len(), Duration::from_secs(3));
+        let destination = destination_fixture();
+        let seed = SURBSeed::new();
+
+        let (surb, record): (SURB, _) = SURB::new_from_seed(
+            &seed,
+            SURBMaterial::new(
+                Route::new(surb_route.clone(), surb_delays, destination.clone()).unwrap(),
+            ),
+        )
+        .unwrap();
+        assert_eq!(&destination.identifier, record.identifier());
+
+        // only the record has to be stored, and it does not hold any secret
+        let record_bytes = record.to_bytes();
+        assert_eq!(
+            IDENTIFIER_LENGTH + NODE_ADDRESS_LENGTH + PUBLIC_KEY_SIZE,
+            record_bytes.len()
+        );
+        let recovered_record = SURBSeedRecord::from_bytes(&record_bytes).unwrap();
+        assert_eq!(record_bytes, recovered_record.to_bytes());
+        assert!(SURBSeedRecord::<DefaultGroup>::from_bytes(&record_bytes[1..]).is_err());
+
+        let regenerated_keys =
+            SURBDecryptionKeys::from_seed::<DefaultCipherSuite>(&seed, &recovered_record);
+        assert_eq!(destination.identifier, *regenerated_keys.identifier());
+
+        // keys are bound to the seed
+        let other_keys =
+            SURBDecryptionKeys::from_seed::<DefaultCipherSuite>(&SURBSeed::new(), &record);
+        assert_ne!(
+            regenerated_keys.to_bytes()[IDENTIFIER_LENGTH..],
+            other_keys.to_bytes()[IDENTIFIER_LENGTH..]
+        );
+
+        let plaintext_message = vec![42u8; 160];
+        let (packet, _) = surb
+            .use_surb(&plaintext_message, DEFAULT_PAYLOAD_SIZE)
+            .unwrap();
+        match packet.process(&node_sk).unwrap() {
+            crate::ProcessedPacket::FinalHop(_, _, payload, _, _) => assert_eq!(
+                plaintext_message,
+                regenerated_keys.recover_reply(payload).unwrap()
+            ),
+            _ => panic!("expected final hop"),
+        }
+    }
 }
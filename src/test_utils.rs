@@ -24,6 +24,7 @@ pub mod fixtures {
         constants::{
             BLINDING_FACTOR_SIZE, DESTINATION_ADDRESS_LENGTH, HEADER_INTEGRITY_MAC_SIZE,
             IDENTIFIER_LENGTH, INTEGRITY_MAC_KEY_SIZE, NODE_ADDRESS_LENGTH, PAYLOAD_KEY_SIZE,
+            REPLAY_TAG_SIZE,
         },
         crypto,
         header::{
@@ -63,6 +64,7 @@ pub mod fixtures {
             header_integrity_hmac_key: [2u8; INTEGRITY_MAC_KEY_SIZE],
             payload_key: [3u8; PAYLOAD_KEY_SIZE],
             blinding_factor: [4u8; BLINDING_FACTOR_SIZE],
+            replay_tag: [5u8; REPLAY_TAG_SIZE],
         }
     }
 
@@ -71,11 +73,11 @@ pub mod fixtures {
     }
 
     pub fn encrypted_routing_information_fixture() -> EncryptedRoutingInformation {
-        EncryptedRoutingInformation::from_bytes([5u8; ENCRYPTED_ROUTING_INFO_SIZE])
+        EncryptedRoutingInformation::from_bytes(vec![5u8; ENCRYPTED_ROUTING_INFO_SIZE])
     }
 
     pub fn header_integrity_mac_fixture() -> HeaderIntegrityMac {
-        HeaderIntegrityMac::from_bytes([6u8; HEADER_INTEGRITY_MAC_SIZE])
+        HeaderIntegrityMac::from_bytes(&[6u8; HEADER_INTEGRITY_MAC_SIZE])
     }
 
     pub fn encapsulated_routing_information_fixture() -> EncapsulatedRoutingInformation {
@@ -16,7 +16,7 @@ extern crate sphinx_packet;
 
 use sphinx_packet::crypto;
 use sphinx_packet::header::delays;
-use sphinx_packet::route::{Destination, Node};
+use sphinx_packet::route::{Destination, Node, Route};
 use sphinx_packet::SphinxPacket;
 
 // const PAYLOAD_SIZE: usize = 1024;
@@ -61,11 +61,11 @@ mod create_and_process_sphinx_packet {
         );
 
         let message = vec![13u8, 16];
-        let sphinx_packet =
-            SphinxPacket::new(message.clone(), &route, &destination, &delays).unwrap();
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        let sphinx_packet = SphinxPacket::new(message.clone(), &route).unwrap();
 
         let next_sphinx_packet_1 = match sphinx_packet.process(&node1_sk).unwrap() {
-            ProcessedPacket::ForwardHop(next_packet, next_hop_addr1, _delay1) => {
+            ProcessedPacket::ForwardHop(next_packet, next_hop_addr1, _delay1, _, _) => {
                 assert_eq!(
                     NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
                     next_hop_addr1
@@ -75,27 +75,749 @@ mod create_and_process_sphinx_packet {
             _ => panic!(),
         };
 
-        let next_sphinx_packet_2 = match next_sphinx_packet_1.process(&node2_sk).unwrap() {
-            ProcessedPacket::ForwardHop(next_packet, next_hop_addr2, _delay2) => {
-                assert_eq!(
-                    NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]),
-                    next_hop_addr2
-                );
-                next_packet
+        let next_sphinx_packet_2 = match next_sphinx_packet_1.process(&node2_sk).unwrap() {
+            ProcessedPacket::ForwardHop(next_packet, next_hop_addr2, _delay2, _, _) => {
+                assert_eq!(
+                    NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]),
+                    next_hop_addr2
+                );
+                next_packet
+            }
+            _ => panic!(),
+        };
+
+        match next_sphinx_packet_2.process(&node3_sk).unwrap() {
+            ProcessedPacket::FinalHop(_, _, payload, _, _) => {
+                let zero_bytes = vec![0u8; SECURITY_PARAMETER];
+                let additional_padding =
+                    vec![0u8; PAYLOAD_SIZE - SECURITY_PARAMETER - message.len() - 1];
+                let expected_payload = [zero_bytes, message, vec![1], additional_padding].concat();
+                assert_eq!(expected_payload, payload.as_bytes());
+            }
+            _ => panic!(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod create_and_process_sphinx_packet_with_alternative_cipher_suite {
+    use super::*;
+    use sphinx_packet::crypto::ChaCha20Blake2bSuite;
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{
+        constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH},
+        ProcessedPacket, SphinxPacketBuilder,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn returns_the_correct_data_at_each_hop_for_route_of_2_mixnodes() {
+        let (node1_sk, node1_pk) = crypto::keygen();
+        let node1 = Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node1_pk,
+        );
+        let (node2_sk, node2_pk) = crypto::keygen();
+        let node2 = Node::new(
+            NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+            node2_pk,
+        );
+
+        let route = [node1, node2];
+        let average_delay = Duration::from_secs_f64(1.0);
+        let delays = delays::generate_from_average_duration(route.len(), average_delay);
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+            [4u8; IDENTIFIER_LENGTH],
+        );
+
+        let message = vec![13u8, 16];
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        let sphinx_packet = SphinxPacketBuilder::<ChaCha20Blake2bSuite>::new()
+            .build_packet(&message, &route)
+            .unwrap();
+
+        // the packet should survive being sent through the network
+        let sphinx_packet =
+            SphinxPacket::<ChaCha20Blake2bSuite>::from_bytes(&sphinx_packet.to_bytes()).unwrap();
+
+        let next_sphinx_packet = match sphinx_packet.process(&node1_sk).unwrap() {
+            ProcessedPacket::ForwardHop(next_packet, next_hop_addr, _, _, _) => {
+                assert_eq!(
+                    NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+                    next_hop_addr
+                );
+                next_packet
+            }
+            _ => panic!(),
+        };
+
+        match next_sphinx_packet.process(&node2_sk).unwrap() {
+            ProcessedPacket::FinalHop(_, _, payload, _, _) => {
+                assert_eq!(message, payload.recover_plaintext().unwrap());
+            }
+            _ => panic!(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod create_and_process_sphinx_packet_in_alternative_groups {
+    use super::*;
+    use sphinx_packet::crypto::{
+        Aes128HmacSha256Suite, ChaCha20Blake2bSuite, CipherSuite, PrivateKey, PublicKey,
+        Ristretto255, X448,
+    };
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{
+        constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH},
+        ProcessedPacket, SphinxPacketBuilder,
+    };
+    use std::time::Duration;
+
+    fn build_and_process_through_3_mixnodes<C: CipherSuite>() {
+        let node1_sk = PrivateKey::<C::Group>::new();
+        let node1 = Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            PublicKey::from(&node1_sk),
+        );
+        let node2_sk = PrivateKey::<C::Group>::new();
+        let node2 = Node::new(
+            NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+            PublicKey::from(&node2_sk),
+        );
+        let node3_sk = PrivateKey::<C::Group>::new();
+        let node3 = Node::new(
+            NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]),
+            PublicKey::from(&node3_sk),
+        );
+
+        let route = [node1, node2, node3];
+        let average_delay = Duration::from_secs_f64(1.0);
+        let delays = delays::generate_from_average_duration(route.len(), average_delay);
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+            [4u8; IDENTIFIER_LENGTH],
+        );
+
+        let message = vec![13u8, 16];
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        let sphinx_packet = SphinxPacketBuilder::<C>::new()
+            .build_packet(&message, &route)
+            .unwrap();
+
+        // the packet should survive being sent through the network
+        let sphinx_packet = SphinxPacket::<C>::from_bytes(&sphinx_packet.to_bytes()).unwrap();
+
+        let next_sphinx_packet_1 = match sphinx_packet.process(&node1_sk).unwrap() {
+            ProcessedPacket::ForwardHop(next_packet, next_hop_addr, _, _, _) => {
+                assert_eq!(
+                    NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+                    next_hop_addr
+                );
+                next_packet
+            }
+            _ => panic!(),
+        };
+
+        let next_sphinx_packet_2 = match next_sphinx_packet_1.process(&node2_sk).unwrap() {
+            ProcessedPacket::ForwardHop(next_packet, next_hop_addr, _, _, _) => {
+                assert_eq!(
+                    NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]),
+                    next_hop_addr
+                );
+                next_packet
+            }
+            _ => panic!(),
+        };
+
+        match next_sphinx_packet_2.process(&node3_sk).unwrap() {
+            ProcessedPacket::FinalHop(_, _, payload, _, _) => {
+                assert_eq!(message, payload.recover_plaintext().unwrap());
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn returns_the_correct_data_at_each_hop_when_using_ristretto255() {
+        build_and_process_through_3_mixnodes::<Aes128HmacSha256Suite<Ristretto255>>()
+    }
+
+    #[test]
+    fn returns_the_correct_data_at_each_hop_when_using_x448() {
+        build_and_process_through_3_mixnodes::<Aes128HmacSha256Suite<X448>>()
+    }
+
+    #[test]
+    fn returns_the_correct_data_at_each_hop_when_using_x448_with_chacha20_blake2b() {
+        build_and_process_through_3_mixnodes::<ChaCha20Blake2bSuite<X448>>()
+    }
+}
+
+#[cfg(test)]
+mod create_and_process_sphinx_packet_with_custom_params {
+    use super::*;
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{
+        constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH},
+        ProcessedPacket, SphinxPacketBuilder, SphinxParams,
+    };
+    use std::time::Duration;
+
+    fn node_address(i: u8, params: &SphinxParams) -> NodeAddressBytes {
+        // addresses can only be as long as the params allow
+        let mut address = [0u8; NODE_ADDRESS_LENGTH];
+        address[..params.node_address_length()].fill(i + 1);
+        NodeAddressBytes::from_bytes(address)
+    }
+
+    fn build_and_process_through_mixnodes(params: SphinxParams, route_len: usize) {
+        let (node_sks, route): (Vec<_>, Vec<_>) = (0..route_len)
+            .map(|i| {
+                let (sk, pk) = crypto::keygen();
+                (sk, Node::new(node_address(i as u8, &params), pk))
+            })
+            .unzip();
+
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_millis(10));
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+            [4u8; IDENTIFIER_LENGTH],
+        );
+
+        let message = vec![13u8, 16];
+        let route =
+            Route::new_with_params(route, delays.clone(), destination.clone(), &params).unwrap();
+        let sphinx_packet: SphinxPacket = SphinxPacketBuilder::new()
+            .with_params(params)
+            .build_packet(&message, &route)
+            .unwrap();
+
+        let packet_bytes = sphinx_packet.to_bytes();
+        assert_eq!(packet_bytes.len(), sphinx_packet.len());
+        let mut sphinx_packet: SphinxPacket =
+            SphinxPacket::from_bytes_with_params(&packet_bytes, &params).unwrap();
+
+        for (i, node_sk) in node_sks.iter().enumerate() {
+            match sphinx_packet.process(node_sk).unwrap() {
+                ProcessedPacket::ForwardHop(next_packet, next_hop_addr, delay, _, _) => {
+                    assert_eq!(route.nodes()[i + 1].address, next_hop_addr);
+                    assert_eq!(delays[i], delay);
+                    sphinx_packet = *next_packet;
+                }
+                ProcessedPacket::FinalHop(final_destination, _, payload, _, _) => {
+                    assert_eq!(route_len - 1, i);
+                    assert_eq!(destination.address, final_destination);
+                    assert_eq!(message, payload.recover_plaintext().unwrap());
+                    return;
+                }
+                _ => panic!(),
+            }
+        }
+        panic!("packet has not reached its destination")
+    }
+
+    #[test]
+    fn returns_the_correct_data_at_each_hop_for_route_of_7_mixnodes() {
+        let params = SphinxParams::new(7, NODE_ADDRESS_LENGTH, 16, 8).unwrap();
+        build_and_process_through_mixnodes(params, 7)
+    }
+
+    #[test]
+    fn returns_the_correct_data_at_each_hop_with_short_addresses_and_long_macs() {
+        let params = SphinxParams::new(3, 16, 32, 4).unwrap();
+        build_and_process_through_mixnodes(params, 3);
+        build_and_process_through_mixnodes(params, 1);
+    }
+
+    #[test]
+    fn it_is_not_possible_to_recover_packet_using_different_params() {
+        let (node_sk, node_pk) = crypto::keygen();
+        let route = [Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node_pk,
+        )];
+        let delays = delays::generate_from_average_duration(1, Duration::from_millis(10));
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+            [4u8; IDENTIFIER_LENGTH],
+        );
+
+        let params = SphinxParams::new(7, NODE_ADDRESS_LENGTH, 16, 8).unwrap();
+        let route = Route::new_with_params(route.to_vec(), delays, destination, &params).unwrap();
+        let sphinx_packet: SphinxPacket = SphinxPacketBuilder::new()
+            .with_params(params)
+            .build_packet(vec![42u8], &route)
+            .unwrap();
+        let packet_bytes = sphinx_packet.to_bytes();
+
+        assert!(<SphinxPacket>::from_bytes_with_params(&packet_bytes, &params).is_ok());
+        // the payload is of dynamic size so the bytes would still 'fit' the default header,
+        // however, the integrity mac would be read from a wrong place
+        let packet: SphinxPacket = SphinxPacket::from_bytes(&packet_bytes).unwrap();
+        assert!(packet.process(&node_sk).is_err());
+    }
+}
+
+#[cfg(test)]
+mod process_sphinx_packet_of_multiple_versions {
+    use super::*;
+    use sphinx_packet::header::routing::Version;
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{
+        constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH},
+        ErrorReason, HeaderLayouts, ProcessedPacket, SphinxPacketBuilder, SphinxParams,
+    };
+    use std::time::Duration;
+
+    fn next_major_version() -> Version {
+        Version::from_parts(Version::new().major() + 1, 0, 0)
+    }
+
+    fn packet_bytes(params: SphinxParams, route: Vec<Node>, message: &[u8]) -> Vec<u8> {
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_millis(10));
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+            [4u8; IDENTIFIER_LENGTH],
+        );
+        let route = Route::new_with_params(route, delays, destination, &params).unwrap();
+        let sphinx_packet: SphinxPacket = SphinxPacketBuilder::new()
+            .with_params(params)
+            .build_packet(message, &route)
+            .unwrap();
+        sphinx_packet.to_bytes()
+    }
+
+    #[test]
+    fn nodes_process_packets_of_all_of_their_layouts() {
+        let current = SphinxParams::default();
+        let next = SphinxParams::new(7, NODE_ADDRESS_LENGTH, 16, 8)
+            .unwrap()
+            .with_version(next_major_version());
+        let layouts = HeaderLayouts::new(vec![current, next]).unwrap();
+
+        let (node_sks, route): (Vec<_>, Vec<_>) = (0..3u8)
+            .map(|i| {
+                let (sk, pk) = crypto::keygen();
+                let address = NodeAddressBytes::from_bytes([i; NODE_ADDRESS_LENGTH]);
+                (sk, Node::new(address, pk))
+            })
+            .unzip();
+
+        for params in [current, next] {
+            let message = vec![13u8, 16];
+            let mut bytes = packet_bytes(params, route.clone(), &message);
+            for (i, node_sk) in node_sks.iter().enumerate() {
+                let processed =
+                    <SphinxPacket>::process_with_layouts(&bytes, node_sk, &layouts).unwrap();
+                assert_eq!(params.version(), processed.version());
+                match processed {
+                    ProcessedPacket::ForwardHop(next_packet, next_hop_addr, ..) => {
+                        assert_eq!(route[i + 1].address, next_hop_addr);
+                        assert_eq!(&params, next_packet.header.params());
+                        bytes = next_packet.to_bytes();
+                    }
+                    ProcessedPacket::FinalHop(_, _, payload, ..) => {
+                        assert_eq!(route.len() - 1, i);
+                        assert_eq!(message, payload.recover_plaintext().unwrap());
+                    }
+                    _ => panic!(),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nodes_process_packets_of_layouts_differing_only_in_version() {
+        let current = SphinxParams::default();
+        let next = SphinxParams::default().with_version(next_major_version());
+        let (node_sk, node_pk) = crypto::keygen();
+        let route = vec![Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node_pk,
+        )];
+
+        for layouts in [vec![current, next], vec![next, current]] {
+            let layouts = HeaderLayouts::new(layouts).unwrap();
+            for params in [current, next] {
+                let bytes = packet_bytes(params, route.clone(), &[42u8]);
+                let processed =
+                    <SphinxPacket>::process_with_layouts(&bytes, &node_sk, &layouts).unwrap();
+                assert_eq!(params.version(), processed.version());
+                match processed {
+                    ProcessedPacket::FinalHop(_, _, payload, ..) => {
+                        assert_eq!(vec![42u8], payload.recover_plaintext().unwrap())
+                    }
+                    _ => panic!(),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nodes_reject_packets_of_unsupported_major_version() {
+        let (node_sk, node_pk) = crypto::keygen();
+        let route = vec![Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node_pk,
+        )];
+        // same layout, so that only the version tells the packets apart
+        let next = SphinxParams::default().with_version(next_major_version());
+        let bytes = packet_bytes(next, route, &[42u8]);
+
+        let err = <SphinxPacket>::process_with_layouts(&bytes, &node_sk, &HeaderLayouts::default())
+            .err()
+            .unwrap();
+        assert_eq!(
+            Some(&ErrorReason::UnsupportedVersion {
+                version: next_major_version().major()
+            }),
+            err.reason()
+        );
+        let packet: SphinxPacket = SphinxPacket::from_bytes(&bytes).unwrap();
+        assert!(packet.process(&node_sk).is_err());
+    }
+}
+
+#[cfg(test)]
+mod create_and_process_sphinx_packet_with_hop_records {
+    use super::*;
+    use sphinx_packet::header::tlv::{TlvRecord, TlvStream};
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{
+        constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH},
+        ProcessedPacket, SphinxPacketBuilder,
+    };
+    use std::time::Duration;
+
+    fn records_for_hop(i: usize) -> TlvStream {
+        TlvStream::from_records(vec![
+            TlvRecord::new(1, vec![i as u8; 5 + i]),
+            TlvRecord::new(300, vec![42u8; 3]),
+        ])
+        .unwrap()
+    }
+
+    fn route_of_length(route_len: usize) -> (Vec<crypto::PrivateKey>, Vec<Node>) {
+        (0..route_len)
+            .map(|i| {
+                let (sk, pk) = crypto::keygen();
+                (
+                    sk,
+                    Node::new(
+                        NodeAddressBytes::from_bytes([i as u8; NODE_ADDRESS_LENGTH]),
+                        pk,
+                    ),
+                )
+            })
+            .unzip()
+    }
+
+    fn destination() -> Destination {
+        Destination::new(
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+            [4u8; IDENTIFIER_LENGTH],
+        )
+    }
+
+    #[test]
+    fn each_hop_receives_its_own_records() {
+        let (node_sks, route) = route_of_length(4);
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_millis(10));
+        let destination = destination();
+
+        // the second hop does not get any records and uses the regular layout
+        let message = vec![13u8, 16];
+        let route = Route::new(route.to_vec(), delays.clone(), destination.clone()).unwrap();
+        let sphinx_packet: SphinxPacket = SphinxPacketBuilder::new()
+            .with_hop_records(0, records_for_hop(0))
+            .with_hop_records(2, records_for_hop(2))
+            .with_hop_records(3, records_for_hop(3))
+            .build_packet(&message, &route)
+            .unwrap();
+        let mut sphinx_packet: SphinxPacket =
+            SphinxPacket::from_bytes(&sphinx_packet.to_bytes()).unwrap();
+
+        for (i, node_sk) in node_sks.iter().enumerate() {
+            let expected_records = if i == 1 {
+                TlvStream::new()
+            } else {
+                records_for_hop(i)
+            };
+            match sphinx_packet.process(node_sk).unwrap() {
+                ProcessedPacket::ForwardHop(next_packet, next_hop_addr, delay, records, _) => {
+                    assert_eq!(route.nodes()[i + 1].address, next_hop_addr);
+                    assert_eq!(delays[i], delay);
+                    assert_eq!(expected_records, records);
+                    sphinx_packet = *next_packet;
+                }
+                ProcessedPacket::FinalHop(final_destination, _, payload, records, _) => {
+                    assert_eq!(route.len() - 1, i);
+                    assert_eq!(destination.address, final_destination);
+                    assert_eq!(expected_records, records);
+                    assert_eq!(message, payload.recover_plaintext().unwrap());
+                    return;
+                }
+                _ => panic!(),
+            }
+        }
+        panic!("packet has not reached its destination")
+    }
+
+    #[test]
+    fn it_is_not_possible_to_attach_records_that_do_not_fit_in_the_header() {
+        let (_, route) = route_of_length(3);
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_millis(10));
+        let records = TlvStream::from_records(vec![TlvRecord::new(1, vec![0u8; 1000])]).unwrap();
+
+        let route = Route::new(route.to_vec(), delays, destination()).unwrap();
+        let result: sphinx_packet::Result<SphinxPacket> = SphinxPacketBuilder::new()
+            .with_hop_records(1, records)
+            .build_packet(vec![42u8], &route);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn it_is_not_possible_to_attach_records_to_hop_outside_the_route() {
+        let (_, route) = route_of_length(2);
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_millis(10));
+
+        let route = Route::new(route.to_vec(), delays, destination()).unwrap();
+        let result: sphinx_packet::Result<SphinxPacket> = SphinxPacketBuilder::new()
+            .with_hop_records(2, records_for_hop(2))
+            .build_packet(vec![42u8], &route);
+        assert!(result.is_err());
+    }
+}
+
+#[cfg(test)]
+mod processing_replayed_sphinx_packet {
+    use super::*;
+    use sphinx_packet::replay::{BloomReplayFilter, ExactReplayFilter, ReplayFilter};
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{
+        constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH},
+        ErrorKind, ProcessedPacket,
+    };
+    use std::time::Duration;
+
+    fn check_replays_are_rejected<F: ReplayFilter>(mut replay_filter: F) {
+        let (node1_sk, node1_pk) = crypto::keygen();
+        let node1 = Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node1_pk,
+        );
+        let (_, node2_pk) = crypto::keygen();
+        let node2 = Node::new(
+            NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+            node2_pk,
+        );
+        let route = [node1, node2];
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_millis(10));
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+            [4u8; IDENTIFIER_LENGTH],
+        );
+
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        let packet_bytes = SphinxPacket::new(vec![42u8], &route).unwrap().to_bytes();
+
+        // a corrupted copy must not prevent the original packet from being processed
+        let mut corrupted_bytes = packet_bytes.clone();
+        corrupted_bytes[40] ^= 1;
+        let corrupted_packet: SphinxPacket = SphinxPacket::from_bytes(&corrupted_bytes).unwrap();
+        assert_ne!(
+            ErrorKind::Replay,
+            corrupted_packet
+                .process_with_replay_check(&node1_sk, &mut replay_filter)
+                .err()
+                .unwrap()
+                .kind()
+        );
+
+        let packet: SphinxPacket = SphinxPacket::from_bytes(&packet_bytes).unwrap();
+        match packet
+            .process_with_replay_check(&node1_sk, &mut replay_filter)
+            .unwrap()
+        {
+            ProcessedPacket::ForwardHop(_, next_hop_addr, _, _, _) => {
+                assert_eq!(route.nodes()[1].address, next_hop_addr)
+            }
+            _ => panic!(),
+        }
+
+        let replayed_packet: SphinxPacket = SphinxPacket::from_bytes(&packet_bytes).unwrap();
+        assert_eq!(
+            ErrorKind::Replay,
+            replayed_packet
+                .process_with_replay_check(&node1_sk, &mut replay_filter)
+                .err()
+                .unwrap()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn replayed_packet_is_rejected_by_exact_filter() {
+        check_replays_are_rejected(ExactReplayFilter::new())
+    }
+
+    #[test]
+    fn replayed_packet_is_rejected_by_bloom_filter() {
+        check_replays_are_rejected(BloomReplayFilter::new(1000, 0.0001).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod processing_sphinx_packet_with_key_cache {
+    use super::*;
+    use sphinx_packet::key_cache::RoutingKeysCache;
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{
+        constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH},
+        ProcessedPacket, SphinxPacketBuilder,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn keys_are_only_derived_once_for_packets_with_reused_initial_secret() {
+        let (node1_sk, node1_pk) = crypto::keygen();
+        let node1 = Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node1_pk,
+        );
+        let (_, node2_pk) = crypto::keygen();
+        let node2 = Node::new(
+            NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+            node2_pk,
+        );
+        let route = [node1, node2];
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_millis(10));
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+            [4u8; IDENTIFIER_LENGTH],
+        );
+
+        let initial_secret = crypto::EphemeralSecret::new();
+        let builder: SphinxPacketBuilder =
+            SphinxPacketBuilder::new().with_initial_secret(&initial_secret);
+        let mut cache = RoutingKeysCache::new(16).unwrap();
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        for i in 0..3u8 {
+            let packet = builder.build_packet(vec![i], &route).unwrap();
+            match packet
+                .process_with_key_cache(&node1_sk, &mut cache)
+                .unwrap()
+            {
+                ProcessedPacket::ForwardHop(_, next_hop_addr, _, _, _) => {
+                    assert_eq!(route.nodes()[1].address, next_hop_addr)
+                }
+                _ => panic!(),
+            }
+        }
+        assert_eq!(1, cache.misses());
+        assert_eq!(2, cache.hits());
+    }
+}
+
+#[cfg(test)]
+mod processing_sphinx_packet_with_node_keyring {
+    use super::*;
+    use sphinx_packet::replay::ExactReplayFilter;
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{
+        constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH},
+        ErrorKind, NodeKeyring, ProcessedPacket, SphinxPacketBuilder,
+    };
+    use std::time::Duration;
+
+    fn packet_bytes_for(node_pk: crypto::PublicKey, bound_epoch: Option<u32>) -> Vec<u8> {
+        let route = [Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node_pk,
+        )];
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_millis(10));
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+            [4u8; IDENTIFIER_LENGTH],
+        );
+
+        let mut builder = SphinxPacketBuilder::new();
+        if let Some(epoch) = bound_epoch {
+            builder = builder.with_hop_epoch(0, epoch);
+        }
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        let packet: SphinxPacket = builder.build_packet(vec![42u8], &route).unwrap();
+        packet.to_bytes()
+    }
+
+    fn process(keyring: &mut NodeKeyring, packet_bytes: &[u8]) -> sphinx_packet::Result<()> {
+        match keyring.process(SphinxPacket::from_bytes(packet_bytes).unwrap())? {
+            ProcessedPacket::FinalHop(_, _, payload, _, _) => {
+                assert_eq!(vec![42u8], payload.recover_plaintext().unwrap());
+                Ok(())
             }
-            _ => panic!(),
-        };
+            _ => panic!("expected final hop"),
+        }
+    }
 
-        match next_sphinx_packet_2.process(&node3_sk).unwrap() {
-            ProcessedPacket::FinalHop(_, _, payload) => {
-                let zero_bytes = vec![0u8; SECURITY_PARAMETER];
-                let additional_padding =
-                    vec![0u8; PAYLOAD_SIZE - SECURITY_PARAMETER - message.len() - 1];
-                let expected_payload = [zero_bytes, message, vec![1], additional_padding].concat();
-                assert_eq!(expected_payload, payload.as_bytes());
-            }
-            _ => panic!(),
-        };
+    #[test]
+    fn packets_for_keys_of_all_known_epochs_are_processed() {
+        let (epoch1_sk, epoch1_pk) = crypto::keygen();
+        let (epoch2_sk, epoch2_pk) = crypto::keygen();
+        let (epoch3_sk, epoch3_pk) = crypto::keygen();
+
+        let mut keyring = NodeKeyring::new(1, epoch1_sk, ExactReplayFilter::new());
+        keyring
+            .set_next(2, epoch2_sk, ExactReplayFilter::new())
+            .unwrap();
+
+        let epoch1_packet = packet_bytes_for(epoch1_pk, Some(1));
+        let early_epoch2_packet = packet_bytes_for(epoch2_pk, None);
+        process(&mut keyring, &epoch1_packet).unwrap();
+        process(&mut keyring, &early_epoch2_packet).unwrap();
+
+        keyring.rotate().unwrap();
+        keyring
+            .set_next(3, epoch3_sk, ExactReplayFilter::new())
+            .unwrap();
+        let late_epoch1_packet = packet_bytes_for(epoch1_pk, Some(1));
+        let epoch3_packet = packet_bytes_for(epoch3_pk, Some(3));
+        process(&mut keyring, &late_epoch1_packet).unwrap();
+        process(&mut keyring, &epoch3_packet).unwrap();
+
+        // replay state is kept for the whole lifetime of the epoch key
+        assert_eq!(
+            ErrorKind::Replay,
+            process(&mut keyring, &epoch1_packet).unwrap_err().kind()
+        );
+        assert_eq!(
+            ErrorKind::Replay,
+            process(&mut keyring, &early_epoch2_packet)
+                .unwrap_err()
+                .kind()
+        );
+
+        keyring.expire_previous();
+        assert_eq!(
+            ErrorKind::InvalidHeader,
+            process(&mut keyring, &packet_bytes_for(epoch1_pk, None))
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn packet_bound_to_different_epoch_is_rejected() {
+        let (epoch1_sk, epoch1_pk) = crypto::keygen();
+        let mut keyring = NodeKeyring::new(1, epoch1_sk, ExactReplayFilter::new());
+
+        let packet = packet_bytes_for(epoch1_pk, Some(2));
+        assert_eq!(
+            ErrorKind::InvalidRouting,
+            process(&mut keyring, &packet).unwrap_err().kind()
+        );
     }
 }
 
@@ -139,14 +861,15 @@ mod converting_sphinx_packet_to_and_from_bytes {
         );
 
         let message = vec![13u8, 16];
-        let sphinx_packet =
-            SphinxPacket::new(message.clone(), &route, &destination, &delays).unwrap();
+        let route = Route::new(route.to_vec(), delays.clone(), destination).unwrap();
+        let sphinx_packet = SphinxPacket::new(message.clone(), &route).unwrap();
 
         let sphinx_packet_bytes = sphinx_packet.to_bytes();
-        let recovered_packet = SphinxPacket::from_bytes(&sphinx_packet_bytes).unwrap();
+        let recovered_packet: SphinxPacket =
+            SphinxPacket::from_bytes(&sphinx_packet_bytes).unwrap();
 
         let next_sphinx_packet_1 = match recovered_packet.process(&node1_sk).unwrap() {
-            ProcessedPacket::ForwardHop(next_packet, next_hop_address, delay) => {
+            ProcessedPacket::ForwardHop(next_packet, next_hop_address, delay, _, _) => {
                 assert_eq!(
                     NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
                     next_hop_address
@@ -158,7 +881,7 @@ mod converting_sphinx_packet_to_and_from_bytes {
         };
 
         let next_sphinx_packet_2 = match next_sphinx_packet_1.process(&node2_sk).unwrap() {
-            ProcessedPacket::ForwardHop(next_packet, next_hop_address, delay) => {
+            ProcessedPacket::ForwardHop(next_packet, next_hop_address, delay, _, _) => {
                 assert_eq!(
                     NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]),
                     next_hop_address
@@ -170,7 +893,7 @@ mod converting_sphinx_packet_to_and_from_bytes {
         };
 
         match next_sphinx_packet_2.process(&node3_sk).unwrap() {
-            ProcessedPacket::FinalHop(_, _, payload) => {
+            ProcessedPacket::FinalHop(_, _, payload, _, _) => {
                 let zero_bytes = vec![0u8; SECURITY_PARAMETER];
                 let additional_padding =
                     vec![0u8; PAYLOAD_SIZE - SECURITY_PARAMETER - message.len() - 1];
@@ -209,10 +932,11 @@ mod converting_sphinx_packet_to_and_from_bytes {
         );
 
         let message = vec![13u8, 16];
-        let sphinx_packet = SphinxPacket::new(message, &route, &destination, &delays).unwrap();
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        let sphinx_packet = SphinxPacket::new(message, &route).unwrap();
 
         let sphinx_packet_bytes = &sphinx_packet.to_bytes()[..300];
-        SphinxPacket::from_bytes(sphinx_packet_bytes).unwrap();
+        SphinxPacket::<crypto::DefaultCipherSuite>::from_bytes(sphinx_packet_bytes).unwrap();
     }
 }
 
@@ -223,10 +947,8 @@ mod create_and_process_surb {
     use sphinx_packet::route::NodeAddressBytes;
     use sphinx_packet::surb::{SURBMaterial, SURB};
     use sphinx_packet::{
-        constants::{NODE_ADDRESS_LENGTH, PAYLOAD_SIZE, SECURITY_PARAMETER},
-        packet::builder::DEFAULT_PAYLOAD_SIZE,
-        test_utils::fixtures::destination_fixture,
-        ProcessedPacket,
+        constants::NODE_ADDRESS_LENGTH, packet::builder::DEFAULT_PAYLOAD_SIZE,
+        test_utils::fixtures::destination_fixture, ProcessedPacket,
     };
     use std::time::Duration;
 
@@ -254,11 +976,17 @@ mod create_and_process_surb {
         let surb_delays =
             delays::generate_from_average_duration(surb_route.len(), Duration::from_secs(3));
 
-        let pre_surb = SURB::new(
+        let (pre_surb, surb_keys): (SURB, _) = SURB::new(
             surb_initial_secret,
-            SURBMaterial::new(surb_route, surb_delays.clone(), surb_destination),
+            SURBMaterial::new(
+                Route::new(surb_route, surb_delays.clone(), surb_destination.clone()).unwrap(),
+            ),
         )
         .unwrap();
+        assert_eq!(&surb_destination.identifier, surb_keys.identifier());
+
+        // the SURB given away does not contain the keys of the hops
+        let pre_surb: SURB = SURB::from_bytes(&pre_surb.to_bytes().unwrap()).unwrap();
 
         let plaintext_message = vec![42u8; 160];
         let (surb_sphinx_packet, first_hop) =
@@ -270,7 +998,7 @@ mod create_and_process_surb {
         );
 
         let next_sphinx_packet_1 = match surb_sphinx_packet.process(&node1_sk).unwrap() {
-            ProcessedPacket::ForwardHop(next_packet, next_hop_addr1, _delay1) => {
+            ProcessedPacket::ForwardHop(next_packet, next_hop_addr1, _delay1, _, _) => {
                 assert_eq!(
                     NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
                     next_hop_addr1
@@ -282,7 +1010,7 @@ mod create_and_process_surb {
         };
 
         let next_sphinx_packet_2 = match next_sphinx_packet_1.process(&node2_sk).unwrap() {
-            ProcessedPacket::ForwardHop(next_packet, next_hop_addr2, _delay2) => {
+            ProcessedPacket::ForwardHop(next_packet, next_hop_addr2, _delay2, _, _) => {
                 assert_eq!(
                     NodeAddressBytes::from_bytes([2u8; NODE_ADDRESS_LENGTH]),
                     next_hop_addr2
@@ -294,15 +1022,711 @@ mod create_and_process_surb {
         };
 
         match next_sphinx_packet_2.process(&node3_sk).unwrap() {
-            ProcessedPacket::FinalHop(_, _, payload) => {
-                let zero_bytes = vec![0u8; SECURITY_PARAMETER];
-                let additional_padding =
-                    vec![0u8; PAYLOAD_SIZE - SECURITY_PARAMETER - plaintext_message.len() - 1];
-                let expected_payload =
-                    [zero_bytes, plaintext_message, vec![1], additional_padding].concat();
-                assert_eq!(expected_payload, payload.as_bytes());
+            ProcessedPacket::FinalHop(_, identifier, payload, _, _) => {
+                assert_eq!(surb_keys.identifier(), &identifier);
+                assert_eq!(DEFAULT_PAYLOAD_SIZE, payload.len());
+                assert_eq!(plaintext_message, surb_keys.recover_reply(payload).unwrap());
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn reply_keys_are_found_in_the_key_store_only_once() {
+        use sphinx_packet::crypto::DefaultCipherSuite;
+        use sphinx_packet::payload::Payload;
+        use sphinx_packet::surb::key_store::{InMemorySurbKeyStore, SurbKeyStore};
+
+        let (node_sk, node_pk) = crypto::keygen();
+        let surb_route = vec![Node {
+            address: NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            pub_key: node_pk,
+        }];
+        let surb_delays =
+            delays::generate_from_average_duration(surb_route.len(), Duration::from_secs(3));
+
+        let mut key_store = InMemorySurbKeyStore::new();
+        let surb: SURB =
+            SURBMaterial::new(Route::new(surb_route, surb_delays, destination_fixture()).unwrap())
+                .construct_SURB_with_key_store(&mut key_store)
+                .unwrap();
+        assert_eq!(1, key_store.len());
+
+        let plaintext_message = vec![42u8; 160];
+        let (packet, _) = surb
+            .use_surb(&plaintext_message, DEFAULT_PAYLOAD_SIZE)
+            .unwrap();
+        let packet_bytes = packet.to_bytes();
+
+        let process = |bytes: &[u8]| match <SphinxPacket>::from_bytes(bytes)
+            .unwrap()
+            .process(&node_sk)
+            .unwrap()
+        {
+            ProcessedPacket::FinalHop(_, identifier, payload, _, _) => (identifier, payload),
+            _ => panic!(),
+        };
+
+        // reply which can not be recovered does not burn the SURB
+        let (identifier, payload) = process(&packet_bytes);
+        let mut forged_payload = payload.as_bytes().to_vec();
+        forged_payload[0] ^= 1;
+        let forged_payload = Payload::from_bytes(&forged_payload).unwrap();
+        assert!(key_store
+            .recover_reply::<DefaultCipherSuite>(&identifier, forged_payload)
+            .is_err());
+        assert_eq!(1, key_store.len());
+
+        assert_eq!(
+            plaintext_message,
+            key_store.recover_reply(&identifier, payload).unwrap()
+        );
+
+        let (identifier, payload) = process(&packet_bytes);
+        assert!(key_store.recover_reply(&identifier, payload).is_err());
+    }
+}
+
+#[cfg(test)]
+mod create_and_process_sphinx_packet_with_surb_ack {
+    use super::*;
+    use sphinx_packet::constants::{
+        DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH,
+    };
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::surb::ack::{extract_ack, ProcessedAckPacket};
+    use sphinx_packet::{
+        AckPacket, ProcessedPacket, SURBAck, SURBMaterial, SphinxPacketBuilder, SphinxParams,
+    };
+    use std::time::Duration;
+
+    fn node(i: u8) -> (crypto::PrivateKey, Node) {
+        let (sk, pk) = crypto::keygen();
+        (
+            sk,
+            Node::new(NodeAddressBytes::from_bytes([i; NODE_ADDRESS_LENGTH]), pk),
+        )
+    }
+
+    #[test]
+    fn final_hop_sends_back_the_ack_of_delivered_packet() {
+        let (node1_sk, node1) = node(1);
+        let (node2_sk, node2) = node(2);
+        let (ack_node_sk, ack_node) = node(3);
+
+        let ack_destination = Destination::new(
+            DestinationAddressBytes::from_bytes([7u8; DESTINATION_ADDRESS_LENGTH]),
+            [8u8; IDENTIFIER_LENGTH],
+        );
+        let ack_delays = delays::generate_from_average_duration(1, Duration::from_secs(1));
+        let ack: SURBAck = SURBAck::new(
+            &crypto::EphemeralSecret::new(),
+            SURBMaterial::new(
+                Route::new(vec![ack_node], ack_delays, ack_destination.clone()).unwrap(),
+            ),
+        )
+        .unwrap();
+
+        let route = [node1, node2];
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+            [4u8; IDENTIFIER_LENGTH],
+        );
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_secs(1));
+        let message = vec![13u8; 100];
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        let packet: SphinxPacket = SphinxPacketBuilder::new()
+            .build_packet_with_ack(&message, &route, &ack)
+            .unwrap();
+
+        let packet = match packet.process(&node1_sk).unwrap() {
+            ProcessedPacket::ForwardHop(packet, ..) => packet,
+            _ => panic!("expected forward hop"),
+        };
+        let payload = match packet.process(&node2_sk).unwrap() {
+            ProcessedPacket::FinalHop(_, _, payload, _, _) => payload,
+            _ => panic!("expected final hop"),
+        };
+
+        let (ack_packet, first_hop, received_message) =
+            extract_ack(payload, &SphinxParams::default()).unwrap();
+        assert_eq!(message, received_message);
+        assert_eq!(
+            NodeAddressBytes::from_bytes([3u8; NODE_ADDRESS_LENGTH]),
+            first_hop
+        );
+
+        // acks consist of just the header
+        let ack_bytes = ack_packet.to_bytes();
+        assert_eq!(ack_packet.len(), ack_bytes.len());
+        let ack_packet: AckPacket = AckPacket::from_bytes(&ack_bytes).unwrap();
+        match ack_packet.process(&ack_node_sk).unwrap() {
+            ProcessedAckPacket::FinalHop(address, identifier, _) => {
+                assert_eq!(ack_destination.address, address);
+                assert_eq!(ack_destination.identifier, identifier);
+            }
+            _ => panic!("expected final hop"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod reporting_failure_back_to_the_sender {
+    use super::*;
+    use sphinx_packet::failure::{
+        Failure, FailureRelay, FailureReport, FailureReturn, FailureReturnRoute,
+        FailureSurbDeposit, FailureSurbStore, ReceivedPacket, POLICY_REJECTION_FAILURE,
+    };
+    use sphinx_packet::header::tlv::TlvStream;
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{
+        constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH},
+        ProcessedPacket, SURBMaterial, SphinxPacketBuilder, SphinxParams,
+    };
+    use std::time::Duration;
+
+    fn node(i: u8) -> (crypto::PrivateKey, Node) {
+        let (sk, pk) = crypto::keygen();
+        (
+            sk,
+            Node::new(NodeAddressBytes::from_bytes([i; NODE_ADDRESS_LENGTH]), pk),
+        )
+    }
+
+    fn destination(i: u8) -> Destination {
+        Destination::new(
+            DestinationAddressBytes::from_bytes([i; DESTINATION_ADDRESS_LENGTH]),
+            [i; IDENTIFIER_LENGTH],
+        )
+    }
+
+    fn route(nodes: Vec<Node>, destination: Destination) -> Route {
+        let delays = delays::generate_from_average_duration(nodes.len(), Duration::from_millis(10));
+        Route::new(nodes, delays, destination).unwrap()
+    }
+
+    fn forward(packet: SphinxPacket, node_sk: &crypto::PrivateKey) -> (SphinxPacket, TlvStream) {
+        match packet.process(node_sk).unwrap() {
+            ProcessedPacket::ForwardHop(next_packet, _, _, records, _) => (*next_packet, records),
+            _ => panic!("packet has not been forwarded"),
+        }
+    }
+
+    // the previous hop receives the report inside a regular packet
+    fn deliver(report_packet: SphinxPacket, node_sk: &crypto::PrivateKey) -> FailureReport {
+        match report_packet.process(node_sk).unwrap() {
+            ProcessedPacket::FinalHop(_, _, payload, records, _) => {
+                FailureReport::from_final_hop(&records, payload)
+                    .unwrap()
+                    .unwrap()
+            }
+            _ => panic!("report has not been delivered"),
+        }
+    }
+
+    #[test]
+    fn sender_learns_which_hop_rejected_the_packet() {
+        let builder = SphinxPacketBuilder::new();
+
+        // the sender deposits the SURB routed back to it with its first hop beforehand
+        let return_nodes: Vec<_> = (4..7).map(node).collect();
+        let return_route = route(
+            return_nodes.iter().map(|(_, node)| node.clone()).collect(),
+            destination(8),
+        );
+        let (surb, surb_keys) = SURBMaterial::new(return_route).construct_SURB().unwrap();
+        let failure_return = FailureReturn::new(*surb_keys.identifier());
+
+        let (node_sks, nodes): (Vec<_>, Vec<_>) = (1..4).map(node).unzip();
+        let deposit_packet = builder
+            .build_failure_surb_deposit(
+                &FailureSurbDeposit::new(*surb_keys.identifier(), surb),
+                &nodes[0],
+            )
+            .unwrap();
+        let mut surb_store = FailureSurbStore::new();
+        match deposit_packet.process(&node_sks[0]).unwrap() {
+            ProcessedPacket::FinalHop(_, _, payload, records, _) => surb_store.deposit(
+                FailureSurbDeposit::from_final_hop(&records, payload, &SphinxParams::default())
+                    .unwrap()
+                    .unwrap(),
+            ),
+            _ => panic!("SURB has not been deposited"),
+        }
+        let (packet, failure_keys) = builder
+            .build_packet_with_failure_return(
+                vec![42u8],
+                &route(nodes.clone(), destination(7)),
+                &failure_return,
+            )
+            .unwrap();
+        let packet_size = packet.len();
+
+        // the first two hops forward the packet and remember where it came from
+        let mut relays: Vec<FailureRelay> = vec![FailureRelay::new(), FailureRelay::new()];
+        let mut packet = packet;
+        for (hop, relay) in relays.iter_mut().enumerate() {
+            let previous_hop = hop.checked_sub(1).map(|previous| nodes[previous].clone());
+            let received = ReceivedPacket::new(&packet, &node_sks[hop], previous_hop);
+            let (next_packet, records) = forward(packet, &node_sks[hop]);
+            relay.remember(received, &next_packet, &records).unwrap();
+            packet = next_packet;
+        }
+
+        // while the last one rejects it
+        let failure = Failure::new(POLICY_REJECTION_FAILURE, b"too many packets".to_vec()).unwrap();
+        let received = ReceivedPacket::new(&packet, &node_sks[2], Some(nodes[1].clone()));
+        let mut route_back = received
+            .report(&failure, &TlvStream::new())
+            .unwrap()
+            .unwrap();
+
+        // and the report travels back hop by hop in packets of the regular size
+        for hop in (0..2).rev() {
+            let report = match route_back {
+                FailureReturnRoute::PreviousHop(previous_hop, report) => {
+                    assert_eq!(nodes[hop].address, previous_hop.address);
+                    let report_packet = builder
+                        .build_failure_report(&report, &previous_hop)
+                        .unwrap();
+                    assert_eq!(packet_size, report_packet.len());
+                    deliver(report_packet, &node_sks[hop])
+                }
+                FailureReturnRoute::Sender(..) => panic!("report has left the route too early"),
+            };
+            route_back = relays[hop].relay(report).unwrap();
+        }
+
+        // until the first hop sends it to the sender with the deposited SURB
+        let (surb_identifier, message) = match route_back {
+            FailureReturnRoute::Sender(surb_identifier, message) => (surb_identifier, message),
+            FailureReturnRoute::PreviousHop(..) => panic!("report has not reached the first hop"),
+        };
+        assert_eq!(failure_return.surb_identifier(), &surb_identifier);
+        let surb = surb_store.take(&surb_identifier).unwrap();
+        let (mut return_packet, first_hop_address) = surb
+            .use_surb(message.as_bytes(), builder.payload_size())
+            .unwrap();
+        assert_eq!(return_nodes[0].1.address, first_hop_address);
+        assert_eq!(packet_size, return_packet.len());
+
+        for (node_sk, _) in &return_nodes[..2] {
+            return_packet = forward(return_packet, node_sk).0;
+        }
+        match return_packet.process(&return_nodes[2].0).unwrap() {
+            ProcessedPacket::FinalHop(address, _, payload, _, _) => {
+                assert_eq!(destination(8).address, address);
+                let reply = surb_keys.recover_reply(payload).unwrap();
+                assert_eq!((2, failure), failure_keys.recover_failure(&reply).unwrap());
+            }
+            _ => panic!("report has not reached the sender"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod sending_fragmented_message {
+    use super::*;
+    use sphinx_packet::constants::{
+        DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH,
+    };
+    use sphinx_packet::fragment::{self, Reassembler};
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{ProcessedPacket, SphinxPacketBuilder};
+    use std::time::Duration;
+
+    #[test]
+    fn message_longer_than_payload_is_reassembled_at_the_final_hop() {
+        let (node_sk, node_pk) = crypto::keygen();
+        let route = [Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node_pk,
+        )];
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+            [4u8; IDENTIFIER_LENGTH],
+        );
+        let delays = delays::generate_from_average_duration(route.len(), Duration::from_secs(1));
+
+        let builder = SphinxPacketBuilder::new().with_payload_size(256);
+        let message: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let fragments = fragment::split_message(&message, builder.payload_size()).unwrap();
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+        let mut reassembled = None;
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        for fragment in fragments.into_iter().rev() {
+            let packet: SphinxPacket = builder.build_packet(fragment.to_bytes(), &route).unwrap();
+            match packet.process(&node_sk).unwrap() {
+                ProcessedPacket::FinalHop(_, _, payload, _, _) => {
+                    reassembled = reassembler.insert_payload(payload).unwrap()
+                }
+                _ => panic!("expected final hop"),
+            }
+        }
+        assert_eq!(Some(message), reassembled);
+    }
+}
+
+#[cfg(test)]
+mod sealing_message_to_the_recipient {
+    use super::*;
+    use sphinx_packet::recipient;
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{
+        constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH},
+        ProcessedPacket, SphinxPacketBuilder,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn final_hop_only_sees_the_ciphertext_which_recipient_can_open() {
+        let (node1_sk, node1_pk) = crypto::keygen();
+        let node1 = Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node1_pk,
+        );
+        let (node2_sk, node2_pk) = crypto::keygen();
+        let node2 = Node::new(
+            NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+            node2_pk,
+        );
+        let (recipient_sk, recipient_pk) = crypto::keygen();
+
+        let route = [node1, node2];
+        let average_delay = Duration::from_secs_f64(1.0);
+        let delays = delays::generate_from_average_duration(route.len(), average_delay);
+        let destination_address =
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]);
+        let destination = Destination::new(destination_address, [4u8; IDENTIFIER_LENGTH]);
+
+        let message = vec![13u8, 16];
+        let route = Route::new(route.to_vec(), delays, destination).unwrap();
+        let sphinx_packet: SphinxPacket = SphinxPacketBuilder::new()
+            .with_recipient_key(recipient_pk)
+            .build_packet(&message, &route)
+            .unwrap();
+
+        let next_sphinx_packet = match sphinx_packet.process(&node1_sk).unwrap() {
+            ProcessedPacket::ForwardHop(next_packet, _, _, _, _) => next_packet,
+            _ => panic!(),
+        };
+
+        match next_sphinx_packet.process(&node2_sk).unwrap() {
+            ProcessedPacket::FinalHop(destination, _, payload, _, _) => {
+                assert_eq!(destination_address, destination);
+                let sealed = payload.recover_plaintext().unwrap();
+                assert_eq!(
+                    message.len() + recipient::SEALED_MESSAGE_OVERHEAD,
+                    sealed.len()
+                );
+                assert_ne!(message, sealed[sealed.len() - message.len()..]);
+                assert_eq!(message, recipient::open(&sealed, &recipient_sk).unwrap());
             }
             _ => panic!(),
         };
     }
 }
+
+#[cfg(test)]
+mod reaching_recipient_through_blinded_route {
+    use super::*;
+    use sphinx_packet::blinded::BlindedRoute;
+    use sphinx_packet::crypto::DefaultCipherSuite;
+    use sphinx_packet::crypto::PrivateKey;
+    use sphinx_packet::packet::ProcessedPacketInPlace;
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{
+        constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH},
+        ProcessedPacket, SphinxPacketBuilder, SphinxParams,
+    };
+    use std::time::Duration;
+
+    // records of the blinded hops do not fit in the routing information of the default params
+    fn params() -> SphinxParams {
+        SphinxParams::new(10, NODE_ADDRESS_LENGTH, 16, 8).unwrap()
+    }
+
+    fn node(address: u8) -> (PrivateKey, Node) {
+        let (secret_key, public_key) = crypto::keygen();
+        let address = NodeAddressBytes::from_bytes([address; NODE_ADDRESS_LENGTH]);
+        (secret_key, Node::new(address, public_key))
+    }
+
+    // the sender only knows the first node, while the recipient picks the other three
+    fn setup() -> (Vec<PrivateKey>, Vec<Node>, Destination, SphinxPacket) {
+        let (secret_keys, nodes): (Vec<_>, Vec<_>) = (1..=4).map(node).unzip();
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+            [4u8; IDENTIFIER_LENGTH],
+        );
+
+        let published = BlindedRoute::new(&nodes[1..], &destination)
+            .unwrap()
+            .to_bytes();
+        let blinded_route = BlindedRoute::from_bytes(&published).unwrap();
+        assert_eq!(nodes[1].address, blinded_route.introduction_node());
+
+        let delays = delays::generate_from_average_duration(4, Duration::from_millis(10));
+        let packet = SphinxPacketBuilder::new()
+            .with_params(params())
+            .build_packet_with_blinded_route(b"foomp", &nodes[..1], &blinded_route, delays)
+            .unwrap();
+        (secret_keys, nodes, destination, packet)
+    }
+
+    #[test]
+    fn each_hop_learns_only_its_next_hop() {
+        let (secret_keys, nodes, destination, mut packet) = setup();
+
+        for (i, secret_key) in secret_keys.iter().enumerate() {
+            match packet.process(secret_key).unwrap() {
+                ProcessedPacket::ForwardHop(next_packet, next_hop_address, _, _, _) => {
+                    assert_eq!(nodes[i + 1].address, next_hop_address);
+                    packet = *next_packet;
+                }
+                ProcessedPacket::FinalHop(destination_address, identifier, payload, _, _) => {
+                    assert_eq!(3, i);
+                    assert_eq!(destination.address, destination_address);
+                    assert_eq!(destination.identifier, identifier);
+                    assert_eq!(b"foomp".to_vec(), payload.recover_plaintext().unwrap());
+                    return;
+                }
+                _ => panic!(),
+            }
+        }
+        panic!("packet has not reached its destination")
+    }
+
+    #[test]
+    fn it_can_be_processed_in_place() {
+        let (secret_keys, nodes, destination, packet) = setup();
+        let mut buffer = packet.to_bytes();
+
+        for (i, secret_key) in secret_keys.iter().enumerate() {
+            match SphinxPacket::<DefaultCipherSuite>::process_in_place_with_params(
+                &mut buffer,
+                secret_key,
+                &params(),
+            )
+            .unwrap()
+            {
+                ProcessedPacketInPlace::ForwardHop(_, next_hop_address, _, _, _) => {
+                    assert_eq!(nodes[i + 1].address, next_hop_address);
+                }
+                ProcessedPacketInPlace::FinalHop(destination_address, identifier, _, _, _) => {
+                    assert_eq!(3, i);
+                    assert_eq!(destination.address, destination_address);
+                    assert_eq!(destination.identifier, identifier);
+                    return;
+                }
+                _ => panic!(),
+            }
+        }
+        panic!("packet has not reached its destination")
+    }
+
+    #[test]
+    fn blinded_hop_can_not_be_processed_by_any_other_node() {
+        let (_, _, _, packet) = setup();
+        let (other_secret_key, _) = node(9);
+        assert!(packet.process(&other_secret_key).is_err());
+    }
+}
+
+#[cfg(test)]
+mod forwarding_inner_packet_at_trampoline_hop {
+    use super::*;
+    use sphinx_packet::crypto::{DefaultCipherSuite, PrivateKey};
+    use sphinx_packet::packet::ProcessedPacketInPlace;
+    use sphinx_packet::payload::Payload;
+    use sphinx_packet::route::{DestinationAddressBytes, NodeAddressBytes};
+    use sphinx_packet::{
+        constants::{DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH},
+        ProcessedPacket, SphinxPacketBuilder,
+    };
+    use std::time::Duration;
+
+    fn route_of(addresses: std::ops::RangeInclusive<u8>) -> (Vec<PrivateKey>, Vec<Node>) {
+        addresses
+            .map(|address| {
+                let (secret_key, public_key) = crypto::keygen();
+                let address = NodeAddressBytes::from_bytes([address; NODE_ADDRESS_LENGTH]);
+                (secret_key, Node::new(address, public_key))
+            })
+            .unzip()
+    }
+
+    // both routes are as long as a single packet allows
+    fn setup() -> (Vec<PrivateKey>, Vec<Node>, Destination, SphinxPacket) {
+        let (outer_sks, outer_nodes) = route_of(1..=5);
+        let (inner_sks, inner_nodes) = route_of(6..=10);
+        let destination = Destination::new(
+            DestinationAddressBytes::from_bytes([11u8; DESTINATION_ADDRESS_LENGTH]),
+            [12u8; IDENTIFIER_LENGTH],
+        );
+
+        let delays = delays::generate_from_average_duration(5, Duration::from_millis(10));
+        let inner_route =
+            Route::new(inner_nodes.clone(), delays.clone(), destination.clone()).unwrap();
+        let inner_packet = SphinxPacketBuilder::new()
+            .build_inner_packet(b"foomp", &inner_route)
+            .unwrap();
+
+        let packet = SphinxPacketBuilder::new()
+            .build_packet_with_inner_packet(
+                &inner_packet,
+                inner_nodes[0].address,
+                &outer_nodes,
+                delays,
+            )
+            .unwrap();
+
+        let secret_keys = outer_sks.into_iter().chain(inner_sks).collect();
+        let nodes = outer_nodes.into_iter().chain(inner_nodes).collect();
+        (secret_keys, nodes, destination, packet)
+    }
+
+    fn ordinary_packet(nodes: &[Node], destination: Destination) -> SphinxPacket {
+        let delays = delays::generate_from_average_duration(5, Duration::from_millis(10));
+        let ordinary_route = Route::new(nodes.to_vec(), delays, destination).unwrap();
+        SphinxPacketBuilder::new()
+            .build_packet(b"foomp", &ordinary_route)
+            .unwrap()
+    }
+
+    #[test]
+    fn packet_traverses_both_routes() {
+        let (secret_keys, nodes, destination, mut packet) = setup();
+
+        let payload_tail =
+            |packet: &SphinxPacket| packet.payload.as_bytes()[packet.payload.len() - 32..].to_vec();
+        for (i, secret_key) in secret_keys.iter().enumerate() {
+            let previous_payload_tail = payload_tail(&packet);
+            match packet.process(secret_key).unwrap() {
+                ProcessedPacket::ForwardHop(next_packet, next_hop_address, _, _, _) => {
+                    assert_ne!(4, i);
+                    assert_eq!(nodes[i + 1].address, next_hop_address);
+                    // the padding of the inner route is never forwarded unchanged
+                    if i > 4 {
+                        assert_ne!(previous_payload_tail, payload_tail(&next_packet));
+                    }
+                    packet = *next_packet;
+                }
+                ProcessedPacket::TrampolineHop(inner_packet, next_hop_address, _, _) => {
+                    assert_eq!(4, i);
+                    assert_eq!(nodes[i + 1].address, next_hop_address);
+                    packet = *inner_packet;
+                }
+                ProcessedPacket::FinalHop(destination_address, identifier, payload, _, _) => {
+                    assert_eq!(9, i);
+                    assert_eq!(destination.address, destination_address);
+                    assert_eq!(destination.identifier, identifier);
+                    assert_eq!(b"foomp".to_vec(), payload.recover_plaintext().unwrap());
+                    return;
+                }
+            }
+        }
+        panic!("packet has not reached its destination")
+    }
+
+    #[test]
+    fn trampoline_hop_can_be_processed_in_place() {
+        let (secret_keys, nodes, _, packet) = setup();
+        let packet_length = packet.len();
+        let mut buffer = packet.to_bytes();
+
+        for (i, secret_key) in secret_keys[..5].iter().enumerate() {
+            match SphinxPacket::<DefaultCipherSuite>::process_in_place(&mut buffer, secret_key)
+                .unwrap()
+            {
+                ProcessedPacketInPlace::ForwardHop(..) => assert_ne!(4, i),
+                ProcessedPacketInPlace::TrampolineHop(next_hop_address, payload, _, _) => {
+                    assert_eq!(4, i);
+                    assert_eq!(nodes[5].address, next_hop_address);
+                    let inner_packet: SphinxPacket = SphinxPacket::recover_inner_packet(
+                        Payload::from_bytes(payload).unwrap(),
+                        &Default::default(),
+                    )
+                    .unwrap();
+                    assert_eq!(packet_length, inner_packet.len());
+                    assert!(matches!(
+                        inner_packet.process(&secret_keys[5]).unwrap(),
+                        ProcessedPacket::ForwardHop(_, address, ..) if address == nodes[6].address
+                    ));
+                    return;
+                }
+                _ => panic!(),
+            }
+        }
+        panic!("packet has not reached the trampoline hop")
+    }
+
+    #[test]
+    fn outer_and_forwarded_inner_packets_are_of_the_same_size_as_ordinary_packets() {
+        let (secret_keys, nodes, destination, mut packet) = setup();
+        let ordinary_packet = ordinary_packet(&nodes[5..], destination);
+        assert_eq!(ordinary_packet.len(), packet.len());
+
+        for secret_key in &secret_keys[..9] {
+            packet = match packet.process(secret_key).unwrap() {
+                ProcessedPacket::ForwardHop(next_packet, ..) => *next_packet,
+                ProcessedPacket::TrampolineHop(inner_packet, ..) => *inner_packet,
+                _ => panic!("packet has reached its destination too early"),
+            };
+            assert_eq!(ordinary_packet.len(), packet.len());
+        }
+    }
+
+    #[test]
+    fn inner_packet_has_to_be_built_to_fit_in_the_ordinary_payload() {
+        let (_, nodes, destination, _) = setup();
+        let ordinary_packet = ordinary_packet(&nodes[5..], destination);
+
+        let delays = delays::generate_from_average_duration(5, Duration::from_millis(10));
+        let result: sphinx_packet::Result<SphinxPacket> = SphinxPacketBuilder::new()
+            .build_packet_with_inner_packet(
+                &ordinary_packet,
+                nodes[5].address,
+                &nodes[..5],
+                delays,
+            );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn inner_route_hops_can_be_processed_in_place() {
+        let (secret_keys, _, destination, packet) = setup();
+        let mut packet = packet;
+        for secret_key in &secret_keys[..5] {
+            packet = match packet.process(secret_key).unwrap() {
+                ProcessedPacket::ForwardHop(next_packet, ..) => *next_packet,
+                ProcessedPacket::TrampolineHop(inner_packet, ..) => *inner_packet,
+                _ => panic!(),
+            };
+        }
+
+        let mut buffer = packet.to_bytes();
+        for (i, secret_key) in secret_keys[5..].iter().enumerate() {
+            match SphinxPacket::<DefaultCipherSuite>::process_in_place(&mut buffer, secret_key)
+                .unwrap()
+            {
+                ProcessedPacketInPlace::ForwardHop(..) => assert_ne!(4, i),
+                ProcessedPacketInPlace::FinalHop(destination_address, _, payload, _, _) => {
+                    assert_eq!(4, i);
+                    assert_eq!(destination.address, destination_address);
+                    assert_eq!(
+                        b"foomp".to_vec(),
+                        Payload::<DefaultCipherSuite>::from_bytes(payload)
+                            .unwrap()
+                            .recover_plaintext()
+                            .unwrap()
+                    );
+                    return;
+                }
+                _ => panic!(),
+            }
+        }
+        panic!("packet has not reached its destination")
+    }
+}
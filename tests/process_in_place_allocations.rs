@@ -0,0 +1,102 @@
+// Copyright 2020 Nym Technologies SA
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// kept apart from the other integration tests as it replaces the global allocator
+
+extern crate sphinx_packet;
+
+use sphinx_packet::constants::{
+    DESTINATION_ADDRESS_LENGTH, IDENTIFIER_LENGTH, NODE_ADDRESS_LENGTH,
+};
+use sphinx_packet::crypto;
+use sphinx_packet::header::delays;
+use sphinx_packet::packet::ProcessedPacketInPlace;
+use sphinx_packet::route::{Destination, DestinationAddressBytes, Node, NodeAddressBytes, Route};
+use sphinx_packet::SphinxPacket;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::time::Duration;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|allocations| allocations.get())
+}
+
+#[test]
+fn processing_in_place_does_not_allocate() {
+    let (node1_sk, node1_pk) = crypto::keygen();
+    let (node2_sk, node2_pk) = crypto::keygen();
+    let route = [
+        Node::new(
+            NodeAddressBytes::from_bytes([5u8; NODE_ADDRESS_LENGTH]),
+            node1_pk,
+        ),
+        Node::new(
+            NodeAddressBytes::from_bytes([4u8; NODE_ADDRESS_LENGTH]),
+            node2_pk,
+        ),
+    ];
+    let destination = Destination::new(
+        DestinationAddressBytes::from_bytes([3u8; DESTINATION_ADDRESS_LENGTH]),
+        [4u8; IDENTIFIER_LENGTH],
+    );
+    let delays = delays::generate_from_average_duration(route.len(), Duration::from_secs(1));
+    let message = vec![13u8; 100];
+    let route = Route::new(route.to_vec(), delays.clone(), destination.clone()).unwrap();
+    let packet = SphinxPacket::new(message.clone(), &route).unwrap();
+    let mut buffer = packet.to_bytes();
+
+    let before = allocations();
+    match <SphinxPacket>::process_in_place(&mut buffer, &node1_sk).unwrap() {
+        ProcessedPacketInPlace::ForwardHop(_, next_hop_address, delay, _, _) => {
+            assert_eq!(route.nodes()[1].address, next_hop_address);
+            assert_eq!(delays[0], delay);
+        }
+        _ => panic!("expected forward hop"),
+    }
+    let payload = match <SphinxPacket>::process_in_place(&mut buffer, &node2_sk).unwrap() {
+        ProcessedPacketInPlace::FinalHop(address, _, payload, _, _) => {
+            assert_eq!(destination.address, address);
+            payload
+        }
+        _ => panic!("expected final hop"),
+    };
+    assert_eq!(before, allocations());
+
+    let plaintext =
+        sphinx_packet::payload::Payload::<crypto::DefaultCipherSuite>::from_bytes(payload)
+            .unwrap()
+            .recover_plaintext()
+            .unwrap();
+    assert_eq!(message, plaintext);
+}